@@ -1,24 +1,67 @@
 //! Tanu Markdown CLI entrypoint.
 
 use std::fs;
+use std::io::{BufRead, IsTerminal, Write};
 use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, bail, Context, Result};
 use base64::engine::general_purpose::STANDARD as BASE64_STANDARD;
 use base64::Engine;
-use clap::{Parser, Subcommand};
-use html_escape::encode_text;
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
+use html_escape::{encode_double_quoted_attribute, encode_text};
 use pulldown_cmark::{html, Options, Parser as MdParser};
 use rusqlite::types::Value as SqlValue;
-use tmd_core::{export_db, import_db, read_from_path, reset_db, write_to_path, Format, TmdDoc};
+use rusqlite::OptionalExtension;
+use tmd_core::{
+    export_db, import_db, probe, read_from_path, read_from_path_with_mode, read_markdown_from_path,
+    read_raw_manifest, read_tmd_buffered, reset_db, write_to_path, write_to_path_atomic,
+    write_to_path_best, write_to_path_with_mode, CompressionHint, Format, ReadMode, SanitizePolicy,
+    SqlCell, TmdDoc, WriteMode,
+};
 
 #[derive(Parser)]
 #[command(name = "tmd", version, about = "Tanu Markdown CLI")]
 struct Cli {
+    /// Print library diagnostics to stderr (`-v` for info, `-vv` for debug).
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    verbose: u8,
     #[command(subcommand)]
     command: Commands,
 }
 
+/// Writes `log` records from the core library to stderr.
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!(
+                "[{} {}] {}",
+                record.level().as_str().to_ascii_lowercase(),
+                record.target(),
+                record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+fn init_logging(verbose: u8) {
+    let level = match verbose {
+        0 => return,
+        1 => log::LevelFilter::Info,
+        _ => log::LevelFilter::Debug,
+    };
+    if log::set_logger(&StderrLogger).is_ok() {
+        log::set_max_level(level);
+    }
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Create a new `.tmd` or `.tmdz` document with an embedded SQLite database.
@@ -26,23 +69,120 @@ enum Commands {
         output: PathBuf,
         #[arg(long)]
         title: Option<String>,
+        /// Copy content from an existing document, assigning a new `doc_id`.
+        #[arg(long)]
+        from: Option<PathBuf>,
+        /// Treat OUTPUT as a stem; write `.tmdz` only if compression shrinks the archive.
+        #[arg(long)]
+        auto: bool,
     },
     /// Convert between `.tmd` and `.tmdz` containers.
-    Convert { input: PathBuf, output: PathBuf },
-    /// Validate a `.tmd` or `.tmdz` document.
-    Validate { input: PathBuf },
+    Convert {
+        input: PathBuf,
+        output: PathBuf,
+        /// Treat OUTPUT as a stem; write `.tmdz` only if compression shrinks the archive.
+        #[arg(long)]
+        auto: bool,
+        /// Deflate every attachment, the text entries, and the embedded database.
+        #[arg(long, conflicts_with = "no_compress")]
+        compress: bool,
+        /// Store every attachment, the text entries, and the embedded database uncompressed.
+        #[arg(long)]
+        no_compress: bool,
+    },
+    /// Replace the embedded database with an empty one, keeping markdown and attachments.
+    StripDb { doc: PathBuf },
+    /// Validate a `.tmd` or `.tmdz` document (`-` reads from stdin).
+    Validate {
+        input: PathBuf,
+        /// Repair a manifest `db_schema_version` that disagrees with the database.
+        #[arg(long)]
+        fix: bool,
+        /// Warn if the `doc_id` appears in this registry file (one id per line).
+        #[arg(long, value_name = "REGISTRY")]
+        check_unique: Option<PathBuf>,
+        /// Also run `PRAGMA integrity_check` on the embedded database.
+        #[arg(long)]
+        deep: bool,
+    },
+    /// Print the JSON Schema for `manifest.json` and attachment metadata.
+    Schema,
+    /// Build a document from a folder of markdown and assets.
+    Import {
+        dir: PathBuf,
+        output: PathBuf,
+        /// Markdown file within `dir` that becomes the document body.
+        #[arg(long, default_value = "index.md")]
+        entry: String,
+        /// Lowercase attachment paths and replace spaces and punctuation so they need
+        /// no escaping in links.
+        #[arg(long)]
+        sanitize: bool,
+    },
+    /// Unpack a document into a directory of its raw parts.
+    Explode { input: PathBuf, dir: PathBuf },
+    /// Build a document from a directory created by `explode`.
+    Pack { dir: PathBuf, output: PathBuf },
+    /// Write the whole document, including binary content as base64, to a JSON file.
+    ToJson { input: PathBuf, output: PathBuf },
+    /// Build a document from a JSON file created by `to-json`.
+    FromJson { input: PathBuf, output: PathBuf },
+    /// Report attachments unused by the markdown and references to missing attachments.
+    Lint { input: PathBuf },
+    /// Describe a file's container structure to diagnose why it fails to open.
+    Probe {
+        input: PathBuf,
+        /// Also print `manifest.json` exactly as stored, without parsing it.
+        #[arg(long)]
+        manifest: bool,
+    },
+    /// Print a document's markdown to stdout without loading its database or attachments.
+    Cat {
+        input: PathBuf,
+        /// Print exactly the stored bytes, without adding a final newline.
+        #[arg(long)]
+        raw: bool,
+    },
     /// Export a `.tmd`/`.tmdz` document to HTML.
     ExportHtml {
         input: PathBuf,
         output: PathBuf,
         #[arg(long)]
         self_contained: bool,
+        /// Embed the database as a downloadable `main.sqlite3` link.
+        #[arg(long)]
+        include_db: bool,
+        /// Render the results of this query as a table after the document body.
+        #[arg(long, value_name = "SQL")]
+        db_query: Option<String>,
     },
     /// Database maintenance commands.
     Db {
         #[command(subcommand)]
         command: DbCommands,
     },
+    /// Attachment inspection commands.
+    Attachments {
+        #[command(subcommand)]
+        command: AttachmentCommands,
+    },
+    /// Apply a manifest change to every document matching a glob pattern.
+    Batch {
+        #[command(subcommand)]
+        command: BatchCommands,
+    },
+}
+
+#[derive(Subcommand)]
+enum BatchCommands {
+    /// Add a tag to each matching document.
+    AddTag { pattern: String, tag: String },
+    /// Remove a tag from each matching document.
+    RemoveTag { pattern: String, tag: String },
+    /// Append an author to each matching document.
+    AddAuthor { pattern: String, author: String },
+    /// Replace the author list of each matching document with a single author.
+    SetAuthor { pattern: String, author: String },
 }
 
 #[derive(Subcommand)]
@@ -60,42 +200,175 @@ enum DbCommands {
         doc: PathBuf,
         #[arg(long)]
         sql: String,
+        /// How BLOB cells are rendered in the output.
+        #[arg(long, value_enum, default_value_t = BlobFormat::Skip)]
+        blob_as: BlobFormat,
+        /// How result rows are printed.
+        #[arg(long, value_enum, default_value_t = RowFormat::Table)]
+        output: RowFormat,
     },
     /// Import a SQLite file, replacing the embedded database.
     Import { doc: PathBuf, source: PathBuf },
     /// Export the embedded SQLite database to a standalone file.
     Export { doc: PathBuf, output: PathBuf },
+    /// Set the manifest schema version to the database's `PRAGMA user_version`.
+    SyncVersion { doc: PathBuf },
+    /// Print the CREATE statements of the embedded database.
+    Schema { doc: PathBuf },
+    /// Run SQL interactively over one connection, saving the document on exit.
+    ///
+    /// Statements end with `;`. Meta-commands: `.tables`, `.schema`, `.save`, `.quit`.
+    Repl {
+        doc: PathBuf,
+        /// How BLOB cells are rendered in the output.
+        #[arg(long, value_enum, default_value_t = BlobFormat::Skip)]
+        blob_as: BlobFormat,
+    },
+    /// Execute a query stored by name in the `_queries` table.
+    Run {
+        doc: PathBuf,
+        query_name: String,
+        /// How BLOB cells are rendered in the output.
+        #[arg(long, value_enum, default_value_t = BlobFormat::Skip)]
+        blob_as: BlobFormat,
+        /// How result rows are printed.
+        #[arg(long, value_enum, default_value_t = RowFormat::Table)]
+        output: RowFormat,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum BlobFormat {
+    Hex,
+    Base64,
+    Skip,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum RowFormat {
+    /// Markdown table.
+    Table,
+    /// RFC 4180 CSV with a header row.
+    Csv,
+    /// One JSON object per row, keyed by column name.
+    Ndjson,
+}
+
+#[derive(Subcommand)]
+enum AttachmentCommands {
+    /// Report attachments with identical content and the bytes deduplication would save.
+    Dupes { doc: PathBuf },
+    /// Write attachments matching a glob (`*`, `?`, `**`) into a directory.
+    Extract {
+        doc: PathBuf,
+        pattern: String,
+        dir: PathBuf,
+    },
 }
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
+    init_logging(cli.verbose);
     match cli.command {
-        Commands::New { output, title } => cmd_new(&output, title.as_deref()),
-        Commands::Convert { input, output } => cmd_convert(&input, &output),
-        Commands::Validate { input } => cmd_validate(&input),
+        Commands::New {
+            output,
+            title,
+            from,
+            auto,
+        } => match from {
+            Some(template) => cmd_new_from_template(&output, &template, title.as_deref(), auto),
+            None => cmd_new(&output, title.as_deref(), auto),
+        },
+        Commands::Convert {
+            input,
+            output,
+            auto,
+            compress,
+            no_compress,
+        } => {
+            let compression = (compress || no_compress).then_some(compress);
+            cmd_convert(&input, &output, auto, compression)
+        }
+        Commands::StripDb { doc } => cmd_strip_db(&doc),
+        Commands::Validate {
+            input,
+            fix,
+            check_unique,
+            deep,
+        } => cmd_validate(&input, fix, check_unique.as_deref(), deep),
+        Commands::Lint { input } => cmd_lint(&input),
+        Commands::Probe { input, manifest } => cmd_probe(&input, manifest),
+        Commands::Cat { input, raw } => cmd_cat(&input, raw),
+        Commands::Schema => cmd_schema(),
+        Commands::Import {
+            dir,
+            output,
+            entry,
+            sanitize,
+        } => cmd_import(&dir, &output, &entry, sanitize),
+        Commands::Explode { input, dir } => cmd_explode(&input, &dir),
+        Commands::Pack { dir, output } => cmd_pack(&dir, &output),
+        Commands::ToJson { input, output } => cmd_to_json(&input, &output),
+        Commands::FromJson { input, output } => cmd_from_json(&input, &output),
         Commands::ExportHtml {
             input,
             output,
             self_contained,
-        } => cmd_export_html(&input, &output, self_contained),
+            include_db,
+            db_query,
+        } => cmd_export_html(
+            &input,
+            &output,
+            self_contained,
+            include_db,
+            db_query.as_deref(),
+        ),
         Commands::Db { command } => match command {
             DbCommands::Init {
                 doc,
                 schema,
                 version,
             } => cmd_db_init(&doc, schema.as_deref(), version),
-            DbCommands::Exec { doc, sql } => cmd_db_exec(&doc, &sql),
+            DbCommands::Exec {
+                doc,
+                sql,
+                blob_as,
+                output,
+            } => cmd_db_exec(&doc, &sql, blob_as, output),
             DbCommands::Import { doc, source } => cmd_db_import(&doc, &source),
             DbCommands::Export { doc, output } => cmd_db_export(&doc, &output),
+            DbCommands::SyncVersion { doc } => cmd_db_sync_version(&doc),
+            DbCommands::Schema { doc } => cmd_db_schema(&doc),
+            DbCommands::Repl { doc, blob_as } => cmd_db_repl(&doc, blob_as),
+            DbCommands::Run {
+                doc,
+                query_name,
+                blob_as,
+                output,
+            } => cmd_db_run(&doc, &query_name, blob_as, output),
+        },
+        Commands::Attachments { command } => match command {
+            AttachmentCommands::Dupes { doc } => cmd_attachments_dupes(&doc),
+            AttachmentCommands::Extract { doc, pattern, dir } => {
+                cmd_attachments_extract(&doc, &pattern, &dir)
+            }
+        },
+        Commands::Batch { command } => match command {
+            BatchCommands::AddTag { pattern, tag } => cmd_batch(&pattern, |doc| doc.add_tag(&tag)),
+            BatchCommands::RemoveTag { pattern, tag } => {
+                cmd_batch(&pattern, |doc| doc.remove_tag(&tag))
+            }
+            BatchCommands::AddAuthor { pattern, author } => {
+                cmd_batch(&pattern, |doc| doc.add_author(&author))
+            }
+            BatchCommands::SetAuthor { pattern, author } => {
+                cmd_batch(&pattern, |doc| doc.set_authors([author.as_str()]))
+            }
         },
     }
 }
 
-fn cmd_new(path: &Path, title: Option<&str>) -> Result<()> {
-    anyhow::ensure!(!path.exists(), "target `{}` already exists", path.display());
-    ensure_parent_directory(path)?;
-
-    let format = detect_format(path)?;
+fn cmd_new(path: &Path, title: Option<&str>, auto: bool) -> Result<()> {
     let display_title = title.unwrap_or("New TMD Document");
     let markdown = format!(
         "# {}\n\nWelcome to **Tanu Markdown**!\n\nThe embedded database is ready for use.",
@@ -105,7 +378,7 @@ fn cmd_new(path: &Path, title: Option<&str>) -> Result<()> {
     doc.manifest.title = Some(display_title.to_string());
     doc.touch();
 
-    write_document(path, &doc, format)?;
+    let (path, format) = write_new_document(path, &doc, auto)?;
     println!(
         "Created new {} document at {}",
         format_display(format),
@@ -114,45 +387,353 @@ fn cmd_new(path: &Path, title: Option<&str>) -> Result<()> {
     Ok(())
 }
 
-fn cmd_convert(input: &Path, output: &Path) -> Result<()> {
+fn cmd_new_from_template(
+    path: &Path,
+    template: &Path,
+    title: Option<&str>,
+    auto: bool,
+) -> Result<()> {
+    let (mut doc, _) = read_document(template)?;
+    let template_id = doc.manifest.doc_id;
+    let doc_id = doc.regenerate_doc_id();
+    doc.manifest.created_utc = doc.manifest.modified_utc;
+    if let Some(title) = title {
+        doc.manifest.title = Some(title.to_string());
+    }
+
+    let (path, format) = write_new_document(path, &doc, auto)?;
+    println!(
+        "Created new {} document at {} from `{}` (doc_id {} -> {})",
+        format_display(format),
+        path.display(),
+        template.display(),
+        template_id,
+        doc_id
+    );
+    Ok(())
+}
+
+/// Convert between formats. `compression` forces every entry to be deflated
+/// (`Some(true)`) or stored (`Some(false)`); `None` keeps each attachment's hint.
+fn cmd_convert(input: &Path, output: &Path, auto: bool, compression: Option<bool>) -> Result<()> {
+    let before = fs::metadata(input)
+        .with_context(|| format!("failed to stat `{}`", input.display()))?
+        .len();
     let (doc, _) = read_document(input)?;
+    let mut mode = WriteMode::default();
+    if let Some(compress) = compression {
+        mode.attachment_compression = Some(if compress {
+            CompressionHint::Deflate
+        } else {
+            CompressionHint::Store
+        });
+        mode.deflate_text = compress;
+        mode.compress_db = compress;
+    }
+
+    let output = if auto {
+        let stem = auto_stem(output);
+        ensure_parent_directory(&stem)?;
+        write_document_best(&stem, &doc, mode)?.0
+    } else {
+        let format = detect_format(output)?;
+        ensure_parent_directory(output)?;
+        write_document_with_mode(output, &doc, format, mode)?;
+        output.to_path_buf()
+    };
+    let after = fs::metadata(&output)?.len();
+    println!(
+        "Converted `{}` ({} bytes) into `{}` ({} bytes, {:+.1}%)",
+        input.display(),
+        before,
+        output.display(),
+        after,
+        (after as f64 - before as f64) * 100.0 / before.max(1) as f64
+    );
+    Ok(())
+}
+
+fn cmd_strip_db(doc_path: &Path) -> Result<()> {
+    let (mut doc, format) = read_document(doc_path)?;
+    doc.strip_db()
+        .context("failed to replace the embedded database")?;
+    write_document(doc_path, &doc, format)?;
+    println!(
+        "Stripped the embedded database from `{}`",
+        doc_path.display()
+    );
+    Ok(())
+}
+
+fn cmd_validate(input: &Path, fix: bool, registry: Option<&Path>, deep: bool) -> Result<()> {
+    if input == Path::new("-") {
+        anyhow::ensure!(!fix, "--fix cannot rewrite a document read from stdin");
+        let doc = read_tmd_buffered(std::io::stdin().lock(), ReadMode::default())
+            .context("failed to read document from stdin")?;
+        return report_validation(Path::new("<stdin>"), &doc, registry, deep);
+    }
+
+    let (mut doc, format) = read_document(input)?;
+    if fix {
+        let user_version = doc
+            .db_user_version()
+            .context("failed to read PRAGMA user_version from embedded database")?;
+        if let Some(expected) = doc.manifest.db_schema_version {
+            if expected != user_version {
+                doc.sync_schema_version()
+                    .context("failed to sync schema version")?;
+                write_document(input, &doc, format)?;
+                println!(
+                    "Repaired manifest db_schema_version {} -> {}",
+                    expected, user_version
+                );
+            }
+        }
+    }
+
+    report_validation(input, &doc, registry, deep)
+}
+
+fn report_validation(
+    input: &Path,
+    doc: &TmdDoc,
+    registry: Option<&Path>,
+    deep: bool,
+) -> Result<()> {
+    let report = doc.validate_full();
+    for issue in report.errors() {
+        println!("{}", issue);
+    }
+    let mut errors = report.errors().count();
+    if deep {
+        let problems = doc
+            .db_integrity_check()
+            .context("failed to run integrity check on embedded database")?;
+        for problem in &problems {
+            println!("error [database]: integrity check: {}", problem);
+        }
+        errors += problems.len();
+    }
+    for issue in report.warnings() {
+        println!("{}", issue);
+    }
+
+    let mut warnings = report.warnings().count();
+    if let Some(registry) = registry {
+        if registry_contains_doc_id(registry, &doc.manifest.doc_id.to_string())? {
+            println!(
+                "warning [manifest]: doc_id {} is already listed in `{}`",
+                doc.manifest.doc_id,
+                registry.display()
+            );
+            warnings += 1;
+        }
+    }
+    if errors > 0 {
+        bail!(
+            "{} failed validation: {} error(s), {} warning(s)",
+            input.display(),
+            errors,
+            warnings
+        );
+    }
+    println!("{} is valid ({} warning(s))", input.display(), warnings);
+    Ok(())
+}
+
+/// Check a registry of known document ids: one id per line, blank lines and `#` comments ignored.
+fn registry_contains_doc_id(registry: &Path, doc_id: &str) -> Result<bool> {
+    let contents = fs::read_to_string(registry)
+        .with_context(|| format!("failed to read registry `{}`", registry.display()))?;
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .any(|line| line.eq_ignore_ascii_case(doc_id)))
+}
+
+fn cmd_schema() -> Result<()> {
+    let schema = serde_json::to_string_pretty(&tmd_core::manifest_json_schema())?;
+    println!("{}", schema);
+    Ok(())
+}
+
+fn cmd_import(dir: &Path, output: &Path, entry: &str, sanitize: bool) -> Result<()> {
     let format = detect_format(output)?;
+    let policy = if sanitize {
+        SanitizePolicy::link_friendly()
+    } else {
+        SanitizePolicy::default()
+    };
+    let doc = TmdDoc::from_content_dir_with_policy(dir, entry, policy)
+        .with_context(|| format!("failed to import content folder `{}`", dir.display()))?;
     ensure_parent_directory(output)?;
     write_document(output, &doc, format)?;
     println!(
-        "Converted `{}` into `{}`",
-        input.display(),
+        "Imported `{}` ({} attachments) into `{}`",
+        dir.display(),
+        doc.attachments.iter().count(),
         output.display()
     );
     Ok(())
 }
 
-fn cmd_validate(input: &Path) -> Result<()> {
+fn cmd_explode(input: &Path, dir: &Path) -> Result<()> {
     let (doc, _) = read_document(input)?;
-    let user_version = doc
-        .db_with_conn(|conn| conn.query_row("PRAGMA user_version", [], |row| row.get::<_, u32>(0)))
-        .context("failed to access embedded database")?
-        .context("failed to read PRAGMA user_version from embedded database")?;
+    doc.explode_to_dir(dir)
+        .with_context(|| format!("failed to explode into `{}`", dir.display()))?;
+    println!("Exploded `{}` into `{}`", input.display(), dir.display());
+    Ok(())
+}
+
+fn cmd_pack(dir: &Path, output: &Path) -> Result<()> {
+    let format = detect_format(output)?;
+    let doc = TmdDoc::from_exploded_dir(dir)
+        .with_context(|| format!("failed to read exploded document `{}`", dir.display()))?;
+    ensure_parent_directory(output)?;
+    write_document(output, &doc, format)?;
+    println!("Packed `{}` into `{}`", dir.display(), output.display());
+    Ok(())
+}
+
+fn cmd_to_json(input: &Path, output: &Path) -> Result<()> {
+    let (doc, _) = read_document(input)?;
+    let json = doc
+        .to_json()
+        .context("failed to convert document to JSON")?;
+    ensure_parent_directory(output)?;
+    fs::write(output, serde_json::to_vec_pretty(&json)?)
+        .with_context(|| format!("failed to write `{}`", output.display()))?;
+    println!(
+        "Wrote `{}` as JSON to `{}`",
+        input.display(),
+        output.display()
+    );
+    Ok(())
+}
+
+fn cmd_from_json(input: &Path, output: &Path) -> Result<()> {
+    let format = detect_format(output)?;
+    let text = fs::read_to_string(input)
+        .with_context(|| format!("failed to read `{}`", input.display()))?;
+    let json: serde_json::Value = serde_json::from_str(&text)
+        .with_context(|| format!("`{}` is not valid JSON", input.display()))?;
+    let doc = TmdDoc::from_json(json)
+        .with_context(|| format!("failed to build a document from `{}`", input.display()))?;
+    ensure_parent_directory(output)?;
+    write_document(output, &doc, format)?;
+    println!("Built `{}` from `{}`", output.display(), input.display());
+    Ok(())
+}
 
-    if let Some(expected) = doc.manifest.db_schema_version {
-        anyhow::ensure!(
-            expected == user_version,
-            "manifest db_schema_version={} but PRAGMA user_version={}",
-            expected,
-            user_version
+fn cmd_cat(input: &Path, raw: bool) -> Result<()> {
+    let markdown = read_markdown_from_path(input)
+        .with_context(|| format!("failed to read markdown from `{}`", input.display()))?;
+    let mut stdout = std::io::stdout().lock();
+    stdout.write_all(markdown.as_bytes())?;
+    if !raw && !markdown.is_empty() && !markdown.ends_with('\n') {
+        stdout.write_all(b"\n")?;
+    }
+    stdout.flush()?;
+    Ok(())
+}
+
+fn cmd_probe(input: &Path, show_manifest: bool) -> Result<()> {
+    let bytes = fs::read(input).with_context(|| format!("failed to read `{}`", input.display()))?;
+    let report = probe(&bytes);
+    let show = |value: Option<String>| value.unwrap_or_else(|| "-".into());
+    println!(
+        "format: {}",
+        show(report.format.map(|f| format!("{:?}", f)))
+    );
+    println!("size: {} bytes", report.size);
+    println!(
+        "eocd offset: {}",
+        show(report.eocd_offset.map(|o| o.to_string()))
+    );
+    println!(
+        "comment length: {}",
+        show(report.comment_len.map(|l| l.to_string()))
+    );
+    println!("tmd marker: {}", report.has_marker);
+    println!("encrypted: {}", report.encrypted);
+    println!(
+        "markdown length: {}{}",
+        show(report.markdown_len.map(|l| l.to_string())),
+        if report.markdown_len.is_some() && !report.markdown_len_valid {
+            " (invalid)"
+        } else {
+            ""
+        }
+    );
+    println!(
+        "zip entries: {}",
+        show(report.entry_count.map(|n| n.to_string()))
+    );
+    println!("streamed entries: {}", report.streamed_entries);
+    println!("reserved entries: {}", report.reserved_present.join(", "));
+    if show_manifest {
+        match read_raw_manifest(input) {
+            Ok(raw) => println!("manifest.json:\n{}", raw),
+            Err(err) => println!("error: cannot read manifest.json: {}", err),
+        }
+    }
+    for problem in &report.problems {
+        println!("error: {}", problem);
+    }
+    if !report.problems.is_empty() {
+        bail!(
+            "{} structural problem(s) in `{}`",
+            report.problems.len(),
+            input.display()
         );
     }
+    Ok(())
+}
 
+fn cmd_lint(input: &Path) -> Result<()> {
+    let (doc, _) = read_document(input)?;
+    let usage = doc.attachment_usage();
+
+    for path in &usage.orphans {
+        println!("warning: attachment `{}` is not referenced", path);
+    }
+    for path in &usage.dangling {
+        println!("error: reference to missing attachment `{}`", path);
+    }
+
+    if !usage.dangling.is_empty() {
+        bail!(
+            "{} dangling attachment reference(s) in `{}`",
+            usage.dangling.len(),
+            input.display()
+        );
+    }
     println!(
-        "{} is valid (user_version = {})",
+        "{}: {} referenced, {} unreferenced attachment(s)",
         input.display(),
-        user_version
+        usage.referenced.len(),
+        usage.orphans.len()
     );
     Ok(())
 }
 
-fn cmd_export_html(input: &Path, output: &Path, self_contained: bool) -> Result<()> {
+fn cmd_export_html(
+    input: &Path,
+    output: &Path,
+    self_contained: bool,
+    include_db: bool,
+    db_query: Option<&str>,
+) -> Result<()> {
     let (doc, _) = read_document(input)?;
+    if let Some(data) = doc.data_engine() {
+        if data.engine != "markdown" {
+            bail!(
+                "cannot export `{}` content as HTML; only the markdown engine is supported",
+                data.engine
+            );
+        }
+    }
     let mut options = Options::empty();
     options.insert(Options::ENABLE_TABLES);
     options.insert(Options::ENABLE_TASKLISTS);
@@ -165,23 +746,49 @@ fn cmd_export_html(input: &Path, output: &Path, self_contained: bool) -> Result<
     } else {
         render_attachment_listing(&doc)
     };
+    let mut db_section = String::new();
+    if let Some(sql) = db_query {
+        db_section.push_str(&render_query_table(&doc, sql)?);
+    }
+    if include_db {
+        db_section.push_str(&render_embedded_db(&doc)?);
+    }
 
     let title = doc
         .manifest
         .title
         .as_deref()
         .unwrap_or("Tanu Markdown Document");
+    let lang = doc.language().unwrap_or("en");
+    let author_meta: String = doc
+        .manifest
+        .authors
+        .iter()
+        .map(|author| {
+            let mut content = author.name.clone();
+            if let Some(email) = &author.email {
+                content.push_str(&format!(" <{}>", email));
+            }
+            if let Some(affiliation) = &author.affiliation {
+                content.push_str(&format!(" ({})", affiliation));
+            }
+            format!(
+                "\n    <meta name=\"author\" content=\"{}\" />",
+                encode_double_quoted_attribute(&content)
+            )
+        })
+        .collect();
 
     let html = format!(
         r#"<!DOCTYPE html>
-<html lang=\"en\">
+<html lang="{lang}">
   <head>
-    <meta charset=\"utf-8\" />
-    <title>{title}</title>
+    <meta charset="utf-8" />
+    <title>{title}</title>{authors}
     <style>
       body {{ font-family: system-ui, sans-serif; margin: 2rem; line-height: 1.6; }}
       pre {{ background: #f5f5f5; padding: 1rem; overflow-x: auto; }}
-      code {{ font-family: ui-monospace, SFMono-Regular, Menlo, Monaco, Consolas, \"Liberation Mono\", \"Courier New\", monospace; }}
+      code {{ font-family: ui-monospace, SFMono-Regular, Menlo, Monaco, Consolas, "Liberation Mono", "Courier New", monospace; }}
       table {{ border-collapse: collapse; }}
       th, td {{ border: 1px solid #ccc; padding: 0.25rem 0.5rem; }}
     </style>
@@ -191,12 +798,16 @@ fn cmd_export_html(input: &Path, output: &Path, self_contained: bool) -> Result<
     {body}
     </article>
     {attachments}
+    {db}
   </body>
 </html>
 "#,
+        lang = encode_double_quoted_attribute(lang),
         title = encode_text(title),
+        authors = author_meta,
         body = body_html,
         attachments = attachment_section,
+        db = db_section,
     );
 
     ensure_parent_directory(output)?;
@@ -245,7 +856,7 @@ fn cmd_db_init(doc_path: &Path, schema_path: Option<&Path>, version: Option<u32>
     Ok(())
 }
 
-fn cmd_db_exec(doc_path: &Path, sql: &str) -> Result<()> {
+fn cmd_db_exec(doc_path: &Path, sql: &str, blob_as: BlobFormat, output: RowFormat) -> Result<()> {
     let (mut doc, format) = read_document(doc_path)?;
     let mut mutated = false;
     let mut has_trailing_sql = false;
@@ -263,27 +874,23 @@ fn cmd_db_exec(doc_path: &Path, sql: &str) -> Result<()> {
                 .map(|name| name.to_string())
                 .collect();
 
-            if column_count > 0 {
-                println!("| {} |", column_names.join(" | "));
-                println!(
-                    "|{}|",
-                    column_names
-                        .iter()
-                        .map(|_| "---")
-                        .collect::<Vec<_>>()
-                        .join("|")
-                );
-            }
+            let printer = RowPrinter {
+                columns: column_names,
+                format: output,
+                blob_as,
+            };
+            printer.print_header();
 
             {
                 let mut rows = stmt.query([])?;
+                let mut cells = Vec::with_capacity(column_count);
                 while let Some(row) = rows.next()? {
-                    let mut values = Vec::with_capacity(column_count);
+                    cells.clear();
                     for idx in 0..column_count {
                         let value: SqlValue = row.get(idx)?;
-                        values.push(display_sql_value(&value));
+                        cells.push(SqlCell::from(value));
                     }
-                    println!("| {} |", values.join(" | "));
+                    printer.print_row(&cells);
                 }
             }
 
@@ -323,15 +930,58 @@ fn cmd_db_exec(doc_path: &Path, sql: &str) -> Result<()> {
     if mutated {
         doc.touch();
         write_document(doc_path, &doc, format)?;
-        println!("Executed SQL and updated `{}`", doc_path.display());
+        eprintln!("Executed SQL and updated `{}`", doc_path.display());
     }
 
     Ok(())
 }
 
+fn cmd_db_run(
+    doc_path: &Path,
+    query_name: &str,
+    blob_as: BlobFormat,
+    output: RowFormat,
+) -> Result<()> {
+    let (doc, _) = read_document(doc_path)?;
+    let stored_sql = doc
+        .db_with_conn(|conn| -> rusqlite::Result<Option<Option<String>>> {
+            let has_table: bool = conn.query_row(
+                "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = '_queries')",
+                [],
+                |row| row.get(0),
+            )?;
+            if !has_table {
+                return Ok(None);
+            }
+            let sql = conn
+                .query_row(
+                    "SELECT sql FROM _queries WHERE name = ?1",
+                    [query_name],
+                    |row| row.get::<_, String>(0),
+                )
+                .optional()?;
+            Ok(Some(sql))
+        })
+        .context("failed to access embedded database")?
+        .context("failed to look up stored query")?;
+
+    let sql = match stored_sql {
+        None => bail!(
+            "`{}` has no `_queries` table in its embedded database",
+            doc_path.display()
+        ),
+        Some(None) => bail!(
+            "no query named `{}` in `_queries` of `{}`",
+            query_name,
+            doc_path.display()
+        ),
+        Some(Some(sql)) => sql,
+    };
+    cmd_db_exec(doc_path, &sql, blob_as, output)
+}
+
 fn leading_sql_keyword(sql: &str) -> Option<String> {
     let token = sql
-        .trim_start()
         .split_whitespace()
         .next()
         .map(|candidate| {
@@ -355,11 +1005,8 @@ fn cmd_db_import(doc_path: &Path, source: &Path) -> Result<()> {
     let (mut doc, format) = read_document(doc_path)?;
     import_db(&mut doc, source).context("failed to import SQLite database")?;
     let user_version = doc
-        .db_with_conn(|conn| conn.query_row("PRAGMA user_version", [], |row| row.get::<_, u32>(0)))
-        .context("failed to access embedded database")?
+        .sync_schema_version()
         .context("failed to query imported user_version")?;
-    doc.manifest.db_schema_version = Some(user_version);
-    doc.touch();
     write_document(doc_path, &doc, format)?;
     println!(
         "Imported database from `{}` into `{}` (user_version = {})",
@@ -370,6 +1017,156 @@ fn cmd_db_import(doc_path: &Path, source: &Path) -> Result<()> {
     Ok(())
 }
 
+/// Why a [`repl_session`] handed control back to [`cmd_db_repl`].
+#[derive(PartialEq)]
+enum ReplExit {
+    Save,
+    Quit,
+}
+
+fn cmd_db_repl(doc_path: &Path, blob_as: BlobFormat) -> Result<()> {
+    let (mut doc, format) = read_document(doc_path)?;
+    let stdin = std::io::stdin();
+    let interactive = stdin.is_terminal();
+    let mut lines = stdin.lock().lines();
+    loop {
+        let mut mutated = false;
+        let exit = doc
+            .db_with_conn(|conn| {
+                repl_session(&doc, conn, &mut lines, interactive, blob_as, &mut mutated)
+            })
+            .context("failed to access embedded database")??;
+        if mutated {
+            doc.touch();
+            write_document(doc_path, &doc, format)?;
+            println!("Saved `{}`", doc_path.display());
+        }
+        if exit == ReplExit::Quit {
+            return Ok(());
+        }
+    }
+}
+
+/// Read and run statements on `conn` until `.save`, `.quit`, or end of input.
+fn repl_session(
+    doc: &TmdDoc,
+    conn: &rusqlite::Connection,
+    lines: &mut impl Iterator<Item = std::io::Result<String>>,
+    interactive: bool,
+    blob_as: BlobFormat,
+    mutated: &mut bool,
+) -> Result<ReplExit> {
+    let mut buffer = String::new();
+    loop {
+        if interactive {
+            print!("{}", if buffer.is_empty() { "tmd> " } else { "...> " });
+            std::io::stdout().flush()?;
+        }
+        let Some(line) = lines.next().transpose()? else {
+            return Ok(ReplExit::Quit);
+        };
+        let line = line.trim();
+        if buffer.is_empty() {
+            match line {
+                "" => continue,
+                ".quit" | ".exit" => return Ok(ReplExit::Quit),
+                ".save" => return Ok(ReplExit::Save),
+                ".tables" => {
+                    let mut stmt = conn.prepare(
+                        "SELECT name FROM sqlite_master WHERE type = 'table' \
+                         AND name NOT LIKE 'sqlite_%' ORDER BY name",
+                    )?;
+                    let names = stmt.query_map([], |row| row.get::<_, String>(0))?;
+                    for name in names {
+                        println!("{}", name?);
+                    }
+                    continue;
+                }
+                ".schema" => {
+                    print!("{}", doc.db_schema_sql()?);
+                    continue;
+                }
+                _ if line.starts_with('.') => {
+                    eprintln!("error: unknown command `{}`", line);
+                    continue;
+                }
+                _ => {}
+            }
+        }
+        buffer.push_str(line);
+        buffer.push('\n');
+        if !line.ends_with(';') {
+            continue;
+        }
+        match run_repl_statement(conn, &buffer, blob_as) {
+            Ok(changed) => *mutated |= changed,
+            Err(err) => eprintln!("error: {}", err),
+        }
+        buffer.clear();
+    }
+}
+
+/// Run one statement, printing any rows; returns whether it may have changed the database.
+fn run_repl_statement(
+    conn: &rusqlite::Connection,
+    sql: &str,
+    blob_as: BlobFormat,
+) -> rusqlite::Result<bool> {
+    let mut stmt = conn.prepare(sql)?;
+    let column_count = stmt.column_count();
+    if column_count == 0 {
+        drop(stmt);
+        conn.execute_batch(sql)?;
+        return Ok(true);
+    }
+    let printer = RowPrinter {
+        columns: stmt
+            .column_names()
+            .into_iter()
+            .map(|name| name.to_string())
+            .collect(),
+        format: RowFormat::Table,
+        blob_as,
+    };
+    printer.print_header();
+    let readonly = stmt.readonly();
+    let mut rows = stmt.query([])?;
+    let mut cells = Vec::with_capacity(column_count);
+    while let Some(row) = rows.next()? {
+        cells.clear();
+        for idx in 0..column_count {
+            cells.push(SqlCell::from(row.get::<_, SqlValue>(idx)?));
+        }
+        printer.print_row(&cells);
+    }
+    Ok(!readonly)
+}
+
+fn cmd_db_schema(doc_path: &Path) -> Result<()> {
+    let (doc, _) = read_document(doc_path)?;
+    let schema = doc
+        .db_schema_sql()
+        .context("failed to read schema from embedded database")?;
+    print!("{}", schema);
+    Ok(())
+}
+
+fn cmd_db_sync_version(doc_path: &Path) -> Result<()> {
+    let (mut doc, format) = read_document(doc_path)?;
+    let previous = doc.manifest.db_schema_version;
+    let version = doc
+        .sync_schema_version()
+        .context("failed to read PRAGMA user_version from embedded database")?;
+    write_document(doc_path, &doc, format)?;
+    println!(
+        "Synced `{}` schema version {:?} -> {}",
+        doc_path.display(),
+        previous,
+        version
+    );
+    Ok(())
+}
+
 fn cmd_db_export(doc_path: &Path, output: &Path) -> Result<()> {
     let (doc, _) = read_document(doc_path)?;
     ensure_parent_directory(output)?;
@@ -382,6 +1179,99 @@ fn cmd_db_export(doc_path: &Path, output: &Path) -> Result<()> {
     Ok(())
 }
 
+fn cmd_attachments_extract(doc_path: &Path, pattern: &str, dir: &Path) -> Result<()> {
+    let (doc, _) = read_document(doc_path)?;
+    let written = doc
+        .export_attachments_matching(pattern, dir)
+        .with_context(|| format!("failed to extract attachments into {}", dir.display()))?;
+    println!(
+        "Extracted {} attachment(s) matching `{}` into {}",
+        written,
+        pattern,
+        dir.display()
+    );
+    Ok(())
+}
+
+fn cmd_attachments_dupes(doc_path: &Path) -> Result<()> {
+    let (doc, _) = read_document(doc_path)?;
+    let groups = doc.duplicate_attachments();
+    if groups.is_empty() {
+        println!("No duplicate attachments in `{}`", doc_path.display());
+        return Ok(());
+    }
+
+    let mut total_saved = 0u64;
+    for group in &groups {
+        println!(
+            "sha256 {} ({} bytes saved):",
+            hex_encode(&group.sha256),
+            group.bytes_saved
+        );
+        for path in &group.logical_paths {
+            println!("  {}", path);
+        }
+        total_saved += group.bytes_saved;
+    }
+    println!(
+        "{} duplicate group(s); deduplication would save {} bytes",
+        groups.len(),
+        total_saved
+    );
+    Ok(())
+}
+
+fn cmd_batch(pattern: &str, mut apply: impl FnMut(&mut TmdDoc) -> bool) -> Result<()> {
+    let paths =
+        glob::glob(pattern).with_context(|| format!("invalid glob pattern `{}`", pattern))?;
+
+    let (mut updated, mut unchanged, mut failed) = (0usize, 0usize, 0usize);
+    for entry in paths {
+        let path = match entry {
+            Ok(path) => path,
+            Err(err) => {
+                eprintln!("error: {}", err);
+                failed += 1;
+                continue;
+            }
+        };
+        let result = (|| -> Result<bool> {
+            // Only the manifest changes, so attachment bytes stay in the file until the
+            // rewrite streams them across.
+            let (mut doc, format) = read_document_lazy(&path)?;
+            if !apply(&mut doc) {
+                return Ok(false);
+            }
+            write_to_path_atomic(&path, &doc, format)
+                .with_context(|| format!("failed to write `{}`", path.display()))?;
+            Ok(true)
+        })();
+        match result {
+            Ok(true) => {
+                println!("updated: {}", path.display());
+                updated += 1;
+            }
+            Ok(false) => {
+                println!("unchanged: {}", path.display());
+                unchanged += 1;
+            }
+            Err(err) => {
+                eprintln!("error: {}: {:#}", path.display(), err);
+                failed += 1;
+            }
+        }
+    }
+
+    println!(
+        "{} updated, {} unchanged, {} failed",
+        updated, unchanged, failed
+    );
+    if failed > 0 {
+        bail!("{} document(s) could not be updated", failed);
+    }
+    Ok(())
+}
+
 fn read_document(path: &Path) -> Result<(TmdDoc, Format)> {
     let format = detect_format(path)?;
     let doc = read_from_path(path, Some(format))
@@ -389,25 +1279,80 @@ fn read_document(path: &Path) -> Result<(TmdDoc, Format)> {
     Ok((doc, format))
 }
 
+/// Like [`read_document`], leaving attachment bytes in the file until they are needed.
+fn read_document_lazy(path: &Path) -> Result<(TmdDoc, Format)> {
+    let format = detect_format(path)?;
+    let mode = ReadMode {
+        lazy_attachments: true,
+        ..ReadMode::default()
+    };
+    let doc = read_from_path_with_mode(path, Some(format), mode)
+        .with_context(|| format!("failed to read `{}`", path.display()))?;
+    Ok((doc, format))
+}
+
 fn write_document(path: &Path, doc: &TmdDoc, format: Format) -> Result<()> {
     write_to_path(path, doc, format)
         .with_context(|| format!("failed to write `{}`", path.display()))
 }
 
+fn write_document_with_mode(
+    path: &Path,
+    doc: &TmdDoc,
+    format: Format,
+    mode: WriteMode,
+) -> Result<()> {
+    write_to_path_with_mode(path, doc, format, mode)
+        .with_context(|| format!("failed to write `{}`", path.display()))
+}
+
+/// Write a freshly created document, refusing to overwrite an existing file.
+///
+/// With `auto`, `path` is a stem and both candidate paths must be free.
+fn write_new_document(path: &Path, doc: &TmdDoc, auto: bool) -> Result<(PathBuf, Format)> {
+    if auto {
+        let stem = auto_stem(path);
+        for format in [Format::Tmd, Format::Tmdz] {
+            let mut candidate = stem.clone().into_os_string();
+            candidate.push(".");
+            candidate.push(format.extension());
+            let candidate = PathBuf::from(candidate);
+            anyhow::ensure!(
+                !candidate.exists(),
+                "target `{}` already exists",
+                candidate.display()
+            );
+        }
+        ensure_parent_directory(&stem)?;
+        return write_document_best(&stem, doc, WriteMode::default());
+    }
+    anyhow::ensure!(!path.exists(), "target `{}` already exists", path.display());
+    ensure_parent_directory(path)?;
+    let format = detect_format(path)?;
+    write_document(path, doc, format)?;
+    Ok((path.to_path_buf(), format))
+}
+
+fn write_document_best(stem: &Path, doc: &TmdDoc, mode: WriteMode) -> Result<(PathBuf, Format)> {
+    write_to_path_best(stem, doc, mode)
+        .with_context(|| format!("failed to write `{}.tmd[z]`", stem.display()))
+}
+
+/// Drop a `.tmd`/`.tmdz` extension so `--auto out.tmd` does not yield `out.tmd.tmdz`.
+fn auto_stem(path: &Path) -> PathBuf {
+    match Format::from_path(path) {
+        Some(_) => path.with_extension(""),
+        None => path.to_path_buf(),
+    }
+}
+
 fn detect_format(path: &Path) -> Result<Format> {
-    match path
-        .extension()
-        .and_then(|ext| ext.to_str())
-        .map(|ext| ext.to_ascii_lowercase())
-        .as_deref()
-    {
-        Some("tmd") => Ok(Format::Tmd),
-        Some("tmdz") => Ok(Format::Tmdz),
-        _ => Err(anyhow!(
+    Format::from_path(path).ok_or_else(|| {
+        anyhow!(
             "unsupported path `{}` — expected extension .tmd or .tmdz",
             path.display()
-        )),
-    }
+        )
+    })
 }
 
 fn ensure_parent_directory(path: &Path) -> Result<()> {
@@ -442,17 +1387,16 @@ fn render_attachment_listing(doc: &TmdDoc) -> String {
 }
 
 fn render_embedded_attachments(doc: &TmdDoc) -> String {
-    let mut entries: Vec<_> = doc.attachments.iter_with_data().collect();
+    let mut entries: Vec<_> = doc.attachments.iter().collect();
     if entries.is_empty() {
         return String::new();
     }
-    entries.sort_by(|(a, _), (b, _)| a.logical_path.cmp(&b.logical_path));
+    entries.sort_by(|a, b| a.logical_path.cmp(&b.logical_path));
 
     let mut out = String::new();
     out.push_str("<section><h2>Attachments</h2><ul>\n");
-    for (meta, data) in entries {
-        let encoded = BASE64_STANDARD.encode(data);
-        let href = format!("data:{};base64,{}", meta.mime, encoded);
+    for meta in entries {
+        let href = doc.attachment_data_uri(meta.id).unwrap_or_default();
         out.push_str(&format!(
             "  <li><a download=\"{name}\" href=\"{href}\">{name}</a> ({size} bytes)</li>\n",
             name = encode_text(&meta.logical_path),
@@ -464,16 +1408,156 @@ fn render_embedded_attachments(doc: &TmdDoc) -> String {
     out
 }
 
-fn display_sql_value(value: &SqlValue) -> String {
-    match value {
-        SqlValue::Null => "NULL".to_string(),
-        SqlValue::Integer(v) => v.to_string(),
-        SqlValue::Real(v) => v.to_string(),
-        SqlValue::Text(v) => v.clone(),
-        SqlValue::Blob(_) => "<blob>".to_string(),
+fn render_embedded_db(doc: &TmdDoc) -> Result<String> {
+    let bytes = fs::read(doc.db.as_path()).context("failed to read embedded database")?;
+    Ok(format!(
+        "<section><h2>Database</h2><p><a download=\"main.sqlite3\" href=\"data:application/vnd.sqlite3;base64,{data}\">main.sqlite3</a> ({size} bytes)</p></section>",
+        data = BASE64_STANDARD.encode(&bytes),
+        size = bytes.len()
+    ))
+}
+
+fn render_query_table(doc: &TmdDoc, sql: &str) -> Result<String> {
+    let result = doc
+        .db_query(sql)
+        .with_context(|| format!("failed to run query `{}`", sql))?;
+    let mut out = String::new();
+    out.push_str("<section><h2>Data</h2><table>\n  <tr>");
+    for column in &result.columns {
+        out.push_str(&format!("<th>{}</th>", encode_text(column)));
+    }
+    out.push_str("</tr>\n");
+    for row in &result.rows {
+        out.push_str("  <tr>");
+        for cell in row {
+            let text = match cell {
+                SqlCell::Null => String::new(),
+                SqlCell::Integer(v) => v.to_string(),
+                SqlCell::Real(v) => v.to_string(),
+                SqlCell::Text(v) => encode_text(v).into_owned(),
+                SqlCell::Blob(v) => format!("&lt;{} byte blob&gt;", v.len()),
+            };
+            out.push_str(&format!("<td>{}</td>", text));
+        }
+        out.push_str("</tr>\n");
+    }
+    out.push_str("</table></section>");
+    Ok(out)
+}
+
+/// Prints query results in the format selected by `--output`.
+struct RowPrinter {
+    columns: Vec<String>,
+    format: RowFormat,
+    blob_as: BlobFormat,
+}
+
+impl RowPrinter {
+    fn print_header(&self) {
+        match self.format {
+            RowFormat::Table => {
+                println!("| {} |", self.columns.join(" | "));
+                println!("|{}|", vec!["---"; self.columns.len()].join("|"));
+            }
+            RowFormat::Csv => {
+                let fields: Vec<String> = self.columns.iter().map(|c| csv_field(c)).collect();
+                print!("{}\r\n", fields.join(","));
+            }
+            RowFormat::Ndjson => {}
+        }
+    }
+
+    fn print_row(&self, cells: &[SqlCell]) {
+        match self.format {
+            RowFormat::Table => {
+                let values: Vec<String> = cells
+                    .iter()
+                    .map(|cell| display_sql_cell(cell, self.blob_as))
+                    .collect();
+                println!("| {} |", values.join(" | "));
+            }
+            RowFormat::Csv => {
+                let fields: Vec<String> = cells
+                    .iter()
+                    .map(|cell| csv_field(&csv_sql_cell(cell, self.blob_as)))
+                    .collect();
+                print!("{}\r\n", fields.join(","));
+            }
+            RowFormat::Ndjson => {
+                let object: serde_json::Map<String, serde_json::Value> = self
+                    .columns
+                    .iter()
+                    .cloned()
+                    .zip(cells.iter().map(|cell| json_sql_cell(cell, self.blob_as)))
+                    .collect();
+                println!("{}", serde_json::Value::Object(object));
+            }
+        }
+    }
+}
+
+fn encode_blob(bytes: &[u8], blob_as: BlobFormat) -> Option<String> {
+    match blob_as {
+        BlobFormat::Hex => Some(hex_encode(bytes)),
+        BlobFormat::Base64 => Some(BASE64_STANDARD.encode(bytes)),
+        BlobFormat::Skip => None,
+    }
+}
+
+fn csv_sql_cell(cell: &SqlCell, blob_as: BlobFormat) -> String {
+    match cell {
+        SqlCell::Null => String::new(),
+        SqlCell::Integer(v) => v.to_string(),
+        SqlCell::Real(v) => v.to_string(),
+        SqlCell::Text(v) => v.clone(),
+        SqlCell::Blob(v) => encode_blob(v, blob_as).unwrap_or_default(),
+    }
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
     }
 }
 
+fn json_sql_cell(cell: &SqlCell, blob_as: BlobFormat) -> serde_json::Value {
+    match cell {
+        SqlCell::Null => serde_json::Value::Null,
+        SqlCell::Integer(v) => (*v).into(),
+        SqlCell::Real(v) => serde_json::Number::from_f64(*v)
+            .map(serde_json::Value::Number)
+            .unwrap_or(serde_json::Value::Null),
+        SqlCell::Text(v) => v.clone().into(),
+        SqlCell::Blob(v) => encode_blob(v, blob_as)
+            .map(serde_json::Value::String)
+            .unwrap_or(serde_json::Value::Null),
+    }
+}
+
+fn display_sql_cell(cell: &SqlCell, blob_as: BlobFormat) -> String {
+    match cell {
+        SqlCell::Null => "NULL".to_string(),
+        SqlCell::Integer(v) => v.to_string(),
+        SqlCell::Real(v) => v.to_string(),
+        SqlCell::Text(v) => escape_table_text(v),
+        SqlCell::Blob(v) => encode_blob(v, blob_as).unwrap_or_else(|| "<blob>".to_string()),
+    }
+}
+
+fn escape_table_text(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('|', "\\|")
+        .replace("\r\n", "\\n")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
 fn format_display(format: Format) -> &'static str {
     match format {
         Format::Tmd => ".tmd",
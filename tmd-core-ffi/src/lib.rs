@@ -21,6 +21,7 @@ type NewFn = unsafe extern "C" fn(*const c_char) -> *mut Doc;
 type ReadFn = unsafe extern "C" fn(*const c_char, i32) -> *mut Doc;
 type WriteFn = unsafe extern "C" fn(*const Doc, *const c_char, i32) -> i32;
 type GetMarkdownFn = unsafe extern "C" fn(*const Doc) -> *mut c_char;
+type MarkdownPtrFn = unsafe extern "C" fn(*const Doc, *mut usize) -> *const u8;
 type SetMarkdownFn = unsafe extern "C" fn(*mut Doc, *const c_char) -> i32;
 type FreeDocFn = unsafe extern "C" fn(*mut Doc);
 type FreeStringFn = unsafe extern "C" fn(*mut c_char);
@@ -31,6 +32,7 @@ keep_symbols!(
     KEEP_TMD_DOC_READ_FROM_PATH: ReadFn = tmd_core::ffi::tmd_doc_read_from_path,
     KEEP_TMD_DOC_WRITE_TO_PATH: WriteFn = tmd_core::ffi::tmd_doc_write_to_path,
     KEEP_TMD_DOC_GET_MARKDOWN: GetMarkdownFn = tmd_core::ffi::tmd_doc_get_markdown,
+    KEEP_TMD_DOC_MARKDOWN_PTR: MarkdownPtrFn = tmd_core::ffi::tmd_doc_markdown_ptr,
     KEEP_TMD_DOC_SET_MARKDOWN: SetMarkdownFn = tmd_core::ffi::tmd_doc_set_markdown,
     KEEP_TMD_DOC_FREE: FreeDocFn = tmd_core::ffi::tmd_doc_free,
     KEEP_TMD_STRING_FREE: FreeStringFn = tmd_core::ffi::tmd_string_free,
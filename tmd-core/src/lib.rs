@@ -1,16 +1,36 @@
 //! Core library for handling Tanu Markdown documents.
 
-pub use attach::{AttachmentDataMut, AttachmentStore, AttachmentStoreIter};
+pub use attach::{AttachmentDataMut, AttachmentStore, AttachmentStoreIter, DuplicateGroup};
 pub use db::{
     export_db, import_db, migrate, reset_db, with_conn, with_conn_mut, DbHandle, DbOptions,
+    QueryResult, SqlCell,
 };
 pub use format::{
-    read_from_path, read_tmd, read_tmdz, sniff_format, write_tmd, write_tmdz, write_to_path,
-    Format, ReadMode, Reader, WriteMode, Writer,
+    probe, read_attachment_from_path, read_from_path, read_from_path_with_mode,
+    read_markdown_from_path, read_raw_manifest, read_spooled, read_tmd, read_tmd_buffered,
+    read_tmdz, sniff_format, write_tmd, write_tmdz, write_to_path, write_to_path_atomic,
+    write_to_path_auto, write_to_path_best, write_to_path_with_mode, DuplicatePolicy, Format,
+    LazyBackend, OrderBy, ProbeReport, ReadMode, ReadProgress, Reader, WriteMode, Writer,
+};
+#[cfg(feature = "crypto")]
+pub use format::{read_from_path_encrypted, write_to_path_encrypted};
+#[cfg(feature = "schemars")]
+pub use manifest::json_schema as manifest_json_schema;
+pub use manifest::{
+    AttachmentDigest, AttachmentMeta, AttachmentRef, Author, CompressionHint, DataSection,
+    HashAlgorithm, LinkRef, Manifest, Semver,
+};
+pub use markdown::AttachmentUsage;
+pub use merge::{DbMerge, MergeOptions, MergeReport};
+pub use util::{
+    guess_mime, normalize_logical_path, now_utc, sanitize_logical_path, set_clock,
+    validate_language_tag, with_clock, Clock, FixedClock, SanitizePolicy, SystemClock,
+};
+pub use validation::{
+    IssueCategory, PortabilityWarning, Severity, ValidationIssue, ValidationReport,
 };
-pub use manifest::{AttachmentMeta, AttachmentRef, LinkRef, Manifest, Semver};
-pub use util::{normalize_logical_path, now_utc};
 
+use chrono::{DateTime, NaiveDateTime, SubsecRound, Utc};
 use mime::Mime;
 use rusqlite::Connection;
 use thiserror::Error;
@@ -37,12 +57,33 @@ pub enum TmdError {
     /// Indicates that an attachment already exists or is invalid.
     #[error("attachment error: {0}")]
     Attachment(String),
+    /// Indicates that a requested attachment or alias does not exist.
+    #[error("not found: {0}")]
+    NotFound(String),
     /// Indicates invalid TMD formatting or structure.
     #[error("invalid format: {0}")]
     InvalidFormat(String),
     /// Wrapper for SQLite related errors.
     #[error("sqlite: {0}")]
     Db(String),
+    /// Indicates that key derivation or encryption failed.
+    #[error("crypto error: {0}")]
+    Crypto(String),
+    /// Indicates that an input exceeded a caller-supplied size limit.
+    #[error("input exceeds limit of {limit} bytes")]
+    TooLarge { limit: u64 },
+}
+
+impl TmdError {
+    /// The error for an attachment id that is not in the store.
+    pub(crate) fn unknown_attachment(id: AttachmentId) -> Self {
+        Self::NotFound(format!("attachment id {} not found", id))
+    }
+
+    /// The error for a logical path that no attachment is stored or aliased under.
+    pub(crate) fn unknown_path(logical_path: &str) -> Self {
+        Self::NotFound(format!("attachment `{}` not found", logical_path))
+    }
 }
 
 impl From<rusqlite::Error> for TmdError {
@@ -58,6 +99,11 @@ pub struct TmdDoc {
     pub manifest: Manifest,
     pub attachments: AttachmentStore,
     pub db: DbHandle,
+    /// ZIP archive comment carried through reads and writes, excluding the `.tmd` marker.
+    pub zip_comment: Vec<u8>,
+    /// Source archive for attachments that are read on demand, see
+    /// [`ReadMode::lazy_attachments`].
+    pub lazy: Option<LazyBackend>,
 }
 
 impl TmdDoc {
@@ -82,7 +128,12 @@ impl TmdDoc {
             cover_image: None,
             links: Vec::new(),
             db_schema_version: None,
+            language: None,
+            data: None,
+            markdown_path: None,
+            content_checksum: None,
             extras: serde_json::Value::default(),
+            unknown: serde_json::Map::new(),
         };
 
         Ok(Self {
@@ -90,15 +141,213 @@ impl TmdDoc {
             manifest,
             attachments: AttachmentStore::new(),
             db,
+            zip_comment: Vec::new(),
+            lazy: None,
         })
     }
 
+    /// Assemble a document from independently built parts.
+    ///
+    /// The parts are taken as-is: the manifest is not touched and attachment references
+    /// are not checked, see [`TmdDoc::validate_full`].
+    pub fn from_parts(
+        markdown: String,
+        manifest: Manifest,
+        attachments: AttachmentStore,
+        db: DbHandle,
+    ) -> Self {
+        Self {
+            markdown,
+            manifest,
+            attachments,
+            db,
+            zip_comment: Vec::new(),
+            lazy: None,
+        }
+    }
+
+    /// Like [`TmdDoc::from_parts`], with a fresh empty database.
+    pub fn from_parts_with_empty_db(
+        markdown: String,
+        manifest: Manifest,
+        attachments: AttachmentStore,
+    ) -> TmdResult<Self> {
+        let mut db = DbHandle::new_empty()?;
+        db.ensure_initialized(None)?;
+        Ok(Self::from_parts(markdown, manifest, attachments, db))
+    }
+
+    /// Give the document a fresh v4 `doc_id`, e.g. after copying it from a template,
+    /// and return the new id.
+    pub fn regenerate_doc_id(&mut self) -> Uuid {
+        let id = Uuid::new_v4();
+        self.manifest.doc_id = id;
+        self.touch();
+        id
+    }
+
+    /// Borrow the Markdown body.
+    pub fn markdown(&self) -> &str {
+        &self.markdown
+    }
+
+    /// Borrow the Markdown body as UTF-8 bytes.
+    pub fn markdown_bytes(&self) -> &[u8] {
+        self.markdown.as_bytes()
+    }
+
+    /// Length of the Markdown body in bytes.
+    pub fn markdown_len(&self) -> usize {
+        self.markdown.len()
+    }
+
+    /// Replace the Markdown body and update the modification time.
+    ///
+    /// When the manifest has no title, it is taken from the first level-one heading.
+    pub fn set_markdown(&mut self, markdown: impl Into<String>) {
+        let markdown = markdown.into();
+        let mut changed = markdown != self.markdown;
+        self.markdown = markdown;
+        if self.manifest.title.is_none() {
+            self.manifest.title = markdown::first_heading(&self.markdown);
+            changed |= self.manifest.title.is_some();
+        }
+        self.touch_if_modified(changed);
+    }
+
     /// Replace the document manifest, returning the updated document.
     pub fn with_manifest(mut self, manifest: Manifest) -> Self {
         self.manifest = manifest;
         self
     }
 
+    /// BCP-47 language tag of the document, if set.
+    pub fn language(&self) -> Option<&str> {
+        self.manifest.language.as_deref()
+    }
+
+    /// Engine that interprets the primary content, if declared.
+    ///
+    /// Documents without a data section are plain markdown.
+    pub fn data_engine(&self) -> Option<&DataSection> {
+        self.manifest.data.as_ref()
+    }
+
+    /// Set or clear the data section.
+    pub fn set_data_engine(&mut self, data: Option<DataSection>) {
+        let changed = self.manifest.data != data;
+        self.manifest.data = data;
+        self.touch_if_modified(changed);
+    }
+
+    /// Name of the archive entry the markdown is stored under.
+    pub fn markdown_path(&self) -> &str {
+        self.manifest
+            .markdown_path
+            .as_deref()
+            .unwrap_or(format::MARKDOWN_ENTRY)
+    }
+
+    /// Store the markdown under `path` instead of `index.md`, or restore the default.
+    ///
+    /// The name must be a normalized logical path that does not collide with the
+    /// other reserved entries or an existing attachment.
+    pub fn set_markdown_path(&mut self, path: Option<&str>) -> TmdResult<()> {
+        let path = path.map(normalize_logical_path).transpose()?;
+        let mut manifest = self.manifest.clone();
+        manifest.markdown_path = path;
+        let entry = format::markdown_entry(&manifest)?;
+        if self.attachments.meta_by_path(entry).is_some() {
+            return Err(TmdError::InvalidFormat(format!(
+                "markdown entry `{}` collides with an attachment",
+                entry
+            )));
+        }
+        let changed = self.manifest.markdown_path != manifest.markdown_path;
+        self.manifest = manifest;
+        self.touch_if_modified(changed);
+        Ok(())
+    }
+
+    /// Set or clear the document language after validating the tag.
+    pub fn set_language(&mut self, language: Option<&str>) -> TmdResult<()> {
+        if let Some(tag) = language {
+            validate_language_tag(tag)?;
+        }
+        let changed = self.manifest.language.as_deref() != language;
+        self.manifest.language = language.map(str::to_string);
+        self.touch_if_modified(changed);
+        Ok(())
+    }
+
+    /// Add a tag to the manifest, returning `false` if it was already present.
+    pub fn add_tag(&mut self, tag: &str) -> bool {
+        if self.manifest.tags.iter().any(|existing| existing == tag) {
+            return false;
+        }
+        self.manifest.tags.push(tag.to_string());
+        self.touch();
+        true
+    }
+
+    /// Remove a tag from the manifest, returning `false` if it was not present.
+    pub fn remove_tag(&mut self, tag: &str) -> bool {
+        let before = self.manifest.tags.len();
+        self.manifest.tags.retain(|existing| existing != tag);
+        if self.manifest.tags.len() == before {
+            return false;
+        }
+        self.touch();
+        true
+    }
+
+    /// Append an author to the manifest, returning `false` if the name was already listed.
+    pub fn add_author(&mut self, author: &str) -> bool {
+        if self
+            .manifest
+            .authors
+            .iter()
+            .any(|existing| existing.name == author)
+        {
+            return false;
+        }
+        self.manifest.authors.push(Author::new(author));
+        self.touch();
+        true
+    }
+
+    /// Add an author with contact details, updating the entry with the same name if there
+    /// is one. Returns `false` if the identical author was already listed.
+    pub fn add_author_full(&mut self, author: Author) -> bool {
+        match self
+            .manifest
+            .authors
+            .iter_mut()
+            .find(|existing| existing.name == author.name)
+        {
+            Some(existing) if *existing == author => return false,
+            Some(existing) => *existing = author,
+            None => self.manifest.authors.push(author),
+        }
+        self.touch();
+        true
+    }
+
+    /// Replace the manifest author list, returning `false` if it was unchanged.
+    pub fn set_authors<I, S>(&mut self, authors: I) -> bool
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<Author>,
+    {
+        let authors: Vec<Author> = authors.into_iter().map(Into::into).collect();
+        if self.manifest.authors == authors {
+            return false;
+        }
+        self.manifest.authors = authors;
+        self.touch();
+        true
+    }
+
     fn add_attachment_inner(
         &mut self,
         logical_path: &str,
@@ -110,6 +359,20 @@ impl TmdDoc {
         self.attachments.insert(id, path, mime, bytes)
     }
 
+    fn add_attachment_from_reader<R: std::io::Read>(
+        &mut self,
+        logical_path: &str,
+        mime: Mime,
+        reader: R,
+        limit: Option<u64>,
+    ) -> TmdResult<AttachmentId> {
+        let path = normalize_logical_path(logical_path)?;
+        let (bytes, digest) =
+            attach::read_hashed(reader, limit, self.attachments.hash_algorithm())?;
+        self.attachments
+            .insert_with_digest(Uuid::new_v4(), path, mime, bytes, digest)
+    }
+
     /// Add an attachment using an owned byte buffer.
     pub fn add_attachment<B: Into<Vec<u8>>>(
         &mut self,
@@ -121,35 +384,139 @@ impl TmdDoc {
     }
 
     /// Add an attachment from a stream, buffering it in memory.
+    ///
+    /// The content digest and length are computed chunk by chunk while reading, so the
+    /// data is not traversed a second time. The whole stream is read without a size cap;
+    /// use [`TmdDoc::add_attachment_stream_limited`] for untrusted sources.
     pub fn add_attachment_stream<R: std::io::Read + Send + 'static>(
         &mut self,
         logical_path: &str,
         mime: Mime,
-        mut reader: R,
+        reader: R,
+    ) -> TmdResult<AttachmentId> {
+        self.add_attachment_from_reader(logical_path, mime, reader, None)
+    }
+
+    /// Add an attachment from a stream, failing with [`TmdError::TooLarge`] if it yields
+    /// more than `max_bytes`.
+    pub fn add_attachment_stream_limited<R: std::io::Read>(
+        &mut self,
+        logical_path: &str,
+        mime: Mime,
+        reader: R,
+        max_bytes: u64,
     ) -> TmdResult<AttachmentId> {
-        let mut buf = Vec::new();
-        reader.read_to_end(&mut buf)?;
-        self.add_attachment_inner(logical_path, mime, buf)
+        self.add_attachment_from_reader(logical_path, mime, reader, Some(max_bytes))
+    }
+
+    /// Choose the [`HashAlgorithm`] used for attachments added or modified from now on.
+    pub fn set_hash_algorithm(&mut self, algorithm: HashAlgorithm) -> TmdResult<()> {
+        self.attachments.set_hash_algorithm(algorithm)
+    }
+
+    /// Limit how many components new attachment paths may have; see
+    /// [`AttachmentStore::set_max_path_depth`].
+    pub fn set_max_path_depth(&mut self, depth: Option<usize>) {
+        self.attachments.set_max_path_depth(depth);
     }
 
     /// Remove an attachment by ID.
     pub fn remove_attachment(&mut self, id: AttachmentId) -> TmdResult<()> {
-        self.attachments
-            .remove(id)
-            .map_err(|e| TmdError::Attachment(e.to_string()))
+        self.attachments.remove(id)
     }
 
-    /// Rename an attachment to a new logical path.
-    pub fn rename_attachment(&mut self, id: AttachmentId, new_logical_path: &str) -> TmdResult<()> {
+    /// Rename an attachment to a new logical path, returning the path it had before so
+    /// references to it can be rewritten.
+    pub fn rename_attachment(
+        &mut self,
+        id: AttachmentId,
+        new_logical_path: &str,
+    ) -> TmdResult<LogicalPath> {
         let path = normalize_logical_path(new_logical_path)?;
         self.attachments.rename(id, path)
     }
 
+    /// Mint new ids for every attachment, keeping logical paths and data, and return the
+    /// old-to-new mapping. The cover image follows its attachment.
+    ///
+    /// Useful before distributing a copy whose attachment identities should not match
+    /// the original's.
+    pub fn reassign_attachment_ids(
+        &mut self,
+    ) -> std::collections::HashMap<AttachmentId, AttachmentId> {
+        let mapping = self.attachments.reassign_ids();
+        if let Some(cover) = self.manifest.cover_image.as_mut() {
+            if let Some(&id) = mapping.get(&cover.id) {
+                cover.id = id;
+            }
+        }
+        if let Some(lazy) = self.lazy.as_mut() {
+            lazy.remap_ids(&mapping);
+        }
+        self.touch_if_modified(!mapping.is_empty());
+        mapping
+    }
+
+    /// Move every attachment under `old_prefix/` to `new_prefix/`, returning the number moved.
+    ///
+    /// Fails without changing anything if a destination path is already taken.
+    pub fn rename_prefix(&mut self, old_prefix: &str, new_prefix: &str) -> TmdResult<usize> {
+        let old_prefix = normalize_logical_path(old_prefix)?;
+        let new_prefix = normalize_logical_path(new_prefix)?;
+        self.attachments.rename_prefix(&old_prefix, &new_prefix)
+    }
+
     /// Get attachment metadata by ID.
     pub fn attachment_meta(&self, id: AttachmentId) -> Option<&AttachmentMeta> {
         self.attachments.meta(id)
     }
 
+    /// Correct the MIME type recorded for an attachment without changing its ID.
+    pub fn set_attachment_mime(&mut self, id: AttachmentId, mime: Mime) -> TmdResult<()> {
+        let changed = self
+            .attachments
+            .meta(id)
+            .is_some_and(|meta| meta.mime != mime);
+        self.attachments.set_mime(id, mime)?;
+        self.touch_if_modified(changed);
+        Ok(())
+    }
+
+    /// Choose how an attachment is compressed when written, or `None` for the default.
+    pub fn set_attachment_compression(
+        &mut self,
+        id: AttachmentId,
+        hint: Option<CompressionHint>,
+    ) -> TmdResult<()> {
+        let changed = self
+            .attachments
+            .meta(id)
+            .is_some_and(|meta| meta.compression_hint != hint);
+        self.attachments.set_compression_hint(id, hint)?;
+        self.touch_if_modified(changed);
+        Ok(())
+    }
+
+    /// Modification time recorded for an attachment's entry in the archive it was read from.
+    pub fn attachment_archive_modified(&self, id: AttachmentId) -> Option<NaiveDateTime> {
+        self.attachments.archive_modified(id)
+    }
+
+    /// Make `alias` resolve to the attachment `id` without duplicating its data.
+    pub fn add_attachment_alias(&mut self, id: AttachmentId, alias: &str) -> TmdResult<()> {
+        let alias = normalize_logical_path(alias)?;
+        self.attachments.add_alias(id, alias)?;
+        self.touch();
+        Ok(())
+    }
+
+    /// Remove an alias previously added with [`TmdDoc::add_attachment_alias`].
+    pub fn remove_attachment_alias(&mut self, id: AttachmentId, alias: &str) -> TmdResult<()> {
+        self.attachments.remove_alias(id, alias)?;
+        self.touch();
+        Ok(())
+    }
+
     /// Get attachment metadata by logical path.
     pub fn attachment_meta_by_path(&self, logical_path: &str) -> Option<&AttachmentMeta> {
         self.attachments.meta_by_path(logical_path)
@@ -160,1443 +527,8681 @@ impl TmdDoc {
         self.attachments.iter()
     }
 
-    /// Execute a read-only closure with a SQLite connection.
-    pub fn db_with_conn<T, F: FnOnce(&Connection) -> T>(&self, f: F) -> TmdResult<T> {
-        self.db.with_conn(f)
+    /// List attachments whose stored length is within `min..=max`; see
+    /// [`AttachmentStore::iter_by_size`].
+    pub fn attachments_by_size(
+        &self,
+        min: u64,
+        max: Option<u64>,
+    ) -> impl Iterator<Item = &AttachmentMeta> {
+        self.attachments.iter_by_size(min, max)
     }
 
-    /// Execute a mutable closure with a SQLite connection.
-    pub fn db_with_conn_mut<T, F: FnOnce(&mut Connection) -> T>(&mut self, f: F) -> TmdResult<T> {
-        self.db.with_conn_mut(f)
+    /// Checksum covering the markdown and the attachment set, as recorded in
+    /// [`Manifest::content_checksum`] by [`WriteMode::compute_checksum`].
+    pub fn content_checksum(&self) -> [u8; 32] {
+        attach::content_checksum(&self.markdown, self.attachments.iter())
     }
-}
 
-/// Utility helper to set the manifest modification timestamp to now.
-fn touch_manifest(manifest: &mut Manifest) {
-    manifest.modified_utc = now_utc();
-}
+    /// Compare the recorded [`Manifest::content_checksum`] with the current content, or
+    /// `None` if no checksum is recorded.
+    pub fn verify_content_checksum(&self) -> Option<bool> {
+        self.manifest
+            .content_checksum
+            .map(|expected| expected == self.content_checksum())
+    }
 
-impl TmdDoc {
-    /// Update the modified timestamp to the current time.
-    pub fn touch(&mut self) {
-        touch_manifest(&mut self.manifest);
+    /// Clone the metadata of every attachment, sorted by logical path.
+    ///
+    /// No attachment data is copied, so the snapshot can outlive this document without
+    /// keeping its buffers alive.
+    pub fn attachment_snapshot(&self) -> Vec<AttachmentMeta> {
+        let mut metas: Vec<AttachmentMeta> = self.attachments.iter().cloned().collect();
+        metas.sort_by(|a, b| a.logical_path.cmp(&b.logical_path));
+        metas
     }
-}
-mod util {
-    use super::{LogicalPath, TmdError, TmdResult};
-    use chrono::{DateTime, Utc};
 
-    /// Return the current UTC time.
-    pub fn now_utc() -> DateTime<Utc> {
-        Utc::now()
+    /// Clone the manifest; see [`TmdDoc::attachment_snapshot`].
+    pub fn manifest_snapshot(&self) -> Manifest {
+        self.manifest.clone()
     }
 
-    /// Normalise a logical attachment path, ensuring POSIX separators and security constraints.
-    pub fn normalize_logical_path(input: &str) -> TmdResult<LogicalPath> {
-        if input.is_empty() {
-            return Err(TmdError::Attachment(
-                "logical path must not be empty".into(),
-            ));
-        }
+    /// Encode an attachment as a `data:{mime};base64,...` URI for inline previews.
+    pub fn attachment_data_uri(&self, id: AttachmentId) -> Option<String> {
+        let meta = self.attachments.meta(id)?;
+        let data = self.attachment_bytes(id).ok()?;
+        Some(util::data_uri(&meta.mime, &data))
+    }
 
-        if input.starts_with('/') {
-            return Err(TmdError::Attachment(
-                "logical path must not start with '/'".into(),
-            ));
+    /// Like [`TmdDoc::attachment_data_uri`], looking the attachment up by logical path.
+    pub fn attachment_data_uri_by_path(&self, logical_path: &str) -> Option<String> {
+        let meta = self.attachments.meta_by_path(logical_path)?;
+        self.attachment_data_uri(meta.id)
+    }
+
+    /// Sum of the stored lengths of all attachments.
+    pub fn attachments_total_size(&self) -> u64 {
+        self.attachments.iter().map(|meta| meta.length).sum()
+    }
+
+    /// Approximate heap bytes held by the markdown and attachments.
+    ///
+    /// The embedded database lives in a temporary file and is not counted.
+    pub fn memory_bytes(&self) -> u64 {
+        self.markdown.len() as u64 + self.attachments.memory_bytes()
+    }
+
+    /// Move every attachment out of the document, leaving it with an empty store.
+    ///
+    /// Lazily read attachments are loaded first, so the returned store holds the data of
+    /// every attachment.
+    pub fn take_attachments(&mut self) -> TmdResult<AttachmentStore> {
+        self.load_attachments()?;
+        let taken = std::mem::take(&mut self.attachments);
+        if !taken.is_empty() {
+            self.touch();
         }
+        Ok(taken)
+    }
 
-        let normalized = input.replace('\\', "/");
-        let mut components = Vec::new();
-        for part in normalized.split('/') {
-            if part.is_empty() || part == "." {
+    /// Compare attachments referenced from the markdown with those present in the store.
+    pub fn attachment_usage(&self) -> AttachmentUsage {
+        let referenced = markdown::referenced_attachment_paths(&self.markdown);
+        let present = self
+            .attachments
+            .iter()
+            .flat_map(|meta| std::iter::once(&meta.logical_path).chain(&meta.aliases))
+            .cloned()
+            .collect();
+        AttachmentUsage::new(referenced, present)
+    }
+
+    /// Embed local files referenced by relative markdown URLs and point the links at them.
+    ///
+    /// Each relative image or link destination is resolved against `base_dir`; existing
+    /// files are stored under their normalized relative path (or `assets/<file name>` when
+    /// that is not a usable logical path) and the destination is rewritten to
+    /// `attach:<path>`. Remote, absolute, `attach:`, and reference-style URLs are left
+    /// alone, as are paths with `..` components or that resolve outside `base_dir`
+    /// through a symlink. Returns the number of attachments added.
+    pub fn embed_local_assets(&mut self, base_dir: &std::path::Path) -> TmdResult<usize> {
+        self.embed_local_assets_with_policy(base_dir, SanitizePolicy::default())
+    }
+
+    /// Like [`TmdDoc::embed_local_assets`], cleaning up the stored paths with `policy`.
+    pub fn embed_local_assets_with_policy(
+        &mut self,
+        base_dir: &std::path::Path,
+        policy: SanitizePolicy,
+    ) -> TmdResult<usize> {
+        let root = base_dir.canonicalize()?;
+        let mut added = 0;
+        let mut replacements = Vec::new();
+        for (range, url) in markdown::inline_destinations(&self.markdown) {
+            if url.starts_with(markdown::ATTACH_SCHEME) {
                 continue;
             }
-            if part == ".." {
-                return Err(TmdError::Attachment(
-                    "logical path must not contain '..'".into(),
-                ));
+            let Some(relative) = markdown::local_file_url(&url) else {
+                continue;
+            };
+            let source = base_dir.join(&relative);
+            let Ok(resolved) = source.canonicalize() else {
+                continue;
+            };
+            if !resolved.starts_with(&root) {
+                log::warn!(
+                    "not embedding `{}`: it resolves outside the base directory",
+                    url
+                );
+                continue;
             }
-            components.push(part);
+            if !resolved.is_file() {
+                continue;
+            }
+            let bytes = std::fs::read(&resolved)?;
+            let preferred = sanitize_logical_path(&relative, policy).or_else(|_| {
+                let name = source
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .unwrap_or("asset");
+                sanitize_logical_path(&format!("assets/{}", name), policy)
+            })?;
+
+            let path = self.embed_path_for(&preferred, &bytes)?;
+            if self.attachments.meta_by_path(&path).is_none() {
+                self.add_attachment(&path, guess_mime(&path), bytes)?;
+                added += 1;
+            }
+            replacements.push((range, format!("{}{}", markdown::ATTACH_SCHEME, path)));
         }
 
-        if components.is_empty() {
-            return Err(TmdError::Attachment(
-                "logical path resolves to empty".into(),
-            ));
+        if !replacements.is_empty() {
+            let mut markdown = self.markdown.clone();
+            for (range, replacement) in replacements.into_iter().rev() {
+                markdown.replace_range(range, &replacement);
+            }
+            self.markdown = markdown;
+            self.touch();
         }
+        Ok(added)
+    }
 
-        Ok(components.join("/"))
+    /// Build a document from a content folder: `entry` (e.g. `index.md`) becomes the
+    /// markdown and every other non-hidden file is attached under its path relative
+    /// to `dir`. The title is taken from the first heading.
+    pub fn from_content_dir(dir: &std::path::Path, entry: &str) -> TmdResult<Self> {
+        Self::from_content_dir_with_policy(dir, entry, SanitizePolicy::default())
     }
-}
-mod manifest {
-    use super::{AttachmentId, LogicalPath};
-    use chrono::{DateTime, Utc};
-    use mime::Mime;
-    use serde::{Deserialize, Serialize};
-    use uuid::Uuid;
 
-    #[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
-    pub struct Semver {
-        pub major: u16,
-        pub minor: u16,
-        pub patch: u16,
+    /// Like [`TmdDoc::from_content_dir`], cleaning up attachment paths with `policy`.
+    ///
+    /// Relative markdown links to a file whose path changed are rewritten to
+    /// `attach:<new path>`; files that sanitize to the same path get a numeric suffix.
+    pub fn from_content_dir_with_policy(
+        dir: &std::path::Path,
+        entry: &str,
+        policy: SanitizePolicy,
+    ) -> TmdResult<Self> {
+        let entry = normalize_logical_path(entry)?;
+        let markdown = std::fs::read_to_string(dir.join(&entry))?;
+        let mut doc = Self::new(String::new())?;
+        doc.set_markdown(markdown);
+
+        let mut files = Vec::new();
+        util::collect_visible_files(dir, &mut files)?;
+        files.sort();
+        let mut renamed = std::collections::HashMap::new();
+        for file in files {
+            let relative = file
+                .strip_prefix(dir)
+                .ok()
+                .and_then(|path| path.to_str())
+                .ok_or_else(|| {
+                    TmdError::Attachment(format!("`{}` is not valid UTF-8", file.display()))
+                })?;
+            let original = normalize_logical_path(relative)?;
+            if original == entry {
+                continue;
+            }
+            let bytes = std::fs::read(&file)?;
+            let preferred = sanitize_logical_path(relative, policy)?;
+            let path = doc.embed_path_for(&preferred, &bytes)?;
+            if doc.attachments.meta_by_path(&path).is_none() {
+                doc.add_attachment(&path, guess_mime(&path), bytes)?;
+            }
+            if path != original {
+                renamed.insert(original, path);
+            }
+        }
+        if !renamed.is_empty() {
+            doc.relink_renamed(&entry, &renamed);
+        }
+        Ok(doc)
     }
 
-    #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
-    pub struct AttachmentRef {
-        pub id: AttachmentId,
+    /// Point relative links that resolve to a key of `renamed`, from the markdown stored
+    /// at `entry`, at `attach:` URLs for the new paths. Both inline destinations and
+    /// reference definitions are rewritten.
+    fn relink_renamed(
+        &mut self,
+        entry: &str,
+        renamed: &std::collections::HashMap<LogicalPath, LogicalPath>,
+    ) {
+        let base = entry.rsplit_once('/').map_or("", |(parent, _)| parent);
+        let mut markdown = self.markdown.clone();
+        for (range, url) in markdown::link_destinations(&self.markdown)
+            .into_iter()
+            .rev()
+        {
+            let Some(relative) = markdown::local_file_url(&url) else {
+                continue;
+            };
+            let joined = if base.is_empty() {
+                relative
+            } else {
+                format!("{}/{}", base, relative)
+            };
+            let Some(path) = normalize_logical_path(&joined)
+                .ok()
+                .and_then(|path| renamed.get(&path))
+            else {
+                continue;
+            };
+            markdown.replace_range(range, &format!("{}{}", markdown::ATTACH_SCHEME, path));
+        }
+        self.set_markdown(markdown);
     }
 
-    #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
-    pub struct LinkRef {
-        pub rel: String,
-        pub href: String,
+    /// Pick a logical path for embedded bytes, reusing an identical attachment or
+    /// appending a numeric suffix to avoid clobbering different content.
+    fn embed_path_for(&self, preferred: &str, bytes: &[u8]) -> TmdResult<LogicalPath> {
+        let same_content = |path: &str| {
+            self.attachments.meta_by_path(path).map(|meta| {
+                self.attachment_bytes(meta.id)
+                    .is_ok_and(|data| data.as_ref() == bytes)
+            })
+        };
+        match same_content(preferred) {
+            None | Some(true) => return Ok(preferred.to_string()),
+            Some(false) => {}
+        }
+        util::free_suffixed_path(preferred, |candidate| {
+            !matches!(same_content(candidate), Some(false))
+        })
     }
 
-    #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
-    pub struct Manifest {
-        pub tmd_version: Semver,
-        pub doc_id: Uuid,
-        pub title: Option<String>,
-        pub authors: Vec<String>,
-        pub created_utc: DateTime<Utc>,
-        pub modified_utc: DateTime<Utc>,
-        pub tags: Vec<String>,
-        pub cover_image: Option<AttachmentRef>,
-        pub links: Vec<LinkRef>,
-        pub db_schema_version: Option<u32>,
-        #[serde(default)]
-        pub extras: serde_json::Value,
+    /// Report attachments that share identical content, without modifying the store.
+    pub fn duplicate_attachments(&self) -> Vec<DuplicateGroup> {
+        self.attachments.duplicate_groups()
     }
 
-    #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
-    pub struct AttachmentMeta {
-        pub id: AttachmentId,
-        pub logical_path: LogicalPath,
-        #[serde(with = "mime_serde")]
-        pub mime: Mime,
-        pub length: u64,
-        #[serde(default, with = "sha_option")]
-        pub sha256: Option<[u8; 32]>,
-        pub title: Option<String>,
-        pub alt: Option<String>,
-        #[serde(default)]
-        pub extras: serde_json::Value,
+    /// Execute a read-only closure with a SQLite connection.
+    pub fn db_with_conn<T, F: FnOnce(&Connection) -> T>(&self, f: F) -> TmdResult<T> {
+        self.db.with_conn(f)
     }
 
-    mod mime_serde {
-        use super::Mime;
-        use serde::de::Error as DeError;
-        use serde::{Deserialize, Deserializer, Serializer};
+    /// Execute a mutable closure with a SQLite connection.
+    pub fn db_with_conn_mut<T, F: FnOnce(&mut Connection) -> T>(&mut self, f: F) -> TmdResult<T> {
+        self.db.with_conn_mut(f)
+    }
 
-        pub fn serialize<S>(mime: &Mime, serializer: S) -> Result<S::Ok, S::Error>
-        where
-            S: Serializer,
-        {
-            serializer.serialize_str(mime.as_ref())
-        }
+    /// Run several statements atomically on one connection.
+    ///
+    /// Unlike nested [`TmdDoc::db_with_conn_mut`] calls, which each open their own
+    /// connection, every statement in `f` shares one transaction. It is committed when
+    /// `f` returns `Ok` and rolled back when it returns `Err`.
+    pub fn db_transaction<T, F>(&mut self, f: F) -> TmdResult<T>
+    where
+        F: FnOnce(&rusqlite::Transaction) -> rusqlite::Result<T>,
+    {
+        self.db.transaction(f)
+    }
 
-        pub fn deserialize<'de, D>(deserializer: D) -> Result<Mime, D::Error>
-        where
-            D: Deserializer<'de>,
-        {
-            let s = String::deserialize(deserializer)?;
-            s.parse().map_err(|_| DeError::custom("invalid MIME type"))
-        }
+    /// Read `PRAGMA user_version` from the embedded database.
+    pub fn db_user_version(&self) -> TmdResult<u32> {
+        self.db
+            .with_conn(|conn| {
+                conn.query_row("PRAGMA user_version", [], |row| row.get::<_, u32>(0))
+            })?
+            .map_err(TmdError::from)
     }
 
-    mod sha_option {
-        use serde::de::Error as DeError;
-        use serde::{Deserialize, Deserializer, Serializer};
+    /// Copy `PRAGMA user_version` into `manifest.db_schema_version`, returning the value.
+    pub fn sync_schema_version(&mut self) -> TmdResult<u32> {
+        let version = self.db_user_version()?;
+        let changed = self.manifest.db_schema_version != Some(version);
+        self.manifest.db_schema_version = Some(version);
+        self.touch_if_modified(changed);
+        Ok(version)
+    }
 
-        pub fn serialize<S>(value: &Option<[u8; 32]>, serializer: S) -> Result<S::Ok, S::Error>
-        where
-            S: Serializer,
-        {
-            match value {
-                Some(bytes) => serializer.serialize_some(&hex::encode(bytes)),
-                None => serializer.serialize_none(),
-            }
-        }
+    /// Replace the embedded database with a fresh empty one and clear
+    /// `manifest.db_schema_version`, leaving a content-only document.
+    pub fn strip_db(&mut self) -> TmdResult<()> {
+        let mut db = DbHandle::new_empty()?;
+        db.ensure_initialized(None)?;
+        self.db = db;
+        self.manifest.db_schema_version = None;
+        self.touch();
+        Ok(())
+    }
 
-        pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<[u8; 32]>, D::Error>
-        where
-            D: Deserializer<'de>,
-        {
-            let opt = Option::<String>::deserialize(deserializer)?;
-            match opt {
-                Some(s) => {
-                    let decoded = hex::decode(&s).map_err(|_| DeError::custom("invalid hex"))?;
-                    if decoded.len() != 32 {
-                        return Err(DeError::custom("invalid sha256 length"));
+    /// Dump the `CREATE` statements of every user table, index, view, and trigger.
+    ///
+    /// Statements appear in creation order, each terminated by `;` and a newline.
+    pub fn db_schema_sql(&self) -> TmdResult<String> {
+        self.db
+            .with_conn(|conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT sql FROM sqlite_master
+                     WHERE sql IS NOT NULL AND name NOT LIKE 'sqlite\\_%' ESCAPE '\\'
+                     ORDER BY rowid",
+                )?;
+                let statements = stmt.query_map([], |row| row.get::<_, String>(0))?;
+                let mut schema = String::new();
+                for sql in statements {
+                    schema.push_str(&sql?);
+                    schema.push_str(";\n");
+                }
+                Ok::<_, rusqlite::Error>(schema)
+            })?
+            .map_err(TmdError::from)
+    }
+
+    /// Count the rows of `table` in the embedded database.
+    ///
+    /// The name is quoted, so tables named after reserved words or containing
+    /// punctuation work as-is.
+    pub fn db_row_count(&self, table: &str) -> TmdResult<u64> {
+        let sql = format!("SELECT COUNT(*) FROM {}", util::quote_ident(table));
+        self.db
+            .with_conn(|conn| conn.query_row(&sql, [], |row| row.get::<_, u64>(0)))?
+            .map_err(TmdError::from)
+    }
+
+    /// Run `PRAGMA integrity_check` on the embedded database.
+    ///
+    /// Returns the reported problems; an empty list means the database is intact.
+    pub fn db_integrity_check(&self) -> TmdResult<Vec<String>> {
+        self.db
+            .with_conn(|conn| {
+                let mut stmt = conn.prepare("PRAGMA integrity_check")?;
+                let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+                let mut problems = Vec::new();
+                for row in rows {
+                    let row = row?;
+                    if row != "ok" {
+                        problems.push(row);
                     }
-                    let mut arr = [0u8; 32];
-                    arr.copy_from_slice(&decoded);
-                    Ok(Some(arr))
                 }
-                None => Ok(None),
-            }
-        }
+                Ok::<_, rusqlite::Error>(problems)
+            })?
+            .map_err(TmdError::from)
     }
-}
-mod attach {
-    use super::{AttachmentId, AttachmentMeta, LogicalPath, TmdError, TmdResult};
-    use mime::Mime;
-    use serde_json;
-    use sha2::{Digest, Sha256};
-    use std::collections::{hash_map::Values, HashMap};
-    use std::ops::{Deref, DerefMut};
 
-    #[derive(Debug)]
-    struct AttachmentEntry {
-        meta: AttachmentMeta,
-        data: Vec<u8>,
+    /// Run a query against the embedded database, calling `f` for each row as it is
+    /// stepped instead of collecting the whole result set.
+    ///
+    /// The statement stays borrowed for the duration of the iteration, so `f` cannot
+    /// mutate this document; an error returned by `f` stops the iteration and is
+    /// propagated.
+    pub fn db_query_each<F>(&self, sql: &str, f: F) -> TmdResult<()>
+    where
+        F: FnMut(&[SqlCell]) -> TmdResult<()>,
+    {
+        self.db.query_each(sql, f)
     }
 
-    #[derive(Debug, Default)]
-    pub struct AttachmentStore {
-        entries: HashMap<AttachmentId, AttachmentEntry>,
-        by_path: HashMap<LogicalPath, AttachmentId>,
+    /// Run a query against the embedded database and collect the typed results.
+    pub fn db_query(&self, sql: &str) -> TmdResult<QueryResult> {
+        self.db.query(sql)
     }
+}
 
-    impl AttachmentStore {
-        pub fn new() -> Self {
-            Self::default()
-        }
+/// Parse an attachment id from its string form, as exchanged over the CLI and FFI.
+pub fn parse_attachment_id(input: &str) -> TmdResult<AttachmentId> {
+    AttachmentId::parse_str(input.trim())
+        .map_err(|err| TmdError::Attachment(format!("invalid attachment id `{}`: {}", input, err)))
+}
 
-        pub fn insert(
-            &mut self,
-            id: AttachmentId,
-            logical_path: LogicalPath,
-            mime: Mime,
-            data: Vec<u8>,
-        ) -> TmdResult<AttachmentId> {
-            if self.entries.contains_key(&id) {
-                return Err(TmdError::Attachment(format!(
-                    "attachment id {} already exists",
-                    id
-                )));
-            }
-            if self.by_path.contains_key(&logical_path) {
-                return Err(TmdError::Attachment(format!(
-                    "attachment `{}` already exists",
-                    logical_path
-                )));
-            }
+/// Format an attachment id in its canonical hyphenated lowercase form.
+pub fn attachment_id_string(id: AttachmentId) -> String {
+    id.hyphenated().to_string()
+}
 
-            let length = data.len() as u64;
-            let sha = Sha256::digest(&data);
-            let mut sha_bytes = [0u8; 32];
-            sha_bytes.copy_from_slice(&sha);
-            let meta = AttachmentMeta {
-                id,
-                logical_path: logical_path.clone(),
-                mime,
-                length,
-                sha256: Some(sha_bytes),
-                title: None,
-                alt: None,
-                extras: serde_json::Value::default(),
-            };
-            self.by_path.insert(logical_path.clone(), id);
-            self.entries.insert(id, AttachmentEntry { meta, data });
-            Ok(id)
-        }
+/// Utility helper to set the manifest modification timestamp to now.
+fn touch_manifest(manifest: &mut Manifest) {
+    manifest.modified_utc = now_utc();
+}
 
-        pub fn remove(&mut self, id: AttachmentId) -> Result<(), String> {
-            if let Some(entry) = self.entries.remove(&id) {
-                self.by_path.remove(&entry.meta.logical_path);
-                Ok(())
-            } else {
-                Err(format!("attachment id {} not found", id))
-            }
-        }
+impl TmdDoc {
+    /// Update the modified timestamp to the current time.
+    pub fn touch(&mut self) {
+        touch_manifest(&mut self.manifest);
+    }
 
-        pub fn rename(&mut self, id: AttachmentId, new_path: LogicalPath) -> TmdResult<()> {
-            if self.by_path.contains_key(&new_path) {
-                return Err(TmdError::Attachment(format!(
-                    "attachment `{}` already exists",
-                    new_path
-                )));
-            }
-            let entry = self
-                .entries
-                .get_mut(&id)
-                .ok_or_else(|| TmdError::Attachment(format!("attachment id {} not found", id)))?;
-            self.by_path.remove(&entry.meta.logical_path);
-            self.by_path.insert(new_path.clone(), id);
-            entry.meta.logical_path = new_path;
-            Ok(())
+    /// Update the modified timestamp only when `changed` is set, returning `changed`.
+    ///
+    /// Mutating helpers use this so that no-op edits leave `modified_utc` alone.
+    pub fn touch_if_modified(&mut self, changed: bool) -> bool {
+        if changed {
+            self.touch();
         }
+        changed
+    }
 
-        pub fn meta(&self, id: AttachmentId) -> Option<&AttachmentMeta> {
-            self.entries.get(&id).map(|entry| &entry.meta)
-        }
+    /// Set the modified timestamp explicitly, truncated to millisecond resolution like
+    /// [`now_utc`].
+    pub fn set_modified(&mut self, when: DateTime<Utc>) {
+        self.manifest.modified_utc = when.trunc_subsecs(3);
+    }
 
-        pub fn meta_by_path(&self, logical_path: &str) -> Option<&AttachmentMeta> {
-            self.by_path
-                .get(logical_path)
-                .and_then(|id| self.entries.get(id))
-                .map(|entry| &entry.meta)
-        }
+    /// Compare markdown, manifest, and attachments, ignoring the database.
+    ///
+    /// Attachments that are not resident only equal other non-resident attachments;
+    /// call [`TmdDoc::load_attachments`] first to compare lazily read documents.
+    pub fn content_eq(&self, other: &Self) -> bool {
+        self.markdown == other.markdown
+            && self.manifest == other.manifest
+            && self.attachments == other.attachments
+    }
 
-        pub fn data(&self, id: AttachmentId) -> Option<&[u8]> {
-            self.entries.get(&id).map(|entry| entry.data.as_slice())
+    /// Compare document content including the raw bytes of the embedded database.
+    pub fn content_eq_with_db(&self, other: &Self) -> TmdResult<bool> {
+        if !self.content_eq(other) {
+            return Ok(false);
         }
+        let ours = std::fs::read(self.db.as_path())?;
+        let theirs = std::fs::read(other.db.as_path())?;
+        Ok(ours == theirs)
+    }
+}
+mod util {
+    use super::{LogicalPath, TmdError, TmdResult};
+    use base64::engine::general_purpose::STANDARD as BASE64_STANDARD;
+    use base64::Engine;
+    use chrono::{DateTime, SubsecRound, Utc};
+    use mime::Mime;
+    use std::cell::RefCell;
+    use std::sync::Arc;
 
-        pub fn data_mut(&mut self, id: AttachmentId) -> Option<AttachmentDataMut<'_>> {
-            self.entries
-                .get_mut(&id)
-                .map(|entry| AttachmentDataMut { entry })
-        }
+    /// Source of the current time used for manifest timestamps.
+    pub trait Clock: Send + Sync {
+        /// Return the current UTC time.
+        fn now(&self) -> DateTime<Utc>;
+    }
 
-        pub fn iter(&self) -> AttachmentStoreIter<'_> {
-            AttachmentStoreIter {
-                inner: self.entries.values(),
-            }
-        }
+    /// Clock backed by the system time; used unless another clock is installed.
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct SystemClock;
 
-        pub fn iter_with_data(&self) -> impl Iterator<Item = (&AttachmentMeta, &[u8])> {
-            self.entries
-                .values()
-                .map(|entry| (&entry.meta, entry.data.as_slice()))
+    impl Clock for SystemClock {
+        fn now(&self) -> DateTime<Utc> {
+            Utc::now()
         }
+    }
 
-        pub fn is_empty(&self) -> bool {
-            self.entries.is_empty()
-        }
+    /// Clock that always returns the same instant, for deterministic tests.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct FixedClock(pub DateTime<Utc>);
 
-        pub fn insert_entry(
-            &mut self,
-            meta: AttachmentMeta,
-            data: Vec<u8>,
-            verify_hashes: bool,
-        ) -> TmdResult<()> {
-            if self.entries.contains_key(&meta.id) {
-                return Err(TmdError::Attachment(format!(
-                    "attachment id {} already exists",
-                    meta.id
-                )));
-            }
-            if self.by_path.contains_key(&meta.logical_path) {
-                return Err(TmdError::Attachment(format!(
-                    "attachment `{}` already exists",
-                    meta.logical_path
-                )));
-            }
-            let length = data.len() as u64;
-            if length != meta.length {
-                return Err(TmdError::Attachment(format!(
-                    "attachment `{}` length mismatch: manifest={} actual={}",
-                    meta.logical_path, meta.length, length
-                )));
-            }
-            if verify_hashes {
-                if let Some(expected) = &meta.sha256 {
-                    let digest = Sha256::digest(&data);
-                    let mut computed = [0u8; 32];
-                    computed.copy_from_slice(&digest);
-                    if expected != &computed {
-                        return Err(TmdError::Attachment(format!(
-                            "attachment `{}` sha256 mismatch",
-                            meta.logical_path
-                        )));
-                    }
-                }
-            }
-            self.by_path.insert(meta.logical_path.clone(), meta.id);
-            self.entries.insert(meta.id, AttachmentEntry { meta, data });
-            Ok(())
+    impl Clock for FixedClock {
+        fn now(&self) -> DateTime<Utc> {
+            self.0
         }
     }
 
-    pub struct AttachmentDataMut<'a> {
-        entry: &'a mut AttachmentEntry,
+    thread_local! {
+        static CLOCK: RefCell<Option<Arc<dyn Clock>>> = const { RefCell::new(None) };
     }
 
-    impl<'a> Deref for AttachmentDataMut<'a> {
-        type Target = Vec<u8>;
+    /// Install `clock` as the time source for the current thread, returning the
+    /// previously installed clock. Passing `None` restores [`SystemClock`].
+    pub fn set_clock(clock: Option<Arc<dyn Clock>>) -> Option<Arc<dyn Clock>> {
+        CLOCK.with(|slot| std::mem::replace(&mut *slot.borrow_mut(), clock))
+    }
 
-        fn deref(&self) -> &Self::Target {
-            &self.entry.data
+    /// Run `f` with `clock` installed as the time source for the current thread,
+    /// restoring the previous clock afterwards even if `f` panics.
+    pub fn with_clock<C, T>(clock: C, f: impl FnOnce() -> T) -> T
+    where
+        C: Clock + 'static,
+    {
+        struct Restore(Option<Option<Arc<dyn Clock>>>);
+
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                if let Some(previous) = self.0.take() {
+                    set_clock(previous);
+                }
+            }
         }
+
+        let _restore = Restore(Some(set_clock(Some(Arc::new(clock)))));
+        f()
     }
 
-    impl<'a> DerefMut for AttachmentDataMut<'a> {
-        fn deref_mut(&mut self) -> &mut Self::Target {
-            &mut self.entry.data
-        }
+    /// Return the current UTC time, truncated to millisecond resolution.
+    ///
+    /// Manifest timestamps use this resolution so they compare equal after a JSON
+    /// round trip through tools that drop sub-millisecond digits. The time comes
+    /// from the clock installed with [`set_clock`] or [`with_clock`], falling back
+    /// to [`SystemClock`].
+    pub fn now_utc() -> DateTime<Utc> {
+        let now = CLOCK.with(|slot| slot.borrow().as_ref().map(|clock| clock.now()));
+        now.unwrap_or_else(Utc::now).trunc_subsecs(3)
     }
 
-    impl<'a> Drop for AttachmentDataMut<'a> {
-        fn drop(&mut self) {
-            self.entry.meta.length = self.entry.data.len() as u64;
-            let digest = Sha256::digest(&self.entry.data);
-            let mut sha = [0u8; 32];
-            sha.copy_from_slice(&digest);
-            self.entry.meta.sha256 = Some(sha);
+    /// Recursively collect the files under `dir`, skipping entries whose name starts
+    /// with a dot.
+    pub(crate) fn collect_visible_files(
+        dir: &std::path::Path,
+        out: &mut Vec<std::path::PathBuf>,
+    ) -> TmdResult<()> {
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            let path = entry.path();
+            if entry.file_type()?.is_dir() {
+                collect_visible_files(&path, out)?;
+            } else {
+                out.push(path);
+            }
         }
+        Ok(())
     }
 
-    pub struct AttachmentStoreIter<'a> {
-        inner: Values<'a, AttachmentId, AttachmentEntry>,
+    /// Build a base64 `data:` URI for `data` of type `mime`.
+    pub(crate) fn data_uri(mime: &Mime, data: &[u8]) -> String {
+        format!("data:{};base64,{}", mime, BASE64_STANDARD.encode(data))
     }
 
-    impl<'a> Iterator for AttachmentStoreIter<'a> {
-        type Item = &'a AttachmentMeta;
+    /// Guess a MIME type from a path's extension, defaulting to `application/octet-stream`.
+    pub fn guess_mime(path: &str) -> Mime {
+        let ext = path
+            .rsplit_once('.')
+            .map(|(_, ext)| ext.to_ascii_lowercase())
+            .unwrap_or_default();
+        let guessed = match ext.as_str() {
+            "png" => "image/png",
+            "jpg" | "jpeg" => "image/jpeg",
+            "gif" => "image/gif",
+            "svg" => "image/svg+xml",
+            "webp" => "image/webp",
+            "pdf" => "application/pdf",
+            "json" => "application/json",
+            "csv" => "text/csv",
+            "md" | "markdown" => "text/markdown",
+            "txt" => "text/plain",
+            "html" | "htm" => "text/html",
+            "sqlite" | "sqlite3" | "db" => "application/vnd.sqlite3",
+            _ => return mime::APPLICATION_OCTET_STREAM,
+        };
+        guessed.parse().unwrap_or(mime::APPLICATION_OCTET_STREAM)
+    }
 
-        fn next(&mut self) -> Option<Self::Item> {
-            self.inner.next().map(|entry| &entry.meta)
+    /// Check that a BCP-47 language tag is well formed: ASCII alphanumeric subtags of
+    /// one to eight characters separated by `-`.
+    pub fn validate_language_tag(tag: &str) -> TmdResult<()> {
+        let valid = !tag.is_empty()
+            && tag.split('-').all(|subtag| {
+                (1..=8).contains(&subtag.len()) && subtag.chars().all(|c| c.is_ascii_alphanumeric())
+            });
+        if valid {
+            Ok(())
+        } else {
+            Err(TmdError::InvalidFormat(format!(
+                "invalid language tag `{}`",
+                tag
+            )))
         }
     }
-}
-mod db {
-    use super::{TmdDoc, TmdError, TmdResult};
-    use rusqlite::Connection;
-    use std::fs;
-    use std::path::{Path, PathBuf};
-    use tempfile::TempDir;
-
-    #[derive(Clone, Debug, Default)]
-    pub struct DbOptions {
-        pub page_size: Option<u32>,
-        pub journal_mode: Option<String>,
-        pub synchronous: Option<String>,
-    }
-
-    #[derive(Debug)]
-    pub struct DbHandle {
-        _temp_dir: TempDir,
-        path: PathBuf,
-    }
 
-    impl DbHandle {
-        pub fn new_empty() -> TmdResult<Self> {
-            let temp_dir = TempDir::new()?;
-            let path = temp_dir.path().join("main.sqlite3");
-            let conn = Connection::open(&path)?;
-            conn.execute_batch("PRAGMA user_version = 0;")?;
-            conn.close()
-                .map_err(|(_, err)| TmdError::Db(err.to_string()))?;
-            Ok(Self {
-                _temp_dir: temp_dir,
-                path,
-            })
+    /// Normalise a logical attachment path, ensuring POSIX separators and security constraints.
+    pub fn normalize_logical_path(input: &str) -> TmdResult<LogicalPath> {
+        if input.is_empty() {
+            return Err(TmdError::Attachment(
+                "logical path must not be empty".into(),
+            ));
         }
 
-        pub fn from_bytes(bytes: &[u8]) -> TmdResult<Self> {
-            let temp_dir = TempDir::new()?;
-            let path = temp_dir.path().join("main.sqlite3");
-            fs::write(&path, bytes)?;
-            Ok(Self {
-                _temp_dir: temp_dir,
-                path,
-            })
+        if input.starts_with('/') {
+            return Err(TmdError::Attachment(
+                "logical path must not start with '/'".into(),
+            ));
         }
 
-        pub fn ensure_initialized(&mut self, opts: Option<DbOptions>) -> TmdResult<()> {
-            let mut conn = Connection::open(&self.path)?;
-            if let Some(opts) = opts {
-                apply_options(&mut conn, &opts)?;
+        let normalized = input.replace('\\', "/");
+        let mut components = Vec::new();
+        for part in normalized.split('/') {
+            if part.is_empty() || part == "." {
+                continue;
             }
-            conn.close()
-                .map_err(|(_, err)| TmdError::Db(err.to_string()))?;
-            Ok(())
-        }
-
-        pub fn with_conn<T, F: FnOnce(&Connection) -> T>(&self, f: F) -> TmdResult<T> {
-            let conn = Connection::open(&self.path)?;
-            let result = f(&conn);
-            conn.close()
-                .map_err(|(_, err)| TmdError::Db(err.to_string()))?;
-            Ok(result)
+            if part == ".." {
+                return Err(TmdError::Attachment(
+                    "logical path must not contain '..'".into(),
+                ));
+            }
+            components.push(part);
         }
 
-        pub fn with_conn_mut<T, F: FnOnce(&mut Connection) -> T>(&mut self, f: F) -> TmdResult<T> {
-            let mut conn = Connection::open(&self.path)?;
-            let result = f(&mut conn);
-            conn.close()
-                .map_err(|(_, err)| TmdError::Db(err.to_string()))?;
-            Ok(result)
+        if components.is_empty() {
+            return Err(TmdError::Attachment(
+                "logical path resolves to empty".into(),
+            ));
         }
 
-        pub fn as_path(&self) -> &Path {
-            &self.path
-        }
+        Ok(components.join("/"))
     }
 
-    fn apply_options(conn: &mut Connection, opts: &DbOptions) -> TmdResult<()> {
-        if let Some(page_size) = opts.page_size {
-            conn.pragma_update(None, "page_size", page_size)?;
-        }
-        if let Some(mode) = &opts.journal_mode {
-            conn.pragma_update(None, "journal_mode", mode.as_str())?;
-        }
-        if let Some(sync) = &opts.synchronous {
-            conn.pragma_update(None, "synchronous", sync.as_str())?;
-        }
-        Ok(())
+    /// How [`sanitize_logical_path`] rewrites file names before normalization.
+    ///
+    /// The default policy changes nothing, leaving plain [`normalize_logical_path`].
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct SanitizePolicy {
+        /// Replace whitespace with `-`.
+        pub replace_spaces: bool,
+        pub lowercase: bool,
+        /// Drop every character other than letters, digits, `.`, `_`, and `-`. Letters
+        /// and digits from any script are kept.
+        pub strip_special: bool,
+        /// Collapse runs of `-` or `_` into one and trim them from segment ends.
+        pub collapse_repeats: bool,
     }
 
-    pub fn with_conn<T, F: FnOnce(&Connection) -> T>(doc: &TmdDoc, f: F) -> TmdResult<T> {
-        doc.db.with_conn(f)
+    impl SanitizePolicy {
+        /// Every rewrite enabled, for paths that need no escaping in markdown links.
+        pub fn link_friendly() -> Self {
+            Self {
+                replace_spaces: true,
+                lowercase: true,
+                strip_special: true,
+                collapse_repeats: true,
+            }
+        }
     }
 
-    pub fn with_conn_mut<T, F: FnOnce(&mut Connection) -> T>(
-        doc: &mut TmdDoc,
-        f: F,
-    ) -> TmdResult<T> {
-        doc.db.with_conn_mut(f)
+    /// Rewrite each segment of `input` according to `policy`, then normalize the result
+    /// with [`normalize_logical_path`]. Segments left empty are dropped, so
+    /// `Photos/My  Trip #2?.JPG` becomes `photos/my-trip-2.jpg` under
+    /// [`SanitizePolicy::link_friendly`].
+    pub fn sanitize_logical_path(input: &str, policy: SanitizePolicy) -> TmdResult<LogicalPath> {
+        let segments: Vec<String> = input
+            .split(['/', '\\'])
+            .map(|segment| sanitize_segment(segment, policy))
+            .collect();
+        normalize_logical_path(&segments.join("/"))
     }
 
-    pub fn export_db(doc: &TmdDoc, out_path: impl AsRef<Path>) -> TmdResult<()> {
-        let out = out_path.as_ref();
-        fs::copy(doc.db.as_path(), out)?;
-        Ok(())
+    fn sanitize_segment(segment: &str, policy: SanitizePolicy) -> String {
+        let mut out = String::with_capacity(segment.len());
+        for ch in segment.chars() {
+            let ch = if policy.replace_spaces && ch.is_whitespace() {
+                '-'
+            } else {
+                ch
+            };
+            if policy.strip_special && !(ch.is_alphanumeric() || matches!(ch, '.' | '_' | '-')) {
+                continue;
+            }
+            if policy.collapse_repeats && matches!(ch, '-' | '_') && out.ends_with(ch) {
+                continue;
+            }
+            if policy.lowercase {
+                out.extend(ch.to_lowercase());
+            } else {
+                out.push(ch);
+            }
+        }
+        if policy.collapse_repeats {
+            // Also drop separators stranded next to the extension dot.
+            while let Some(at) = out.find("-.").or_else(|| out.find("_.")) {
+                out.remove(at);
+            }
+            out = out.trim_matches(['-', '_']).to_string();
+        }
+        out
     }
 
-    pub fn import_db(doc: &mut TmdDoc, in_path: impl AsRef<Path>) -> TmdResult<()> {
-        let bytes = fs::read(in_path)?;
-        fs::write(doc.db.as_path(), bytes)?;
-        Ok(())
+    /// Quote an SQL identifier such as a table or column name, doubling any embedded
+    /// double quotes, so reserved words and punctuation are safe to interpolate.
+    pub(crate) fn quote_ident(name: &str) -> String {
+        format!("\"{}\"", name.replace('"', "\"\""))
     }
 
-    pub fn reset_db(doc: &mut TmdDoc, schema_sql: &str, version: u32) -> TmdResult<()> {
-        doc.db
-            .with_conn_mut(|conn| -> rusqlite::Result<()> {
-                conn.execute_batch("VACUUM;")?;
-                conn.execute_batch(schema_sql)?;
-                conn.pragma_update(None, "user_version", version as i64)?;
-                Ok(())
-            })?
-            .map_err(TmdError::from)?;
-        Ok(())
+    /// Number of `/`-separated components in a logical path.
+    pub(crate) fn path_depth(logical_path: &str) -> usize {
+        logical_path.split('/').count()
     }
 
-    pub fn migrate(doc: &mut TmdDoc, up_sql: &str, from: u32, to: u32) -> TmdResult<()> {
-        let current: u32 = doc
-            .db
-            .with_conn(|conn| conn.query_row("PRAGMA user_version", [], |row| row.get::<_, u32>(0)))
-            .and_then(|res| res.map_err(super::TmdError::from))?;
-        if current != from {
-            return Err(super::TmdError::Db(format!(
-                "expected user_version {} but found {}",
-                from, current
-            )));
+    /// Insert `-n` before the extension of the last path segment, e.g. `a/b.png` -> `a/b-1.png`.
+    pub(crate) fn with_numeric_suffix(path: &str, n: usize) -> LogicalPath {
+        match path.rsplit_once('.') {
+            Some((stem, ext)) if !stem.ends_with('/') && !ext.contains('/') => {
+                format!("{}-{}.{}", stem, n, ext)
+            }
+            _ => format!("{}-{}", path, n),
         }
-        doc.db
-            .with_conn_mut(|conn| -> rusqlite::Result<()> {
-                conn.execute_batch(up_sql)?;
-                conn.pragma_update(None, "user_version", to as i64)?;
-                Ok(())
-            })?
-            .map_err(TmdError::from)?;
-        Ok(())
     }
-}
-mod format {
-    use super::attach::AttachmentStore;
-    use super::db::DbHandle;
-    use super::manifest::{AttachmentMeta, Manifest};
-    use super::{TmdDoc, TmdError, TmdResult};
-    use serde::{Deserialize, Serialize};
-    use serde_json;
-    use std::fs::File;
-    use std::io::{Read, Seek, SeekFrom, Write};
-    use std::path::Path;
-    use zip::write::FileOptions;
-    use zip::{CompressionMethod, ZipArchive, ZipWriter};
 
-    const EOCD_SIGNATURE: [u8; 4] = [0x50, 0x4b, 0x05, 0x06];
-    const MAX_COMMENT_SEARCH: usize = 0xFFFF + 22;
-    const TMD_COMMENT_PREFIX: &[u8] = b"TMD1\0";
+    /// The first [`with_numeric_suffix`] variant of `path`, counting up from `-1`, that
+    /// `is_free` accepts.
+    pub(crate) fn free_suffixed_path(
+        path: &str,
+        mut is_free: impl FnMut(&str) -> bool,
+    ) -> TmdResult<LogicalPath> {
+        (1..=usize::MAX)
+            .map(|n| with_numeric_suffix(path, n))
+            .find(|candidate| is_free(candidate))
+            .ok_or_else(|| TmdError::Attachment(format!("no free path left for `{}`", path)))
+    }
 
-    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
-    pub enum Format {
-        Tmd,
-        Tmdz,
+    /// Match a logical path against a glob pattern.
+    ///
+    /// `*` matches any run of characters within one path segment, `?` matches a single
+    /// character other than `/`, and a `**` segment matches zero or more whole segments.
+    /// Every other character matches itself.
+    pub(crate) fn glob_match(pattern: &str, path: &str) -> bool {
+        let pattern: Vec<&str> = pattern.split('/').collect();
+        let path: Vec<&str> = path.split('/').collect();
+        match_segments(&pattern, &path)
     }
 
-    pub fn sniff_format(header: &[u8]) -> Option<Format> {
-        if header.len() >= 4 && &header[0..4] == b"PK\x03\x04" {
-            Some(Format::Tmdz)
-        } else if !header.is_empty() {
-            Some(Format::Tmd)
-        } else {
-            None
+    fn match_segments(pattern: &[&str], path: &[&str]) -> bool {
+        match pattern.split_first() {
+            None => path.is_empty(),
+            Some((&"**", rest)) => (0..=path.len()).any(|skip| match_segments(rest, &path[skip..])),
+            Some((segment, rest)) => match path.split_first() {
+                Some((name, remaining)) => {
+                    let segment: Vec<char> = segment.chars().collect();
+                    let name: Vec<char> = name.chars().collect();
+                    match_segment(&segment, &name) && match_segments(rest, remaining)
+                }
+                None => false,
+            },
         }
     }
 
-    #[derive(Clone, Copy, Debug)]
-    pub struct ReadMode {
-        pub verify_hashes: bool,
-        pub lazy_attachments: bool,
+    fn match_segment(pattern: &[char], name: &[char]) -> bool {
+        match pattern.split_first() {
+            None => name.is_empty(),
+            Some(('*', rest)) => (0..=name.len()).any(|skip| match_segment(rest, &name[skip..])),
+            Some(('?', rest)) => !name.is_empty() && match_segment(rest, &name[1..]),
+            Some((c, rest)) => name.first() == Some(c) && match_segment(rest, &name[1..]),
+        }
+    }
+}
+mod markdown {
+    use super::{normalize_logical_path, LogicalPath};
+    use pulldown_cmark::{Event, HeadingLevel, LinkType, Parser, Tag};
+    use std::collections::BTreeSet;
+    use std::ops::Range;
+
+    pub(crate) const ATTACH_SCHEME: &str = "attach:";
+
+    /// Attachment references found in markdown compared against the attachment store.
+    #[derive(Clone, Debug, Default, PartialEq, Eq)]
+    pub struct AttachmentUsage {
+        /// Logical paths referenced by markdown image or link URLs.
+        pub referenced: BTreeSet<LogicalPath>,
+        /// Logical paths present in the attachment store.
+        pub present: BTreeSet<LogicalPath>,
+        /// Stored attachments that the markdown never references.
+        pub orphans: BTreeSet<LogicalPath>,
+        /// References that do not resolve to a stored attachment.
+        pub dangling: BTreeSet<LogicalPath>,
     }
 
-    impl Default for ReadMode {
-        fn default() -> Self {
+    impl AttachmentUsage {
+        pub(crate) fn new(
+            referenced: BTreeSet<LogicalPath>,
+            present: BTreeSet<LogicalPath>,
+        ) -> Self {
+            let orphans = present.difference(&referenced).cloned().collect();
+            let dangling = referenced.difference(&present).cloned().collect();
             Self {
-                verify_hashes: true,
-                lazy_attachments: false,
+                referenced,
+                present,
+                orphans,
+                dangling,
             }
         }
     }
 
-    #[derive(Clone, Copy, Debug)]
-    pub struct WriteMode {
-        pub compute_hashes: bool,
-        pub solid_zip: bool,
-        pub dedup_by_hash: bool,
+    /// Map a markdown URL to a logical attachment path, if it points inside the document.
+    ///
+    /// Accepts `attach:` URLs and relative paths; remote, absolute, and anchor URLs are ignored.
+    pub(crate) fn attachment_path_from_url(url: &str) -> Option<LogicalPath> {
+        let target = match url.strip_prefix(ATTACH_SCHEME) {
+            Some(rest) => rest,
+            None => {
+                if url.starts_with('#') || url.starts_with('/') || has_scheme(url) {
+                    return None;
+                }
+                url
+            }
+        };
+        let target = target.split(['?', '#']).next().unwrap_or_default();
+        normalize_logical_path(target).ok()
     }
 
-    impl Default for WriteMode {
-        fn default() -> Self {
-            Self {
-                compute_hashes: true,
-                solid_zip: false,
-                dedup_by_hash: false,
+    /// Return the filesystem-relative part of a markdown URL that points at a local file,
+    /// with percent-escapes such as `%20` decoded.
+    ///
+    /// Paths with a `..` component are rejected so they cannot reach outside the
+    /// directory they are resolved against.
+    pub(crate) fn local_file_url(url: &str) -> Option<String> {
+        if url.starts_with('#') || url.starts_with('/') || has_scheme(url) {
+            return None;
+        }
+        let path = percent_decode(url.split(['?', '#']).next().unwrap_or_default())?;
+        if path.split(['/', '\\']).any(|part| part == "..") {
+            return None;
+        }
+        (!path.is_empty()).then_some(path)
+    }
+
+    /// Decode `%XX` escapes, leaving malformed ones as-is. Returns `None` when the
+    /// decoded bytes are not valid UTF-8.
+    fn percent_decode(text: &str) -> Option<String> {
+        let bytes = text.as_bytes();
+        let mut decoded = Vec::with_capacity(bytes.len());
+        let mut idx = 0;
+        while idx < bytes.len() {
+            let escaped = (bytes[idx] == b'%')
+                .then(|| bytes.get(idx + 1..idx + 3))
+                .flatten()
+                .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            match escaped {
+                Some(byte) => {
+                    decoded.push(byte);
+                    idx += 3;
+                }
+                None => {
+                    decoded.push(bytes[idx]);
+                    idx += 1;
+                }
             }
         }
+        String::from_utf8(decoded).ok()
     }
 
-    pub struct Reader<'a, R: Read + Seek> {
-        inner: R,
-        format: Format,
-        mode: ReadMode,
-        _marker: std::marker::PhantomData<&'a ()>,
+    fn has_scheme(url: &str) -> bool {
+        match url.find(':') {
+            Some(idx) => !url[..idx].contains('/'),
+            None => false,
+        }
     }
 
-    impl<'a, R: Read + Seek> Reader<'a, R> {
-        pub fn new(mut inner: R, assumed: Option<Format>, mode: ReadMode) -> TmdResult<Self> {
-            let format = if let Some(format) = assumed {
-                format
-            } else {
-                let mut header = [0u8; 8];
-                let read = inner.read(&mut header)?;
-                inner.seek(SeekFrom::Start(0))?;
-                sniff_format(&header[..read])
-                    .ok_or_else(|| TmdError::InvalidFormat("unable to sniff format".into()))?
-            };
+    /// Return the text of the first level-one heading, if any.
+    pub(crate) fn first_heading(markdown: &str) -> Option<String> {
+        let mut in_heading = false;
+        let mut text = String::new();
+        for event in Parser::new(markdown) {
+            match event {
+                Event::Start(Tag::Heading(HeadingLevel::H1, _, _)) => in_heading = true,
+                Event::End(Tag::Heading(HeadingLevel::H1, _, _)) => {
+                    let title = text.trim();
+                    return (!title.is_empty()).then(|| title.to_string());
+                }
+                Event::Text(part) | Event::Code(part) if in_heading => text.push_str(&part),
+                _ => {}
+            }
+        }
+        None
+    }
 
-            Ok(Self {
-                inner,
-                format,
-                mode,
-                _marker: std::marker::PhantomData,
-            })
+    /// Locate the destination text of each inline image or link in `markdown`.
+    ///
+    /// Reference-style links are skipped because their destination lives elsewhere.
+    pub(crate) fn inline_destinations(markdown: &str) -> Vec<(Range<usize>, String)> {
+        // Open inline links: their source range, URL, and where their text ends so far.
+        let mut open: Vec<(Range<usize>, String, usize)> = Vec::new();
+        let mut found = Vec::new();
+        for (event, range) in Parser::new(markdown).into_offset_iter() {
+            match event {
+                Event::Start(Tag::Image(LinkType::Inline, url, _))
+                | Event::Start(Tag::Link(LinkType::Inline, url, _)) => {
+                    let text_start = markdown[range.clone()]
+                        .find('[')
+                        .map_or(range.start, |at| range.start + at + 1);
+                    open.push((range, url.to_string(), text_start));
+                }
+                Event::End(Tag::Image(LinkType::Inline, _, _))
+                | Event::End(Tag::Link(LinkType::Inline, _, _)) => {
+                    let Some((outer, url, text_end)) = open.pop() else {
+                        continue;
+                    };
+                    if let Some(last) = open.last_mut() {
+                        last.2 = last.2.max(outer.end);
+                    }
+                    if url.is_empty() {
+                        continue;
+                    }
+                    if let Some(span) = destination_span(markdown, text_end, outer.end) {
+                        found.push((span, url));
+                    }
+                }
+                _ => {
+                    if let Some(last) = open.last_mut() {
+                        last.2 = last.2.max(range.end);
+                    }
+                }
+            }
         }
+        found.sort_by_key(|(span, _)| span.start);
+        found
+    }
 
-        pub fn read_doc(&mut self) -> TmdResult<TmdDoc> {
-            match self.format {
-                Format::Tmd => read_tmd(&mut self.inner, self.mode),
-                Format::Tmdz => read_tmdz(&mut self.inner, self.mode),
+    /// Byte range of the destination in the `](destination "title")` part of an inline
+    /// link whose text ends at or before `text_end` and whose source ends at `end`.
+    fn destination_span(markdown: &str, text_end: usize, end: usize) -> Option<Range<usize>> {
+        let tail = markdown.get(text_end..end)?;
+        let open = text_end + tail.find("](")? + 2;
+        let bytes = markdown.as_bytes();
+        let mut start = open;
+        while start < end && bytes[start].is_ascii_whitespace() {
+            start += 1;
+        }
+        if bytes.get(start) == Some(&b'<') {
+            let close = start + 1 + markdown[start + 1..end].find('>')?;
+            return Some(start + 1..close);
+        }
+        let mut depth = 0usize;
+        let mut at = start;
+        while at < end {
+            match bytes[at] {
+                b'\\' => at += 1,
+                b'(' => depth += 1,
+                b')' if depth == 0 => break,
+                b')' => depth -= 1,
+                byte if byte.is_ascii_whitespace() => break,
+                _ => {}
             }
+            at += 1;
         }
+        Some(start..at.min(end))
     }
 
-    pub struct Writer<'a, W: Write + Seek> {
-        inner: W,
-        format: Format,
-        mode: WriteMode,
-        _marker: std::marker::PhantomData<&'a ()>,
+    /// Locate the destination text of each reference definition, `[label]: destination`,
+    /// in `markdown`.
+    pub(crate) fn reference_destinations(markdown: &str) -> Vec<(Range<usize>, String)> {
+        let parser = Parser::new(markdown);
+        let mut found: Vec<_> = parser
+            .reference_definitions()
+            .iter()
+            .filter_map(|(_, def)| {
+                let end = def.span.end;
+                let mut start = def.span.start + markdown.get(def.span.clone())?.find("]:")? + 2;
+                let bytes = markdown.as_bytes();
+                while start < end && bytes[start].is_ascii_whitespace() {
+                    start += 1;
+                }
+                let span = if bytes.get(start) == Some(&b'<') {
+                    start + 1..start + 1 + markdown[start + 1..end].find('>')?
+                } else {
+                    let len = markdown[start..end]
+                        .find(|ch: char| ch.is_ascii_whitespace())
+                        .unwrap_or(end - start);
+                    start..start + len
+                };
+                Some((span, def.dest.to_string()))
+            })
+            .collect();
+        found.sort_by_key(|(span, _)| span.start);
+        found
     }
 
-    impl<'a, W: Write + Seek> Writer<'a, W> {
-        pub fn new(inner: W, format: Format, mode: WriteMode) -> TmdResult<Self> {
-            Ok(Self {
-                inner,
-                format,
-                mode,
-                _marker: std::marker::PhantomData,
+    /// Locate the destination text of every inline link or image and every reference
+    /// definition in `markdown`, in source order.
+    pub(crate) fn link_destinations(markdown: &str) -> Vec<(Range<usize>, String)> {
+        let mut found = inline_destinations(markdown);
+        found.extend(reference_destinations(markdown));
+        found.sort_by_key(|(span, _)| span.start);
+        found
+    }
+
+    /// Collect the logical paths referenced by image and link destinations.
+    pub(crate) fn referenced_attachment_paths(markdown: &str) -> BTreeSet<LogicalPath> {
+        Parser::new(markdown)
+            .filter_map(|event| match event {
+                Event::Start(Tag::Image(_, url, _)) | Event::Start(Tag::Link(_, url, _)) => {
+                    attachment_path_from_url(&url)
+                }
+                _ => None,
             })
-        }
+            .collect()
+    }
+}
+mod manifest {
+    use super::attach::AttachmentStore;
+    use super::util::path_depth;
+    use super::{AttachmentId, LogicalPath, TmdError, TmdResult};
+    use chrono::{DateTime, Utc};
+    use mime::Mime;
+    use serde::{Deserialize, Serialize};
+    use uuid::Uuid;
 
-        pub fn write_doc(&mut self, doc: &TmdDoc) -> TmdResult<()> {
-            match self.format {
-                Format::Tmd => write_tmd(&mut self.inner, doc, self.mode),
-                Format::Tmdz => write_tmdz(&mut self.inner, doc, self.mode),
-            }
-        }
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+    #[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+    pub struct Semver {
+        pub major: u16,
+        pub minor: u16,
+        pub patch: u16,
+    }
 
-        pub fn finish(self) -> TmdResult<()> {
-            Ok(())
-        }
+    #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+    #[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+    pub struct AttachmentRef {
+        pub id: AttachmentId,
     }
 
-    #[derive(Serialize, Deserialize)]
-    struct AttachmentManifest {
-        attachments: Vec<AttachmentMeta>,
+    #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+    #[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+    pub struct LinkRef {
+        pub rel: String,
+        pub href: String,
     }
 
-    fn find_eocd_offset(data: &[u8]) -> TmdResult<usize> {
-        let min_len = 22;
-        if data.len() < min_len {
-            return Err(TmdError::InvalidFormat(
-                "input too small to contain EOCD".into(),
-            ));
-        }
-        let search_start = if data.len() > MAX_COMMENT_SEARCH {
-            data.len() - MAX_COMMENT_SEARCH
-        } else {
-            0
-        };
+    #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+    #[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+    pub struct Manifest {
+        pub tmd_version: Semver,
+        pub doc_id: Uuid,
+        pub title: Option<String>,
+        #[cfg_attr(feature = "schemars", schemars(with = "Vec<AuthorRepr>"))]
+        pub authors: Vec<Author>,
+        pub created_utc: DateTime<Utc>,
+        pub modified_utc: DateTime<Utc>,
+        pub tags: Vec<String>,
+        pub cover_image: Option<AttachmentRef>,
+        pub links: Vec<LinkRef>,
+        pub db_schema_version: Option<u32>,
+        /// BCP-47 language tag of the document content.
+        #[serde(default)]
+        pub language: Option<String>,
+        /// How the primary content is interpreted; `None` means markdown.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub data: Option<DataSection>,
+        /// Archive entry holding the markdown; `index.md` when absent.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub markdown_path: Option<String>,
+        /// sha256 over the markdown and every attachment's path, length, and digest, so
+        /// edits outside this crate, including removed attachments, can be detected. See
+        /// [`TmdDoc::content_checksum`].
+        #[serde(default, with = "sha_option", skip_serializing_if = "Option::is_none")]
+        #[cfg_attr(feature = "schemars", schemars(with = "Option<String>"))]
+        pub content_checksum: Option<[u8; 32]>,
+        #[serde(default)]
+        pub extras: serde_json::Value,
+        /// Top-level keys this version does not recognize, e.g. from a newer writer. They
+        /// are written back unchanged.
+        #[serde(flatten)]
+        pub unknown: serde_json::Map<String, serde_json::Value>,
+    }
 
-        for idx in (search_start..=data.len() - min_len).rev() {
-            if &data[idx..idx + 4] == EOCD_SIGNATURE {
-                return Ok(idx);
+    /// A document author. Serialized as a bare string when only the name is known, so
+    /// manifests written before structured authors keep their shape.
+    #[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+    #[serde(from = "AuthorRepr", into = "AuthorRepr")]
+    pub struct Author {
+        pub name: String,
+        pub email: Option<String>,
+        pub affiliation: Option<String>,
+    }
+
+    impl Author {
+        pub fn new(name: impl Into<String>) -> Self {
+            Self {
+                name: name.into(),
+                ..Self::default()
             }
         }
-
-        Err(TmdError::InvalidFormat(
-            "ZIP EOCD signature not found".into(),
-        ))
     }
 
-    fn extract_markdown_len_from_comment(comment: &[u8]) -> TmdResult<u64> {
-        if !comment.starts_with(TMD_COMMENT_PREFIX) {
-            return Err(TmdError::InvalidFormat(
-                "missing TMD comment signature".into(),
-            ));
-        }
-        let expected_len = TMD_COMMENT_PREFIX.len() + 8;
-        if comment.len() != expected_len {
-            return Err(TmdError::InvalidFormat(format!(
-                "unexpected TMD comment length: expected {} bytes, got {}",
-                expected_len,
-                comment.len()
-            )));
+    impl From<&str> for Author {
+        fn from(name: &str) -> Self {
+            Self::new(name)
         }
-        let mut len_bytes = [0u8; 8];
-        len_bytes.copy_from_slice(&comment[TMD_COMMENT_PREFIX.len()..]);
-        Ok(u64::from_le_bytes(len_bytes))
     }
 
-    fn split_tmd_bytes(bytes: &[u8]) -> TmdResult<(&[u8], &[u8])> {
-        let eocd_offset = find_eocd_offset(bytes)?;
-        if eocd_offset + 22 > bytes.len() {
-            return Err(TmdError::InvalidFormat(
-                "EOCD extends past end of buffer".into(),
-            ));
-        }
-        let comment_len_start = eocd_offset + 20;
-        let comment_len =
-            u16::from_le_bytes([bytes[comment_len_start], bytes[comment_len_start + 1]]) as usize;
-        let comment_start = eocd_offset + 22;
-        if comment_start + comment_len > bytes.len() {
-            return Err(TmdError::InvalidFormat(
-                "EOCD comment length exceeds buffer".into(),
-            ));
-        }
-        let comment = &bytes[comment_start..comment_start + comment_len];
-        let markdown_len = extract_markdown_len_from_comment(comment)? as usize;
-        if markdown_len > bytes.len() {
-            return Err(TmdError::InvalidFormat(
-                "markdown length exceeds buffer".into(),
-            ));
+    impl From<String> for Author {
+        fn from(name: String) -> Self {
+            Self::new(name)
         }
-        let (markdown, zip_bytes) = bytes.split_at(markdown_len);
-        Ok((markdown, zip_bytes))
     }
 
-    fn read_manifest_from_zip<R: Read + Seek>(zip: &mut ZipArchive<R>) -> TmdResult<Manifest> {
-        let mut file = zip.by_name("manifest.json")?;
-        let mut buf = String::new();
-        file.read_to_string(&mut buf)?;
-        let manifest: Manifest = serde_json::from_str(&buf)?;
-        Ok(manifest)
+    impl PartialEq<&str> for Author {
+        fn eq(&self, other: &&str) -> bool {
+            self.email.is_none() && self.affiliation.is_none() && self.name == *other
+        }
     }
 
-    fn read_markdown_from_zip<R: Read + Seek>(zip: &mut ZipArchive<R>) -> TmdResult<String> {
-        let mut file = zip.by_name("index.md")?;
-        let mut markdown = String::new();
-        file.read_to_string(&mut markdown)?;
-        Ok(markdown)
+    #[derive(Serialize, Deserialize)]
+    #[serde(untagged)]
+    #[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+    enum AuthorRepr {
+        Name(String),
+        Full {
+            name: String,
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            email: Option<String>,
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            affiliation: Option<String>,
+        },
     }
 
-    fn read_attachment_manifest<R: Read + Seek>(
-        zip: &mut ZipArchive<R>,
-    ) -> TmdResult<Vec<AttachmentMeta>> {
-        let mut file = zip.by_name("attachments.json")?;
-        let mut buf = String::new();
-        file.read_to_string(&mut buf)?;
-        let manifest: AttachmentManifest = serde_json::from_str(&buf)?;
-        Ok(manifest.attachments)
+    impl From<AuthorRepr> for Author {
+        fn from(repr: AuthorRepr) -> Self {
+            match repr {
+                AuthorRepr::Name(name) => Self::new(name),
+                AuthorRepr::Full {
+                    name,
+                    email,
+                    affiliation,
+                } => Self {
+                    name,
+                    email,
+                    affiliation,
+                },
+            }
+        }
     }
 
-    fn read_db_from_zip<R: Read + Seek>(zip: &mut ZipArchive<R>) -> TmdResult<DbHandle> {
-        let mut file = zip.by_name("db/main.sqlite3")?;
-        let mut bytes = Vec::new();
-        file.read_to_end(&mut bytes)?;
-        if bytes.len() < 16 || &bytes[..16] != b"SQLite format 3\0" {
-            return Err(TmdError::InvalidFormat(
-                "db/main.sqlite3 is not a SQLite database".into(),
-            ));
+    impl From<Author> for AuthorRepr {
+        fn from(author: Author) -> Self {
+            match author {
+                Author {
+                    name,
+                    email: None,
+                    affiliation: None,
+                } => Self::Name(name),
+                Author {
+                    name,
+                    email,
+                    affiliation,
+                } => Self::Full {
+                    name,
+                    email,
+                    affiliation,
+                },
+            }
         }
-        DbHandle::from_bytes(&bytes)
     }
 
-    fn read_doc_from_zip<R: Read + Seek>(
-        zip: &mut ZipArchive<R>,
-        mode: ReadMode,
-    ) -> TmdResult<TmdDoc> {
-        let markdown = read_markdown_from_zip(zip)?;
-        let manifest = read_manifest_from_zip(zip)?;
-        let attachment_metas = read_attachment_manifest(zip)?;
+    /// Names the engine that interprets a document's primary content.
+    #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+    #[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+    pub struct DataSection {
+        /// Engine identifier, e.g. `markdown`.
+        pub engine: String,
+        /// Entry point within the engine's content, e.g. `main`.
+        pub entry: String,
+    }
 
-        let mut attachments = AttachmentStore::new();
-        for meta in attachment_metas {
-            let mut file = zip.by_name(&meta.logical_path)?;
-            let mut data = Vec::new();
-            file.read_to_end(&mut data)?;
-            attachments.insert_entry(meta, data, mode.verify_hashes)?;
+    impl Manifest {
+        /// Check that manifest references resolve against `attachments`.
+        ///
+        /// The cover image must name a stored attachment id, and every link whose
+        /// `href` is an `attach:` URL or relative path must name a stored logical path.
+        pub fn validate(&self, attachments: &AttachmentStore) -> TmdResult<()> {
+            if let Some(cover) = &self.cover_image {
+                if attachments.meta(cover.id).is_none() {
+                    return Err(TmdError::InvalidFormat(format!(
+                        "cover_image refers to unknown attachment {}",
+                        cover.id
+                    )));
+                }
+            }
+            for link in &self.links {
+                if let Some(path) = super::markdown::attachment_path_from_url(&link.href) {
+                    if attachments.meta_by_path(&path).is_none() {
+                        return Err(TmdError::InvalidFormat(format!(
+                            "link `{}` refers to unknown attachment `{}`",
+                            link.rel, path
+                        )));
+                    }
+                }
+            }
+            Ok(())
         }
-
-        let mut db = read_db_from_zip(zip)?;
-        db.ensure_initialized(None)?;
-
-        Ok(TmdDoc {
-            markdown,
-            manifest,
-            attachments,
-            db,
-        })
     }
 
-    pub fn read_tmd<R: Read + Seek>(reader: &mut R, mode: ReadMode) -> TmdResult<TmdDoc> {
-        reader.seek(SeekFrom::Start(0))?;
-        let mut bytes = Vec::new();
-        reader.read_to_end(&mut bytes)?;
-        let (markdown_bytes, zip_bytes) = split_tmd_bytes(&bytes)?;
-        let markdown = String::from_utf8(markdown_bytes.to_vec())
-            .map_err(|_| TmdError::InvalidFormat("markdown section is not valid UTF-8".into()))?;
-        let cursor = std::io::Cursor::new(zip_bytes.to_vec());
-        let mut zip = ZipArchive::new(cursor)?;
-        let mut doc = read_doc_from_zip(&mut zip, mode)?;
-        doc.markdown = markdown;
-        Ok(doc)
+    /// Per-attachment override for how the entry is compressed in the archive.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    #[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+    pub enum CompressionHint {
+        Store,
+        Deflate,
     }
 
-    pub fn read_tmdz<R: Read + Seek>(reader: &mut R, mode: ReadMode) -> TmdResult<TmdDoc> {
-        reader.seek(SeekFrom::Start(0))?;
-        let mut bytes = Vec::new();
-        reader.read_to_end(&mut bytes)?;
-        let cursor = std::io::Cursor::new(bytes);
-        let mut zip = ZipArchive::new(cursor)?;
-        read_doc_from_zip(&mut zip, mode)
+    /// Algorithm used to compute an attachment's content digest.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    #[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+    pub enum HashAlgorithm {
+        #[default]
+        Sha256,
+        /// Computing or verifying BLAKE3 digests requires the `blake3` feature.
+        Blake3,
     }
 
-    fn set_tmd_comment(zip_bytes: &mut Vec<u8>, markdown_len: u64) -> TmdResult<()> {
-        let eocd_offset = find_eocd_offset(zip_bytes)?;
-        if eocd_offset + 22 > zip_bytes.len() {
-            return Err(TmdError::InvalidFormat(
-                "EOCD extends past end of ZIP buffer".into(),
-            ));
-        }
-        let comment_data = {
-            let mut buf = Vec::with_capacity(TMD_COMMENT_PREFIX.len() + 8);
-            buf.extend_from_slice(TMD_COMMENT_PREFIX);
-            buf.extend_from_slice(&markdown_len.to_le_bytes());
-            buf
-        };
-        if comment_data.len() > u16::MAX as usize {
-            return Err(TmdError::InvalidFormat(
-                "TMD comment would exceed ZIP comment limit".into(),
-            ));
+    impl HashAlgorithm {
+        /// Whether this build can compute digests with the algorithm.
+        pub fn is_supported(self) -> bool {
+            match self {
+                Self::Sha256 => true,
+                Self::Blake3 => cfg!(feature = "blake3"),
+            }
         }
-        let comment_len_pos = eocd_offset + 20;
-        let comment_start = eocd_offset + 22;
-        let comment_len_bytes = (comment_data.len() as u16).to_le_bytes();
-        zip_bytes[comment_len_pos] = comment_len_bytes[0];
-        zip_bytes[comment_len_pos + 1] = comment_len_bytes[1];
-        zip_bytes.truncate(comment_start);
-        zip_bytes.extend_from_slice(&comment_data);
-        Ok(())
     }
 
-    fn build_zip(doc: &TmdDoc, _mode: WriteMode) -> TmdResult<Vec<u8>> {
-        let cursor = std::io::Cursor::new(Vec::new());
-        let mut writer = ZipWriter::new(cursor);
-        let stored = FileOptions::default()
-            .compression_method(CompressionMethod::Stored)
-            .large_file(true);
-
-        // manifest
-        writer.start_file("manifest.json", stored)?;
-        let manifest_json = serde_json::to_vec_pretty(&doc.manifest)?;
-        writer.write_all(&manifest_json)?;
-
-        // attachments manifest
-        let mut attachment_metas: Vec<AttachmentMeta> = doc.attachments.iter().cloned().collect();
-        attachment_metas.sort_by(|a, b| a.logical_path.cmp(&b.logical_path));
-        let attachments_json = serde_json::to_vec_pretty(&AttachmentManifest {
-            attachments: attachment_metas.clone(),
-        })?;
+    /// A content digest recorded under an algorithm other than sha256.
+    #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+    #[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+    pub struct AttachmentDigest {
+        pub algorithm: HashAlgorithm,
+        #[serde(with = "hex::serde")]
+        #[cfg_attr(feature = "schemars", schemars(with = "String"))]
+        pub value: Vec<u8>,
+    }
 
-        // index.md
-        writer.start_file("index.md", stored)?;
-        writer.write_all(doc.markdown.as_bytes())?;
+    #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+    #[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+    pub struct AttachmentMeta {
+        pub id: AttachmentId,
+        pub logical_path: LogicalPath,
+        #[serde(with = "mime_serde")]
+        #[cfg_attr(feature = "schemars", schemars(with = "String"))]
+        pub mime: Mime,
+        pub length: u64,
+        #[serde(default, with = "sha_option")]
+        #[cfg_attr(feature = "schemars", schemars(with = "Option<String>"))]
+        pub sha256: Option<[u8; 32]>,
+        pub title: Option<String>,
+        pub alt: Option<String>,
+        /// Overrides the default (stored) compression for this attachment when writing.
+        #[serde(default)]
+        pub compression_hint: Option<CompressionHint>,
+        /// Additional logical paths that resolve to this attachment's data.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub aliases: Vec<LogicalPath>,
+        /// Digest under a non-sha256 algorithm; `sha256` is authoritative when absent.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub digest: Option<AttachmentDigest>,
+        /// When the attachment was added to the document.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub created_utc: Option<DateTime<Utc>>,
+        /// When the attachment's bytes were last replaced or edited.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub modified_utc: Option<DateTime<Utc>>,
+        #[serde(default)]
+        pub extras: serde_json::Value,
+    }
 
-        writer.start_file("attachments.json", stored)?;
-        writer.write_all(&attachments_json)?;
+    impl AttachmentMeta {
+        /// Number of `/`-separated components in the logical path, e.g. 2 for
+        /// `images/a.png`.
+        pub fn path_depth(&self) -> usize {
+            path_depth(&self.logical_path)
+        }
 
-        // db
-        writer.start_file("db/main.sqlite3", stored)?;
-        let db_bytes = std::fs::read(doc.db.as_path())?;
-        writer.write_all(&db_bytes)?;
+        /// Algorithm the recorded content digest was computed with.
+        pub fn hash_algorithm(&self) -> HashAlgorithm {
+            self.digest
+                .as_ref()
+                .map_or(HashAlgorithm::Sha256, |digest| digest.algorithm)
+        }
 
-        // attachments data
-        for meta in &attachment_metas {
-            let data = doc.attachments.data(meta.id).ok_or_else(|| {
-                TmdError::Attachment(format!("missing data for attachment {}", meta.id))
-            })?;
-            writer.start_file(&meta.logical_path, stored)?;
-            writer.write_all(data)?;
+        /// The recorded content digest, if any, with its algorithm.
+        pub fn content_digest(&self) -> Option<(HashAlgorithm, &[u8])> {
+            match &self.digest {
+                Some(digest) => Some((digest.algorithm, digest.value.as_slice())),
+                None => self
+                    .sha256
+                    .as_ref()
+                    .map(|sha| (HashAlgorithm::Sha256, &sha[..])),
+            }
         }
 
-        let zip_bytes = writer.finish()?.into_inner();
-        Ok(zip_bytes)
+        /// Record `value` as the content digest, keeping `sha256` populated for sha256.
+        pub(crate) fn set_content_digest(&mut self, algorithm: HashAlgorithm, value: Vec<u8>) {
+            match algorithm {
+                HashAlgorithm::Sha256 => {
+                    let mut sha = [0u8; 32];
+                    sha.copy_from_slice(&value);
+                    self.sha256 = Some(sha);
+                    self.digest = None;
+                }
+                _ => {
+                    self.sha256 = None;
+                    self.digest = Some(AttachmentDigest { algorithm, value });
+                }
+            }
+        }
     }
 
-    pub fn write_tmd<W: Write + Seek>(
-        writer: &mut W,
-        doc: &TmdDoc,
-        mode: WriteMode,
-    ) -> TmdResult<()> {
-        let markdown_bytes = doc.markdown.as_bytes();
-        let mut zip_bytes = build_zip(doc, mode)?;
-        let markdown_len = u64::try_from(markdown_bytes.len())
-            .map_err(|_| TmdError::InvalidFormat("markdown length exceeds u64 range".into()))?;
-        set_tmd_comment(&mut zip_bytes, markdown_len)?;
-        writer.write_all(markdown_bytes)?;
-        writer.write_all(&zip_bytes)?;
-        Ok(())
+    /// JSON Schemas describing `manifest.json` and the entries of `attachments.json`.
+    #[cfg(feature = "schemars")]
+    pub fn json_schema() -> serde_json::Value {
+        serde_json::json!({
+            "manifest": schemars::schema_for!(Manifest),
+            "attachment_meta": schemars::schema_for!(AttachmentMeta),
+        })
     }
 
-    pub fn write_tmdz<W: Write + Seek>(
-        writer: &mut W,
-        doc: &TmdDoc,
-        mode: WriteMode,
-    ) -> TmdResult<()> {
-        let zip_bytes = build_zip(doc, mode)?;
-        writer.write_all(&zip_bytes)?;
-        Ok(())
-    }
+    mod mime_serde {
+        use super::Mime;
+        use serde::de::Error as DeError;
+        use serde::{Deserialize, Deserializer, Serializer};
 
-    pub fn read_from_path(path: impl AsRef<Path>, assumed: Option<Format>) -> TmdResult<TmdDoc> {
-        let file = File::open(path.as_ref())?;
-        let mut reader = Reader::new(std::io::BufReader::new(file), assumed, ReadMode::default())?;
-        reader.read_doc()
-    }
+        pub fn serialize<S>(mime: &Mime, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            serializer.serialize_str(mime.as_ref())
+        }
 
-    pub fn write_to_path(path: impl AsRef<Path>, doc: &TmdDoc, format: Format) -> TmdResult<()> {
-        let file = File::create(path.as_ref())?;
-        let mut writer = Writer::new(std::io::BufWriter::new(file), format, WriteMode::default())?;
-        writer.write_doc(doc)?;
-        writer.finish()
+        pub fn deserialize<'de, D>(deserializer: D) -> Result<Mime, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            let s = String::deserialize(deserializer)?;
+            s.parse().map_err(|_| DeError::custom("invalid MIME type"))
+        }
     }
 
-    // No additional helpers
-}
+    mod sha_option {
+        use serde::de::Error as DeError;
+        use serde::{Deserialize, Deserializer, Serializer};
 
-#[cfg(feature = "ffi")]
-pub mod ffi {
-    //! C-compatible bindings for `tmd-core` exposed when the `ffi` feature is enabled.
+        pub fn serialize<S>(value: &Option<[u8; 32]>, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            match value {
+                Some(bytes) => serializer.serialize_some(&hex::encode(bytes)),
+                None => serializer.serialize_none(),
+            }
+        }
 
-    use super::{read_from_path, write_to_path, Format, TmdDoc, TmdError};
-    use std::cell::RefCell;
-    use std::ffi::{CStr, CString};
-    use std::os::raw::c_char;
-    use std::path::PathBuf;
-    use std::ptr;
+        pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<[u8; 32]>, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            let opt = Option::<String>::deserialize(deserializer)?;
+            match opt {
+                Some(s) => {
+                    let decoded = hex::decode(&s).map_err(|_| DeError::custom("invalid hex"))?;
+                    if decoded.len() != 32 {
+                        return Err(DeError::custom("invalid sha256 length"));
+                    }
+                    let mut arr = [0u8; 32];
+                    arr.copy_from_slice(&decoded);
+                    Ok(Some(arr))
+                }
+                None => Ok(None),
+            }
+        }
+    }
+}
+mod attach {
+    use super::util::path_depth;
+    use super::{
+        normalize_logical_path, now_utc, AttachmentId, AttachmentMeta, CompressionHint,
+        HashAlgorithm, LogicalPath, TmdError, TmdResult,
+    };
+    use chrono::NaiveDateTime;
+    use mime::Mime;
+    use sha2::{Digest, Sha256};
+    use std::collections::{hash_map::Values, BTreeMap, HashMap};
+    use std::io::Read;
+    use std::ops::{Deref, DerefMut};
 
-    thread_local! {
-        static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+    const READ_CHUNK: usize = 64 * 1024;
+
+    /// Incremental hasher for any supported [`HashAlgorithm`].
+    enum ContentHasher {
+        Sha256(Sha256),
+        #[cfg(feature = "blake3")]
+        Blake3(Box<blake3::Hasher>),
     }
 
-    const NULL_PTR_MESSAGE: &str = "null pointer provided";
-    const INVALID_UTF8_MESSAGE: &str = "input was not valid UTF-8";
-    const INTERIOR_NUL_MESSAGE: &str = "string contained an interior NUL byte";
+    impl ContentHasher {
+        fn new(algorithm: HashAlgorithm) -> TmdResult<Self> {
+            match algorithm {
+                HashAlgorithm::Sha256 => Ok(Self::Sha256(Sha256::new())),
+                #[cfg(feature = "blake3")]
+                HashAlgorithm::Blake3 => Ok(Self::Blake3(Box::default())),
+                #[cfg(not(feature = "blake3"))]
+                HashAlgorithm::Blake3 => Err(TmdError::Attachment(
+                    "blake3 hashing requires the `blake3` feature".into(),
+                )),
+            }
+        }
 
-    fn set_last_error_message<S: Into<String>>(message: S) {
-        let message = message.into();
-        let c_string =
-            CString::new(message).unwrap_or_else(|_| CString::new(INTERIOR_NUL_MESSAGE).unwrap());
-        LAST_ERROR.with(|slot| {
-            *slot.borrow_mut() = Some(c_string);
-        });
+        fn update(&mut self, data: &[u8]) {
+            match self {
+                Self::Sha256(hasher) => hasher.update(data),
+                #[cfg(feature = "blake3")]
+                Self::Blake3(hasher) => {
+                    hasher.update(data);
+                }
+            }
+        }
+
+        fn finalize(self) -> Vec<u8> {
+            match self {
+                Self::Sha256(hasher) => hasher.finalize().to_vec(),
+                #[cfg(feature = "blake3")]
+                Self::Blake3(hasher) => hasher.finalize().as_bytes().to_vec(),
+            }
+        }
     }
 
-    fn set_last_error(error: TmdError) {
-        set_last_error_message(error.to_string());
+    /// Hash `data` with `algorithm`.
+    pub(crate) fn hash_content(algorithm: HashAlgorithm, data: &[u8]) -> TmdResult<Vec<u8>> {
+        let mut hasher = ContentHasher::new(algorithm)?;
+        hasher.update(data);
+        Ok(hasher.finalize())
     }
 
-    fn clear_last_error() {
-        LAST_ERROR.with(|slot| {
-            *slot.borrow_mut() = None;
-        });
+    /// sha256 of the markdown's sha256 followed by, for each attachment in logical-path
+    /// order, its path, a NUL, its little-endian length, and its recorded digest prefixed
+    /// by the digest's length.
+    pub(crate) fn content_checksum<'a>(
+        markdown: &str,
+        metas: impl IntoIterator<Item = &'a AttachmentMeta>,
+    ) -> [u8; 32] {
+        let mut metas: Vec<&AttachmentMeta> = metas.into_iter().collect();
+        metas.sort_by(|a, b| a.logical_path.cmp(&b.logical_path));
+        let mut hasher = Sha256::new();
+        hasher.update(Sha256::digest(markdown.as_bytes()));
+        for meta in metas {
+            hasher.update(meta.logical_path.as_bytes());
+            hasher.update([0]);
+            hasher.update(meta.length.to_le_bytes());
+            let digest = meta.content_digest().map_or(&[][..], |(_, digest)| digest);
+            hasher.update([digest.len() as u8]);
+            hasher.update(digest);
+        }
+        hasher.finalize().into()
     }
 
-    fn path_from_ptr(ptr: *const c_char) -> Result<PathBuf, String> {
-        if ptr.is_null() {
-            return Err(NULL_PTR_MESSAGE.to_string());
+    /// Hash everything `reader` yields in fixed-size chunks, returning the byte count and
+    /// digest without buffering the content.
+    pub(crate) fn hash_reader<R: Read>(
+        mut reader: R,
+        algorithm: HashAlgorithm,
+    ) -> TmdResult<(u64, Vec<u8>)> {
+        let mut hasher = ContentHasher::new(algorithm)?;
+        let mut chunk = vec![0u8; READ_CHUNK];
+        let mut total = 0u64;
+        loop {
+            let n = match reader.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            hasher.update(&chunk[..n]);
+            total += n as u64;
         }
-        let c_str = unsafe { CStr::from_ptr(ptr) };
-        let utf8 = c_str
-            .to_str()
-            .map_err(|_| INVALID_UTF8_MESSAGE.to_string())?;
-        Ok(PathBuf::from(utf8))
+        Ok((total, hasher.finalize()))
     }
 
-    fn parse_optional_format(value: i32) -> Result<Option<Format>, String> {
-        match value {
-            0 => Ok(None),
-            1 => Ok(Some(Format::Tmd)),
-            2 => Ok(Some(Format::Tmdz)),
-            other => Err(format!("unknown format value: {}", other)),
+    /// Buffer `reader`, hashing each chunk as it arrives so the data is only traversed once.
+    ///
+    /// Fails with [`TmdError::TooLarge`] as soon as more than `limit` bytes have been read.
+    pub(crate) fn read_hashed<R: Read>(
+        mut reader: R,
+        limit: Option<u64>,
+        algorithm: HashAlgorithm,
+    ) -> TmdResult<(Vec<u8>, Vec<u8>)> {
+        let mut hasher = ContentHasher::new(algorithm)?;
+        let mut data = Vec::new();
+        let mut chunk = vec![0u8; READ_CHUNK];
+        loop {
+            let n = match reader.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if let Some(limit) = limit {
+                if (data.len() + n) as u64 > limit {
+                    return Err(TmdError::TooLarge { limit });
+                }
+            }
+            hasher.update(&chunk[..n]);
+            data.extend_from_slice(&chunk[..n]);
         }
+        Ok((data, hasher.finalize()))
     }
 
-    fn parse_required_format(value: i32) -> Result<Format, String> {
-        parse_optional_format(value)?
-            .ok_or_else(|| "format must not be Auto when writing".to_string())
+    /// A set of attachments whose contents hash to the same sha256 digest.
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub struct DuplicateGroup {
+        pub sha256: [u8; 32],
+        pub logical_paths: Vec<LogicalPath>,
+        /// Bytes that would be saved by storing the content only once.
+        pub bytes_saved: u64,
     }
 
-    fn string_from_ptr(ptr: *const c_char) -> Result<String, String> {
-        if ptr.is_null() {
-            return Ok(String::new());
+    #[derive(Debug)]
+    struct AttachmentEntry {
+        meta: AttachmentMeta,
+        /// `None` while the bytes still live in the archive a lazy read came from.
+        data: Option<Vec<u8>>,
+        /// Entry timestamp from the archive the attachment was read from, if any.
+        archive_modified: Option<NaiveDateTime>,
+        /// Position of the entry in insertion order.
+        seq: u64,
+    }
+
+    // Archive timestamps are provenance only and do not affect content equality.
+    impl PartialEq for AttachmentEntry {
+        fn eq(&self, other: &Self) -> bool {
+            self.meta == other.meta && self.data == other.data
         }
-        let c_str = unsafe { CStr::from_ptr(ptr) };
-        Ok(c_str
-            .to_str()
-            .map_err(|_| INVALID_UTF8_MESSAGE.to_string())?
-            .to_owned())
     }
 
-    fn c_string_from_str(value: &str) -> Result<CString, ()> {
-        CString::new(value).map_err(|_| ())
+    impl Eq for AttachmentEntry {}
+
+    #[derive(Debug, Default)]
+    pub struct AttachmentStore {
+        entries: HashMap<AttachmentId, AttachmentEntry>,
+        by_path: HashMap<LogicalPath, AttachmentId>,
+        /// Algorithm used to hash newly inserted or modified attachments.
+        hash_algorithm: HashAlgorithm,
+        /// Most path components a new logical path may have, or `None` for no limit.
+        max_path_depth: Option<usize>,
+        next_seq: u64,
     }
 
-    /// Retrieve the last error message generated by the FFI layer for the current thread.
-    #[no_mangle]
-    pub extern "C" fn tmd_last_error_message() -> *const c_char {
-        LAST_ERROR.with(|slot| {
-            slot.borrow()
-                .as_ref()
-                .map(|s| s.as_ptr())
-                .unwrap_or(ptr::null())
-        })
+    // The hashing configuration is not part of the stored content.
+    impl PartialEq for AttachmentStore {
+        fn eq(&self, other: &Self) -> bool {
+            self.entries == other.entries && self.by_path == other.by_path
+        }
     }
 
-    /// Create a new in-memory document from the provided Markdown string.
-    ///
-    /// # Safety
-    ///
-    /// `markdown` must either be null or point to a valid, NUL-terminated
-    /// UTF-8 string.
-    #[no_mangle]
-    pub unsafe extern "C" fn tmd_doc_new(markdown: *const c_char) -> *mut TmdDoc {
-        let markdown = match string_from_ptr(markdown) {
-            Ok(value) => value,
-            Err(message) => {
-                set_last_error_message(message);
-                return ptr::null_mut();
-            }
-        };
+    impl Eq for AttachmentStore {}
 
-        match TmdDoc::new(markdown) {
-            Ok(doc) => {
-                clear_last_error();
-                Box::into_raw(Box::new(doc))
-            }
-            Err(err) => {
-                set_last_error(err);
-                ptr::null_mut()
-            }
+    impl AttachmentStore {
+        pub fn new() -> Self {
+            Self::default()
         }
-    }
 
-    /// Load a document from disk, optionally specifying the expected format.
-    ///
-    /// Pass `0` for automatic format detection, `1` for `.tmd`, and `2` for `.tmdz`.
-    ///
-    /// # Safety
-    ///
-    /// `path` must either be null or point to a valid, NUL-terminated UTF-8
-    /// string representing a filesystem path.
-    #[no_mangle]
-    pub unsafe extern "C" fn tmd_doc_read_from_path(
-        path: *const c_char,
-        format: i32,
-    ) -> *mut TmdDoc {
-        let assumed = match parse_optional_format(format) {
-            Ok(value) => value,
-            Err(message) => {
-                set_last_error_message(message);
-                return ptr::null_mut();
-            }
-        };
+        pub fn hash_algorithm(&self) -> HashAlgorithm {
+            self.hash_algorithm
+        }
 
-        let path_buf = match path_from_ptr(path) {
-            Ok(path) => path,
-            Err(message) => {
-                set_last_error_message(message);
-                return ptr::null_mut();
+        /// Choose the algorithm for attachments inserted from now on. Existing digests
+        /// keep their recorded algorithm.
+        pub fn set_hash_algorithm(&mut self, algorithm: HashAlgorithm) -> TmdResult<()> {
+            if !algorithm.is_supported() {
+                return Err(TmdError::Attachment(format!(
+                    "hash algorithm {:?} is not supported by this build",
+                    algorithm
+                )));
             }
-        };
+            self.hash_algorithm = algorithm;
+            Ok(())
+        }
 
-        match read_from_path(&path_buf, assumed) {
-            Ok(doc) => {
-                clear_last_error();
-                Box::into_raw(Box::new(doc))
-            }
-            Err(err) => {
-                set_last_error(err);
-                ptr::null_mut()
+        pub fn max_path_depth(&self) -> Option<usize> {
+            self.max_path_depth
+        }
+
+        /// Reject logical paths with more than `depth` components when attachments are
+        /// inserted, renamed, moved, or aliased from now on. Existing paths are not checked.
+        pub fn set_max_path_depth(&mut self, depth: Option<usize>) {
+            self.max_path_depth = depth;
+        }
+
+        fn check_depth(&self, logical_path: &str) -> TmdResult<()> {
+            match self.max_path_depth {
+                Some(limit) if path_depth(logical_path) > limit => {
+                    Err(TmdError::Attachment(format!(
+                        "attachment `{}` is nested {} levels deep, more than the limit of {}",
+                        logical_path,
+                        path_depth(logical_path),
+                        limit
+                    )))
+                }
+                _ => Ok(()),
             }
         }
-    }
 
-    /// Persist the document to disk using the specified format.
-    ///
-    /// Pass `1` for `.tmd` or `2` for `.tmdz`.
-    ///
-    /// # Safety
-    ///
-    /// `doc` must either be null or point to a [`TmdDoc`] previously returned
-    /// by this library. `path` must either be null or point to a valid,
-    /// NUL-terminated UTF-8 string.
-    #[no_mangle]
-    pub unsafe extern "C" fn tmd_doc_write_to_path(
-        doc: *const TmdDoc,
-        path: *const c_char,
-        format: i32,
-    ) -> i32 {
-        if doc.is_null() {
-            set_last_error_message(NULL_PTR_MESSAGE);
-            return -1;
+        pub fn insert(
+            &mut self,
+            id: AttachmentId,
+            logical_path: LogicalPath,
+            mime: Mime,
+            data: Vec<u8>,
+        ) -> TmdResult<AttachmentId> {
+            let digest = hash_content(self.hash_algorithm, &data)?;
+            self.insert_with_digest(id, logical_path, mime, data, digest)
         }
 
-        let format = match parse_required_format(format) {
-            Ok(value) => value,
-            Err(message) => {
-                set_last_error_message(message);
-                return -1;
+        /// Like [`AttachmentStore::insert`], but trusts a digest the caller already computed
+        /// with the store's [`HashAlgorithm`].
+        pub(crate) fn insert_with_digest(
+            &mut self,
+            id: AttachmentId,
+            logical_path: LogicalPath,
+            mime: Mime,
+            data: Vec<u8>,
+            digest: Vec<u8>,
+        ) -> TmdResult<AttachmentId> {
+            if self.entries.contains_key(&id) {
+                return Err(TmdError::Attachment(format!(
+                    "attachment id {} already exists",
+                    id
+                )));
             }
+            if self.by_path.contains_key(&logical_path) {
+                return Err(TmdError::Attachment(format!(
+                    "attachment `{}` already exists",
+                    logical_path
+                )));
+            }
+            self.check_depth(&logical_path)?;
+
+            let length = data.len() as u64;
+            let now = now_utc();
+            let mut meta = AttachmentMeta {
+                id,
+                logical_path: logical_path.clone(),
+                mime,
+                length,
+                sha256: None,
+                title: None,
+                alt: None,
+                compression_hint: None,
+                aliases: Vec::new(),
+                digest: None,
+                created_utc: Some(now),
+                modified_utc: Some(now),
+                extras: serde_json::Value::default(),
+            };
+            meta.set_content_digest(self.hash_algorithm, digest);
+            self.by_path.insert(logical_path.clone(), id);
+            self.entries.insert(
+                id,
+                AttachmentEntry {
+                    meta,
+                    data: Some(data),
+                    archive_modified: None,
+                    seq: self.next_seq,
+                },
+            );
+            self.next_seq += 1;
+            Ok(id)
+        }
+
+        pub fn remove(&mut self, id: AttachmentId) -> TmdResult<()> {
+            if let Some(entry) = self.entries.remove(&id) {
+                self.by_path.remove(&entry.meta.logical_path);
+                for alias in &entry.meta.aliases {
+                    self.by_path.remove(alias);
+                }
+                Ok(())
+            } else {
+                Err(TmdError::unknown_attachment(id))
+            }
+        }
+
+        /// Give every attachment a fresh v4 id, keeping paths, data, and insertion
+        /// order, and return the old-to-new mapping.
+        pub fn reassign_ids(&mut self) -> HashMap<AttachmentId, AttachmentId> {
+            let mapping: HashMap<AttachmentId, AttachmentId> = self
+                .entries
+                .keys()
+                .map(|&old| (old, uuid::Uuid::new_v4()))
+                .collect();
+            self.entries = std::mem::take(&mut self.entries)
+                .into_iter()
+                .map(|(old, mut entry)| {
+                    entry.meta.id = mapping[&old];
+                    (entry.meta.id, entry)
+                })
+                .collect();
+            for id in self.by_path.values_mut() {
+                *id = mapping[id];
+            }
+            mapping
+        }
+
+        /// Move an attachment to `new_path`, returning its previous logical path.
+        pub fn rename(
+            &mut self,
+            id: AttachmentId,
+            new_path: LogicalPath,
+        ) -> TmdResult<LogicalPath> {
+            if self.by_path.contains_key(&new_path) {
+                return Err(TmdError::Attachment(format!(
+                    "attachment `{}` already exists",
+                    new_path
+                )));
+            }
+            self.check_depth(&new_path)?;
+            let entry = self
+                .entries
+                .get_mut(&id)
+                .ok_or_else(|| TmdError::unknown_attachment(id))?;
+            let old_path = &entry.meta.logical_path;
+            Self::check_nesting(&self.by_path, &new_path, |path| path == old_path)?;
+            self.by_path.remove(old_path);
+            self.by_path.insert(new_path.clone(), id);
+            Ok(std::mem::replace(&mut entry.meta.logical_path, new_path))
+        }
+
+        pub fn rename_prefix(&mut self, old_prefix: &str, new_prefix: &str) -> TmdResult<usize> {
+            let old_dir = format!("{}/", old_prefix);
+            let moves: Vec<(AttachmentId, LogicalPath)> = self
+                .entries
+                .values()
+                .filter_map(|entry| {
+                    entry
+                        .meta
+                        .logical_path
+                        .strip_prefix(&old_dir)
+                        .map(|rest| (entry.meta.id, format!("{}/{}", new_prefix, rest)))
+                })
+                .collect();
+
+            for (_, new_path) in &moves {
+                if let Some(existing) = self.by_path.get(new_path) {
+                    if !moves.iter().any(|(id, _)| id == existing) {
+                        return Err(TmdError::Attachment(format!(
+                            "attachment `{}` already exists",
+                            new_path
+                        )));
+                    }
+                }
+                Self::check_nesting(&self.by_path, new_path, |path| path.starts_with(&old_dir))?;
+                self.check_depth(new_path)?;
+            }
+
+            for (id, _) in &moves {
+                let old_path = &self.entries[id].meta.logical_path;
+                self.by_path.remove(old_path);
+            }
+            for (id, new_path) in &moves {
+                self.by_path.insert(new_path.clone(), *id);
+                if let Some(entry) = self.entries.get_mut(id) {
+                    entry.meta.logical_path = new_path.clone();
+                }
+            }
+            Ok(moves.len())
+        }
+
+        /// Reject `path` if it would sit in a directory named like an existing attachment,
+        /// or name a directory that holds one, since extracting both to a filesystem fails.
+        /// Paths for which `moving` returns true are about to be renamed and are ignored.
+        fn check_nesting(
+            by_path: &HashMap<LogicalPath, AttachmentId>,
+            path: &str,
+            moving: impl Fn(&str) -> bool,
+        ) -> TmdResult<()> {
+            let is_dir_of = |dir: &str, inner: &str| {
+                inner.len() > dir.len()
+                    && inner.starts_with(dir)
+                    && inner.as_bytes()[dir.len()] == b'/'
+            };
+            match by_path.keys().find(|other| {
+                !moving(other) && (is_dir_of(path, other) || is_dir_of(other, path))
+            }) {
+                Some(other) => Err(TmdError::Attachment(format!(
+                    "attachment `{}` conflicts with `{}`: a file and a directory cannot share a name",
+                    path, other
+                ))),
+                None => Ok(()),
+            }
+        }
+
+        pub fn meta(&self, id: AttachmentId) -> Option<&AttachmentMeta> {
+            self.entries.get(&id).map(|entry| &entry.meta)
+        }
+
+        /// Look up an attachment by its logical path or one of its aliases.
+        pub fn meta_by_path(&self, logical_path: &str) -> Option<&AttachmentMeta> {
+            self.by_path
+                .get(logical_path)
+                .and_then(|id| self.entries.get(id))
+                .map(|entry| &entry.meta)
+        }
+
+        /// Borrow attachment data by its logical path or one of its aliases.
+        pub fn data_by_path(&self, logical_path: &str) -> Option<&[u8]> {
+            self.by_path
+                .get(logical_path)
+                .and_then(|id| self.entries.get(id))
+                .and_then(|entry| entry.data.as_deref())
+        }
+
+        pub fn add_alias(&mut self, id: AttachmentId, alias: LogicalPath) -> TmdResult<()> {
+            if self.by_path.contains_key(&alias) {
+                return Err(TmdError::Attachment(format!(
+                    "attachment `{}` already exists",
+                    alias
+                )));
+            }
+            self.check_depth(&alias)?;
+            let entry = self
+                .entries
+                .get_mut(&id)
+                .ok_or_else(|| TmdError::unknown_attachment(id))?;
+            self.by_path.insert(alias.clone(), id);
+            entry.meta.aliases.push(alias);
+            Ok(())
+        }
+
+        pub fn remove_alias(&mut self, id: AttachmentId, alias: &str) -> TmdResult<()> {
+            let entry = self
+                .entries
+                .get_mut(&id)
+                .ok_or_else(|| TmdError::unknown_attachment(id))?;
+            let position = entry
+                .meta
+                .aliases
+                .iter()
+                .position(|existing| existing == alias)
+                .ok_or_else(|| {
+                    TmdError::NotFound(format!("`{}` is not an alias of attachment {}", alias, id))
+                })?;
+            entry.meta.aliases.remove(position);
+            self.by_path.remove(alias);
+            Ok(())
+        }
+
+        pub fn set_mime(&mut self, id: AttachmentId, mime: Mime) -> TmdResult<()> {
+            let entry = self
+                .entries
+                .get_mut(&id)
+                .ok_or_else(|| TmdError::unknown_attachment(id))?;
+            entry.meta.mime = mime;
+            Ok(())
+        }
+
+        pub fn set_compression_hint(
+            &mut self,
+            id: AttachmentId,
+            hint: Option<CompressionHint>,
+        ) -> TmdResult<()> {
+            let entry = self
+                .entries
+                .get_mut(&id)
+                .ok_or_else(|| TmdError::unknown_attachment(id))?;
+            entry.meta.compression_hint = hint;
+            Ok(())
+        }
+
+        pub fn archive_modified(&self, id: AttachmentId) -> Option<NaiveDateTime> {
+            self.entries
+                .get(&id)
+                .and_then(|entry| entry.archive_modified)
+        }
+
+        pub fn set_archive_modified(
+            &mut self,
+            id: AttachmentId,
+            modified: Option<NaiveDateTime>,
+        ) -> TmdResult<()> {
+            let entry = self
+                .entries
+                .get_mut(&id)
+                .ok_or_else(|| TmdError::unknown_attachment(id))?;
+            entry.archive_modified = modified;
+            Ok(())
+        }
+
+        /// Borrow attachment data, or `None` if the attachment is unknown or not resident.
+        pub fn data(&self, id: AttachmentId) -> Option<&[u8]> {
+            self.entries
+                .get(&id)
+                .and_then(|entry| entry.data.as_deref())
+        }
+
+        /// Whether the attachment's bytes are held in memory rather than read on demand.
+        pub fn is_resident(&self, id: AttachmentId) -> bool {
+            self.entries
+                .get(&id)
+                .is_some_and(|entry| entry.data.is_some())
+        }
+
+        /// Make a lazily read attachment resident.
+        pub(crate) fn set_resident_data(&mut self, id: AttachmentId, data: Vec<u8>) {
+            if let Some(entry) = self.entries.get_mut(&id) {
+                entry.data = Some(data);
+            }
+        }
+
+        /// Mutably borrow resident attachment data; see [`AttachmentStore::data`].
+        pub fn data_mut(&mut self, id: AttachmentId) -> Option<AttachmentDataMut<'_>> {
+            let entry = self.entries.get_mut(&id)?;
+            Some(AttachmentDataMut {
+                meta: &mut entry.meta,
+                data: entry.data.as_mut()?,
+                archive_modified: &mut entry.archive_modified,
+            })
+        }
+
+        pub fn iter(&self) -> AttachmentStoreIter<'_> {
+            AttachmentStoreIter {
+                inner: self.entries.values(),
+            }
+        }
+
+        /// Iterate attachments whose stored length is at least `min` and, when given, at
+        /// most `max` bytes.
+        pub fn iter_by_size(
+            &self,
+            min: u64,
+            max: Option<u64>,
+        ) -> impl Iterator<Item = &AttachmentMeta> {
+            self.iter()
+                .filter(move |meta| meta.length >= min && max.is_none_or(|max| meta.length <= max))
+        }
+
+        /// Iterate resident attachments together with their data.
+        pub fn iter_with_data(&self) -> impl Iterator<Item = (&AttachmentMeta, &[u8])> {
+            self.entries
+                .values()
+                .filter_map(|entry| Some((&entry.meta, entry.data.as_deref()?)))
+        }
+
+        /// Approximate heap bytes held by the store: attachment data plus per-entry
+        /// bookkeeping and the variable-length metadata strings.
+        pub fn memory_bytes(&self) -> u64 {
+            let per_entry = std::mem::size_of::<AttachmentEntry>()
+                + std::mem::size_of::<(LogicalPath, AttachmentId)>()
+                + std::mem::size_of::<AttachmentId>();
+            self.entries
+                .values()
+                .map(|entry| {
+                    let meta = &entry.meta;
+                    let strings = 2 * meta.logical_path.len()
+                        + meta.mime.as_ref().len()
+                        + meta.title.as_ref().map_or(0, String::len)
+                        + meta.alt.as_ref().map_or(0, String::len);
+                    (entry.data.as_ref().map_or(0, Vec::len) + per_entry + strings) as u64
+                })
+                .sum()
+        }
+
+        /// Consume the store, yielding each attachment's metadata together with its owned data.
+        ///
+        /// Fails if any attachment is not resident, since its data would be lost; load lazily
+        /// read attachments with [`TmdDoc::load_attachments`](crate::TmdDoc::load_attachments)
+        /// first.
+        pub fn into_entries(self) -> TmdResult<impl Iterator<Item = (AttachmentMeta, Vec<u8>)>> {
+            if let Some(entry) = self.entries.values().find(|entry| entry.data.is_none()) {
+                return Err(TmdError::Attachment(format!(
+                    "missing data for attachment `{}`",
+                    entry.meta.logical_path
+                )));
+            }
+            Ok(self
+                .entries
+                .into_values()
+                .filter_map(|entry| Some((entry.meta, entry.data?))))
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.entries.is_empty()
+        }
+
+        /// Group attachments by content hash, recomputing digests that are not stored.
+        ///
+        /// Non-resident attachments without a stored sha256 are not considered.
+        pub fn duplicate_groups(&self) -> Vec<DuplicateGroup> {
+            let mut by_hash: BTreeMap<[u8; 32], Vec<&AttachmentMeta>> = BTreeMap::new();
+            for entry in self.entries.values() {
+                let sha = entry.meta.sha256.or_else(|| {
+                    let digest = Sha256::digest(entry.data.as_ref()?);
+                    let mut arr = [0u8; 32];
+                    arr.copy_from_slice(&digest);
+                    Some(arr)
+                });
+                if let Some(sha) = sha {
+                    by_hash.entry(sha).or_default().push(&entry.meta);
+                }
+            }
+
+            by_hash
+                .into_iter()
+                .filter(|(_, metas)| metas.len() > 1)
+                .map(|(sha256, metas)| {
+                    let mut logical_paths: Vec<LogicalPath> =
+                        metas.iter().map(|meta| meta.logical_path.clone()).collect();
+                    logical_paths.sort();
+                    let bytes_saved = metas[0].length * (metas.len() as u64 - 1);
+                    DuplicateGroup {
+                        sha256,
+                        logical_paths,
+                        bytes_saved,
+                    }
+                })
+                .collect()
+        }
+
+        pub fn insert_entry(
+            &mut self,
+            meta: AttachmentMeta,
+            data: Vec<u8>,
+            verify_hashes: bool,
+        ) -> TmdResult<()> {
+            self.check_new_entry(&meta)?;
+            let length = data.len() as u64;
+            if length != meta.length {
+                return Err(TmdError::Attachment(format!(
+                    "attachment `{}` length mismatch: manifest={} actual={}",
+                    meta.logical_path, meta.length, length
+                )));
+            }
+            if verify_hashes {
+                verify_digest(&meta, &data)?;
+            }
+            self.register_entry(meta, Some(data));
+            Ok(())
+        }
+
+        /// Register an attachment whose bytes stay in the source archive until requested.
+        pub(crate) fn insert_lazy_entry(&mut self, meta: AttachmentMeta) -> TmdResult<()> {
+            self.check_new_entry(&meta)?;
+            self.register_entry(meta, None);
+            Ok(())
+        }
+
+        fn check_new_entry(&self, meta: &AttachmentMeta) -> TmdResult<()> {
+            if self.entries.contains_key(&meta.id) {
+                return Err(TmdError::Attachment(format!(
+                    "attachment id {} already exists",
+                    meta.id
+                )));
+            }
+            if self.by_path.contains_key(&meta.logical_path) {
+                return Err(TmdError::Attachment(format!(
+                    "attachment `{}` already exists",
+                    meta.logical_path
+                )));
+            }
+            self.check_depth(&meta.logical_path)?;
+            for (index, alias) in meta.aliases.iter().enumerate() {
+                self.check_depth(alias)?;
+                if normalize_logical_path(alias)? != *alias {
+                    return Err(TmdError::Attachment(format!(
+                        "attachment alias `{}` is not normalized",
+                        alias
+                    )));
+                }
+                if self.by_path.contains_key(alias)
+                    || *alias == meta.logical_path
+                    || meta.aliases[..index].contains(alias)
+                {
+                    return Err(TmdError::Attachment(format!(
+                        "attachment alias `{}` collides with an existing path",
+                        alias
+                    )));
+                }
+            }
+            Ok(())
+        }
+
+        fn register_entry(&mut self, meta: AttachmentMeta, data: Option<Vec<u8>>) {
+            self.by_path.insert(meta.logical_path.clone(), meta.id);
+            for alias in &meta.aliases {
+                self.by_path.insert(alias.clone(), meta.id);
+            }
+            self.entries.insert(
+                meta.id,
+                AttachmentEntry {
+                    meta,
+                    data,
+                    archive_modified: None,
+                    seq: self.next_seq,
+                },
+            );
+            self.next_seq += 1;
+        }
+
+        /// Rank of `id` in insertion order; earlier insertions rank lower.
+        pub(crate) fn insertion_rank(&self, id: AttachmentId) -> Option<u64> {
+            self.entries.get(&id).map(|entry| entry.seq)
+        }
+    }
+
+    /// Check `data` against the digest recorded in `meta`, if the build supports it.
+    pub(crate) fn verify_digest(meta: &AttachmentMeta, data: &[u8]) -> TmdResult<()> {
+        match meta.content_digest() {
+            Some((algorithm, _)) if !algorithm.is_supported() => {
+                log::warn!(
+                    "cannot verify {:?} digest of `{}` in this build",
+                    algorithm,
+                    meta.logical_path
+                );
+            }
+            Some((algorithm, expected)) => {
+                let computed = hash_content(algorithm, data)?;
+                if expected != computed.as_slice() {
+                    log::warn!(
+                        "{:?} mismatch for `{}`: manifest={} actual={}",
+                        algorithm,
+                        meta.logical_path,
+                        hex::encode(expected),
+                        hex::encode(&computed)
+                    );
+                    return Err(TmdError::Attachment(format!(
+                        "attachment `{}` {:?} digest mismatch",
+                        meta.logical_path, algorithm
+                    )));
+                }
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// Overwrite a stale `length` or content digest in `meta` with values computed from
+    /// `data`, logging what changed. Digests under unsupported algorithms are left alone.
+    pub(crate) fn repair_metadata(meta: &mut AttachmentMeta, data: &[u8]) -> TmdResult<()> {
+        let length = data.len() as u64;
+        if meta.length != length {
+            log::warn!(
+                "repairing length of `{}`: manifest={} actual={}",
+                meta.logical_path,
+                meta.length,
+                length
+            );
+            meta.length = length;
+        }
+        let repaired = match meta.content_digest() {
+            Some((algorithm, expected)) if algorithm.is_supported() => {
+                let computed = hash_content(algorithm, data)?;
+                (expected != computed.as_slice()).then_some((algorithm, computed))
+            }
+            _ => None,
+        };
+        if let Some((algorithm, computed)) = repaired {
+            log::warn!(
+                "repairing {:?} digest of `{}`",
+                algorithm,
+                meta.logical_path
+            );
+            meta.set_content_digest(algorithm, computed);
+        }
+        Ok(())
+    }
+
+    pub struct AttachmentDataMut<'a> {
+        meta: &'a mut AttachmentMeta,
+        data: &'a mut Vec<u8>,
+        archive_modified: &'a mut Option<NaiveDateTime>,
+    }
+
+    impl<'a> Deref for AttachmentDataMut<'a> {
+        type Target = Vec<u8>;
+
+        fn deref(&self) -> &Self::Target {
+            self.data
+        }
+    }
+
+    impl<'a> DerefMut for AttachmentDataMut<'a> {
+        fn deref_mut(&mut self) -> &mut Self::Target {
+            self.data
+        }
+    }
+
+    impl<'a> Drop for AttachmentDataMut<'a> {
+        fn drop(&mut self) {
+            let algorithm = match self.meta.hash_algorithm() {
+                algorithm if algorithm.is_supported() => algorithm,
+                _ => HashAlgorithm::Sha256,
+            };
+            let digest = hash_content(algorithm, self.data).expect("supported hash algorithm");
+            // A borrow that leaves the bytes as they were is not an edit.
+            if self.meta.length == self.data.len() as u64
+                && self.meta.content_digest() == Some((algorithm, digest.as_slice()))
+            {
+                return;
+            }
+            *self.archive_modified = None;
+            self.meta.length = self.data.len() as u64;
+            self.meta.set_content_digest(algorithm, digest);
+            self.meta.modified_utc = Some(now_utc());
+        }
+    }
+
+    pub struct AttachmentStoreIter<'a> {
+        inner: Values<'a, AttachmentId, AttachmentEntry>,
+    }
+
+    impl<'a> Iterator for AttachmentStoreIter<'a> {
+        type Item = &'a AttachmentMeta;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            self.inner.next().map(|entry| &entry.meta)
+        }
+    }
+}
+mod db {
+    use super::{TmdDoc, TmdError, TmdResult};
+    use base64::engine::general_purpose::STANDARD as BASE64_STANDARD;
+    use base64::Engine;
+    use rusqlite::types::Value;
+    use rusqlite::{Connection, ErrorCode};
+    use serde::{Serialize, Serializer};
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    /// A single typed value returned from the embedded database.
+    #[derive(Clone, Debug, PartialEq)]
+    pub enum SqlCell {
+        Null,
+        Integer(i64),
+        Real(f64),
+        Text(String),
+        /// Binary data, serialised as a base64 string.
+        Blob(Vec<u8>),
+    }
+
+    impl From<Value> for SqlCell {
+        fn from(value: Value) -> Self {
+            match value {
+                Value::Null => Self::Null,
+                Value::Integer(v) => Self::Integer(v),
+                Value::Real(v) => Self::Real(v),
+                Value::Text(v) => Self::Text(v),
+                Value::Blob(v) => Self::Blob(v),
+            }
+        }
+    }
+
+    impl Serialize for SqlCell {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            match self {
+                Self::Null => serializer.serialize_none(),
+                Self::Integer(v) => serializer.serialize_i64(*v),
+                Self::Real(v) => serializer.serialize_f64(*v),
+                Self::Text(v) => serializer.serialize_str(v),
+                Self::Blob(v) => serializer.serialize_str(&BASE64_STANDARD.encode(v)),
+            }
+        }
+    }
+
+    /// Column names and rows produced by a query.
+    #[derive(Clone, Debug, Default, PartialEq, Serialize)]
+    pub struct QueryResult {
+        pub columns: Vec<String>,
+        pub rows: Vec<Vec<SqlCell>>,
+    }
+
+    #[derive(Clone, Debug, Default)]
+    pub struct DbOptions {
+        pub page_size: Option<u32>,
+        pub journal_mode: Option<String>,
+        pub synchronous: Option<String>,
+        /// How long each connection waits on a locked database before failing with
+        /// `SQLITE_BUSY`. Unlike the pragmas, this is reapplied on every open.
+        pub busy_timeout_ms: Option<u32>,
+    }
+
+    #[derive(Debug)]
+    pub struct DbHandle {
+        _temp_dir: TempDir,
+        path: PathBuf,
+        busy_timeout: Option<Duration>,
+    }
+
+    impl DbHandle {
+        pub fn new_empty() -> TmdResult<Self> {
+            let temp_dir = TempDir::new()?;
+            let path = temp_dir.path().join("main.sqlite3");
+            let conn = Connection::open(&path)?;
+            conn.execute_batch("PRAGMA user_version = 0;")?;
+            conn.close()
+                .map_err(|(_, err)| TmdError::Db(err.to_string()))?;
+            Ok(Self {
+                _temp_dir: temp_dir,
+                path,
+                busy_timeout: None,
+            })
+        }
+
+        pub fn from_bytes(bytes: &[u8]) -> TmdResult<Self> {
+            let temp_dir = TempDir::new()?;
+            let path = temp_dir.path().join("main.sqlite3");
+            fs::write(&path, bytes)?;
+            Ok(Self {
+                _temp_dir: temp_dir,
+                path,
+                busy_timeout: None,
+            })
+        }
+
+        pub fn ensure_initialized(&mut self, opts: Option<DbOptions>) -> TmdResult<()> {
+            if let Some(ms) = opts.as_ref().and_then(|opts| opts.busy_timeout_ms) {
+                self.busy_timeout = Some(Duration::from_millis(u64::from(ms)));
+            }
+            let mut conn = self.open()?;
+            if let Some(opts) = opts {
+                apply_options(&mut conn, &opts)?;
+            }
+            conn.close()
+                .map_err(|(_, err)| TmdError::Db(err.to_string()))?;
+            Ok(())
+        }
+
+        fn open(&self) -> TmdResult<Connection> {
+            let conn = Connection::open(&self.path)?;
+            if let Some(timeout) = self.busy_timeout {
+                conn.busy_timeout(timeout)?;
+            }
+            Ok(conn)
+        }
+
+        pub fn with_conn<T, F: FnOnce(&Connection) -> T>(&self, f: F) -> TmdResult<T> {
+            let conn = self.open()?;
+            let result = f(&conn);
+            conn.close()
+                .map_err(|(_, err)| TmdError::Db(err.to_string()))?;
+            Ok(result)
+        }
+
+        pub fn with_conn_mut<T, F: FnOnce(&mut Connection) -> T>(&mut self, f: F) -> TmdResult<T> {
+            let mut conn = self.open()?;
+            let result = f(&mut conn);
+            conn.close()
+                .map_err(|(_, err)| TmdError::Db(err.to_string()))?;
+            Ok(result)
+        }
+
+        /// Run `f`, retrying up to `retries` more times with exponential backoff while it
+        /// fails with `SQLITE_BUSY` or `SQLITE_LOCKED`. Other errors are returned at once.
+        pub fn with_conn_retry<T, F>(&self, retries: u32, mut f: F) -> TmdResult<T>
+        where
+            F: FnMut(&Connection) -> rusqlite::Result<T>,
+        {
+            let mut attempt = 0;
+            loop {
+                match self.with_conn(&mut f)? {
+                    Err(err) if attempt < retries && is_busy(&err) => {
+                        let delay = RETRY_BASE_DELAY * 2u32.pow(attempt.min(6));
+                        log::debug!("database busy ({}), retrying in {:?}", err, delay);
+                        std::thread::sleep(delay);
+                        attempt += 1;
+                    }
+                    result => return result.map_err(TmdError::from),
+                }
+            }
+        }
+
+        /// Run `f` inside a single transaction, committing on `Ok` and rolling back on `Err`.
+        pub fn transaction<T, F>(&mut self, f: F) -> TmdResult<T>
+        where
+            F: FnOnce(&rusqlite::Transaction) -> rusqlite::Result<T>,
+        {
+            self.with_conn_mut(|conn| -> rusqlite::Result<T> {
+                let tx = conn.transaction()?;
+                // Dropping an uncommitted transaction rolls it back.
+                let value = f(&tx)?;
+                tx.commit()?;
+                Ok(value)
+            })?
+            .map_err(TmdError::from)
+        }
+
+        pub fn as_path(&self) -> &Path {
+            &self.path
+        }
+
+        pub fn query(&self, sql: &str) -> TmdResult<QueryResult> {
+            self.with_conn(|conn| -> TmdResult<QueryResult> {
+                let mut stmt = conn.prepare(sql)?;
+                let columns: Vec<String> = stmt
+                    .column_names()
+                    .into_iter()
+                    .map(|name| name.to_string())
+                    .collect();
+                let mut rows = Vec::new();
+                each_row(&mut stmt, |cells| {
+                    rows.push(cells.to_vec());
+                    Ok(())
+                })?;
+                Ok(QueryResult { columns, rows })
+            })?
+        }
+
+        pub fn query_each<F>(&self, sql: &str, f: F) -> TmdResult<()>
+        where
+            F: FnMut(&[SqlCell]) -> TmdResult<()>,
+        {
+            self.with_conn(|conn| -> TmdResult<()> {
+                let mut stmt = conn.prepare(sql)?;
+                each_row(&mut stmt, f)
+            })?
+        }
+    }
+
+    const RETRY_BASE_DELAY: Duration = Duration::from_millis(10);
+
+    fn is_busy(err: &rusqlite::Error) -> bool {
+        matches!(
+            err.sqlite_error_code(),
+            Some(ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked)
+        )
+    }
+
+    /// Step through `stmt`, handing each row to `f` in a reused buffer.
+    fn each_row<F>(stmt: &mut rusqlite::Statement<'_>, mut f: F) -> TmdResult<()>
+    where
+        F: FnMut(&[SqlCell]) -> TmdResult<()>,
+    {
+        let column_count = stmt.column_count();
+        let mut cells = Vec::with_capacity(column_count);
+        let mut cursor = stmt.query([])?;
+        while let Some(row) = cursor.next()? {
+            cells.clear();
+            for idx in 0..column_count {
+                cells.push(SqlCell::from(row.get::<_, Value>(idx)?));
+            }
+            f(&cells)?;
+        }
+        Ok(())
+    }
+
+    fn apply_options(conn: &mut Connection, opts: &DbOptions) -> TmdResult<()> {
+        if let Some(page_size) = opts.page_size {
+            conn.pragma_update(None, "page_size", page_size)?;
+        }
+        if let Some(mode) = &opts.journal_mode {
+            conn.pragma_update(None, "journal_mode", mode.as_str())?;
+        }
+        if let Some(sync) = &opts.synchronous {
+            conn.pragma_update(None, "synchronous", sync.as_str())?;
+        }
+        Ok(())
+    }
+
+    pub fn with_conn<T, F: FnOnce(&Connection) -> T>(doc: &TmdDoc, f: F) -> TmdResult<T> {
+        doc.db.with_conn(f)
+    }
+
+    pub fn with_conn_mut<T, F: FnOnce(&mut Connection) -> T>(
+        doc: &mut TmdDoc,
+        f: F,
+    ) -> TmdResult<T> {
+        doc.db.with_conn_mut(f)
+    }
+
+    pub fn export_db(doc: &TmdDoc, out_path: impl AsRef<Path>) -> TmdResult<()> {
+        let out = out_path.as_ref();
+        fs::copy(doc.db.as_path(), out)?;
+        Ok(())
+    }
+
+    pub fn import_db(doc: &mut TmdDoc, in_path: impl AsRef<Path>) -> TmdResult<()> {
+        let bytes = fs::read(in_path)?;
+        fs::write(doc.db.as_path(), bytes)?;
+        Ok(())
+    }
+
+    pub fn reset_db(doc: &mut TmdDoc, schema_sql: &str, version: u32) -> TmdResult<()> {
+        doc.db
+            .with_conn_mut(|conn| -> rusqlite::Result<()> {
+                conn.execute_batch("VACUUM;")?;
+                conn.execute_batch(schema_sql)?;
+                conn.pragma_update(None, "user_version", version as i64)?;
+                Ok(())
+            })?
+            .map_err(TmdError::from)?;
+        Ok(())
+    }
+
+    pub fn migrate(doc: &mut TmdDoc, up_sql: &str, from: u32, to: u32) -> TmdResult<()> {
+        let current: u32 = doc
+            .db
+            .with_conn(|conn| conn.query_row("PRAGMA user_version", [], |row| row.get::<_, u32>(0)))
+            .and_then(|res| res.map_err(super::TmdError::from))?;
+        if current != from {
+            return Err(super::TmdError::Db(format!(
+                "expected user_version {} but found {}",
+                from, current
+            )));
+        }
+        doc.db
+            .with_conn_mut(|conn| -> rusqlite::Result<()> {
+                conn.execute_batch(up_sql)?;
+                conn.pragma_update(None, "user_version", to as i64)?;
+                Ok(())
+            })?
+            .map_err(TmdError::from)?;
+        Ok(())
+    }
+}
+mod format {
+    use super::attach::{
+        content_checksum, hash_content, hash_reader, repair_metadata, verify_digest,
+        AttachmentStore,
+    };
+    use super::db::DbHandle;
+    use super::manifest::{AttachmentMeta, CompressionHint, HashAlgorithm, Manifest};
+    use super::util::{free_suffixed_path, glob_match, guess_mime, now_utc};
+    use super::{normalize_logical_path, AttachmentId, LogicalPath, TmdDoc, TmdError, TmdResult};
+    use base64::engine::general_purpose::STANDARD as BASE64_STANDARD;
+    use base64::Engine;
+    use chrono::{Datelike, NaiveDate, NaiveDateTime, Timelike};
+    use serde::{Deserialize, Serialize};
+    use std::borrow::Cow;
+    use std::collections::{HashMap, HashSet};
+    use std::fs::File;
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::path::{Path, PathBuf};
+    use std::sync::Mutex;
+    use zip::write::FileOptions;
+    use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+    const EOCD_SIGNATURE: [u8; 4] = [0x50, 0x4b, 0x05, 0x06];
+    const LOCAL_FILE_SIGNATURE: [u8; 4] = [0x50, 0x4b, 0x03, 0x04];
+    const MAX_COMMENT_SEARCH: usize = 0xFFFF + 22;
+    const TMD_COMMENT_PREFIX: &[u8] = b"TMD1\0";
+    /// Follows [`TMD_COMMENT_PREFIX`] in documents whose payload is encrypted.
+    const ENCRYPTED_MARKER: &[u8] = b"ENC1";
+
+    pub(crate) const MARKDOWN_ENTRY: &str = "index.md";
+    const MANIFEST_ENTRY: &str = "manifest.json";
+    const ATTACHMENTS_ENTRY: &str = "attachments.json";
+    const DB_ENTRY: &str = "db/main.sqlite3";
+    /// Manifest extras key [`WriteMode::record_mode`] writes the effective mode under.
+    const WRITE_MODE_EXTRA: &str = "_tmd_write_mode";
+    pub(crate) const RESERVED_ENTRIES: [&str; 4] =
+        [MARKDOWN_ENTRY, MANIFEST_ENTRY, ATTACHMENTS_ENTRY, DB_ENTRY];
+    pub(crate) const SQLITE_MAGIC: &[u8; 16] = b"SQLite format 3\0";
+    /// Largest slice of attachment data written between syncs under
+    /// [`WriteMode::checkpoint_sync`].
+    const CHECKPOINT_CHUNK: usize = 64 * 1024 * 1024;
+    const STABLE_ID_NAMESPACE: AttachmentId =
+        AttachmentId::from_u128(0x6d1f_3c2a_9b84_4e07_a5d2_7c41_0e9f_b318);
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum Format {
+        Tmd,
+        Tmdz,
+    }
+
+    impl Format {
+        /// Map a file extension (without the leading dot) to a format, ignoring case.
+        pub fn from_extension(ext: &str) -> Option<Self> {
+            match ext.to_ascii_lowercase().as_str() {
+                "tmd" => Some(Self::Tmd),
+                "tmdz" => Some(Self::Tmdz),
+                _ => None,
+            }
+        }
+
+        /// The file extension (without the leading dot) for this format.
+        pub fn extension(self) -> &'static str {
+            match self {
+                Self::Tmd => "tmd",
+                Self::Tmdz => "tmdz",
+            }
+        }
+
+        /// Infer the format from a path's extension.
+        pub fn from_path(path: &Path) -> Option<Self> {
+            path.extension()
+                .and_then(|ext| ext.to_str())
+                .and_then(Self::from_extension)
+        }
+    }
+
+    /// Length of the `.tmd` marker: [`TMD_COMMENT_PREFIX`] plus the little-endian
+    /// markdown length.
+    ///
+    /// The marker is the fixed-size suffix of the ZIP comment, and so always ends the
+    /// file; any comment the archive already carried is kept in front of it:
+    ///
+    /// ```text
+    /// EOCD (22 bytes) | user comment (0..n bytes) | "TMD1\0" | markdown_len: u64 LE
+    /// ```
+    const TMD_TRAILER_LEN: usize = TMD_COMMENT_PREFIX.len() + 8;
+
+    /// Whether `tail` (the last bytes of a file) ends with the `.tmd` marker.
+    fn has_tmd_trailer(tail: &[u8]) -> bool {
+        tail.len() >= TMD_TRAILER_LEN
+            && tail[tail.len() - TMD_TRAILER_LEN..].starts_with(TMD_COMMENT_PREFIX)
+    }
+
+    /// The part of a ZIP comment that precedes the `.tmd` marker, if there is one.
+    fn user_zip_comment(comment: &[u8]) -> &[u8] {
+        if has_tmd_trailer(comment) {
+            &comment[..comment.len() - TMD_TRAILER_LEN]
+        } else {
+            comment
+        }
+    }
+
+    /// Sniff a complete file, recognising `.tmd` files with an empty markdown section
+    /// (which begin directly with the ZIP payload) by their trailing comment.
+    fn sniff_format_bytes(bytes: &[u8]) -> Option<Format> {
+        match sniff_format(bytes) {
+            Some(Format::Tmdz) if has_tmd_trailer(bytes) => Some(Format::Tmd),
+            other => other,
+        }
+    }
+
+    pub fn sniff_format(header: &[u8]) -> Option<Format> {
+        if header.starts_with(&LOCAL_FILE_SIGNATURE) {
+            Some(Format::Tmdz)
+        } else if !header.is_empty() {
+            Some(Format::Tmd)
+        } else {
+            None
+        }
+    }
+
+    /// What to do when `attachments.json` lists the same logical path more than once.
+    ///
+    /// Under `Skip` and `Rename`, the archive may also hold several entries of that name;
+    /// they are matched to the listings in order.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum DuplicatePolicy {
+        /// Abort the read.
+        #[default]
+        Error,
+        /// Keep the first entry and drop later ones.
+        Skip,
+        /// Keep later entries under the path with a numeric suffix, e.g. `a-1.png`.
+        Rename,
+    }
+
+    #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+    #[serde(default)]
+    pub struct ReadMode {
+        pub verify_hashes: bool,
+        /// Leave attachment bytes in the source file and read them on demand through
+        /// [`TmdDoc::attachment_bytes`]. Only [`read_from_path_with_mode`] honors this;
+        /// other readers load eagerly.
+        pub lazy_attachments: bool,
+        /// Reject manifests whose cover image or links point at missing attachments.
+        pub validate_manifest: bool,
+        /// Trust attachment bytes over `attachments.json`: a mismatched length or digest is
+        /// logged and recomputed instead of failing the read. Lazy reads repair lengths
+        /// only, since their bytes are not hashed until requested.
+        pub repair_metadata: bool,
+        /// Treat a missing `attachments.json` as an index of every non-reserved ZIP entry,
+        /// with MIME types guessed from extensions and lengths and digests computed from
+        /// the content, instead of failing the read.
+        pub infer_attachments: bool,
+        /// Fail when [`Manifest::content_checksum`] is recorded and does not match the
+        /// markdown and attachments read.
+        pub verify_checksum: bool,
+        /// Reject `.tmdz` input with bytes after the end of the ZIP archive instead of
+        /// only logging a warning.
+        pub strict_trailing: bool,
+        pub on_duplicate_path: DuplicatePolicy,
+    }
+
+    impl Default for ReadMode {
+        fn default() -> Self {
+            Self {
+                verify_hashes: true,
+                lazy_attachments: false,
+                validate_manifest: false,
+                repair_metadata: false,
+                infer_attachments: false,
+                verify_checksum: false,
+                strict_trailing: false,
+                on_duplicate_path: DuplicatePolicy::Error,
+            }
+        }
+    }
+
+    /// Order in which attachments are laid out in the archive and listed in
+    /// `attachments.json`. Readers do not depend on it.
+    #[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum OrderBy {
+        #[default]
+        LogicalPath,
+        /// The order attachments were added to the document.
+        InsertionOrder,
+        /// Group by MIME type, then by logical path.
+        MimeThenPath,
+        /// The listed attachments first, in the given order, then the rest by logical path.
+        Custom(Vec<AttachmentId>),
+    }
+
+    impl OrderBy {
+        fn sort(&self, metas: &mut [AttachmentMeta], attachments: &AttachmentStore) {
+            metas.sort_by(|a, b| a.logical_path.cmp(&b.logical_path));
+            match self {
+                Self::LogicalPath => {}
+                Self::InsertionOrder => {
+                    metas.sort_by_key(|meta| attachments.insertion_rank(meta.id));
+                }
+                Self::MimeThenPath => {
+                    metas.sort_by(|a, b| a.mime.essence_str().cmp(b.mime.essence_str()));
+                }
+                Self::Custom(ids) => {
+                    let rank: HashMap<AttachmentId, usize> = ids
+                        .iter()
+                        .enumerate()
+                        .map(|(index, id)| (*id, index))
+                        .collect();
+                    metas.sort_by_key(|meta| rank.get(&meta.id).copied().unwrap_or(ids.len()));
+                }
+            }
+        }
+    }
+
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    #[serde(default)]
+    pub struct WriteMode {
+        pub compute_hashes: bool,
+        pub solid_zip: bool,
+        pub dedup_by_hash: bool,
+        /// Reuse the entry timestamps captured when attachments were read from an archive.
+        pub preserve_timestamps: bool,
+        /// Serialize attachment IDs as v5 UUIDs derived from their logical paths, so
+        /// identical content yields identical manifests. Attachment timestamps are omitted for
+        /// the same reason. The in-memory document is unchanged.
+        pub stable_ids: bool,
+        /// `sync_all` file-backed output before reporting success.
+        pub durable: bool,
+        /// Record [`Manifest::content_checksum`]. Otherwise any checksum the manifest
+        /// carries is dropped, since it may no longer match the content.
+        pub compute_checksum: bool,
+        /// When writing to a path, stream to `<path>.part` and `sync_data` after the markdown
+        /// prefix, after each archive entry, and within attachments every 64 MiB, renaming
+        /// into place once complete. An interrupted write leaves a `.part` file rather than a truncated
+        /// document. Each sync waits for the device, so this can be several times slower
+        /// than a plain write on spinning disks; prefer [`WriteMode::durable`] when only the
+        /// final result needs to be on disk.
+        pub checkpoint_sync: bool,
+        /// Refuse to write documents whose attachments total more than this many bytes.
+        pub max_total_size: Option<u64>,
+        /// Deflate the markdown, `manifest.json`, and `attachments.json` entries of
+        /// `.tmdz` output. `.tmd` output always stores them.
+        pub deflate_text: bool,
+        /// Deflate `db/main.sqlite3`. SQLite pages are often mostly zero padding, so this
+        /// can shrink database-heavy documents considerably; the database is stored
+        /// uncompressed otherwise.
+        pub compress_db: bool,
+        /// Store every attachment this way, overriding its own
+        /// [`AttachmentMeta::compression_hint`]. The recorded hints are not changed.
+        pub attachment_compression: Option<CompressionHint>,
+        /// Indent `manifest.json` and `attachments.json` for human inspection. Compact
+        /// output is noticeably smaller for documents with many attachments; readers
+        /// accept either.
+        pub pretty_json: bool,
+        /// Record this mode in the manifest extras under `_tmd_write_mode`, so
+        /// [`TmdDoc::last_write_mode`] can report how the document was produced.
+        pub record_mode: bool,
+        pub order_by: OrderBy,
+    }
+
+    impl Default for WriteMode {
+        fn default() -> Self {
+            Self {
+                compute_hashes: true,
+                solid_zip: false,
+                dedup_by_hash: false,
+                preserve_timestamps: false,
+                stable_ids: false,
+                durable: false,
+                compute_checksum: false,
+                checkpoint_sync: false,
+                max_total_size: None,
+                deflate_text: true,
+                compress_db: false,
+                attachment_compression: None,
+                pretty_json: true,
+                record_mode: false,
+                order_by: OrderBy::LogicalPath,
+            }
+        }
+    }
+
+    impl WriteMode {
+        /// One-line list of the options that differ from [`WriteMode::default`], or
+        /// `default` when none do.
+        pub fn summary(&self) -> String {
+            mode_summary(self)
+        }
+    }
+
+    impl ReadMode {
+        /// One-line list of the options that differ from [`ReadMode::default`], or
+        /// `default` when none do.
+        pub fn summary(&self) -> String {
+            mode_summary(self)
+        }
+    }
+
+    /// Render the fields of `mode` that differ from `T::default()` as `name=value`.
+    fn mode_summary<T: Serialize + Default>(mode: &T) -> String {
+        let (Ok(serde_json::Value::Object(fields)), Ok(serde_json::Value::Object(defaults))) = (
+            serde_json::to_value(mode),
+            serde_json::to_value(T::default()),
+        ) else {
+            return String::new();
+        };
+        let changed: Vec<String> = fields
+            .iter()
+            .filter(|(name, value)| defaults.get(*name) != Some(*value))
+            .map(|(name, value)| format!("{name}={value}"))
+            .collect();
+        if changed.is_empty() {
+            "default".to_string()
+        } else {
+            changed.join(" ")
+        }
+    }
+
+    /// A recoverable problem a [`Reader`] worked around, reported to
+    /// [`Reader::on_progress`].
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub enum ReadProgress {
+        /// A later attachment listed under `path` was dropped per
+        /// [`DuplicatePolicy::Skip`].
+        DuplicateSkipped { path: LogicalPath },
+        /// A later attachment listed under `path` was kept under `renamed` per
+        /// [`DuplicatePolicy::Rename`].
+        DuplicateRenamed {
+            path: LogicalPath,
+            renamed: LogicalPath,
+        },
+    }
+
+    pub struct Reader<'a, R: Read + Seek> {
+        inner: R,
+        format: Format,
+        mode: ReadMode,
+        /// Total source length given to [`Reader::with_len`].
+        len: Option<u64>,
+        progress: Box<dyn FnMut(ReadProgress) + 'a>,
+    }
+
+    impl<'a, R: Read + Seek> Reader<'a, R> {
+        pub fn new(mut inner: R, assumed: Option<Format>, mode: ReadMode) -> TmdResult<Self> {
+            let format = match assumed {
+                Some(format) => format,
+                None => sniff_source(&mut inner)?,
+            };
+            Ok(Self {
+                inner,
+                format,
+                mode,
+                len: None,
+                progress: Box::new(|_| {}),
+            })
+        }
+
+        /// Like [`Reader::new`] for a source whose total length is known up front, such as
+        /// a remote object read through range requests.
+        ///
+        /// The source only ever sees absolute seeks: positions relative to the end are
+        /// resolved against `len`. Use [`Reader::into_doc`] to read without buffering the
+        /// whole source.
+        pub fn with_len(
+            inner: R,
+            len: u64,
+            assumed: Option<Format>,
+            mode: ReadMode,
+        ) -> TmdResult<Self> {
+            let mut source = KnownLen::new(inner, len)?;
+            let format = match assumed {
+                Some(format) => format,
+                None => sniff_source(&mut source)?,
+            };
+            Ok(Self {
+                inner: source.inner,
+                format,
+                mode,
+                len: Some(len),
+                progress: Box::new(|_| {}),
+            })
+        }
+
+        /// The container format being read, as given or sniffed.
+        pub fn format(&self) -> Format {
+            self.format
+        }
+
+        /// Call `progress` for each problem worked around while reading, such as
+        /// duplicate attachments skipped or renamed per [`ReadMode::on_duplicate_path`].
+        pub fn on_progress(mut self, progress: impl FnMut(ReadProgress) + 'a) -> Self {
+            self.progress = Box::new(progress);
+            self
+        }
+
+        pub fn read_doc(&mut self) -> TmdResult<TmdDoc> {
+            let bytes = read_source(&mut self.inner)?;
+            match self.format {
+                Format::Tmd => read_tmd_bytes(&bytes, self.mode, &mut self.progress),
+                Format::Tmdz => read_tmdz_bytes(bytes, self.mode, &mut self.progress),
+            }
+        }
+
+        /// Consume the reader and parse the document.
+        ///
+        /// With a length from [`Reader::with_len`], only the trailer, the central
+        /// directory, and the entries needed are read, seeking past everything else; with
+        /// [`ReadMode::lazy_attachments`] the source is kept in the document's
+        /// [`LazyBackend`] so attachments are fetched only when requested. Without a
+        /// length this is [`Reader::read_doc`].
+        pub fn into_doc(mut self) -> TmdResult<TmdDoc>
+        where
+            R: Send + 'static,
+        {
+            match self.len {
+                Some(len) => read_seekable(
+                    KnownLen::new(self.inner, len)?,
+                    self.format,
+                    self.mode,
+                    &mut self.progress,
+                ),
+                None => self.read_doc(),
+            }
+        }
+    }
+
+    /// Sniff the format of a seekable source, leaving it rewound.
+    fn sniff_source<R: Read + Seek>(inner: &mut R) -> TmdResult<Format> {
+        let mut header = [0u8; 8];
+        let read = inner.read(&mut header)?;
+        let mut format = sniff_format(&header[..read])
+            .ok_or_else(|| TmdError::InvalidFormat("unable to sniff format".into()))?;
+        // An empty markdown section leaves a `.tmd` starting with the ZIP header.
+        if format == Format::Tmdz {
+            let len = inner.seek(SeekFrom::End(0))?;
+            if len >= TMD_TRAILER_LEN as u64 {
+                let mut tail = [0u8; TMD_TRAILER_LEN];
+                inner.seek(SeekFrom::End(-(TMD_TRAILER_LEN as i64)))?;
+                inner.read_exact(&mut tail)?;
+                if has_tmd_trailer(&tail) {
+                    format = Format::Tmd;
+                }
+            }
+        }
+        inner.seek(SeekFrom::Start(0))?;
+        Ok(format)
+    }
+
+    /// A source of known length that resolves end- and current-relative seeks itself,
+    /// passing only absolute seeks to the inner reader.
+    struct KnownLen<R> {
+        inner: R,
+        len: u64,
+        pos: u64,
+    }
+
+    impl<R: Read + Seek> KnownLen<R> {
+        fn new(mut inner: R, len: u64) -> TmdResult<Self> {
+            inner.seek(SeekFrom::Start(0))?;
+            Ok(Self { inner, len, pos: 0 })
+        }
+    }
+
+    impl<R: Read> Read for KnownLen<R> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = self.inner.read(buf)?;
+            self.pos += n as u64;
+            Ok(n)
+        }
+    }
+
+    impl<R: Seek> Seek for KnownLen<R> {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            let target = match pos {
+                SeekFrom::Start(offset) => Some(offset),
+                SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+                SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+            }
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "seek to a negative position",
+                )
+            })?;
+            self.pos = self.inner.seek(SeekFrom::Start(target))?;
+            Ok(self.pos)
+        }
+    }
+
+    /// Parse a document through its ZIP index instead of buffering the whole source.
+    ///
+    /// Used for lazy reads and sources of known length; `.tmdz` trailing bytes are not
+    /// checked since the source is never read to the end.
+    fn read_seekable<R: Read + Seek + Send + 'static>(
+        mut source: R,
+        format: Format,
+        mode: ReadMode,
+        progress: &mut dyn FnMut(ReadProgress),
+    ) -> TmdResult<TmdDoc> {
+        let prefix = match format {
+            Format::Tmd => read_markdown_prefix(&mut source)?,
+            Format::Tmdz => None,
+        };
+        // The zip reader tolerates the markdown prefix of a `.tmd` file.
+        let source: Box<dyn LazySource> = Box::new(std::io::BufReader::new(source));
+        let mut zip = ZipArchive::new(source)?;
+        let mut entries = HashMap::new();
+        let lazy_entries = mode.lazy_attachments.then_some(&mut entries);
+        let mut doc = read_doc_from_zip(&mut zip, mode, lazy_entries, progress)?;
+        if let Some(markdown) = prefix.filter(|markdown| !markdown.is_empty()) {
+            doc.markdown = markdown;
+        }
+        check_content_checksum(&doc, mode)?;
+        if mode.lazy_attachments {
+            log::debug!("{} attachments left in the source", entries.len());
+            doc.lazy = Some(LazyBackend {
+                archive: Mutex::new(zip),
+                entries,
+                verify_hashes: mode.verify_hashes,
+                source: None,
+            });
+        }
+        Ok(doc)
+    }
+
+    pub struct Writer<'a, W: Write + Seek> {
+        inner: W,
+        format: Format,
+        mode: WriteMode,
+        _marker: std::marker::PhantomData<&'a ()>,
+    }
+
+    impl<'a, W: Write + Seek> Writer<'a, W> {
+        pub fn new(inner: W, format: Format, mode: WriteMode) -> TmdResult<Self> {
+            Ok(Self {
+                inner,
+                format,
+                mode,
+                _marker: std::marker::PhantomData,
+            })
+        }
+
+        pub fn write_doc(&mut self, doc: &TmdDoc) -> TmdResult<()> {
+            match self.format {
+                Format::Tmd => write_tmd(&mut self.inner, doc, self.mode.clone()),
+                Format::Tmdz => write_tmdz(&mut self.inner, doc, self.mode.clone()),
+            }
+        }
+
+        /// Flush buffered output, propagating any I/O error, and hand back the inner writer.
+        pub fn finish(mut self) -> TmdResult<W> {
+            self.inner.flush()?;
+            Ok(self.inner)
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct AttachmentManifest {
+        attachments: Vec<AttachmentMeta>,
+    }
+
+    fn find_eocd_offset(data: &[u8]) -> TmdResult<usize> {
+        let min_len = 22;
+        if data.len() < min_len {
+            return Err(TmdError::InvalidFormat(
+                "input too small to contain EOCD".into(),
+            ));
+        }
+        let search_start = if data.len() > MAX_COMMENT_SEARCH {
+            data.len() - MAX_COMMENT_SEARCH
+        } else {
+            0
+        };
+
+        for idx in (search_start..=data.len() - min_len).rev() {
+            if data[idx..idx + 4] == EOCD_SIGNATURE {
+                log::debug!("found ZIP EOCD at offset {} of {}", idx, data.len());
+                return Ok(idx);
+            }
+        }
+        log::warn!(
+            "no ZIP EOCD signature in the last {} bytes",
+            data.len() - search_start
+        );
+
+        Err(TmdError::InvalidFormat(
+            "ZIP EOCD signature not found".into(),
+        ))
+    }
+
+    /// Number of bytes after the ZIP end-of-central-directory record and its comment.
+    ///
+    /// `ZipArchive` ignores such a tail, so it is only visible by comparing the archive
+    /// end recorded in the EOCD against the buffer length.
+    fn trailing_len(data: &[u8]) -> TmdResult<usize> {
+        let eocd_offset = find_eocd_offset(data)?;
+        let comment_len =
+            u16::from_le_bytes([data[eocd_offset + 20], data[eocd_offset + 21]]) as usize;
+        let end = eocd_offset + 22 + comment_len;
+        data.len()
+            .checked_sub(end)
+            .ok_or_else(|| TmdError::InvalidFormat("EOCD comment length exceeds buffer".into()))
+    }
+
+    /// Fail with a clear error if `comment` marks the payload as encrypted.
+    fn reject_encrypted(comment: &[u8]) -> TmdResult<()> {
+        if comment.starts_with(TMD_COMMENT_PREFIX)
+            && comment[TMD_COMMENT_PREFIX.len()..].starts_with(ENCRYPTED_MARKER)
+        {
+            return Err(TmdError::InvalidFormat(
+                "document is encrypted; a passphrase is required to open it".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn extract_markdown_len_from_comment(comment: &[u8]) -> TmdResult<u64> {
+        reject_encrypted(comment)?;
+        if !has_tmd_trailer(comment) {
+            return Err(TmdError::InvalidFormat(
+                "missing TMD comment signature".into(),
+            ));
+        }
+        let mut len_bytes = [0u8; 8];
+        len_bytes.copy_from_slice(&comment[comment.len() - 8..]);
+        Ok(u64::from_le_bytes(len_bytes))
+    }
+
+    /// Convert a length or offset read from a file to `usize`, failing instead of
+    /// truncating where `usize` is narrower than 64 bits.
+    pub(crate) fn checked_len(value: u64, what: &str) -> TmdResult<usize> {
+        usize::try_from(value).map_err(|_| {
+            TmdError::InvalidFormat(format!(
+                "{} {} exceeds the addressable range of this platform",
+                what, value
+            ))
+        })
+    }
+
+    fn split_tmd_bytes(bytes: &[u8]) -> TmdResult<(&[u8], &[u8])> {
+        let eocd_offset = find_eocd_offset(bytes)?;
+        let comment_start = eocd_offset
+            .checked_add(22)
+            .filter(|&end| end <= bytes.len())
+            .ok_or_else(|| TmdError::InvalidFormat("EOCD extends past end of buffer".into()))?;
+        let comment_len_start = eocd_offset + 20;
+        let comment_len =
+            u16::from_le_bytes([bytes[comment_len_start], bytes[comment_len_start + 1]]) as usize;
+        let comment = comment_start
+            .checked_add(comment_len)
+            .and_then(|end| bytes.get(comment_start..end))
+            .ok_or_else(|| TmdError::InvalidFormat("EOCD comment length exceeds buffer".into()))?;
+        let markdown_len = checked_len(
+            extract_markdown_len_from_comment(comment)?,
+            "markdown length",
+        )?;
+        if markdown_len > bytes.len() {
+            return Err(TmdError::InvalidFormat(
+                "markdown length exceeds buffer".into(),
+            ));
+        }
+        let (markdown, zip_bytes) = bytes.split_at(markdown_len);
+        if !zip_bytes.starts_with(&LOCAL_FILE_SIGNATURE) && !zip_bytes.starts_with(&EOCD_SIGNATURE)
+        {
+            return Err(TmdError::InvalidFormat(
+                "markdown length points outside ZIP payload".into(),
+            ));
+        }
+        log::debug!(
+            "split .tmd into {} markdown bytes and {} ZIP bytes",
+            markdown.len(),
+            zip_bytes.len()
+        );
+        Ok((markdown, zip_bytes))
+    }
+
+    /// Structural diagnostics for a file that may or may not be a valid document.
+    #[derive(Clone, Debug, Default, PartialEq, Eq)]
+    pub struct ProbeReport {
+        /// Format guessed from the leading bytes and trailing marker.
+        pub format: Option<Format>,
+        pub size: u64,
+        /// Offset of the ZIP end-of-central-directory record.
+        pub eocd_offset: Option<u64>,
+        /// Length of the ZIP comment, including the `.tmd` marker if present.
+        pub comment_len: Option<usize>,
+        /// Whether the comment ends with the `.tmd` marker.
+        pub has_marker: bool,
+        pub encrypted: bool,
+        /// Markdown length declared by the marker.
+        pub markdown_len: Option<u64>,
+        /// Whether the declared markdown length lands on the start of the ZIP payload.
+        pub markdown_len_valid: bool,
+        pub entry_count: Option<usize>,
+        /// Reserved entries (`manifest.json`, `index.md`, ...) found in the archive.
+        pub reserved_present: Vec<&'static str>,
+        /// Entries written in streaming mode, whose sizes follow the data in a data
+        /// descriptor. Their extents are taken from the central directory.
+        pub streamed_entries: usize,
+        /// Human-readable descriptions of everything found to be wrong.
+        pub problems: Vec<String>,
+    }
+
+    /// Inspect the container structure of `bytes` without parsing attachments or the
+    /// database, reporting where a malformed file goes wrong.
+    pub fn probe(bytes: &[u8]) -> ProbeReport {
+        let mut report = ProbeReport {
+            format: sniff_format_bytes(bytes),
+            size: bytes.len() as u64,
+            ..ProbeReport::default()
+        };
+        let eocd_offset = match find_eocd_offset(bytes) {
+            Ok(offset) => offset,
+            Err(err) => {
+                report.problems.push(err.to_string());
+                return report;
+            }
+        };
+        report.eocd_offset = Some(eocd_offset as u64);
+
+        let comment_len =
+            u16::from_le_bytes([bytes[eocd_offset + 20], bytes[eocd_offset + 21]]) as usize;
+        let comment_start = eocd_offset + 22;
+        let Some(comment) = bytes.get(comment_start..comment_start + comment_len) else {
+            report
+                .problems
+                .push("EOCD comment length exceeds buffer".into());
+            return report;
+        };
+        report.comment_len = Some(comment_len);
+        let trailing = bytes.len() - (comment_start + comment_len);
+        if trailing > 0 {
+            report
+                .problems
+                .push(format!("{} trailing bytes after the ZIP archive", trailing));
+        }
+        report.has_marker = has_tmd_trailer(comment);
+        report.encrypted = comment.starts_with(TMD_COMMENT_PREFIX)
+            && comment[TMD_COMMENT_PREFIX.len()..].starts_with(ENCRYPTED_MARKER);
+
+        let mut zip_bytes = bytes;
+        if report.has_marker && !report.encrypted {
+            let markdown_len = extract_markdown_len_from_comment(comment).unwrap_or_default();
+            report.markdown_len = Some(markdown_len);
+            match usize::try_from(markdown_len)
+                .ok()
+                .and_then(|start| bytes.get(start..))
+            {
+                Some(rest)
+                    if rest.starts_with(&LOCAL_FILE_SIGNATURE)
+                        || rest.starts_with(&EOCD_SIGNATURE) =>
+                {
+                    report.markdown_len_valid = true;
+                    zip_bytes = rest;
+                }
+                _ => report.problems.push(format!(
+                    "declared markdown length {} does not point at the ZIP payload",
+                    markdown_len
+                )),
+            }
+        } else if report.format == Some(Format::Tmd) {
+            report.problems.push(if report.encrypted {
+                "document is encrypted".into()
+            } else {
+                "missing TMD comment signature".into()
+            });
+        }
+        if report.encrypted {
+            return report;
+        }
+
+        match ZipArchive::new(std::io::Cursor::new(zip_bytes)) {
+            Ok(mut archive) => {
+                report.entry_count = Some(archive.len());
+                for index in 0..archive.len() {
+                    let header_start = match archive.by_index_raw(index) {
+                        Ok(file) => file.header_start(),
+                        Err(err) => {
+                            report.problems.push(format!("zip error: {}", err));
+                            continue;
+                        }
+                    };
+                    // General-purpose flags follow the signature and version needed.
+                    let header = usize::try_from(header_start)
+                        .ok()
+                        .and_then(|start| zip_bytes.get(start..start.checked_add(8)?));
+                    match header {
+                        Some(header) if header.starts_with(&LOCAL_FILE_SIGNATURE) => {
+                            if header[6] & 0x08 != 0 {
+                                report.streamed_entries += 1;
+                            }
+                        }
+                        _ => report.problems.push(format!(
+                            "entry {} has no local header at offset {}",
+                            index, header_start
+                        )),
+                    }
+                }
+                let names: Vec<&str> = archive.file_names().collect();
+                report.reserved_present = RESERVED_ENTRIES
+                    .into_iter()
+                    .filter(|name| names.contains(name))
+                    .collect();
+                for name in RESERVED_ENTRIES {
+                    if name != MARKDOWN_ENTRY && !names.contains(&name) {
+                        report.problems.push(format!("missing `{}`", name));
+                    }
+                }
+            }
+            Err(err) => report.problems.push(format!("zip error: {}", err)),
+        }
+        report
+    }
+
+    /// Fail if two central-directory records share a name.
+    ///
+    /// `ZipArchive::by_name` silently resolves such names to one of the entries, so
+    /// which `manifest.json` or attachment wins would depend on directory order.
+    /// Reject archives listing a reserved entry more than once, or any attachment entry
+    /// more than once unless `policy` salvages duplicates.
+    fn reject_duplicate_entries<R: Read + Seek>(
+        zip: &mut ZipArchive<R>,
+        policy: DuplicatePolicy,
+    ) -> TmdResult<()> {
+        let mut seen = HashSet::with_capacity(zip.len());
+        for index in 0..zip.len() {
+            let name = zip.by_index_raw(index)?.name().to_string();
+            let reserved = RESERVED_ENTRIES.contains(&name.as_str());
+            if !seen.insert(name.clone()) && (reserved || policy == DuplicatePolicy::Error) {
+                log::warn!("archive lists `{}` more than once", name);
+                return Err(TmdError::InvalidFormat(format!(
+                    "duplicate entry: {}",
+                    name
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn read_manifest_from_zip<R: Read + Seek>(zip: &mut ZipArchive<R>) -> TmdResult<Manifest> {
+        let mut file = zip.by_name(MANIFEST_ENTRY)?;
+        let mut buf = String::new();
+        file.read_to_string(&mut buf)?;
+        let manifest: Manifest = serde_json::from_str(&buf)?;
+        Ok(manifest)
+    }
+
+    /// The archive entry holding the markdown, honoring `manifest.markdown_path`.
+    pub(crate) fn markdown_entry(manifest: &Manifest) -> TmdResult<&str> {
+        let Some(path) = manifest.markdown_path.as_deref() else {
+            return Ok(MARKDOWN_ENTRY);
+        };
+        if super::normalize_logical_path(path)? != path {
+            return Err(TmdError::InvalidFormat(format!(
+                "markdown_path `{}` is not normalized",
+                path
+            )));
+        }
+        if path != MARKDOWN_ENTRY && RESERVED_ENTRIES.contains(&path) {
+            return Err(TmdError::InvalidFormat(format!(
+                "markdown_path `{}` collides with a reserved entry",
+                path
+            )));
+        }
+        Ok(path)
+    }
+
+    fn read_markdown_from_zip<R: Read + Seek>(
+        zip: &mut ZipArchive<R>,
+        entry: &str,
+    ) -> TmdResult<String> {
+        let mut file = zip.by_name(entry)?;
+        let mut markdown = String::new();
+        file.read_to_string(&mut markdown)?;
+        Ok(markdown)
+    }
+
+    fn read_attachment_manifest<R: Read + Seek>(
+        zip: &mut ZipArchive<R>,
+    ) -> TmdResult<Vec<AttachmentMeta>> {
+        let mut file = zip.by_name(ATTACHMENTS_ENTRY)?;
+        let mut buf = String::new();
+        file.read_to_string(&mut buf)?;
+        let manifest: AttachmentManifest = serde_json::from_str(&buf)?;
+        Ok(manifest.attachments)
+    }
+
+    /// Synthesize metadata for every file entry that is neither reserved nor the
+    /// markdown entry. Entries whose names are not normalized logical paths are skipped.
+    fn infer_attachment_metas<R: Read + Seek>(
+        zip: &mut ZipArchive<R>,
+        markdown_path: &str,
+    ) -> TmdResult<Vec<AttachmentMeta>> {
+        let mut metas = Vec::new();
+        for index in 0..zip.len() {
+            let file = zip.by_index(index)?;
+            let name = file.name().to_string();
+            if file.is_dir() || name == markdown_path || RESERVED_ENTRIES.contains(&name.as_str()) {
+                continue;
+            }
+            if normalize_logical_path(&name).ok().as_deref() != Some(name.as_str()) {
+                log::warn!("skipping entry `{}`: not a valid logical path", name);
+                continue;
+            }
+            let (length, digest) = hash_reader(file, HashAlgorithm::Sha256)?;
+            let mut meta = AttachmentMeta {
+                id: AttachmentId::new_v4(),
+                mime: guess_mime(&name),
+                logical_path: name,
+                length,
+                sha256: None,
+                title: None,
+                alt: None,
+                compression_hint: None,
+                aliases: Vec::new(),
+                digest: None,
+                created_utc: None,
+                modified_utc: None,
+                extras: serde_json::Value::default(),
+            };
+            meta.set_content_digest(HashAlgorithm::Sha256, digest);
+            log::debug!(
+                "inferred attachment `{}` ({})",
+                meta.logical_path,
+                meta.mime
+            );
+            metas.push(meta);
+        }
+        Ok(metas)
+    }
+
+    fn read_db_from_zip<R: Read + Seek>(zip: &mut ZipArchive<R>) -> TmdResult<DbHandle> {
+        let mut file = zip.by_name(DB_ENTRY)?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        db_from_checked_bytes(&bytes)
+    }
+
+    fn db_from_checked_bytes(bytes: &[u8]) -> TmdResult<DbHandle> {
+        if !bytes.starts_with(SQLITE_MAGIC) {
+            return Err(TmdError::InvalidFormat(format!(
+                "{} is not a SQLite database",
+                DB_ENTRY
+            )));
+        }
+        DbHandle::from_bytes(bytes)
+    }
+
+    fn checked_attachment_path(meta: &AttachmentMeta) -> TmdResult<&str> {
+        let normalized = super::normalize_logical_path(&meta.logical_path)?;
+        if normalized != meta.logical_path {
+            return Err(TmdError::Attachment(format!(
+                "attachment path `{}` is not normalized",
+                meta.logical_path
+            )));
+        }
+        if RESERVED_ENTRIES.contains(&meta.logical_path.as_str()) {
+            return Err(TmdError::Attachment(format!(
+                "attachment path `{}` collides with a reserved entry",
+                meta.logical_path
+            )));
+        }
+        Ok(&meta.logical_path)
+    }
+
+    /// Resolve `relative` under `dir` for extraction, creating missing parent
+    /// directories on the way.
+    ///
+    /// Intermediate directories and the target itself must not be symlinks, and the
+    /// canonicalized parent must stay within the canonicalized `dir`, so an
+    /// existing link on disk cannot redirect the write outside `dir`.
+    fn confined_target(dir: &Path, relative: &str) -> TmdResult<PathBuf> {
+        std::fs::create_dir_all(dir)?;
+        let root = dir.canonicalize()?;
+        let escapes = || {
+            TmdError::Attachment(format!(
+                "refusing to write `{relative}` through a symlink outside {}",
+                dir.display()
+            ))
+        };
+
+        let mut segments: Vec<&str> = relative.split('/').collect();
+        let file_name = segments.pop().unwrap_or_default();
+        let mut current = root.clone();
+        for segment in segments {
+            current.push(segment);
+            match std::fs::symlink_metadata(&current) {
+                Ok(meta) if meta.file_type().is_symlink() => return Err(escapes()),
+                Ok(_) => {}
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                    std::fs::create_dir(&current)?;
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+        if !current.canonicalize()?.starts_with(&root) {
+            return Err(escapes());
+        }
+
+        current.push(file_name);
+        if let Ok(meta) = std::fs::symlink_metadata(&current) {
+            if meta.file_type().is_symlink() {
+                return Err(escapes());
+            }
+        }
+        Ok(current)
+    }
+
+    impl TmdDoc {
+        /// Attachment bytes, borrowed when resident or read from the [`LazyBackend`].
+        pub fn attachment_bytes(&self, id: AttachmentId) -> TmdResult<Cow<'_, [u8]>> {
+            if let Some(data) = self.attachments.data(id) {
+                return Ok(Cow::Borrowed(data));
+            }
+            let meta = self
+                .attachments
+                .meta(id)
+                .ok_or_else(|| TmdError::unknown_attachment(id))?;
+            match &self.lazy {
+                Some(lazy) => lazy.read(meta).map(Cow::Owned),
+                None => Err(TmdError::Attachment(format!(
+                    "missing data for attachment {}",
+                    id
+                ))),
+            }
+        }
+
+        /// Check an attachment's bytes against its recorded length and digest.
+        ///
+        /// Attachments still in the [`LazyBackend`] are streamed through the hasher rather
+        /// than read into memory. An attachment without a recorded digest only has its
+        /// length checked.
+        pub fn verify_attachment(&self, id: AttachmentId) -> TmdResult<bool> {
+            let meta = self
+                .attachments
+                .meta(id)
+                .ok_or_else(|| TmdError::unknown_attachment(id))?;
+            let algorithm = match meta.content_digest() {
+                Some((algorithm, _)) if !algorithm.is_supported() => {
+                    return Err(TmdError::Attachment(format!(
+                        "cannot verify {:?} digest of `{}` in this build",
+                        algorithm, meta.logical_path
+                    )));
+                }
+                Some((algorithm, _)) => algorithm,
+                None => HashAlgorithm::Sha256,
+            };
+            let (length, digest) = match (self.attachments.data(id), &self.lazy) {
+                (Some(data), _) => (data.len() as u64, hash_content(algorithm, data)?),
+                (None, Some(lazy)) => lazy.hash(meta, algorithm)?,
+                (None, None) => {
+                    return Err(TmdError::Attachment(format!(
+                        "missing data for attachment {}",
+                        id
+                    )))
+                }
+            };
+            let digest_ok = meta
+                .content_digest()
+                .is_none_or(|(_, expected)| expected == digest.as_slice());
+            Ok(length == meta.length && digest_ok)
+        }
+
+        /// Replace an attachment's bytes only if their current sha256 is `expected_sha`.
+        ///
+        /// Returns `false` without changing anything when the content has moved on, so
+        /// editors can detect a concurrent update instead of clobbering it. The digest is
+        /// computed from the current bytes, so this works whatever [`HashAlgorithm`] the
+        /// attachment records.
+        pub fn replace_attachment_if(
+            &mut self,
+            id: AttachmentId,
+            expected_sha: [u8; 32],
+            new_bytes: Vec<u8>,
+        ) -> TmdResult<bool> {
+            let current = hash_content(HashAlgorithm::Sha256, &self.attachment_bytes(id)?)?;
+            if current != expected_sha {
+                return Ok(false);
+            }
+            // Identical bytes leave the attachment and its timestamps untouched.
+            if hash_content(HashAlgorithm::Sha256, &new_bytes)? == current {
+                return Ok(true);
+            }
+            if !self.attachments.is_resident(id) {
+                self.attachments.set_resident_data(id, Vec::new());
+            }
+            // Dropping the guard recomputes the length and digest.
+            if let Some(mut data) = self.attachments.data_mut(id) {
+                *data = new_bytes;
+            }
+            self.touch();
+            Ok(true)
+        }
+
+        /// Read every non-resident attachment into memory and release the source file,
+        /// returning how many were loaded.
+        pub fn load_attachments(&mut self) -> TmdResult<usize> {
+            let Some(lazy) = &self.lazy else {
+                return Ok(0);
+            };
+            let pending: Vec<AttachmentMeta> = self
+                .attachments
+                .iter()
+                .filter(|meta| !self.attachments.is_resident(meta.id))
+                .cloned()
+                .collect();
+            let mut loaded = Vec::with_capacity(pending.len());
+            for meta in &pending {
+                loaded.push((meta.id, lazy.read(meta)?));
+            }
+            for (id, data) in loaded {
+                self.attachments.set_resident_data(id, data);
+            }
+            self.lazy = None;
+            Ok(pending.len())
+        }
+
+        /// Serialize the document into an in-memory buffer.
+        pub fn to_bytes(&self, format: Format) -> TmdResult<Vec<u8>> {
+            let mut writer = Writer::new(
+                std::io::Cursor::new(Vec::new()),
+                format,
+                WriteMode::default(),
+            )?;
+            writer.write_doc(self)?;
+            Ok(writer.finish()?.into_inner())
+        }
+
+        /// The [`WriteMode`] recorded by the writer that produced this document, if it set
+        /// [`WriteMode::record_mode`].
+        pub fn last_write_mode(&self) -> Option<WriteMode> {
+            let recorded = self.manifest.extras.get(WRITE_MODE_EXTRA)?;
+            match serde_json::from_value(recorded.clone()) {
+                Ok(mode) => Some(mode),
+                Err(err) => {
+                    log::warn!("ignoring unreadable {WRITE_MODE_EXTRA}: {err}");
+                    None
+                }
+            }
+        }
+
+        /// Number of bytes writing the document as `format` with `mode` would produce.
+        ///
+        /// The archive is built in memory and discarded; nothing touches the disk.
+        pub fn serialized_size(&self, format: Format, mode: WriteMode) -> TmdResult<u64> {
+            let (markdown, zip_bytes) = build_sections(self, &mode, format)?;
+            Ok(markdown.len() as u64 + zip_bytes.len() as u64)
+        }
+
+        /// Write the document's parts into `dir`, mirroring the archive layout.
+        ///
+        /// Produces the markdown entry (`index.md` unless overridden), `manifest.json`,
+        /// `attachments.json`, `db/main.sqlite3`, and one file per attachment at its
+        /// logical path.
+        pub fn explode_to_dir(&self, dir: &Path) -> TmdResult<()> {
+            std::fs::write(
+                confined_target(dir, markdown_entry(&self.manifest)?)?,
+                self.markdown.as_bytes(),
+            )?;
+            std::fs::write(
+                confined_target(dir, MANIFEST_ENTRY)?,
+                serde_json::to_vec_pretty(&self.manifest)?,
+            )?;
+
+            let mut attachment_metas: Vec<AttachmentMeta> =
+                self.attachments.iter().cloned().collect();
+            attachment_metas.sort_by(|a, b| a.logical_path.cmp(&b.logical_path));
+            std::fs::write(
+                confined_target(dir, ATTACHMENTS_ENTRY)?,
+                serde_json::to_vec_pretty(&AttachmentManifest {
+                    attachments: attachment_metas.clone(),
+                })?,
+            )?;
+            std::fs::copy(self.db.as_path(), confined_target(dir, DB_ENTRY)?)?;
+
+            for meta in &attachment_metas {
+                let target = confined_target(dir, checked_attachment_path(meta)?)?;
+                std::fs::write(target, self.attachment_bytes(meta.id)?)?;
+            }
+            Ok(())
+        }
+
+        /// Write every attachment whose logical path matches `pattern` into `dir`,
+        /// preserving its directory structure, and return how many were written.
+        ///
+        /// Supported wildcards are `*` (within a segment), `?` (one character), and
+        /// `**` (any number of segments), e.g. `figures/**/*.png`.
+        pub fn export_attachments_matching(&self, pattern: &str, dir: &Path) -> TmdResult<usize> {
+            let mut written = 0;
+            for meta in self.attachments.iter() {
+                if !glob_match(pattern, &meta.logical_path) {
+                    continue;
+                }
+                let target = confined_target(dir, checked_attachment_path(meta)?)?;
+                std::fs::write(target, self.attachment_bytes(meta.id)?)?;
+                written += 1;
+            }
+            Ok(written)
+        }
+
+        /// Reassemble a document from a directory produced by [`TmdDoc::explode_to_dir`].
+        pub fn from_exploded_dir(dir: &Path) -> TmdResult<Self> {
+            let manifest: Manifest =
+                serde_json::from_slice(&std::fs::read(dir.join(MANIFEST_ENTRY))?)?;
+            let markdown = std::fs::read_to_string(dir.join(markdown_entry(&manifest)?))?;
+            let attachment_manifest: AttachmentManifest =
+                serde_json::from_slice(&std::fs::read(dir.join(ATTACHMENTS_ENTRY))?)?;
+
+            let mut attachments = AttachmentStore::new();
+            for meta in attachment_manifest.attachments {
+                let data = std::fs::read(dir.join(checked_attachment_path(&meta)?))?;
+                attachments.insert_entry(meta, data, true)?;
+            }
+
+            let mut db = db_from_checked_bytes(&std::fs::read(dir.join(DB_ENTRY))?)?;
+            db.ensure_initialized(None)?;
+
+            Ok(Self {
+                markdown,
+                manifest,
+                attachments,
+                db,
+                zip_comment: Vec::new(),
+                lazy: None,
+            })
+        }
+
+        /// Represent the whole document, including attachment bytes and the database, as
+        /// JSON. Binary content is base64 encoded.
+        pub fn to_json(&self) -> TmdResult<serde_json::Value> {
+            let mut metas: Vec<&AttachmentMeta> = self.attachments.iter().collect();
+            metas.sort_by(|a, b| a.logical_path.cmp(&b.logical_path));
+            let attachments = metas
+                .into_iter()
+                .map(|meta| {
+                    Ok(JsonAttachment {
+                        meta: meta.clone(),
+                        data_base64: BASE64_STANDARD.encode(self.attachment_bytes(meta.id)?),
+                    })
+                })
+                .collect::<TmdResult<Vec<_>>>()?;
+            let doc = JsonDoc {
+                manifest: self.manifest.clone(),
+                markdown: self.markdown.clone(),
+                attachments,
+                db_base64: BASE64_STANDARD.encode(std::fs::read(self.db.as_path())?),
+            };
+            Ok(serde_json::to_value(doc)?)
+        }
+
+        /// Rebuild a document from the output of [`TmdDoc::to_json`], checking attachment
+        /// lengths and digests as a regular read does.
+        pub fn from_json(value: serde_json::Value) -> TmdResult<Self> {
+            let doc: JsonDoc = serde_json::from_value(value)?;
+            let decode = |what: &str, text: &str| {
+                BASE64_STANDARD.decode(text).map_err(|err| {
+                    TmdError::InvalidFormat(format!("invalid base64 in {}: {}", what, err))
+                })
+            };
+
+            let mut attachments = AttachmentStore::new();
+            for attachment in doc.attachments {
+                let data = decode(&attachment.meta.logical_path, &attachment.data_base64)?;
+                checked_attachment_path(&attachment.meta)?;
+                attachments.insert_entry(attachment.meta, data, true)?;
+            }
+            let mut db = db_from_checked_bytes(&decode(DB_ENTRY, &doc.db_base64)?)?;
+            db.ensure_initialized(None)?;
+
+            Ok(Self {
+                markdown: doc.markdown,
+                manifest: doc.manifest,
+                attachments,
+                db,
+                zip_comment: Vec::new(),
+                lazy: None,
+            })
+        }
+    }
+
+    /// Shape of [`TmdDoc::to_json`] output.
+    #[derive(Serialize, Deserialize)]
+    struct JsonDoc {
+        manifest: Manifest,
+        markdown: String,
+        attachments: Vec<JsonAttachment>,
+        db_base64: String,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct JsonAttachment {
+        meta: AttachmentMeta,
+        data_base64: String,
+    }
+
+    /// Read a document from `zip`. When `lazy_entries` is given, attachment bytes are
+    /// left in the archive and each attachment's entry name is recorded there instead.
+    fn read_doc_from_zip<R: Read + Seek>(
+        zip: &mut ZipArchive<R>,
+        mode: ReadMode,
+        mut lazy_entries: Option<&mut HashMap<AttachmentId, usize>>,
+        progress: &mut dyn FnMut(ReadProgress),
+    ) -> TmdResult<TmdDoc> {
+        reject_encrypted(zip.comment())?;
+        reject_duplicate_entries(zip, mode.on_duplicate_path)?;
+        let manifest = read_manifest_from_zip(zip)?;
+        let markdown_path = markdown_entry(&manifest)?;
+        let markdown = read_markdown_from_zip(zip, markdown_path)?;
+        let attachment_metas =
+            if mode.infer_attachments && !zip.file_names().any(|name| name == ATTACHMENTS_ENTRY) {
+                log::warn!(
+                    "no {} in archive, inferring attachments from entries",
+                    ATTACHMENTS_ENTRY
+                );
+                infer_attachment_metas(zip, markdown_path)?
+            } else {
+                read_attachment_manifest(zip)?
+            };
+
+        log::debug!(
+            "reading {} attachments from {} ZIP entries",
+            attachment_metas.len(),
+            zip.len()
+        );
+        // Entries sharing a name, in archive order, so duplicate listings each get their own.
+        let mut entry_indices: HashMap<String, Vec<usize>> = HashMap::new();
+        for index in 0..zip.len() {
+            let name = zip.by_index_raw(index)?.name().to_string();
+            entry_indices.entry(name).or_default().push(index);
+        }
+        let mut listed: HashMap<LogicalPath, usize> = HashMap::new();
+        let mut attachments = AttachmentStore::new();
+        for mut meta in attachment_metas {
+            let duplicate = attachments.meta_by_path(&meta.logical_path).is_some();
+            if duplicate && mode.on_duplicate_path == DuplicatePolicy::Skip {
+                log::warn!(
+                    "skipping duplicate attachment entry `{}`",
+                    meta.logical_path
+                );
+                progress(ReadProgress::DuplicateSkipped {
+                    path: meta.logical_path.clone(),
+                });
+                continue;
+            }
+            let occurrence = listed.entry(meta.logical_path.clone()).or_default();
+            let entry_index = entry_indices
+                .get(&meta.logical_path)
+                .and_then(|indices| indices.get(*occurrence).or(indices.first()).copied())
+                .ok_or(zip::result::ZipError::FileNotFound)?;
+            *occurrence += 1;
+            let mut file = zip.by_index(entry_index)?;
+            let modified = naive_from_zip_time(file.last_modified());
+            let data = if lazy_entries.is_some() {
+                if file.size() != meta.length && mode.repair_metadata {
+                    log::warn!(
+                        "repairing length of `{}`: manifest={} actual={}",
+                        meta.logical_path,
+                        meta.length,
+                        file.size()
+                    );
+                    meta.length = file.size();
+                } else if file.size() != meta.length {
+                    return Err(TmdError::Attachment(format!(
+                        "attachment `{}` length mismatch: manifest={} actual={}",
+                        meta.logical_path,
+                        meta.length,
+                        file.size()
+                    )));
+                }
+                None
+            } else {
+                let mut data = Vec::new();
+                file.read_to_end(&mut data)?;
+                log::debug!(
+                    "read entry `{}`: {} bytes ({} compressed, {:?})",
+                    meta.logical_path,
+                    data.len(),
+                    file.compressed_size(),
+                    file.compression()
+                );
+                Some(data)
+            };
+            drop(file);
+            if duplicate && mode.on_duplicate_path == DuplicatePolicy::Rename {
+                let renamed = free_suffixed_path(&meta.logical_path, |candidate| {
+                    attachments.meta_by_path(candidate).is_none()
+                })?;
+                log::warn!(
+                    "renaming duplicate attachment entry `{}` to `{}`",
+                    meta.logical_path,
+                    renamed
+                );
+                progress(ReadProgress::DuplicateRenamed {
+                    path: std::mem::replace(&mut meta.logical_path, renamed.clone()),
+                    renamed,
+                });
+            }
+            let id = meta.id;
+            if !mode.verify_hashes && meta.content_digest().is_some() {
+                log::debug!("skipping digest verification of `{}`", meta.logical_path);
+            }
+            if let (Some(data), true) = (&data, mode.repair_metadata) {
+                repair_metadata(&mut meta, data)?;
+            }
+            match (data, lazy_entries.as_deref_mut()) {
+                (Some(data), _) => attachments.insert_entry(meta, data, mode.verify_hashes)?,
+                (None, Some(entries)) => {
+                    attachments.insert_lazy_entry(meta)?;
+                    entries.insert(id, entry_index);
+                }
+                (None, None) => unreachable!("attachment data is read unless lazy"),
+            }
+            attachments.set_archive_modified(id, modified)?;
+        }
+
+        if mode.validate_manifest {
+            manifest.validate(&attachments)?;
+        }
+
+        let mut db = read_db_from_zip(zip)?;
+        db.ensure_initialized(None)?;
+        log::debug!("loaded document {}", manifest.doc_id);
+
+        Ok(TmdDoc {
+            markdown,
+            manifest,
+            attachments,
+            db,
+            zip_comment: user_zip_comment(zip.comment()).to_vec(),
+            lazy: None,
+        })
+    }
+
+    pub fn read_tmd<R: Read + Seek>(reader: &mut R, mode: ReadMode) -> TmdResult<TmdDoc> {
+        read_tmd_bytes(&read_source(reader)?, mode, &mut |_| {})
+    }
+
+    /// Read all of `reader` from its start.
+    fn read_source<R: Read + Seek>(reader: &mut R) -> TmdResult<Vec<u8>> {
+        reader.seek(SeekFrom::Start(0))?;
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn read_tmd_bytes(
+        bytes: &[u8],
+        mode: ReadMode,
+        progress: &mut dyn FnMut(ReadProgress),
+    ) -> TmdResult<TmdDoc> {
+        let (markdown_bytes, zip_bytes) = split_tmd_bytes(bytes)?;
+        let markdown = String::from_utf8(markdown_bytes.to_vec())
+            .map_err(|_| TmdError::InvalidFormat("markdown section is not valid UTF-8".into()))?;
+        let cursor = std::io::Cursor::new(zip_bytes.to_vec());
+        let mut zip = ZipArchive::new(cursor)?;
+        let mut doc = read_doc_from_zip(&mut zip, mode, None, progress)?;
+        // A zero-length prefix means the archive's markdown entry is authoritative.
+        if markdown.is_empty() {
+            log::debug!(
+                "markdown prefix is empty; using `{}`",
+                markdown_entry(&doc.manifest)?
+            );
+        } else {
+            doc.markdown = markdown;
+        }
+        check_content_checksum(&doc, mode)?;
+        Ok(doc)
+    }
+
+    /// Enforce [`ReadMode::verify_checksum`] once the document's markdown is final.
+    fn check_content_checksum(doc: &TmdDoc, mode: ReadMode) -> TmdResult<()> {
+        if !mode.verify_checksum {
+            return Ok(());
+        }
+        match doc.verify_content_checksum() {
+            Some(false) => Err(TmdError::InvalidFormat(
+                "content checksum mismatch: the markdown or attachment set was changed".into(),
+            )),
+            Some(true) => Ok(()),
+            None => {
+                log::debug!("no content checksum recorded; nothing to verify");
+                Ok(())
+            }
+        }
+    }
+
+    pub fn read_tmdz<R: Read + Seek>(reader: &mut R, mode: ReadMode) -> TmdResult<TmdDoc> {
+        read_tmdz_bytes(read_source(reader)?, mode, &mut |_| {})
+    }
+
+    fn read_tmdz_bytes(
+        bytes: Vec<u8>,
+        mode: ReadMode,
+        progress: &mut dyn FnMut(ReadProgress),
+    ) -> TmdResult<TmdDoc> {
+        let trailing = trailing_len(&bytes)?;
+        if trailing > 0 {
+            if mode.strict_trailing {
+                return Err(TmdError::InvalidFormat(format!(
+                    "{} trailing bytes after the ZIP archive",
+                    trailing
+                )));
+            }
+            log::warn!("ignoring {} trailing bytes after the ZIP archive", trailing);
+        }
+        let cursor = std::io::Cursor::new(bytes);
+        let mut zip = ZipArchive::new(cursor)?;
+        let doc = read_doc_from_zip(&mut zip, mode, None, progress)?;
+        check_content_checksum(&doc, mode)?;
+        Ok(doc)
+    }
+
+    /// Read a `.tmd` or `.tmdz` document from a non-seekable stream such as stdin.
+    ///
+    /// The entire input is buffered in memory before parsing, since both containers
+    /// keep their directory at the end of the file. Prefer [`Reader`] over a seekable
+    /// source for large files.
+    pub fn read_tmd_buffered<R: Read>(mut reader: R, mode: ReadMode) -> TmdResult<TmdDoc> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        match sniff_format_bytes(&bytes) {
+            Some(Format::Tmd) => read_tmd_bytes(&bytes, mode, &mut |_| {}),
+            Some(Format::Tmdz) => read_tmdz_bytes(bytes, mode, &mut |_| {}),
+            None => Err(TmdError::InvalidFormat("unable to sniff format".into())),
+        }
+    }
+
+    /// Parse a `.tmd` or `.tmdz` document held in memory, sniffing its format.
+    impl TryFrom<&[u8]> for TmdDoc {
+        type Error = TmdError;
+
+        fn try_from(bytes: &[u8]) -> TmdResult<Self> {
+            match sniff_format_bytes(bytes) {
+                Some(Format::Tmd) => read_tmd_bytes(bytes, ReadMode::default(), &mut |_| {}),
+                Some(Format::Tmdz) => {
+                    read_tmdz_bytes(bytes.to_vec(), ReadMode::default(), &mut |_| {})
+                }
+                None => Err(TmdError::InvalidFormat("unable to sniff format".into())),
+            }
+        }
+    }
+
+    /// Serialize a document as `.tmd`, the canonical in-memory representation.
+    impl TryFrom<&TmdDoc> for Vec<u8> {
+        type Error = TmdError;
+
+        fn try_from(doc: &TmdDoc) -> TmdResult<Self> {
+            doc.to_bytes(Format::Tmd)
+        }
+    }
+
+    /// Inputs up to this size are spooled in memory by [`read_spooled`].
+    const SPOOL_MEMORY_LIMIT: usize = 8 * 1024 * 1024;
+
+    /// Read a document from a non-seekable stream, spooling it to a temporary file once
+    /// it grows past a few megabytes instead of holding it all in memory.
+    ///
+    /// The spool is deleted when parsing finishes.
+    pub fn read_spooled<R: Read>(mut reader: R, mode: ReadMode) -> TmdResult<TmdDoc> {
+        let mut spool = tempfile::SpooledTempFile::new(SPOOL_MEMORY_LIMIT);
+        let copied = std::io::copy(&mut reader, &mut spool)?;
+        log::debug!(
+            "spooled {} bytes ({})",
+            copied,
+            if spool.is_rolled() {
+                "on disk"
+            } else {
+                "in memory"
+            }
+        );
+        spool.seek(SeekFrom::Start(0))?;
+        Reader::new(spool, None, mode)?.read_doc()
+    }
+
+    fn set_tmd_comment(zip_bytes: &mut Vec<u8>, markdown_len: u64) -> TmdResult<()> {
+        let eocd_offset = find_eocd_offset(zip_bytes)?;
+        if eocd_offset + 22 > zip_bytes.len() {
+            return Err(TmdError::InvalidFormat(
+                "EOCD extends past end of ZIP buffer".into(),
+            ));
+        }
+        let comment_len_pos = eocd_offset + 20;
+        let comment_start = eocd_offset + 22;
+        let existing_len =
+            u16::from_le_bytes([zip_bytes[comment_len_pos], zip_bytes[comment_len_pos + 1]])
+                as usize;
+        let existing = zip_bytes
+            .get(comment_start..comment_start + existing_len)
+            .ok_or_else(|| TmdError::InvalidFormat("EOCD comment length exceeds buffer".into()))?;
+        let comment_data = tmd_comment(existing, markdown_len)?;
+        let comment_len_bytes = (comment_data.len() as u16).to_le_bytes();
+        zip_bytes[comment_len_pos] = comment_len_bytes[0];
+        zip_bytes[comment_len_pos + 1] = comment_len_bytes[1];
+        zip_bytes.truncate(comment_start);
+        zip_bytes.extend_from_slice(&comment_data);
+        Ok(())
+    }
+
+    /// The archive comment of a `.tmd` file: any user comment followed by the TMD trailer
+    /// recording `markdown_len`.
+    fn tmd_comment(existing: &[u8], markdown_len: u64) -> TmdResult<Vec<u8>> {
+        let user = user_zip_comment(existing);
+        let mut buf = Vec::with_capacity(user.len() + TMD_TRAILER_LEN);
+        buf.extend_from_slice(user);
+        buf.extend_from_slice(TMD_COMMENT_PREFIX);
+        buf.extend_from_slice(&markdown_len.to_le_bytes());
+        if buf.len() > u16::MAX as usize {
+            return Err(TmdError::InvalidFormat(
+                "TMD comment would exceed ZIP comment limit".into(),
+            ));
+        }
+        Ok(buf)
+    }
+
+    fn naive_from_zip_time(time: zip::DateTime) -> Option<NaiveDateTime> {
+        NaiveDate::from_ymd_opt(time.year().into(), time.month().into(), time.day().into())?
+            .and_hms_opt(
+                time.hour().into(),
+                time.minute().into(),
+                time.second().into(),
+            )
+    }
+
+    fn zip_time_from_naive(time: NaiveDateTime) -> Option<zip::DateTime> {
+        zip::DateTime::from_date_and_time(
+            u16::try_from(time.year()).ok()?,
+            time.month() as u8,
+            time.day() as u8,
+            time.hour() as u8,
+            time.minute() as u8,
+            time.second() as u8,
+        )
+        .ok()
+    }
+
+    /// Attachment ID written for `logical_path` when [`WriteMode::stable_ids`] is set.
+    fn stable_attachment_id(logical_path: &str) -> AttachmentId {
+        AttachmentId::new_v5(&STABLE_ID_NAMESPACE, logical_path.as_bytes())
+    }
+
+    fn json_bytes<T: Serialize>(value: &T, pretty: bool) -> TmdResult<Vec<u8>> {
+        Ok(if pretty {
+            serde_json::to_vec_pretty(value)?
+        } else {
+            serde_json::to_vec(value)?
+        })
+    }
+
+    fn build_zip(doc: &TmdDoc, mode: &WriteMode, format: Format) -> TmdResult<Vec<u8>> {
+        let cursor = std::io::Cursor::new(Vec::new());
+        let comment = doc.zip_comment.clone();
+        let cursor = write_zip(cursor, doc, mode, format, comment, &mut |_| Ok(()))?;
+        Ok(cursor.into_inner())
+    }
+
+    /// Write the archive of `doc` to `out`, calling `checkpoint` with the entry name each
+    /// time an entry, or a [`CHECKPOINT_CHUNK`] slice of an attachment, has been written.
+    fn write_zip<W: Write + Seek>(
+        out: W,
+        doc: &TmdDoc,
+        mode: &WriteMode,
+        format: Format,
+        comment: Vec<u8>,
+        checkpoint: &mut dyn FnMut(&str) -> std::io::Result<()>,
+    ) -> TmdResult<W> {
+        if let Some(limit) = mode.max_total_size {
+            let total = doc.attachments_total_size();
+            if total > limit {
+                return Err(TmdError::InvalidFormat(format!(
+                    "document exceeds configured max size ({} > {} bytes)",
+                    total, limit
+                )));
+            }
+        }
+        let mut writer = ZipWriter::new(out);
+        if !comment.is_empty() {
+            writer.set_raw_comment(comment);
+        }
+        // Entry times come from the document clock rather than the wall clock, so a
+        // fixed clock produces byte-identical archives.
+        let written_at = zip_time_from_naive(now_utc().naive_utc()).unwrap_or_default();
+        let stored = FileOptions::default()
+            .compression_method(CompressionMethod::Stored)
+            .last_modified_time(written_at)
+            .large_file(true);
+        let text = if format == Format::Tmdz && mode.deflate_text {
+            stored.compression_method(CompressionMethod::Deflated)
+        } else {
+            stored
+        };
+
+        let mut attachment_metas: Vec<AttachmentMeta> = doc.attachments.iter().cloned().collect();
+        mode.order_by.sort(&mut attachment_metas, &doc.attachments);
+        // IDs the attachments are stored under in memory, parallel to `attachment_metas`.
+        let source_ids: Vec<AttachmentId> = attachment_metas.iter().map(|meta| meta.id).collect();
+        let mut manifest = doc.manifest.clone();
+        if mode.stable_ids {
+            for meta in &mut attachment_metas {
+                let stable = stable_attachment_id(&meta.logical_path);
+                if let Some(cover) = manifest.cover_image.as_mut() {
+                    if cover.id == meta.id {
+                        cover.id = stable;
+                    }
+                }
+                meta.id = stable;
+                meta.created_utc = None;
+                meta.modified_utc = None;
+            }
+        }
+        manifest.content_checksum = mode
+            .compute_checksum
+            .then(|| content_checksum(&doc.markdown, &attachment_metas));
+        // A mode recorded by an earlier write no longer describes this one.
+        if let Some(extras) = manifest.extras.as_object_mut() {
+            extras.remove(WRITE_MODE_EXTRA);
+        }
+        if mode.record_mode {
+            if manifest.extras.is_null() {
+                manifest.extras = serde_json::Value::Object(Default::default());
+            }
+            match manifest.extras.as_object_mut() {
+                Some(extras) => {
+                    extras.insert(WRITE_MODE_EXTRA.to_string(), serde_json::to_value(mode)?);
+                }
+                None => log::warn!("manifest extras is not an object; write mode not recorded"),
+            }
+        }
+
+        // manifest
+        writer.start_file(MANIFEST_ENTRY, text)?;
+        let manifest_json = json_bytes(&manifest, mode.pretty_json)?;
+        writer.write_all(&manifest_json)?;
+        checkpoint(MANIFEST_ENTRY)?;
+
+        // attachments manifest
+        let attachments_json = json_bytes(
+            &AttachmentManifest {
+                attachments: attachment_metas.clone(),
+            },
+            mode.pretty_json,
+        )?;
+
+        // markdown entry
+        let markdown_path = markdown_entry(&manifest)?;
+        if attachment_metas
+            .iter()
+            .any(|meta| meta.logical_path == markdown_path)
+        {
+            return Err(TmdError::InvalidFormat(format!(
+                "markdown entry `{}` collides with an attachment",
+                markdown_path
+            )));
+        }
+        writer.start_file(markdown_path, text)?;
+        writer.write_all(doc.markdown.as_bytes())?;
+        checkpoint(markdown_path)?;
+
+        writer.start_file(ATTACHMENTS_ENTRY, text)?;
+        writer.write_all(&attachments_json)?;
+        checkpoint(ATTACHMENTS_ENTRY)?;
+
+        // db
+        let db_options = if mode.compress_db {
+            stored.compression_method(CompressionMethod::Deflated)
+        } else {
+            stored
+        };
+        writer.start_file(DB_ENTRY, db_options)?;
+        let db_bytes = std::fs::read(doc.db.as_path())?;
+        writer.write_all(&db_bytes)?;
+        checkpoint(DB_ENTRY)?;
+
+        // attachments data
+        for (meta, &source_id) in attachment_metas.iter().zip(&source_ids) {
+            let data = doc.attachment_bytes(source_id)?;
+            let method = match mode.attachment_compression.or(meta.compression_hint) {
+                Some(CompressionHint::Deflate) => CompressionMethod::Deflated,
+                Some(CompressionHint::Store) | None => CompressionMethod::Stored,
+            };
+            log::debug!(
+                "writing entry `{}`: {} bytes, {:?} (hint {:?})",
+                meta.logical_path,
+                data.len(),
+                method,
+                meta.compression_hint
+            );
+            let mut options = stored.compression_method(method);
+            if mode.preserve_timestamps {
+                if let Some(time) = doc
+                    .attachments
+                    .archive_modified(source_id)
+                    .and_then(zip_time_from_naive)
+                {
+                    options = options.last_modified_time(time);
+                }
+            }
+            writer.start_file(&meta.logical_path, options)?;
+            for chunk in data.chunks(CHECKPOINT_CHUNK) {
+                writer.write_all(chunk)?;
+                checkpoint(&meta.logical_path)?;
+            }
+        }
+
+        Ok(writer.finish()?)
+    }
+
+    /// Presents the tail of a stream, starting at `base`, as a stream of its own, so an
+    /// archive written after a markdown prefix records offsets relative to its own start.
+    struct ArchiveOffset<W> {
+        inner: W,
+        base: u64,
+    }
+
+    impl<W: Write> Write for ArchiveOffset<W> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    impl<W: Seek> Seek for ArchiveOffset<W> {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            let pos = match pos {
+                SeekFrom::Start(offset) => SeekFrom::Start(self.base + offset),
+                other => other,
+            };
+            self.inner.seek(pos)?.checked_sub(self.base).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "seek before start of archive",
+                )
+            })
+        }
+    }
+
+    pub fn write_tmd<W: Write + Seek>(
+        writer: &mut W,
+        doc: &TmdDoc,
+        mode: WriteMode,
+    ) -> TmdResult<()> {
+        let (markdown_bytes, zip_bytes) = build_sections(doc, &mode, Format::Tmd)?;
+        writer.write_all(markdown_bytes)?;
+        writer.write_all(&zip_bytes)?;
+        Ok(())
+    }
+
+    /// Serialize `doc` as its markdown prefix, empty for `.tmdz`, and the archive that
+    /// follows it.
+    fn build_sections<'d>(
+        doc: &'d TmdDoc,
+        mode: &WriteMode,
+        format: Format,
+    ) -> TmdResult<(&'d [u8], Vec<u8>)> {
+        let mut zip_bytes = build_zip(doc, mode, format)?;
+        match format {
+            Format::Tmd => {
+                let markdown_bytes = doc.markdown.as_bytes();
+                let markdown_len = u64::try_from(markdown_bytes.len()).map_err(|_| {
+                    TmdError::InvalidFormat("markdown length exceeds u64 range".into())
+                })?;
+                set_tmd_comment(&mut zip_bytes, markdown_len)?;
+                Ok((markdown_bytes, zip_bytes))
+            }
+            Format::Tmdz => Ok((&[], zip_bytes)),
+        }
+    }
+
+    pub fn write_tmdz<W: Write + Seek>(
+        writer: &mut W,
+        doc: &TmdDoc,
+        mode: WriteMode,
+    ) -> TmdResult<()> {
+        let zip_bytes = build_zip(doc, &mode, Format::Tmdz)?;
+        writer.write_all(&zip_bytes)?;
+        Ok(())
+    }
+
+    pub fn read_from_path(path: impl AsRef<Path>, assumed: Option<Format>) -> TmdResult<TmdDoc> {
+        read_from_path_with_mode(path, assumed, ReadMode::default())
+    }
+
+    /// Read a document from `path` with explicit [`ReadMode`] options.
+    ///
+    /// With [`ReadMode::lazy_attachments`] set, the file stays open in the returned
+    /// document's [`LazyBackend`] and attachment bytes are only read when requested.
+    pub fn read_from_path_with_mode(
+        path: impl AsRef<Path>,
+        assumed: Option<Format>,
+        mode: ReadMode,
+    ) -> TmdResult<TmdDoc> {
+        let file = File::open(path.as_ref())?;
+        if !mode.lazy_attachments {
+            let mut reader = Reader::new(std::io::BufReader::new(file), assumed, mode)?;
+            return reader.read_doc();
+        }
+        // A `.tmd` without its trailer reads like a `.tmdz`, so only skip the prefix
+        // when told to.
+        let format = assumed.unwrap_or(Format::Tmd);
+        let mut doc = read_seekable(file, format, mode, &mut |_| {})?;
+        if let Some(lazy) = doc.lazy.as_mut() {
+            lazy.source = Some(path.as_ref().canonicalize()?);
+        }
+        Ok(doc)
+    }
+
+    /// Read the markdown section of a `.tmd` file using only its trailing marker, or
+    /// `None` if `file` has no marker.
+    fn read_markdown_prefix<R: Read + Seek>(file: &mut R) -> TmdResult<Option<String>> {
+        let len = file.seek(SeekFrom::End(0))?;
+        if len < TMD_TRAILER_LEN as u64 {
+            return Ok(None);
+        }
+        let mut tail = [0u8; TMD_TRAILER_LEN];
+        file.seek(SeekFrom::End(-(TMD_TRAILER_LEN as i64)))?;
+        file.read_exact(&mut tail)?;
+        if !has_tmd_trailer(&tail) {
+            return Ok(None);
+        }
+        let markdown_len = extract_markdown_len_from_comment(&tail)?;
+        if markdown_len > len {
+            return Err(TmdError::InvalidFormat(
+                "markdown length exceeds buffer".into(),
+            ));
+        }
+        let mut markdown = Vec::with_capacity(checked_len(markdown_len, "markdown length")?);
+        file.seek(SeekFrom::Start(0))?;
+        (&mut *file).take(markdown_len).read_to_end(&mut markdown)?;
+        file.seek(SeekFrom::Start(0))?;
+        String::from_utf8(markdown)
+            .map(Some)
+            .map_err(|_| TmdError::InvalidFormat("markdown section is not valid UTF-8".into()))
+    }
+
+    /// A source [`LazyBackend`] can keep open across threads.
+    trait LazySource: Read + Seek + Send {}
+
+    impl<T: Read + Seek + Send> LazySource for T {}
+
+    /// An open source archive that non-resident attachments are read from on demand.
+    pub struct LazyBackend {
+        archive: Mutex<ZipArchive<Box<dyn LazySource>>>,
+        /// Index of the ZIP entry each lazily read attachment is stored under.
+        entries: HashMap<AttachmentId, usize>,
+        verify_hashes: bool,
+        /// Canonical path of the file the archive was opened from, when read from a path.
+        source: Option<PathBuf>,
+    }
+
+    impl std::fmt::Debug for LazyBackend {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("LazyBackend")
+                .field("entries", &self.entries)
+                .field("verify_hashes", &self.verify_hashes)
+                .field("source", &self.source)
+                .finish_non_exhaustive()
+        }
+    }
+
+    impl LazyBackend {
+        /// Follow attachments whose ids were changed by [`AttachmentStore::reassign_ids`].
+        pub(crate) fn remap_ids(&mut self, mapping: &HashMap<AttachmentId, AttachmentId>) {
+            self.entries = std::mem::take(&mut self.entries)
+                .into_iter()
+                .map(|(id, name)| (mapping.get(&id).copied().unwrap_or(id), name))
+                .collect();
+        }
+
+        /// Whether `path` names the file the archive was opened from.
+        fn is_source(&self, path: &Path) -> bool {
+            self.source
+                .as_deref()
+                .is_some_and(|source| path.canonicalize().is_ok_and(|path| path == source))
+        }
+
+        fn read(&self, meta: &AttachmentMeta) -> TmdResult<Vec<u8>> {
+            let index = *self.entries.get(&meta.id).ok_or_else(|| {
+                TmdError::Attachment(format!("missing data for attachment {}", meta.id))
+            })?;
+            let mut archive = self
+                .archive
+                .lock()
+                .map_err(|_| TmdError::Attachment("lazy attachment source is poisoned".into()))?;
+            let mut file = archive.by_index(index)?;
+            // The recorded length is untrusted, so let the buffer grow with the data read.
+            let mut data = Vec::new();
+            file.read_to_end(&mut data)?;
+            log::debug!(
+                "read entry `{}` on demand: {} bytes",
+                file.name(),
+                data.len()
+            );
+            if data.len() as u64 != meta.length {
+                return Err(TmdError::Attachment(format!(
+                    "attachment `{}` length mismatch: manifest={} actual={}",
+                    meta.logical_path,
+                    meta.length,
+                    data.len()
+                )));
+            }
+            if self.verify_hashes {
+                verify_digest(meta, &data)?;
+            }
+            Ok(data)
+        }
+
+        /// Stream the entry for `meta` through a hasher, returning its length and digest.
+        fn hash(
+            &self,
+            meta: &AttachmentMeta,
+            algorithm: HashAlgorithm,
+        ) -> TmdResult<(u64, Vec<u8>)> {
+            let index = *self.entries.get(&meta.id).ok_or_else(|| {
+                TmdError::Attachment(format!("missing data for attachment {}", meta.id))
+            })?;
+            let mut archive = self
+                .archive
+                .lock()
+                .map_err(|_| TmdError::Attachment("lazy attachment source is poisoned".into()))?;
+            let file = archive.by_index(index)?;
+            hash_reader(file, algorithm)
+        }
+    }
+
+    /// Read a single attachment from the document at `path` without parsing the rest.
+    ///
+    /// Only `attachments.json` and the matching entry are decompressed; the markdown,
+    /// database and other attachments are skipped. Aliases resolve to their target.
+    pub fn read_attachment_from_path(
+        path: impl AsRef<Path>,
+        logical_path: &str,
+    ) -> TmdResult<(AttachmentMeta, Vec<u8>)> {
+        let logical_path = normalize_logical_path(logical_path)?;
+        let file = File::open(path.as_ref())?;
+        // The zip reader tolerates the markdown prefix of a `.tmd` file.
+        let mut zip = ZipArchive::new(std::io::BufReader::new(file))?;
+        let meta = read_attachment_manifest(&mut zip)?
+            .into_iter()
+            .find(|meta| meta.logical_path == logical_path || meta.aliases.contains(&logical_path))
+            .ok_or_else(|| TmdError::unknown_path(&logical_path))?;
+        let mut data = Vec::new();
+        zip.by_name(&meta.logical_path)?.read_to_end(&mut data)?;
+        if let Some((algorithm, expected)) = meta.content_digest() {
+            if algorithm.is_supported() && hash_content(algorithm, &data)? != expected {
+                return Err(TmdError::Attachment(format!(
+                    "attachment `{}` {:?} digest mismatch",
+                    meta.logical_path, algorithm
+                )));
+            }
+        }
+        Ok((meta, data))
+    }
+
+    /// Return the `manifest.json` of the document at `path` exactly as stored, without
+    /// deserializing it, for inspecting manifests that fail to parse.
+    pub fn read_raw_manifest(path: impl AsRef<Path>) -> TmdResult<String> {
+        let file = File::open(path.as_ref())?;
+        // The zip reader tolerates the markdown prefix of a `.tmd` file.
+        let mut zip = ZipArchive::new(std::io::BufReader::new(file))?;
+        let mut entry = zip.by_name(MANIFEST_ENTRY)?;
+        let mut bytes = Vec::new();
+        entry.read_to_end(&mut bytes)?;
+        String::from_utf8(bytes).map_err(|err| {
+            TmdError::InvalidFormat(format!("{} is not valid UTF-8: {}", MANIFEST_ENTRY, err))
+        })
+    }
+
+    /// Read only the markdown of the document at `path`.
+    ///
+    /// A `.tmd` prefix is read directly; otherwise just `manifest.json` and the markdown
+    /// entry are decompressed. The database and attachments are never loaded.
+    pub fn read_markdown_from_path(path: impl AsRef<Path>) -> TmdResult<String> {
+        let mut file = File::open(path.as_ref())?;
+        if let Some(markdown) = read_markdown_prefix(&mut file)?.filter(|md| !md.is_empty()) {
+            return Ok(markdown);
+        }
+        let mut zip = ZipArchive::new(std::io::BufReader::new(file))?;
+        let manifest = read_manifest_from_zip(&mut zip)?;
+        read_markdown_from_zip(&mut zip, markdown_entry(&manifest)?)
+    }
+
+    pub fn write_to_path(path: impl AsRef<Path>, doc: &TmdDoc, format: Format) -> TmdResult<()> {
+        write_to_path_with_mode(path, doc, format, WriteMode::default())
+    }
+
+    /// Write a document to `path` with explicit [`WriteMode`] options.
+    ///
+    /// With [`WriteMode::durable`] set, the file is synced to disk before returning.
+    pub fn write_to_path_with_mode(
+        path: impl AsRef<Path>,
+        doc: &TmdDoc,
+        format: Format,
+        mode: WriteMode,
+    ) -> TmdResult<()> {
+        if mode.checkpoint_sync {
+            return write_with_checkpoints(path.as_ref(), doc, format, &mode);
+        }
+        write_file(path.as_ref(), doc, mode.durable, |file| {
+            let mut writer = Writer::new(std::io::BufWriter::new(file), format, mode)?;
+            writer.write_doc(doc)?;
+            writer
+                .finish()?
+                .into_inner()
+                .map_err(|err| TmdError::Io(err.into_error()))
+        })
+    }
+
+    /// Create `path`, fill it with `write`, and `sync_all` it when `durable` is set.
+    ///
+    /// When `path` is the file a lazily read `doc` still reads attachments from, the
+    /// output goes to `<path>.part` and is renamed over it instead: truncating the source
+    /// in place would leave the backend reading stale offsets.
+    fn write_file(
+        path: &Path,
+        doc: &TmdDoc,
+        durable: bool,
+        write: impl FnOnce(File) -> TmdResult<File>,
+    ) -> TmdResult<()> {
+        let in_place = doc.lazy.as_ref().is_some_and(|lazy| lazy.is_source(path));
+        let target = if in_place {
+            part_path(path)
+        } else {
+            path.to_path_buf()
+        };
+        let file = write(File::create(&target)?)?;
+        if durable {
+            file.sync_all()?;
+        }
+        if in_place {
+            file.set_permissions(std::fs::metadata(path)?.permissions())?;
+            drop(file);
+            std::fs::rename(&target, path)?;
+        }
+        Ok(())
+    }
+
+    /// `path` with `.part` appended, where interrupted writes are left.
+    fn part_path(path: &Path) -> PathBuf {
+        let mut part = path.as_os_str().to_owned();
+        part.push(".part");
+        PathBuf::from(part)
+    }
+
+    /// Stream `doc` into `<path>.part`, syncing at section boundaries, then rename it to
+    /// `path`.
+    fn write_with_checkpoints(
+        path: &Path,
+        doc: &TmdDoc,
+        format: Format,
+        mode: &WriteMode,
+    ) -> TmdResult<()> {
+        let (markdown, comment) = match format {
+            Format::Tmd => {
+                let markdown = doc.markdown.as_bytes();
+                let markdown_len = u64::try_from(markdown.len()).map_err(|_| {
+                    TmdError::InvalidFormat("markdown length exceeds u64 range".into())
+                })?;
+                (markdown, tmd_comment(&doc.zip_comment, markdown_len)?)
+            }
+            Format::Tmdz => (&[][..], doc.zip_comment.clone()),
+        };
+        let part = part_path(path);
+
+        let mut file = File::create(&part)?;
+        if !markdown.is_empty() {
+            file.write_all(markdown)?;
+            file.sync_data()?;
+            log::debug!("checkpoint: {} bytes of markdown", markdown.len());
+        }
+        let archive = ArchiveOffset {
+            inner: &file,
+            base: markdown.len() as u64,
+        };
+        write_zip(archive, doc, mode, format, comment, &mut |entry| {
+            file.sync_data()?;
+            log::debug!("checkpoint: `{}`", entry);
+            Ok(())
+        })?;
+        file.sync_all()?;
+        drop(file);
+        std::fs::rename(&part, path)?;
+        Ok(())
+    }
+
+    /// Write a document to a temporary file beside `path` and rename it into place.
+    ///
+    /// Readers never observe a partially written document at `path`.
+    pub fn write_to_path_atomic(
+        path: impl AsRef<Path>,
+        doc: &TmdDoc,
+        format: Format,
+    ) -> TmdResult<()> {
+        let path = path.as_ref();
+        let dir = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let mut temp = tempfile::NamedTempFile::new_in(dir)?;
+        {
+            let mut out = std::io::BufWriter::new(temp.as_file_mut());
+            match format {
+                Format::Tmd => write_tmd(&mut out, doc, WriteMode::default())?,
+                Format::Tmdz => write_tmdz(&mut out, doc, WriteMode::default())?,
+            }
+            out.flush()?;
+        }
+        // The temporary file is created private; keep the mode of the file it replaces.
+        if let Ok(existing) = std::fs::metadata(path) {
+            temp.as_file().set_permissions(existing.permissions())?;
+        }
+        temp.as_file().sync_all()?;
+        temp.persist(path).map_err(|err| TmdError::Io(err.error))?;
+        Ok(())
+    }
+
+    /// Write a document, inferring the format from the path extension.
+    pub fn write_to_path_auto(path: impl AsRef<Path>, doc: &TmdDoc) -> TmdResult<()> {
+        let path = path.as_ref();
+        let format = Format::from_path(path).ok_or_else(|| {
+            TmdError::InvalidFormat(format!(
+                "cannot infer format from `{}`: expected extension .tmd or .tmdz",
+                path.display()
+            ))
+        })?;
+        write_to_path(path, doc, format)
+    }
+
+    /// Serialize `doc` as both `.tmd` and `.tmdz`, write whichever archive is smaller, and
+    /// return the path written and the format chosen.
+    ///
+    /// The `.tmd` markdown prefix and trailer are not counted: they are what keeps the
+    /// file readable as plain markdown, so `.tmdz` is only chosen when compressing the
+    /// archive saves space. The extension for the chosen format is appended to
+    /// `path_stem`, so `notes` becomes `notes.tmd` or `notes.tmdz`. Ties go to `.tmd`.
+    pub fn write_to_path_best(
+        path_stem: impl AsRef<Path>,
+        doc: &TmdDoc,
+        mode: WriteMode,
+    ) -> TmdResult<(PathBuf, Format)> {
+        let tmd = build_sections(doc, &mode, Format::Tmd)?;
+        let tmdz = build_sections(doc, &mode, Format::Tmdz)?;
+        let (format, (markdown, zip_bytes)) = if tmdz.1.len() < tmd.1.len() - TMD_TRAILER_LEN {
+            (Format::Tmdz, tmdz)
+        } else {
+            (Format::Tmd, tmd)
+        };
+        let mut path = path_stem.as_ref().as_os_str().to_owned();
+        path.push(".");
+        path.push(format.extension());
+        let path = PathBuf::from(path);
+        write_file(&path, doc, mode.durable, |mut file| {
+            file.write_all(markdown)?;
+            file.write_all(&zip_bytes)?;
+            Ok(file)
+        })?;
+        Ok((path, format))
+    }
+
+    #[cfg(feature = "crypto")]
+    pub use self::crypto::{read_from_path_encrypted, write_to_path_encrypted};
+
+    /// Passphrase-based encryption of whole documents.
+    ///
+    /// The serialized `.tmd`/`.tmdz` bytes are sealed with AES-256-GCM under a key derived
+    /// by Argon2id, then followed by an empty ZIP end-of-central-directory record whose
+    /// comment carries the `TMD1` prefix, [`ENCRYPTED_MARKER`], the salt, and the nonce.
+    #[cfg(feature = "crypto")]
+    mod crypto {
+        use super::{
+            find_eocd_offset, read_tmd_buffered, Format, ReadMode, WriteMode, Writer,
+            ENCRYPTED_MARKER, EOCD_SIGNATURE, TMD_COMMENT_PREFIX,
+        };
+        use crate::{TmdDoc, TmdError, TmdResult};
+        use aes_gcm::aead::rand_core::RngCore;
+        use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+        use aes_gcm::{Aes256Gcm, Nonce};
+        use std::path::Path;
+
+        const SALT_LEN: usize = 16;
+        const NONCE_LEN: usize = 12;
+        const HEADER_LEN: usize =
+            TMD_COMMENT_PREFIX.len() + ENCRYPTED_MARKER.len() + SALT_LEN + NONCE_LEN;
+
+        fn cipher_for(passphrase: &str, salt: &[u8]) -> TmdResult<Aes256Gcm> {
+            let mut key = [0u8; 32];
+            argon2::Argon2::default()
+                .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+                .map_err(|err| TmdError::Crypto(format!("key derivation failed: {}", err)))?;
+            Ok(Aes256Gcm::new(&key.into()))
+        }
+
+        /// Serialize `doc` as `format`, encrypt it with `passphrase`, and write it to `path`.
+        pub fn write_to_path_encrypted(
+            path: impl AsRef<Path>,
+            doc: &TmdDoc,
+            format: Format,
+            passphrase: &str,
+        ) -> TmdResult<()> {
+            let mut plaintext = std::io::Cursor::new(Vec::new());
+            Writer::new(&mut plaintext, format, WriteMode::default())?.write_doc(doc)?;
+
+            let mut salt = [0u8; SALT_LEN];
+            OsRng.fill_bytes(&mut salt);
+            let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+            let mut header = Vec::with_capacity(HEADER_LEN);
+            header.extend_from_slice(TMD_COMMENT_PREFIX);
+            header.extend_from_slice(ENCRYPTED_MARKER);
+            header.extend_from_slice(&salt);
+            header.extend_from_slice(&nonce);
+
+            let mut out = cipher_for(passphrase, &salt)?
+                .encrypt(
+                    &nonce,
+                    Payload {
+                        msg: plaintext.get_ref(),
+                        aad: &header,
+                    },
+                )
+                .map_err(|_| TmdError::Crypto("encryption failed".into()))?;
+
+            // Empty end-of-central-directory record pointing just past the ciphertext. The
+            // record has no ZIP64 form here, so larger documents cannot be encrypted.
+            let cd_offset = u32::try_from(out.len()).map_err(|_| TmdError::TooLarge {
+                limit: u64::from(u32::MAX),
+            })?;
+            out.extend_from_slice(&EOCD_SIGNATURE);
+            out.extend_from_slice(&[0u8; 12]);
+            out.extend_from_slice(&cd_offset.to_le_bytes());
+            out.extend_from_slice(&(HEADER_LEN as u16).to_le_bytes());
+            out.extend_from_slice(&header);
+            std::fs::write(path, out)?;
+            Ok(())
+        }
+
+        /// Read a document written by [`write_to_path_encrypted`].
+        ///
+        /// A wrong passphrase and a tampered file both fail with [`TmdError::InvalidFormat`].
+        pub fn read_from_path_encrypted(
+            path: impl AsRef<Path>,
+            passphrase: &str,
+        ) -> TmdResult<TmdDoc> {
+            let bytes = std::fs::read(path)?;
+            let eocd_offset = find_eocd_offset(&bytes)?;
+            let header = &bytes[eocd_offset + 22..];
+            let marker_start = TMD_COMMENT_PREFIX.len();
+            let salt_start = marker_start + ENCRYPTED_MARKER.len();
+            if header.len() != HEADER_LEN
+                || !header.starts_with(TMD_COMMENT_PREFIX)
+                || &header[marker_start..salt_start] != ENCRYPTED_MARKER
+            {
+                return Err(TmdError::InvalidFormat("document is not encrypted".into()));
+            }
+            let salt = &header[salt_start..salt_start + SALT_LEN];
+            let nonce = Nonce::from_slice(&header[salt_start + SALT_LEN..]);
+
+            let plaintext = cipher_for(passphrase, salt)?
+                .decrypt(
+                    nonce,
+                    Payload {
+                        msg: &bytes[..eocd_offset],
+                        aad: header,
+                    },
+                )
+                .map_err(|_| {
+                    TmdError::InvalidFormat(
+                        "unable to decrypt document: wrong passphrase or corrupted data".into(),
+                    )
+                })?;
+            read_tmd_buffered(plaintext.as_slice(), ReadMode::default())
+        }
+    }
+}
+
+mod validation {
+    use super::attach::hash_content;
+    use super::format::{markdown_entry, RESERVED_ENTRIES, SQLITE_MAGIC};
+    use super::util::validate_language_tag;
+    use super::{markdown, normalize_logical_path, TmdDoc};
+    use std::collections::BTreeMap;
+    use std::fmt;
+    use std::io::Read;
+
+    /// How serious a [`ValidationIssue`] is.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+    pub enum Severity {
+        Warning,
+        Error,
+    }
+
+    /// Which part of the document a [`ValidationIssue`] concerns.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum IssueCategory {
+        Manifest,
+        Attachment,
+        Reference,
+        Database,
+    }
+
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub struct ValidationIssue {
+        pub severity: Severity,
+        pub category: IssueCategory,
+        pub message: String,
+    }
+
+    impl fmt::Display for ValidationIssue {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let severity = match self.severity {
+                Severity::Warning => "warning",
+                Severity::Error => "error",
+            };
+            let category = match self.category {
+                IssueCategory::Manifest => "manifest",
+                IssueCategory::Attachment => "attachment",
+                IssueCategory::Reference => "reference",
+                IssueCategory::Database => "database",
+            };
+            write!(f, "{} [{}]: {}", severity, category, self.message)
+        }
+    }
+
+    /// Every issue found by [`TmdDoc::validate_full`].
+    #[derive(Clone, Debug, Default, PartialEq, Eq)]
+    pub struct ValidationReport {
+        pub issues: Vec<ValidationIssue>,
+    }
+
+    impl ValidationReport {
+        fn push(&mut self, severity: Severity, category: IssueCategory, message: String) {
+            self.issues.push(ValidationIssue {
+                severity,
+                category,
+                message,
+            });
+        }
+
+        pub fn errors(&self) -> impl Iterator<Item = &ValidationIssue> {
+            self.issues
+                .iter()
+                .filter(|issue| issue.severity == Severity::Error)
+        }
+
+        pub fn warnings(&self) -> impl Iterator<Item = &ValidationIssue> {
+            self.issues
+                .iter()
+                .filter(|issue| issue.severity == Severity::Warning)
+        }
+
+        pub fn has_errors(&self) -> bool {
+            self.errors().next().is_some()
+        }
+    }
+
+    /// Device names Windows refuses as file names, with or without an extension.
+    const WINDOWS_RESERVED_NAMES: [&str; 22] = [
+        "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+        "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+    ];
+    /// Windows' classic `MAX_PATH`, which also bounds many archive tools.
+    const PORTABLE_PATH_LIMIT: usize = 260;
+    /// Longest file name most file systems accept, in bytes.
+    const PORTABLE_COMPONENT_LIMIT: usize = 255;
+
+    /// A logical path that is valid in a document but may not extract cleanly on every
+    /// platform.
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub enum PortabilityWarning {
+        /// Paths that differ only by case and collide on case-insensitive file systems.
+        CaseCollision { paths: Vec<String> },
+        /// A path component is a reserved Windows device name such as `NUL`.
+        ReservedName { path: String, component: String },
+        /// A path component ends with a dot or space, which Windows strips.
+        TrailingDotOrSpace { path: String },
+        /// The path or one of its components exceeds a common length limit.
+        TooLong { path: String, limit: usize },
+    }
+
+    impl fmt::Display for PortabilityWarning {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Self::CaseCollision { paths } => write!(
+                    f,
+                    "paths differ only by case: {}",
+                    paths
+                        .iter()
+                        .map(|path| format!("`{}`", path))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+                Self::ReservedName { path, component } => write!(
+                    f,
+                    "`{}` uses the reserved Windows name `{}`",
+                    path, component
+                ),
+                Self::TrailingDotOrSpace { path } => {
+                    write!(f, "`{}` has a component ending in a dot or space", path)
+                }
+                Self::TooLong { path, limit } => {
+                    write!(f, "`{}` exceeds the {}-byte length limit", path, limit)
+                }
+            }
+        }
+    }
+
+    impl TmdDoc {
+        /// Flag attachment paths that would collide or be rejected when extracted on
+        /// Windows or macOS, even though they are fine in the case-sensitive store.
+        pub fn check_path_portability(&self) -> Vec<PortabilityWarning> {
+            let mut paths: Vec<&str> = self
+                .attachments
+                .iter()
+                .map(|meta| meta.logical_path.as_str())
+                .collect();
+            paths.sort_unstable();
+
+            let mut warnings = Vec::new();
+            let mut by_folded: BTreeMap<String, Vec<String>> = BTreeMap::new();
+            for path in &paths {
+                by_folded
+                    .entry(path.to_lowercase())
+                    .or_default()
+                    .push(path.to_string());
+            }
+            for group in by_folded.into_values() {
+                if group.len() > 1 {
+                    warnings.push(PortabilityWarning::CaseCollision { paths: group });
+                }
+            }
+
+            for path in paths {
+                for component in path.split('/') {
+                    let stem = component.split('.').next().unwrap_or_default();
+                    if WINDOWS_RESERVED_NAMES
+                        .iter()
+                        .any(|name| name.eq_ignore_ascii_case(stem))
+                    {
+                        warnings.push(PortabilityWarning::ReservedName {
+                            path: path.to_string(),
+                            component: component.to_string(),
+                        });
+                    }
+                }
+                if path
+                    .split('/')
+                    .any(|component| component.ends_with('.') || component.ends_with(' '))
+                {
+                    warnings.push(PortabilityWarning::TrailingDotOrSpace {
+                        path: path.to_string(),
+                    });
+                }
+                if path.len() > PORTABLE_PATH_LIMIT {
+                    warnings.push(PortabilityWarning::TooLong {
+                        path: path.to_string(),
+                        limit: PORTABLE_PATH_LIMIT,
+                    });
+                } else if path
+                    .split('/')
+                    .any(|component| component.len() > PORTABLE_COMPONENT_LIMIT)
+                {
+                    warnings.push(PortabilityWarning::TooLong {
+                        path: path.to_string(),
+                        limit: PORTABLE_COMPONENT_LIMIT,
+                    });
+                }
+            }
+            warnings
+        }
+
+        /// Check the whole document and collect every problem instead of stopping at the first.
+        ///
+        /// Covers manifest fields, attachment lengths, hashes, and paths, cover image and
+        /// link references, markdown `attach:` references, duplicate and unreferenced
+        /// attachments, non-portable paths, and the embedded database header and schema
+        /// version.
+        pub fn validate_full(&self) -> ValidationReport {
+            use IssueCategory::*;
+            use Severity::*;
+
+            let mut report = ValidationReport::default();
+            let manifest = &self.manifest;
+
+            if manifest.tmd_version.major != 1 {
+                report.push(
+                    Error,
+                    Manifest,
+                    format!(
+                        "unsupported tmd_version {}.{}.{}",
+                        manifest.tmd_version.major,
+                        manifest.tmd_version.minor,
+                        manifest.tmd_version.patch
+                    ),
+                );
+            }
+            if manifest.modified_utc < manifest.created_utc {
+                report.push(
+                    Warning,
+                    Manifest,
+                    "modified_utc is earlier than created_utc".into(),
+                );
+            }
+            if let Some(language) = &manifest.language {
+                if let Err(err) = validate_language_tag(language) {
+                    report.push(Error, Manifest, err.to_string());
+                }
+            }
+            match markdown_entry(manifest) {
+                Ok(entry) if self.attachments.meta_by_path(entry).is_some() => report.push(
+                    Error,
+                    Manifest,
+                    format!("markdown entry `{}` collides with an attachment", entry),
+                ),
+                Ok(_) => {}
+                Err(err) => report.push(Error, Manifest, err.to_string()),
+            }
+            if manifest.tags.iter().any(|tag| tag.trim().is_empty()) {
+                report.push(Warning, Manifest, "manifest contains an empty tag".into());
+            }
+
+            let mut metas: Vec<_> = self.attachments.iter().collect();
+            metas.sort_by(|a, b| a.logical_path.cmp(&b.logical_path));
+            for meta in metas {
+                let path = &meta.logical_path;
+                let data = match self.attachment_bytes(meta.id) {
+                    Ok(data) => data,
+                    Err(err) => {
+                        report.push(Error, Attachment, err.to_string());
+                        continue;
+                    }
+                };
+                if normalize_logical_path(path).ok().as_ref() != Some(path) {
+                    report.push(
+                        Error,
+                        Attachment,
+                        format!("path `{}` is not normalized", path),
+                    );
+                }
+                if RESERVED_ENTRIES.contains(&path.as_str()) {
+                    report.push(
+                        Error,
+                        Attachment,
+                        format!("path `{}` collides with a reserved entry", path),
+                    );
+                }
+                if meta.length != data.len() as u64 {
+                    report.push(
+                        Error,
+                        Attachment,
+                        format!(
+                            "`{}` length mismatch: manifest={} actual={}",
+                            path,
+                            meta.length,
+                            data.len()
+                        ),
+                    );
+                }
+                match meta.content_digest() {
+                    Some((algorithm, _)) if !algorithm.is_supported() => report.push(
+                        Warning,
+                        Attachment,
+                        format!(
+                            "`{}` has a {:?} digest this build cannot verify",
+                            path, algorithm
+                        ),
+                    ),
+                    Some((algorithm, expected)) => {
+                        if hash_content(algorithm, &data).ok().as_deref() != Some(expected) {
+                            report.push(
+                                Error,
+                                Attachment,
+                                format!("`{}` {:?} digest mismatch", path, algorithm),
+                            );
+                        }
+                    }
+                    None => {
+                        report.push(Warning, Attachment, format!("`{}` has no digest", path));
+                    }
+                }
+            }
+            for group in self.attachments.duplicate_groups() {
+                report.push(
+                    Warning,
+                    Attachment,
+                    format!(
+                        "identical content stored at {} ({} bytes could be saved)",
+                        group
+                            .logical_paths
+                            .iter()
+                            .map(|path| format!("`{}`", path))
+                            .collect::<Vec<_>>()
+                            .join(", "),
+                        group.bytes_saved
+                    ),
+                );
+            }
+            for warning in self.check_path_portability() {
+                report.push(Warning, Attachment, warning.to_string());
+            }
+
+            if let Some(cover) = &manifest.cover_image {
+                if self.attachments.meta(cover.id).is_none() {
+                    report.push(
+                        Error,
+                        Reference,
+                        format!("cover_image refers to unknown attachment {}", cover.id),
+                    );
+                }
+            }
+            for link in &manifest.links {
+                if let Some(path) = markdown::attachment_path_from_url(&link.href) {
+                    if self.attachments.meta_by_path(&path).is_none() {
+                        report.push(
+                            Error,
+                            Reference,
+                            format!(
+                                "link `{}` refers to unknown attachment `{}`",
+                                link.rel, path
+                            ),
+                        );
+                    }
+                }
+            }
+            let usage = self.attachment_usage();
+            for path in &usage.dangling {
+                report.push(
+                    Error,
+                    Reference,
+                    format!("markdown references missing attachment `{}`", path),
+                );
+            }
+            for path in &usage.orphans {
+                report.push(
+                    Warning,
+                    Reference,
+                    format!("attachment `{}` is not referenced", path),
+                );
+            }
+
+            let mut header = [0u8; 16];
+            let header_ok = std::fs::File::open(self.db.as_path())
+                .and_then(|mut file| file.read_exact(&mut header))
+                .map(|()| &header == SQLITE_MAGIC);
+            match header_ok {
+                Ok(true) => match self.db_user_version() {
+                    Ok(user_version) => {
+                        if let Some(expected) = manifest.db_schema_version {
+                            if expected != user_version {
+                                report.push(
+                                    Error,
+                                    Database,
+                                    format!(
+                                        "manifest db_schema_version={} but PRAGMA user_version={}",
+                                        expected, user_version
+                                    ),
+                                );
+                            }
+                        }
+                    }
+                    Err(err) => report.push(Error, Database, err.to_string()),
+                },
+                Ok(false) => {
+                    report.push(Error, Database, "database is not a SQLite file".into());
+                }
+                Err(err) => report.push(
+                    Error,
+                    Database,
+                    format!("cannot read database header: {}", err),
+                ),
+            }
+
+            report
+        }
+    }
+}
+
+mod merge {
+    use super::format::DuplicatePolicy;
+    use super::markdown::{self, ATTACH_SCHEME};
+    use super::util::{free_suffixed_path, quote_ident};
+    use super::{AttachmentId, LogicalPath, TmdDoc, TmdError, TmdResult};
+    use std::collections::{BTreeMap, HashSet};
+    use uuid::Uuid;
+
+    /// What [`TmdDoc::merge`] does with the other document's database.
+    #[derive(Clone, Debug, Default, PartialEq, Eq)]
+    pub enum DbMerge {
+        /// Leave the database untouched; the other document's tables are reported as dropped.
+        #[default]
+        Skip,
+        /// Copy each table of the other database as `<prefix><name>`. Rows and column
+        /// types are copied; indexes, triggers, and constraints are not.
+        Prefix(String),
+    }
+
+    /// Options for [`TmdDoc::merge`].
+    #[derive(Clone, Debug)]
+    pub struct MergeOptions {
+        /// Inserted between the two markdown bodies.
+        pub separator: String,
+        /// Handling of attachments whose logical path already exists. `Rename` appends a
+        /// numeric suffix and rewrites the merged markdown's links to match.
+        pub on_collision: DuplicatePolicy,
+        pub db: DbMerge,
+    }
+
+    impl Default for MergeOptions {
+        fn default() -> Self {
+            Self {
+                separator: "\n\n".into(),
+                on_collision: DuplicatePolicy::Rename,
+                db: DbMerge::Skip,
+            }
+        }
+    }
+
+    /// What [`TmdDoc::merge`] changed beyond a plain append.
+    #[derive(Clone, Debug, Default, PartialEq, Eq)]
+    pub struct MergeReport {
+        /// Attachments stored under a new path, as `(original, renamed)`.
+        pub renamed: Vec<(LogicalPath, LogicalPath)>,
+        /// Human-readable descriptions of content that was not carried over.
+        pub dropped: Vec<String>,
+        /// Tables copied from the other database, under their new names.
+        pub tables: Vec<String>,
+    }
+
+    impl TmdDoc {
+        /// Append `other` to this document.
+        ///
+        /// The markdown is joined with [`MergeOptions::separator`], attachments are
+        /// imported according to [`MergeOptions::on_collision`], tags and authors are
+        /// unioned, and the database is handled per [`MergeOptions::db`]. The other
+        /// document's title, cover image, links, and extras are not carried over.
+        ///
+        /// Collisions are resolved before anything is modified, and attachments inserted
+        /// or tables copied before a later failure are removed again, so an error leaves
+        /// this document unchanged.
+        pub fn merge(
+            &mut self,
+            mut other: TmdDoc,
+            options: MergeOptions,
+        ) -> TmdResult<MergeReport> {
+            let mut report = MergeReport::default();
+            other.load_attachments()?;
+
+            // Plan where each incoming attachment lands.
+            let mut metas: Vec<_> = other.attachments.iter().cloned().collect();
+            metas.sort_by(|a, b| a.logical_path.cmp(&b.logical_path));
+            let mut taken: HashSet<LogicalPath> = HashSet::new();
+            let mut targets: BTreeMap<AttachmentId, LogicalPath> = BTreeMap::new();
+            for meta in &metas {
+                let free = |path: &str| {
+                    self.attachments.meta_by_path(path).is_none() && !taken.contains(path)
+                };
+                let target = if free(&meta.logical_path) {
+                    meta.logical_path.clone()
+                } else {
+                    match options.on_collision {
+                        DuplicatePolicy::Error => {
+                            return Err(TmdError::Attachment(format!(
+                                "attachment `{}` already exists",
+                                meta.logical_path
+                            )));
+                        }
+                        DuplicatePolicy::Skip => {
+                            report.dropped.push(format!(
+                                "attachment `{}` (path already exists)",
+                                meta.logical_path
+                            ));
+                            continue;
+                        }
+                        DuplicatePolicy::Rename => {
+                            let renamed = free_suffixed_path(&meta.logical_path, free)?;
+                            report
+                                .renamed
+                                .push((meta.logical_path.clone(), renamed.clone()));
+                            renamed
+                        }
+                    }
+                };
+                taken.insert(target.clone());
+                targets.insert(meta.id, target);
+            }
+
+            // Point the incoming markdown at the renamed attachments.
+            let mut incoming = other.markdown.clone();
+            if !report.renamed.is_empty() {
+                let renames: BTreeMap<_, _> = report.renamed.iter().cloned().collect();
+                for (range, url) in markdown::link_destinations(&other.markdown)
+                    .into_iter()
+                    .rev()
+                {
+                    let Some(path) = markdown::attachment_path_from_url(&url) else {
+                        continue;
+                    };
+                    if let Some(new_path) = renames.get(&path) {
+                        let replacement = if url.starts_with(ATTACH_SCHEME) {
+                            format!("{}{}", ATTACH_SCHEME, new_path)
+                        } else {
+                            new_path.clone()
+                        };
+                        incoming.replace_range(range, &replacement);
+                    }
+                }
+            }
+
+            // Attachments go in first and the table copy is a single transaction, so a
+            // failure in either only has the inserted attachments to undo.
+            let entries = std::mem::take(&mut other.attachments).into_entries()?;
+            let mut inserted = Vec::new();
+            let imported = (|| -> TmdResult<()> {
+                for (mut meta, data) in entries {
+                    let Some(target) = targets.remove(&meta.id) else {
+                        continue;
+                    };
+                    meta.aliases.retain(|alias| {
+                        let free = self.attachments.meta_by_path(alias).is_none()
+                            && !taken.contains(alias);
+                        if !free {
+                            report
+                                .dropped
+                                .push(format!("alias `{}` of `{}`", alias, meta.logical_path));
+                        }
+                        free
+                    });
+                    taken.extend(meta.aliases.iter().cloned());
+                    if self.attachments.meta(meta.id).is_some() {
+                        meta.id = Uuid::new_v4();
+                    }
+                    meta.logical_path = target;
+                    let id = meta.id;
+                    self.attachments.insert_entry(meta, data, false)?;
+                    inserted.push(id);
+                }
+
+                if let DbMerge::Prefix(prefix) = &options.db {
+                    report.tables = self.copy_tables_from(&other, prefix)?;
+                } else {
+                    let tables = other.user_tables()?;
+                    if !tables.is_empty() {
+                        report
+                            .dropped
+                            .push(format!("database tables: {}", tables.join(", ")));
+                    }
+                }
+                Ok(())
+            })();
+            if let Err(err) = imported {
+                for id in inserted {
+                    self.attachments.remove(id)?;
+                }
+                return Err(err);
+            }
+
+            for tag in &other.manifest.tags {
+                self.add_tag(tag);
+            }
+            for author in &other.manifest.authors {
+                if !self.manifest.authors.contains(author) {
+                    self.manifest.authors.push(author.clone());
+                }
+            }
+            if let Some(title) = &other.manifest.title {
+                report.dropped.push(format!("title `{}`", title));
+            }
+            if other.manifest.cover_image.is_some() {
+                report.dropped.push("cover image".into());
+            }
+            if !other.manifest.links.is_empty() {
+                report
+                    .dropped
+                    .push(format!("{} manifest link(s)", other.manifest.links.len()));
+            }
+
+            if !self.markdown.is_empty() && !incoming.is_empty() {
+                self.markdown.push_str(&options.separator);
+            }
+            self.markdown.push_str(&incoming);
+            self.touch();
+            Ok(report)
+        }
+
+        fn user_tables(&self) -> TmdResult<Vec<String>> {
+            self.db
+                .with_conn(|conn| {
+                    let mut stmt = conn.prepare(
+                        "SELECT name FROM sqlite_master
+                         WHERE type = 'table' AND name NOT LIKE 'sqlite\\_%' ESCAPE '\\'
+                         ORDER BY rowid",
+                    )?;
+                    let names = stmt.query_map([], |row| row.get::<_, String>(0))?;
+                    names.collect::<rusqlite::Result<Vec<_>>>()
+                })?
+                .map_err(TmdError::from)
+        }
+
+        fn copy_tables_from(&mut self, other: &TmdDoc, prefix: &str) -> TmdResult<Vec<String>> {
+            let tables = other.user_tables()?;
+            let source = other.db.as_path().to_string_lossy().into_owned();
+            self.db.with_conn_mut(|conn| -> TmdResult<Vec<String>> {
+                // ATTACH cannot run inside a transaction, so the copy is wrapped separately.
+                conn.execute("ATTACH DATABASE ?1 AS merge_source", [&source])?;
+                let copied = (|| {
+                    let tx = conn.transaction()?;
+                    let names = tables
+                        .iter()
+                        .map(|table| {
+                            let name = format!("{}{}", prefix, table);
+                            tx.execute_batch(&format!(
+                                "CREATE TABLE main.{} AS SELECT * FROM merge_source.{};",
+                                quote_ident(&name),
+                                quote_ident(table)
+                            ))?;
+                            Ok(name)
+                        })
+                        .collect::<rusqlite::Result<Vec<_>>>()?;
+                    // Dropping an uncommitted transaction rolls it back.
+                    tx.commit()?;
+                    Ok::<_, rusqlite::Error>(names)
+                })();
+                conn.execute_batch("DETACH DATABASE merge_source;")?;
+                Ok(copied?)
+            })?
+        }
+    }
+}
+
+#[cfg(feature = "image")]
+mod thumbnail {
+    use super::{AttachmentId, TmdDoc, TmdError, TmdResult};
+    use image::{imageops::FilterType, ImageFormat};
+    use std::io::Cursor;
+
+    const THUMBNAIL_DIR: &str = "thumbnails";
+
+    fn image_format(mime: &mime::Mime) -> Option<ImageFormat> {
+        if mime.type_() != mime::IMAGE {
+            return None;
+        }
+        match mime.subtype().as_str() {
+            "png" => Some(ImageFormat::Png),
+            "jpeg" | "jpg" => Some(ImageFormat::Jpeg),
+            _ => None,
+        }
+    }
+
+    impl TmdDoc {
+        /// Decode a PNG or JPEG attachment and return a PNG scaled to fit within `max_dim`.
+        ///
+        /// Images already within the bound are re-encoded without resizing.
+        pub fn generate_thumbnail(&self, id: AttachmentId, max_dim: u32) -> TmdResult<Vec<u8>> {
+            if max_dim == 0 {
+                return Err(TmdError::Attachment(
+                    "thumbnail size must be greater than zero".into(),
+                ));
+            }
+            let meta = self
+                .attachments
+                .meta(id)
+                .ok_or_else(|| TmdError::unknown_attachment(id))?;
+            let format = image_format(&meta.mime).ok_or_else(|| {
+                TmdError::Attachment(format!(
+                    "attachment `{}` has unsupported image type {}",
+                    meta.logical_path, meta.mime
+                ))
+            })?;
+            let data = self.attachment_bytes(id)?;
+
+            let decoded = image::load_from_memory_with_format(&data, format).map_err(|e| {
+                TmdError::Attachment(format!("failed to decode `{}`: {}", meta.logical_path, e))
+            })?;
+            let scaled = if decoded.width() > max_dim || decoded.height() > max_dim {
+                decoded.resize(max_dim, max_dim, FilterType::Triangle)
+            } else {
+                decoded
+            };
+
+            let mut out = Cursor::new(Vec::new());
+            scaled
+                .write_to(&mut out, ImageFormat::Png)
+                .map_err(|e| TmdError::Attachment(format!("failed to encode thumbnail: {}", e)))?;
+            Ok(out.into_inner())
+        }
+
+        /// Generate a thumbnail and store it as `thumbnails/<logical path>.png`.
+        ///
+        /// An existing thumbnail at that path is replaced.
+        pub fn cache_thumbnail(
+            &mut self,
+            id: AttachmentId,
+            max_dim: u32,
+        ) -> TmdResult<AttachmentId> {
+            let bytes = self.generate_thumbnail(id, max_dim)?;
+            let source_path = self
+                .attachments
+                .meta(id)
+                .map(|meta| meta.logical_path.clone())
+                .ok_or_else(|| TmdError::unknown_attachment(id))?;
+            let thumb_path = format!("{}/{}.png", THUMBNAIL_DIR, source_path);
+            if let Some(existing) = self.attachments.meta_by_path(&thumb_path).map(|m| m.id) {
+                self.remove_attachment(existing)?;
+            }
+            self.add_attachment(&thumb_path, mime::IMAGE_PNG, bytes)
+        }
+    }
+}
+
+#[cfg(feature = "ffi")]
+pub mod ffi {
+    //! C-compatible bindings for `tmd-core` exposed when the `ffi` feature is enabled.
+
+    use super::{read_from_path, write_to_path, Format, TmdDoc, TmdError};
+    use std::cell::RefCell;
+    use std::ffi::{CStr, CString};
+    use std::os::raw::c_char;
+    use std::path::PathBuf;
+    use std::ptr;
+
+    thread_local! {
+        static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+    }
+
+    const NULL_PTR_MESSAGE: &str = "null pointer provided";
+    const INVALID_UTF8_MESSAGE: &str = "input was not valid UTF-8";
+    const INTERIOR_NUL_MESSAGE: &str = "string contained an interior NUL byte";
+
+    fn set_last_error_message<S: Into<String>>(message: S) {
+        let message = message.into();
+        let c_string =
+            CString::new(message).unwrap_or_else(|_| CString::new(INTERIOR_NUL_MESSAGE).unwrap());
+        LAST_ERROR.with(|slot| {
+            *slot.borrow_mut() = Some(c_string);
+        });
+    }
+
+    fn set_last_error(error: TmdError) {
+        set_last_error_message(error.to_string());
+    }
+
+    fn clear_last_error() {
+        LAST_ERROR.with(|slot| {
+            *slot.borrow_mut() = None;
+        });
+    }
+
+    fn path_from_ptr(ptr: *const c_char) -> Result<PathBuf, String> {
+        if ptr.is_null() {
+            return Err(NULL_PTR_MESSAGE.to_string());
+        }
+        let c_str = unsafe { CStr::from_ptr(ptr) };
+        let utf8 = c_str
+            .to_str()
+            .map_err(|_| INVALID_UTF8_MESSAGE.to_string())?;
+        Ok(PathBuf::from(utf8))
+    }
+
+    fn parse_optional_format(value: i32) -> Result<Option<Format>, String> {
+        match value {
+            0 => Ok(None),
+            1 => Ok(Some(Format::Tmd)),
+            2 => Ok(Some(Format::Tmdz)),
+            other => Err(format!("unknown format value: {}", other)),
+        }
+    }
+
+    fn parse_required_format(value: i32) -> Result<Format, String> {
+        parse_optional_format(value)?
+            .ok_or_else(|| "format must not be Auto when writing".to_string())
+    }
+
+    fn string_from_ptr(ptr: *const c_char) -> Result<String, String> {
+        if ptr.is_null() {
+            return Ok(String::new());
+        }
+        let c_str = unsafe { CStr::from_ptr(ptr) };
+        Ok(c_str
+            .to_str()
+            .map_err(|_| INVALID_UTF8_MESSAGE.to_string())?
+            .to_owned())
+    }
+
+    fn c_string_from_str(value: &str) -> Result<CString, ()> {
+        CString::new(value).map_err(|_| ())
+    }
+
+    /// Retrieve the last error message generated by the FFI layer for the current thread.
+    #[no_mangle]
+    pub extern "C" fn tmd_last_error_message() -> *const c_char {
+        LAST_ERROR.with(|slot| {
+            slot.borrow()
+                .as_ref()
+                .map(|s| s.as_ptr())
+                .unwrap_or(ptr::null())
+        })
+    }
+
+    /// Create a new in-memory document from the provided Markdown string.
+    ///
+    /// # Safety
+    ///
+    /// `markdown` must either be null or point to a valid, NUL-terminated
+    /// UTF-8 string.
+    #[no_mangle]
+    pub unsafe extern "C" fn tmd_doc_new(markdown: *const c_char) -> *mut TmdDoc {
+        let markdown = match string_from_ptr(markdown) {
+            Ok(value) => value,
+            Err(message) => {
+                set_last_error_message(message);
+                return ptr::null_mut();
+            }
+        };
+
+        match TmdDoc::new(markdown) {
+            Ok(doc) => {
+                clear_last_error();
+                Box::into_raw(Box::new(doc))
+            }
+            Err(err) => {
+                set_last_error(err);
+                ptr::null_mut()
+            }
+        }
+    }
+
+    /// Load a document from disk, optionally specifying the expected format.
+    ///
+    /// Pass `0` for automatic format detection, `1` for `.tmd`, and `2` for `.tmdz`.
+    ///
+    /// # Safety
+    ///
+    /// `path` must either be null or point to a valid, NUL-terminated UTF-8
+    /// string representing a filesystem path.
+    #[no_mangle]
+    pub unsafe extern "C" fn tmd_doc_read_from_path(
+        path: *const c_char,
+        format: i32,
+    ) -> *mut TmdDoc {
+        let assumed = match parse_optional_format(format) {
+            Ok(value) => value,
+            Err(message) => {
+                set_last_error_message(message);
+                return ptr::null_mut();
+            }
+        };
+
+        let path_buf = match path_from_ptr(path) {
+            Ok(path) => path,
+            Err(message) => {
+                set_last_error_message(message);
+                return ptr::null_mut();
+            }
+        };
+
+        match read_from_path(&path_buf, assumed) {
+            Ok(doc) => {
+                clear_last_error();
+                Box::into_raw(Box::new(doc))
+            }
+            Err(err) => {
+                set_last_error(err);
+                ptr::null_mut()
+            }
+        }
+    }
+
+    /// Persist the document to disk using the specified format.
+    ///
+    /// Pass `1` for `.tmd` or `2` for `.tmdz`.
+    ///
+    /// # Safety
+    ///
+    /// `doc` must either be null or point to a [`TmdDoc`] previously returned
+    /// by this library. `path` must either be null or point to a valid,
+    /// NUL-terminated UTF-8 string.
+    #[no_mangle]
+    pub unsafe extern "C" fn tmd_doc_write_to_path(
+        doc: *const TmdDoc,
+        path: *const c_char,
+        format: i32,
+    ) -> i32 {
+        if doc.is_null() {
+            set_last_error_message(NULL_PTR_MESSAGE);
+            return -1;
+        }
+
+        let format = match parse_required_format(format) {
+            Ok(value) => value,
+            Err(message) => {
+                set_last_error_message(message);
+                return -1;
+            }
+        };
+
+        let path_buf = match path_from_ptr(path) {
+            Ok(path) => path,
+            Err(message) => {
+                set_last_error_message(message);
+                return -1;
+            }
+        };
+
+        let doc_ref = unsafe { &*doc };
+        match write_to_path(&path_buf, doc_ref, format) {
+            Ok(()) => {
+                clear_last_error();
+                0
+            }
+            Err(err) => {
+                set_last_error(err);
+                -1
+            }
+        }
+    }
+
+    /// Retrieve the Markdown content of the document.
+    ///
+    /// The returned pointer must be released with [`tmd_string_free`].
+    ///
+    /// # Safety
+    ///
+    /// `doc` must either be null or point to a [`TmdDoc`] allocated by this
+    /// library.
+    #[no_mangle]
+    pub unsafe extern "C" fn tmd_doc_get_markdown(doc: *const TmdDoc) -> *mut c_char {
+        if doc.is_null() {
+            set_last_error_message(NULL_PTR_MESSAGE);
+            return ptr::null_mut();
+        }
+
+        let doc_ref = unsafe { &*doc };
+        match c_string_from_str(&doc_ref.markdown) {
+            Ok(markdown) => {
+                clear_last_error();
+                markdown.into_raw()
+            }
+            Err(()) => {
+                set_last_error_message(INTERIOR_NUL_MESSAGE);
+                ptr::null_mut()
+            }
+        }
+    }
+
+    /// Borrow the Markdown content of the document without copying it.
+    ///
+    /// Returns a pointer to the document's UTF-8 bytes and writes their length to
+    /// `out_len`. The buffer is **not** NUL-terminated and is owned by the document:
+    /// it stays valid only until the next call that mutates or frees `doc`, and must
+    /// not be passed to [`tmd_string_free`]. Use [`tmd_doc_get_markdown`] for an
+    /// owned copy. Returns null (and leaves `out_len` untouched) if either pointer
+    /// is null.
+    ///
+    /// # Safety
+    ///
+    /// `doc` must either be null or point to a [`TmdDoc`] allocated by this
+    /// library. `out_len` must either be null or point to writable memory for a
+    /// `usize`.
+    #[no_mangle]
+    pub unsafe extern "C" fn tmd_doc_markdown_ptr(
+        doc: *const TmdDoc,
+        out_len: *mut usize,
+    ) -> *const u8 {
+        if doc.is_null() || out_len.is_null() {
+            set_last_error_message(NULL_PTR_MESSAGE);
+            return ptr::null();
+        }
+
+        let doc_ref = unsafe { &*doc };
+        let bytes = doc_ref.markdown_bytes();
+        unsafe { *out_len = bytes.len() };
+        clear_last_error();
+        bytes.as_ptr()
+    }
+
+    /// Replace the Markdown content of the document.
+    ///
+    /// # Safety
+    ///
+    /// `doc` must either be null or point to a [`TmdDoc`] allocated by this
+    /// library. `markdown` must either be null or point to a valid,
+    /// NUL-terminated UTF-8 string.
+    #[no_mangle]
+    pub unsafe extern "C" fn tmd_doc_set_markdown(
+        doc: *mut TmdDoc,
+        markdown: *const c_char,
+    ) -> i32 {
+        if doc.is_null() {
+            set_last_error_message(NULL_PTR_MESSAGE);
+            return -1;
+        }
+
+        let markdown = match string_from_ptr(markdown) {
+            Ok(value) => value,
+            Err(message) => {
+                set_last_error_message(message);
+                return -1;
+            }
+        };
+
+        let doc_ref = unsafe { &mut *doc };
+        doc_ref.set_markdown(markdown);
+        clear_last_error();
+        0
+    }
+
+    /// Release a document created by the FFI helpers.
+    ///
+    /// # Safety
+    ///
+    /// `doc` must be a pointer previously returned by this library or null.
+    /// Each document must be freed at most once.
+    #[no_mangle]
+    pub unsafe extern "C" fn tmd_doc_free(doc: *mut TmdDoc) {
+        if doc.is_null() {
+            return;
+        }
+        unsafe {
+            drop(Box::from_raw(doc));
+        }
+        clear_last_error();
+    }
+
+    /// Release a string allocated by the FFI helpers.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be a pointer previously returned by this library or null.
+    /// Each string must be freed at most once.
+    #[no_mangle]
+    pub unsafe extern "C" fn tmd_string_free(ptr: *mut c_char) {
+        if ptr.is_null() {
+            return;
+        }
+        unsafe {
+            drop(CString::from_raw(ptr));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+    use mime::TEXT_PLAIN;
+    use sha2::{Digest, Sha256};
+    use std::io::{Cursor, Read, Seek, SeekFrom};
+    use tempfile::tempdir;
+
+    fn sample_doc() -> TmdDoc {
+        TmdDoc::new("# Sample\n".to_string()).expect("doc creation")
+    }
+
+    #[test]
+    fn attachment_id_strings_round_trip() {
+        let id = Uuid::new_v4();
+        let text = attachment_id_string(id);
+        assert_eq!(text.len(), 36);
+        assert_eq!(text, text.to_lowercase());
+        assert_eq!(parse_attachment_id(&text).unwrap(), id);
+        assert_eq!(parse_attachment_id(&text.to_uppercase()).unwrap(), id);
+        assert!(matches!(
+            parse_attachment_id("not-an-id"),
+            Err(TmdError::Attachment(msg)) if msg.contains("not-an-id")
+        ));
+    }
+
+    #[test]
+    fn normalize_logical_path_rejects_invalid_segments() {
+        assert!(normalize_logical_path("foo/../bar").is_err());
+        assert!(normalize_logical_path("/absolute").is_err());
+        assert_eq!(
+            normalize_logical_path("images/figure.png").unwrap(),
+            "images/figure.png"
+        );
+    }
+
+    #[test]
+    fn new_doc_initializes_database() {
+        let doc = sample_doc();
+        let result = doc
+            .db_with_conn(|conn| {
+                conn.query_row("SELECT 1", [], |row| row.get::<_, i32>(0))
+                    .unwrap()
+            })
+            .expect("db query");
+        assert_eq!(result, 1);
+    }
+
+    #[test]
+    fn fixed_clock_controls_manifest_timestamps() {
+        let created = chrono::DateTime::parse_from_rfc3339("2024-01-02T03:04:05.678Z")
+            .unwrap()
+            .with_timezone(&chrono::Utc);
+        let mut doc = with_clock(FixedClock(created), || {
+            TmdDoc::new("# Clock".to_string()).unwrap()
+        });
+        assert_eq!(doc.manifest.created_utc, created);
+        assert_eq!(doc.manifest.modified_utc, created);
+
+        let later = created + chrono::Duration::microseconds(1_500_250);
+        with_clock(FixedClock(later), || doc.touch());
+        let expected = chrono::DateTime::parse_from_rfc3339("2024-01-02T03:04:07.178Z")
+            .unwrap()
+            .with_timezone(&chrono::Utc);
+        assert_eq!(doc.manifest.modified_utc, expected);
+
+        assert!(set_clock(None).is_none());
+        doc.touch();
+        assert_ne!(doc.manifest.modified_utc, expected);
+    }
+
+    #[test]
+    fn attachments_by_size_filters_on_length() {
+        let mut doc = sample_doc();
+        for (path, len) in [("a.bin", 1), ("b.bin", 10), ("c.bin", 100)] {
+            doc.add_attachment(path, mime::APPLICATION_OCTET_STREAM, vec![0; len])
+                .unwrap();
+        }
+        let paths = |min, max| {
+            let mut paths: Vec<_> = doc
+                .attachments_by_size(min, max)
+                .map(|meta| meta.logical_path.clone())
+                .collect();
+            paths.sort();
+            paths
+        };
+        assert_eq!(paths(10, None), ["b.bin", "c.bin"]);
+        assert_eq!(paths(0, Some(10)), ["a.bin", "b.bin"]);
+        assert_eq!(paths(2, Some(99)), ["b.bin"]);
+        assert!(paths(101, None).is_empty());
+    }
+
+    #[test]
+    fn no_op_edits_keep_modified_time() {
+        let start = chrono::DateTime::parse_from_rfc3339("2024-05-06T07:08:09Z")
+            .unwrap()
+            .with_timezone(&chrono::Utc);
+        let mut doc = build_doc_with_attachment();
+        let id = doc.attachment_meta_by_path("images/pixel.png").unwrap().id;
+        doc.set_language(Some("en")).unwrap();
+        doc.sync_schema_version().unwrap();
+        doc.set_modified(start);
+
+        with_clock(FixedClock(start + chrono::Duration::hours(1)), || {
+            let markdown = doc.markdown().to_string();
+            doc.set_markdown(markdown);
+            doc.set_language(Some("en")).unwrap();
+            doc.set_data_engine(None);
+            doc.set_markdown_path(None).unwrap();
+            doc.set_attachment_mime(id, mime::IMAGE_PNG).unwrap();
+            doc.set_attachment_compression(id, None).unwrap();
+            let sha: [u8; 32] = Sha256::digest([0u8, 1, 2, 3]).into();
+            assert!(doc
+                .replace_attachment_if(id, sha, vec![0, 1, 2, 3])
+                .unwrap());
+            doc.sync_schema_version().unwrap();
+        });
+        assert_eq!(doc.manifest.modified_utc, start);
+
+        with_clock(FixedClock(start + chrono::Duration::hours(3)), || {
+            doc.set_language(Some("fr")).unwrap();
+        });
+        assert_eq!(
+            doc.manifest.modified_utc,
+            start + chrono::Duration::hours(3)
+        );
+        assert!(!doc.touch_if_modified(false));
+    }
+
+    #[test]
+    fn reassign_attachment_ids_keeps_paths_and_data() {
+        let mut doc = build_doc_with_attachment();
+        let old = doc.attachment_meta_by_path("images/pixel.png").unwrap().id;
+        doc.add_attachment_alias(old, "img/p.png").unwrap();
+        doc.manifest.cover_image = Some(AttachmentRef { id: old });
+
+        let mapping = doc.reassign_attachment_ids();
+        let new = mapping[&old];
+        assert_ne!(new, old);
+        assert!(doc.attachment_meta(old).is_none());
+        assert_eq!(
+            doc.attachment_meta_by_path("images/pixel.png").unwrap().id,
+            new
+        );
+        assert_eq!(doc.attachment_meta_by_path("img/p.png").unwrap().id, new);
+        assert_eq!(doc.manifest.cover_image.as_ref().unwrap().id, new);
+        assert_eq!(doc.attachment_bytes(new).unwrap().as_ref(), &[0, 1, 2, 3]);
+
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("lazy.tmd");
+        write_to_path(&path, &doc, Format::Tmd).unwrap();
+        let mode = ReadMode {
+            lazy_attachments: true,
+            ..ReadMode::default()
+        };
+        let mut lazy = read_from_path_with_mode(&path, None, mode).unwrap();
+        let mapping = lazy.reassign_attachment_ids();
+        assert_eq!(
+            lazy.attachment_bytes(mapping[&new]).unwrap().as_ref(),
+            &[0, 1, 2, 3]
+        );
+    }
+
+    #[test]
+    fn lazy_document_saves_over_its_source() {
+        let doc = build_doc_with_attachment();
+        let dir = tempdir().unwrap();
+        for format in [Format::Tmd, Format::Tmdz] {
+            let path = dir.path().join(format!("doc.{}", format.extension()));
+            write_to_path(&path, &doc, format).unwrap();
+            let mode = ReadMode {
+                lazy_attachments: true,
+                ..ReadMode::default()
+            };
+            let mut lazy = read_from_path_with_mode(&path, None, mode).unwrap();
+            assert!(lazy.lazy.is_some());
+            // Grow the markdown so every entry moves within the rewritten file.
+            lazy.markdown.push_str(&"More text.\n".repeat(64));
+
+            write_to_path(&path, &lazy, format).unwrap();
+            assert!(!dir
+                .path()
+                .join(format!("doc.{}.part", format.extension()))
+                .exists());
+            let id = lazy.attachment_meta_by_path("images/pixel.png").unwrap().id;
+            assert!(!lazy.attachments.is_resident(id));
+            assert_eq!(lazy.attachment_bytes(id).unwrap().as_ref(), &[0, 1, 2, 3]);
+
+            let reread = read_from_path(&path, None).unwrap();
+            assert_eq!(reread.markdown, lazy.markdown, "{:?}", format);
+            let id = reread
+                .attachment_meta_by_path("images/pixel.png")
+                .unwrap()
+                .id;
+            assert_eq!(reread.attachment_bytes(id).unwrap().as_ref(), &[0, 1, 2, 3]);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn atomic_write_keeps_file_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let doc = build_doc_with_attachment();
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("doc.tmd");
+        write_to_path(&path, &doc, Format::Tmd).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        write_to_path_atomic(&path, &doc, Format::Tmd).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o644);
+    }
+
+    #[test]
+    fn unknown_attachments_report_not_found() {
+        let mut doc = build_doc_with_attachment();
+        let id = doc.attachment_meta_by_path("images/pixel.png").unwrap().id;
+        let unknown = Uuid::new_v4();
+        let results = [
+            doc.remove_attachment(unknown).map(|_| ()),
+            doc.rename_attachment(unknown, "a.png").map(|_| ()),
+            doc.set_attachment_compression(unknown, None),
+            doc.attachment_bytes(unknown).map(|_| ()),
+            doc.verify_attachment(unknown).map(|_| ()),
+            doc.remove_attachment_alias(id, "not/an/alias.png"),
+        ];
+        for result in results {
+            assert!(matches!(result, Err(TmdError::NotFound(_))), "{:?}", result);
+        }
+    }
+
+    #[test]
+    fn attachment_lifecycle() {
+        let mut doc = sample_doc();
+        let attachment_id = doc
+            .add_attachment("attachments/data.bin", TEXT_PLAIN, vec![1, 2, 3])
+            .expect("add attachment");
+        let meta = doc.attachment_meta(attachment_id).expect("meta exists");
+        assert_eq!(meta.logical_path, "attachments/data.bin");
+        assert_eq!(meta.length, 3);
+
+        let old_path = doc
+            .rename_attachment(attachment_id, "data/renamed.bin")
+            .expect("rename");
+        assert_eq!(old_path, "attachments/data.bin");
+        assert!(doc
+            .attachment_meta_by_path("attachments/data.bin")
+            .is_none());
+        assert!(doc.attachment_meta_by_path("data/renamed.bin").is_some());
+
+        doc.remove_attachment(attachment_id).expect("remove");
+        assert!(doc.attachment_meta(attachment_id).is_none());
+    }
+
+    #[test]
+    fn rename_prefix_moves_subtree_atomically() {
+        let mut doc = sample_doc();
+        doc.add_attachment("images/a.png", TEXT_PLAIN, vec![1])
+            .expect("add a");
+        doc.add_attachment("images/nested/b.png", TEXT_PLAIN, vec![2])
+            .expect("add b");
+        doc.add_attachment("imagesque.png", TEXT_PLAIN, vec![3])
+            .expect("add c");
+        doc.add_attachment("media/a.png", TEXT_PLAIN, vec![4])
+            .expect("add d");
+
+        let err = doc.rename_prefix("images", "media");
+        assert!(err.is_err());
+        assert!(doc.attachment_meta_by_path("images/a.png").is_some());
+        assert!(doc.attachment_meta_by_path("images/nested/b.png").is_some());
+
+        let moved = doc.rename_prefix("images/", "assets\\img").expect("rename");
+        assert_eq!(moved, 2);
+        assert!(doc.attachment_meta_by_path("assets/img/a.png").is_some());
+        assert!(doc
+            .attachment_meta_by_path("assets/img/nested/b.png")
+            .is_some());
+        assert!(doc.attachment_meta_by_path("imagesque.png").is_some());
+        assert!(doc.attachment_meta_by_path("images/a.png").is_none());
+    }
+
+    #[test]
+    fn max_path_depth_limits_new_paths() {
+        let mut doc = sample_doc();
+        let shallow = doc.add_attachment("a/b.txt", TEXT_PLAIN, vec![1]).unwrap();
+        assert_eq!(doc.attachment_meta(shallow).unwrap().path_depth(), 2);
+        doc.add_attachment("a/b/c/d.txt", TEXT_PLAIN, vec![2])
+            .unwrap();
+
+        doc.set_max_path_depth(Some(2));
+        let err = doc
+            .add_attachment("x/y/z.txt", TEXT_PLAIN, vec![3])
+            .unwrap_err();
+        assert!(matches!(err, TmdError::Attachment(_)));
+        assert!(doc.rename_attachment(shallow, "a/b/c.txt").is_err());
+        assert!(doc.add_attachment_alias(shallow, "p/q/r.txt").is_err());
+        doc.add_attachment("x/z.txt", TEXT_PLAIN, vec![3]).unwrap();
+
+        let mut part = TmdDoc::new("# Part".into()).unwrap();
+        part.add_attachment("p/q/r.txt", TEXT_PLAIN, vec![4])
+            .unwrap();
+        let before = doc.attachments.iter().count();
+        let err = doc.merge(part, MergeOptions::default()).unwrap_err();
+        assert!(matches!(err, TmdError::Attachment(_)));
+        assert_eq!(doc.attachments.iter().count(), before);
+        assert!(doc.attachment_meta_by_path("p/q/r.txt").is_none());
+
+        doc.set_max_path_depth(None);
+        doc.add_attachment("x/y/z.txt", TEXT_PLAIN, vec![3])
+            .unwrap();
+    }
+
+    #[test]
+    fn rename_rejects_file_directory_collisions() {
+        let mut doc = sample_doc();
+        let a = doc.add_attachment("a.png", TEXT_PLAIN, vec![1]).unwrap();
+        let b = doc
+            .add_attachment("images/b.png", TEXT_PLAIN, vec![2])
+            .unwrap();
+        doc.add_attachment("docs/c.txt", TEXT_PLAIN, vec![3])
+            .unwrap();
+
+        let err = doc.rename_attachment(a, "images").unwrap_err();
+        assert!(err.to_string().contains("`images/b.png`"), "{}", err);
+        assert!(doc.rename_attachment(b, "a.png/b.png").is_err());
+        assert!(doc.rename_prefix("images", "docs/c.txt").is_err());
+        assert!(doc.attachment_meta_by_path("a.png").is_some());
+        assert!(doc.attachment_meta_by_path("images/b.png").is_some());
+
+        // A path that only shares a textual prefix is fine, as is moving out of the way.
+        doc.rename_attachment(a, "imagesque.png").unwrap();
+        doc.rename_attachment(b, "images").unwrap();
+    }
+
+    #[test]
+    fn attachment_usage_reports_orphans_and_dangling_references() {
+        let mut doc = TmdDoc::new(
+            "![a](attach:images/a.png)\n\
+             [b](./docs/b.pdf#page=2)\n\
+             ![missing](images/missing.png)\n\
+             [remote](https://example.com/x.png) [anchor](#top)\n"
+                .to_string(),
+        )
+        .expect("doc");
+        doc.add_attachment("images/a.png", TEXT_PLAIN, vec![1])
+            .expect("add a");
+        doc.add_attachment("docs/b.pdf", TEXT_PLAIN, vec![2])
+            .expect("add b");
+        doc.add_attachment("unused.bin", TEXT_PLAIN, vec![3])
+            .expect("add unused");
+
+        let usage = doc.attachment_usage();
+        assert_eq!(
+            usage.referenced.iter().collect::<Vec<_>>(),
+            vec!["docs/b.pdf", "images/a.png", "images/missing.png"]
+        );
+        assert_eq!(usage.orphans.iter().collect::<Vec<_>>(), vec!["unused.bin"]);
+        assert_eq!(
+            usage.dangling.iter().collect::<Vec<_>>(),
+            vec!["images/missing.png"]
+        );
+    }
+
+    #[test]
+    fn add_attachment_stream_hashes_incrementally() {
+        let mut doc = TmdDoc::new("# Stream".into()).unwrap();
+        let payload: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let id = doc
+            .add_attachment_stream(
+                "data/big.bin",
+                mime::APPLICATION_OCTET_STREAM,
+                std::io::Cursor::new(payload.clone()),
+            )
+            .unwrap();
+        let meta = doc.attachments.meta(id).unwrap();
+        assert_eq!(meta.length, payload.len() as u64);
+        let expected = Sha256::digest(&payload);
+        assert_eq!(meta.sha256.as_ref().map(|d| &d[..]), Some(&expected[..]));
+        assert_eq!(doc.attachments.data(id).unwrap(), &payload[..]);
+    }
+
+    #[test]
+    fn set_attachment_mime_keeps_id() {
+        let mut doc = TmdDoc::new("# Mime".into()).unwrap();
+        let id = doc
+            .add_attachment("images/a.png", mime::TEXT_PLAIN, vec![1, 2, 3])
+            .unwrap();
+        doc.set_attachment_mime(id, mime::IMAGE_PNG).unwrap();
+        assert_eq!(doc.attachment_meta(id).unwrap().mime, mime::IMAGE_PNG);
+        assert!(matches!(
+            doc.set_attachment_mime(Uuid::new_v4(), mime::IMAGE_PNG),
+            Err(TmdError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn attachment_data_uri_encodes_base64() {
+        let mut doc = sample_doc();
+        let id = doc
+            .add_attachment("notes/hi.txt", TEXT_PLAIN, b"hi".to_vec())
+            .unwrap();
+        let expected = "data:text/plain;base64,aGk=";
+        assert_eq!(doc.attachment_data_uri(id).as_deref(), Some(expected));
+        assert_eq!(
+            doc.attachment_data_uri_by_path("notes/hi.txt").as_deref(),
+            Some(expected)
+        );
+        assert!(doc
+            .attachment_data_uri_by_path("notes/missing.txt")
+            .is_none());
+    }
+
+    #[test]
+    fn take_attachments_moves_entries_into_another_doc() {
+        let mut source = TmdDoc::new("# Source".into()).unwrap();
+        source
+            .add_attachment("images/a.png", mime::IMAGE_PNG, vec![1, 2, 3])
+            .unwrap();
+        source
+            .add_attachment("notes/b.txt", mime::TEXT_PLAIN, b"hello".to_vec())
+            .unwrap();
+
+        let taken = source.take_attachments().unwrap();
+        assert!(source.attachments.is_empty());
+        assert!(source.attachment_meta_by_path("images/a.png").is_none());
+
+        let mut target = TmdDoc::new("# Target".into()).unwrap();
+        for (meta, data) in taken.into_entries().unwrap() {
+            target.attachments.insert_entry(meta, data, true).unwrap();
+        }
+        let meta = target.attachment_meta_by_path("notes/b.txt").unwrap();
+        assert_eq!(target.attachments.data(meta.id).unwrap(), b"hello");
+        assert_eq!(target.list_attachments().count(), 2);
+    }
+
+    #[test]
+    fn taking_lazy_attachments_keeps_their_data() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("doc.tmdz");
+        write_to_path(&path, &build_doc_with_attachment(), Format::Tmdz).unwrap();
+        let mode = ReadMode {
+            lazy_attachments: true,
+            ..ReadMode::default()
+        };
+
+        let mut lazy = read_from_path_with_mode(&path, None, mode).unwrap();
+        let untouched = std::mem::take(&mut lazy.attachments);
+        assert!(matches!(
+            untouched.into_entries(),
+            Err(TmdError::Attachment(msg)) if msg.contains("images/pixel.png")
+        ));
+
+        let mut lazy = read_from_path_with_mode(&path, None, mode).unwrap();
+        let taken = lazy.take_attachments().unwrap();
+        let entries: Vec<_> = taken.into_entries().unwrap().collect();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].0.logical_path, "images/pixel.png");
+        assert_eq!(entries[0].1, [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn glob_match_supports_wildcards() {
+        use crate::util::glob_match;
+        assert!(glob_match("figures/*.png", "figures/a.png"));
+        assert!(!glob_match("figures/*.png", "figures/sub/a.png"));
+        assert!(glob_match("figures/**/*.png", "figures/a.png"));
+        assert!(glob_match("figures/**/*.png", "figures/sub/deep/a.png"));
+        assert!(glob_match("**", "any/path.txt"));
+        assert!(glob_match("img?.jpg", "img1.jpg"));
+        assert!(!glob_match("img?.jpg", "img10.jpg"));
+        assert!(!glob_match("*.png", "figures/a.png"));
+    }
+
+    #[test]
+    fn export_attachments_matching_writes_subset() {
+        let mut doc = TmdDoc::new("# Export".into()).unwrap();
+        doc.add_attachment("figures/a.png", mime::IMAGE_PNG, vec![1])
+            .unwrap();
+        doc.add_attachment("figures/sub/b.png", mime::IMAGE_PNG, vec![2])
+            .unwrap();
+        doc.add_attachment("figures/c.jpg", mime::IMAGE_JPEG, vec![3])
+            .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+
+        let written = doc
+            .export_attachments_matching("figures/**/*.png", dir.path())
+            .unwrap();
+        assert_eq!(written, 2);
+        assert_eq!(
+            std::fs::read(dir.path().join("figures/sub/b.png")).unwrap(),
+            vec![2]
+        );
+        assert!(dir.path().join("figures/a.png").exists());
+        assert!(!dir.path().join("figures/c.jpg").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn export_refuses_to_traverse_symlinked_directories() {
+        let mut doc = TmdDoc::new("# Export".into()).unwrap();
+        doc.add_attachment("figures/a.png", mime::IMAGE_PNG, vec![1])
+            .unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(outside.path(), dir.path().join("figures")).unwrap();
+
+        let err = doc
+            .export_attachments_matching("**", dir.path())
+            .unwrap_err();
+        assert!(matches!(err, TmdError::Attachment(_)));
+        assert!(!outside.path().join("a.png").exists());
+
+        let exploded = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(outside.path(), exploded.path().join("figures")).unwrap();
+        assert!(doc.explode_to_dir(exploded.path()).is_err());
+        assert!(!outside.path().join("a.png").exists());
+
+        let exploded = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(outside.path(), exploded.path().join("db")).unwrap();
+        assert!(doc.explode_to_dir(exploded.path()).is_err());
+        assert!(!outside.path().join("main.sqlite3").exists());
+
+        for entry in ["index.md", "manifest.json", "attachments.json"] {
+            let exploded = tempfile::tempdir().unwrap();
+            let victim = outside.path().join(entry);
+            std::fs::write(&victim, b"untouched").unwrap();
+            std::os::unix::fs::symlink(&victim, exploded.path().join(entry)).unwrap();
+            let err = doc.explode_to_dir(exploded.path()).unwrap_err();
+            assert!(matches!(err, TmdError::Attachment(_)), "{entry}: {err}");
+            assert_eq!(std::fs::read(&victim).unwrap(), b"untouched");
+        }
+    }
+
+    #[test]
+    fn memory_bytes_tracks_markdown_and_attachments() {
+        let mut doc = TmdDoc::new("# Memory".into()).unwrap();
+        assert_eq!(doc.memory_bytes(), doc.markdown.len() as u64);
+
+        doc.add_attachment(
+            "data/blob.bin",
+            mime::APPLICATION_OCTET_STREAM,
+            vec![0; 4096],
+        )
+        .unwrap();
+        let with_blob = doc.memory_bytes();
+        assert!(with_blob > doc.markdown.len() as u64 + 4096);
+        assert_eq!(
+            with_blob,
+            doc.markdown.len() as u64 + doc.attachments.memory_bytes()
+        );
+    }
+
+    #[test]
+    fn max_total_size_rejects_oversized_documents() {
+        let doc = build_doc_with_attachment();
+        let total = doc.attachments_total_size();
+        assert!(total > 0);
+
+        let mut buffer = Cursor::new(Vec::new());
+        let mode = WriteMode {
+            max_total_size: Some(total - 1),
+            ..WriteMode::default()
+        };
+        assert!(matches!(
+            write_tmd(&mut buffer, &doc, mode),
+            Err(TmdError::InvalidFormat(msg)) if msg.contains("max size")
+        ));
+        assert!(buffer.get_ref().is_empty());
+
+        let mode = WriteMode {
+            max_total_size: Some(total),
+            ..WriteMode::default()
+        };
+        write_tmd(&mut buffer, &doc, mode).expect("within limit");
+    }
+
+    #[test]
+    fn regenerate_doc_id_assigns_fresh_identity() {
+        let mut doc = TmdDoc::new("# Template".into()).unwrap();
+        let original = doc.manifest.doc_id;
+        let fresh = doc.regenerate_doc_id();
+        assert_ne!(fresh, original);
+        assert_eq!(doc.manifest.doc_id, fresh);
+    }
+
+    #[test]
+    fn add_attachment_stream_limited_enforces_cap() {
+        let mut doc = sample_doc();
+        let id = doc
+            .add_attachment_stream_limited("ok.bin", TEXT_PLAIN, Cursor::new(vec![0u8; 8]), 8)
+            .expect("within limit");
+        assert_eq!(doc.attachment_meta(id).unwrap().length, 8);
+
+        let err = doc
+            .add_attachment_stream_limited("big.bin", TEXT_PLAIN, std::io::repeat(1), 8)
+            .expect_err("over limit");
+        assert!(matches!(err, TmdError::TooLarge { limit: 8 }));
+        assert!(doc.attachment_meta_by_path("big.bin").is_none());
+    }
+
+    #[test]
+    fn embed_local_assets_imports_and_rewrites_links() {
+        let dir = tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("img")).unwrap();
+        std::fs::write(dir.path().join("img/diagram.png"), [1, 2, 3]).unwrap();
+        std::fs::write(dir.path().join("notes.csv"), "a,b\n").unwrap();
+
+        let mut doc = TmdDoc::new(
+            "![d](./img/diagram.png)\n\
+             [again](img/diagram.png) [csv](notes.csv?raw=1)\n\
+             [missing](nope.png) [web](https://example.com/x.png)\n"
+                .to_string(),
+        )
+        .expect("doc");
+        doc.add_attachment("notes.csv", TEXT_PLAIN, b"other".to_vec())
+            .expect("pre-existing attachment");
+
+        let added = doc.embed_local_assets(dir.path()).expect("embed");
+        assert_eq!(added, 2);
+        assert_eq!(
+            doc.markdown,
+            "![d](attach:img/diagram.png)\n\
+             [again](attach:img/diagram.png) [csv](attach:notes-1.csv)\n\
+             [missing](nope.png) [web](https://example.com/x.png)\n"
+        );
+        let png = doc.attachment_meta_by_path("img/diagram.png").unwrap();
+        assert_eq!(png.mime, mime::IMAGE_PNG);
+        assert_eq!(
+            doc.attachment_meta_by_path("notes-1.csv").unwrap().length,
+            4
+        );
+        assert_eq!(
+            doc.attachment_usage()
+                .dangling
+                .into_iter()
+                .collect::<Vec<_>>(),
+            vec!["nope.png"]
+        );
+    }
+
+    #[test]
+    fn embed_local_assets_rewrites_destinations_not_titles() {
+        let dir = tempdir().unwrap();
+        std::fs::write(dir.path().join("a.png"), [1]).unwrap();
+        std::fs::write(dir.path().join("b.png"), [2]).unwrap();
+
+        let mut doc = TmdDoc::new(
+            "![x](a.png \"a.png\") [![y](b.png)](a.png 'see a.png')\n\
+             [ref][r] [](<a.png>)\n\n[r]: b.png\n"
+                .to_string(),
+        )
+        .unwrap();
+        assert_eq!(doc.embed_local_assets(dir.path()).unwrap(), 2);
+        assert_eq!(
+            doc.markdown,
+            "![x](attach:a.png \"a.png\") [![y](attach:b.png)](attach:a.png 'see a.png')\n\
+             [ref][r] [](<attach:a.png>)\n\n[r]: b.png\n"
+        );
+    }
+
+    #[test]
+    fn embed_local_assets_stays_inside_base_dir() {
+        let outer = tempdir().unwrap();
+        let base = outer.path().join("base");
+        std::fs::create_dir_all(&base).unwrap();
+        std::fs::write(outer.path().join("secret.txt"), b"secret").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(outer.path().join("secret.txt"), base.join("link.txt")).unwrap();
+
+        let markdown = "[s](../secret.txt) [t](sub/../../secret.txt) [l](link.txt)\n";
+        let mut doc = TmdDoc::new(markdown.to_string()).unwrap();
+        assert_eq!(doc.embed_local_assets(&base).unwrap(), 0);
+        assert_eq!(doc.markdown, markdown);
+        assert_eq!(doc.attachments.iter().count(), 0);
+    }
+
+    #[test]
+    fn from_content_dir_imports_markdown_and_assets() {
+        let dir = tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("images/nested")).unwrap();
+        std::fs::create_dir_all(dir.path().join(".git")).unwrap();
+        std::fs::write(dir.path().join("index.md"), "Intro\n\n# Site Title\n").unwrap();
+        std::fs::write(dir.path().join("images/nested/a.png"), [1u8, 2, 3]).unwrap();
+        std::fs::write(dir.path().join("notes.txt"), b"notes").unwrap();
+        std::fs::write(dir.path().join(".hidden"), b"x").unwrap();
+        std::fs::write(dir.path().join(".git/HEAD"), b"ref").unwrap();
+
+        let doc = TmdDoc::from_content_dir(dir.path(), "index.md").unwrap();
+        assert_eq!(doc.manifest.title.as_deref(), Some("Site Title"));
+        let mut paths: Vec<_> = doc
+            .attachments
+            .iter()
+            .map(|m| m.logical_path.clone())
+            .collect();
+        paths.sort();
+        assert_eq!(paths, ["images/nested/a.png", "notes.txt"]);
+        let png = doc.attachments.meta_by_path("images/nested/a.png").unwrap();
+        assert_eq!(png.mime.as_ref(), "image/png");
+
+        assert!(TmdDoc::from_content_dir(dir.path(), "missing.md").is_err());
+    }
+
+    #[test]
+    fn percent_encoded_links_are_relinked_after_sanitizing() {
+        let policy = SanitizePolicy::link_friendly();
+        let dir = tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("My Images")).unwrap();
+        std::fs::write(
+            dir.path().join("index.md"),
+            "![a](a%20b.png) [c][ref] [d](%2E%2E/a%20b.png)\n\n[ref]: My%20Images/c%20d.txt\n",
+        )
+        .unwrap();
+        std::fs::write(dir.path().join("a b.png"), [1u8]).unwrap();
+        std::fs::write(dir.path().join("My Images/c d.txt"), b"notes").unwrap();
+
+        let doc = TmdDoc::from_content_dir_with_policy(dir.path(), "index.md", policy).unwrap();
+        assert_eq!(
+            doc.markdown,
+            "![a](attach:a-b.png) [c][ref] [d](%2E%2E/a%20b.png)\n\n\
+             [ref]: attach:my-images/c-d.txt\n"
+        );
+
+        let mut embedded = TmdDoc::new("![a](a%20b.png) ![b](%2e%2e/x.png)\n".into()).unwrap();
+        assert_eq!(
+            embedded
+                .embed_local_assets_with_policy(dir.path(), policy)
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            embedded.markdown,
+            "![a](attach:a-b.png) ![b](%2e%2e/x.png)\n"
+        );
+    }
+
+    #[test]
+    fn sanitize_policy_cleans_imported_paths() {
+        let policy = SanitizePolicy::link_friendly();
+        assert_eq!(
+            sanitize_logical_path("Café Menu/Été  2024 (final)_.PDF", policy).unwrap(),
+            "café-menu/été-2024-final.pdf"
+        );
+        assert_eq!(
+            sanitize_logical_path("a//b c.png", SanitizePolicy::default()).unwrap(),
+            "a/b c.png"
+        );
+        assert!(sanitize_logical_path("###/???", policy).is_err());
+
+        let dir = tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("My Images")).unwrap();
+        std::fs::write(
+            dir.path().join("index.md"),
+            "![a](<My Images/Photo (1).PNG>) [b](notes.txt) [c][ref]\n\n\
+             [ref]: <My Images/photo 1.png> \"Photo\"\n",
+        )
+        .unwrap();
+        std::fs::write(dir.path().join("My Images/Photo (1).PNG"), [1u8]).unwrap();
+        std::fs::write(dir.path().join("My Images/photo 1.png"), [2u8]).unwrap();
+        std::fs::write(dir.path().join("notes.txt"), b"notes").unwrap();
+
+        let doc = TmdDoc::from_content_dir_with_policy(dir.path(), "index.md", policy).unwrap();
+        let mut paths: Vec<_> = doc
+            .attachments
+            .iter()
+            .map(|m| m.logical_path.clone())
+            .collect();
+        paths.sort();
+        assert_eq!(
+            paths,
+            [
+                "my-images/photo-1-1.png",
+                "my-images/photo-1.png",
+                "notes.txt"
+            ]
+        );
+        assert_eq!(
+            doc.markdown,
+            "![a](<attach:my-images/photo-1.png>) [b](notes.txt) [c][ref]\n\n\
+             [ref]: <attach:my-images/photo-1-1.png> \"Photo\"\n"
+        );
+
+        let mut embedded = TmdDoc::new("![a](<My Images/photo 1.png>)\n".into()).unwrap();
+        embedded
+            .embed_local_assets_with_policy(dir.path(), policy)
+            .unwrap();
+        assert_eq!(embedded.markdown, "![a](<attach:my-images/photo-1.png>)\n");
+        assert_eq!(
+            embedded
+                .attachment_bytes(
+                    embedded
+                        .attachment_meta_by_path("my-images/photo-1.png")
+                        .unwrap()
+                        .id
+                )
+                .unwrap()
+                .as_ref(),
+            &[2]
+        );
+    }
+
+    #[test]
+    fn attachment_data_mut_refreshes_metadata() {
+        let mut doc = sample_doc();
+        let attachment_id = doc
+            .add_attachment("attachments/blob.bin", TEXT_PLAIN, vec![0, 1, 2, 3])
+            .expect("add attachment");
+
+        {
+            let mut data = doc
+                .attachments
+                .data_mut(attachment_id)
+                .expect("mutable handle");
+            data.extend_from_slice(&[4, 5, 6]);
+        }
+
+        let meta = doc
+            .attachment_meta(attachment_id)
+            .expect("updated metadata");
+        assert_eq!(meta.length, 7);
+
+        let expected = {
+            let digest = Sha256::digest([0, 1, 2, 3, 4, 5, 6]);
+            let mut arr = [0u8; 32];
+            arr.copy_from_slice(&digest);
+            arr
+        };
+        assert_eq!(meta.sha256, Some(expected));
+    }
+
+    #[test]
+    fn attachment_timestamps_track_insert_and_edit() {
+        let mut doc = sample_doc();
+        let attachment_id = doc
+            .add_attachment("attachments/blob.bin", TEXT_PLAIN, vec![0, 1, 2, 3])
+            .expect("add attachment");
+        let meta = doc.attachment_meta(attachment_id).unwrap();
+        let created = meta.created_utc.expect("created timestamp");
+        assert_eq!(meta.modified_utc, Some(created));
+
+        let later = created + chrono::Duration::hours(1);
+        with_clock(FixedClock(later), || {
+            let mut data = doc.attachments.data_mut(attachment_id).unwrap();
+            data.push(4);
+            data.pop();
+        });
+        let meta = doc.attachment_meta(attachment_id).unwrap();
+        assert_eq!(meta.modified_utc, Some(created));
+
+        with_clock(FixedClock(later), || {
+            doc.attachments
+                .data_mut(attachment_id)
+                .expect("mutable handle")
+                .push(4)
+        });
+        let meta = doc.attachment_meta(attachment_id).unwrap();
+        assert_eq!(meta.created_utc, Some(created));
+        assert_eq!(meta.modified_utc, Some(later));
+
+        let bytes = doc.to_bytes(Format::Tmd).unwrap();
+        let rebuilt = TmdDoc::try_from(bytes.as_slice()).unwrap();
+        assert_eq!(
+            rebuilt.attachment_meta(attachment_id),
+            doc.attachment_meta(attachment_id)
+        );
+    }
+
+    #[test]
+    fn writing_after_mutation_keeps_manifest_consistent() {
+        let mut doc = sample_doc();
+        let attachment_id = doc
+            .add_attachment("attachments/data.bin", TEXT_PLAIN, vec![1, 2, 3, 4])
+            .expect("add attachment");
+
+        {
+            let mut data = doc
+                .attachments
+                .data_mut(attachment_id)
+                .expect("mutable handle");
+            data.extend_from_slice(&[5, 6]);
+        }
+
+        let mut buffer = Cursor::new(Vec::new());
+        write_tmd(&mut buffer, &doc, WriteMode::default()).expect("write");
+        buffer.seek(SeekFrom::Start(0)).unwrap();
+        let mut reader =
+            Reader::new(buffer, Some(Format::Tmd), ReadMode::default()).expect("reader");
+        let rebuilt = reader.read_doc().expect("read");
+
+        let rebuilt_meta = rebuilt
+            .attachment_meta(attachment_id)
+            .expect("attachment meta");
+        assert_eq!(rebuilt_meta.length, 6);
+        assert_eq!(
+            rebuilt.attachments.data(attachment_id).unwrap(),
+            &[1, 2, 3, 4, 5, 6]
+        );
+    }
+
+    fn build_doc_with_attachment() -> TmdDoc {
+        let mut doc = sample_doc();
+        doc.markdown.push_str("Body text\n");
+        doc.manifest.title = Some("Roundtrip".into());
+        doc.manifest.tags = vec!["report".into()];
+        doc.add_attachment(
+            "images/pixel.png",
+            "image/png".parse().unwrap(),
+            vec![0, 1, 2, 3],
+        )
+        .expect("add attachment");
+        doc.db_with_conn_mut(|conn| {
+            conn.execute("CREATE TABLE items(id INTEGER PRIMARY KEY, name TEXT)", [])
+                .unwrap();
+            conn.execute("INSERT INTO items(name) VALUES ('apricot')", [])
+                .unwrap();
+            conn.pragma_update(None, "user_version", 2).unwrap();
+        })
+        .expect("populate db");
+        doc.manifest.db_schema_version = Some(2);
+        doc
+    }
+
+    /// Copy every entry of the archive `bytes` into a fresh one, passing each through
+    /// `edit`, which returns the entry's new contents or `None` to drop it.
+    fn rewrite_archive(
+        bytes: Vec<u8>,
+        mut edit: impl FnMut(&str, Vec<u8>) -> Option<Vec<u8>>,
+    ) -> Vec<u8> {
+        let mut source = zip::ZipArchive::new(Cursor::new(bytes)).unwrap();
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for index in 0..source.len() {
+            let mut file = source.by_index(index).unwrap();
+            let mut data = Vec::new();
+            file.read_to_end(&mut data).unwrap();
+            let name = file.name().to_string();
+            let Some(data) = edit(&name, data) else {
+                continue;
+            };
+            writer
+                .start_file(name, zip::write::FileOptions::default())
+                .unwrap();
+            std::io::Write::write_all(&mut writer, &data).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn duplicate_attachments_groups_identical_content() {
+        let mut doc = sample_doc();
+        doc.add_attachment("a/one.bin", TEXT_PLAIN, vec![9; 10])
+            .expect("add one");
+        let two = doc
+            .add_attachment("b/two.bin", TEXT_PLAIN, vec![9; 10])
+            .expect("add two");
+        doc.add_attachment("unique.bin", TEXT_PLAIN, vec![1, 2, 3])
+            .expect("add unique");
+
+        let mut unhashed = doc.attachment_meta(two).unwrap().clone();
+        unhashed.id = Uuid::new_v4();
+        unhashed.logical_path = "c/three.bin".into();
+        unhashed.sha256 = None;
+        doc.attachments
+            .insert_entry(unhashed, vec![9; 10], true)
+            .expect("insert unhashed");
+
+        let groups = doc.duplicate_attachments();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(
+            groups[0].logical_paths,
+            vec!["a/one.bin", "b/two.bin", "c/three.bin"]
+        );
+        assert_eq!(groups[0].bytes_saved, 20);
+    }
+
+    #[test]
+    fn tmd_rejects_markdown_length_not_at_zip_start() {
+        let doc = build_doc_with_attachment();
+        let mut buffer = Cursor::new(Vec::new());
+        write_tmd(&mut buffer, &doc, WriteMode::default()).expect("write");
+        let mut bytes = buffer.into_inner();
+
+        let len_pos = bytes.len() - 8;
+        let markdown_len = doc.markdown.len() as u64 - 1;
+        bytes[len_pos..].copy_from_slice(&markdown_len.to_le_bytes());
+
+        let err = read_tmd(&mut Cursor::new(bytes), ReadMode::default()).unwrap_err();
+        assert!(
+            matches!(&err, TmdError::InvalidFormat(msg) if msg == "markdown length points outside ZIP payload"),
+            "unexpected error: {:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn oversized_markdown_length_is_rejected() {
+        assert_eq!(format::checked_len(42, "markdown length").unwrap(), 42);
+        let beyond_u32 = u64::from(u32::MAX) + 1;
+        assert_eq!(
+            format::checked_len(beyond_u32, "markdown length").is_ok(),
+            usize::BITS > 32
+        );
+
+        let doc = build_doc_with_attachment();
+        let mut bytes = doc.to_bytes(Format::Tmd).unwrap();
+        let len_pos = bytes.len() - 8;
+        bytes[len_pos..].copy_from_slice(&u64::MAX.to_le_bytes());
+        let err = read_tmd(&mut Cursor::new(bytes), ReadMode::default()).unwrap_err();
+        assert!(matches!(err, TmdError::InvalidFormat(_)), "{:?}", err);
+    }
+
+    #[test]
+    fn stable_ids_make_manifests_reproducible() {
+        let build = || {
+            let mut doc = TmdDoc::new("# Stable".into()).unwrap();
+            let cover = doc
+                .add_attachment("images/cover.png", mime::IMAGE_PNG, vec![1, 2, 3])
+                .unwrap();
+            doc.add_attachment("notes/a.txt", mime::TEXT_PLAIN, b"a".to_vec())
+                .unwrap();
+            doc.manifest.cover_image = Some(AttachmentRef { id: cover });
+            doc
+        };
+        let first = build();
+        let mut second = build();
+        second.manifest.doc_id = first.manifest.doc_id;
+        second.manifest.created_utc = first.manifest.created_utc;
+        second.manifest.modified_utc = first.manifest.modified_utc;
+
+        let mode = WriteMode {
+            stable_ids: true,
+            ..WriteMode::default()
+        };
+        let entries = |doc: &TmdDoc| {
+            let mut buffer = Cursor::new(Vec::new());
+            write_tmdz(&mut buffer, doc, mode.clone()).expect("write");
+            let mut zip = zip::ZipArchive::new(Cursor::new(buffer.into_inner())).unwrap();
+            let mut read = |name: &str| {
+                let mut text = String::new();
+                zip.by_name(name)
+                    .unwrap()
+                    .read_to_string(&mut text)
+                    .unwrap();
+                text
+            };
+            (read("manifest.json"), read("attachments.json"))
+        };
+        assert_eq!(entries(&first), entries(&second));
+
+        let mut buffer = Cursor::new(Vec::new());
+        write_tmdz(&mut buffer, &first, mode).expect("write");
+        let reread = read_tmdz(&mut buffer, ReadMode::default()).expect("read");
+        let cover_id = reread.manifest.cover_image.as_ref().unwrap().id;
+        assert_eq!(
+            reread.attachment_meta(cover_id).unwrap().logical_path,
+            "images/cover.png"
+        );
+        assert!(first.attachment_meta(cover_id).is_none());
+    }
+
+    #[test]
+    fn order_by_controls_attachment_layout() {
+        let mut doc = TmdDoc::new("# Order".into()).unwrap();
+        doc.add_attachment("docs/z.txt", TEXT_PLAIN, b"z".to_vec())
+            .unwrap();
+        let cover = doc
+            .add_attachment("images/b.png", mime::IMAGE_PNG, vec![1])
+            .unwrap();
+        doc.add_attachment("images/a.png", mime::IMAGE_PNG, vec![2])
+            .unwrap();
+        let layout = |order_by: OrderBy| {
+            let mode = WriteMode {
+                order_by,
+                ..WriteMode::default()
+            };
+            let mut buffer = Cursor::new(Vec::new());
+            write_tmdz(&mut buffer, &doc, mode).expect("write");
+            let mut zip = zip::ZipArchive::new(Cursor::new(buffer.into_inner())).unwrap();
+            (0..zip.len())
+                .map(|index| zip.by_index(index).unwrap().name().to_owned())
+                .filter(|name| name.starts_with("images/") || name.starts_with("docs/"))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            layout(OrderBy::LogicalPath),
+            ["docs/z.txt", "images/a.png", "images/b.png"]
+        );
+        assert_eq!(
+            layout(OrderBy::InsertionOrder),
+            ["docs/z.txt", "images/b.png", "images/a.png"]
+        );
+        assert_eq!(
+            layout(OrderBy::MimeThenPath),
+            ["images/a.png", "images/b.png", "docs/z.txt"]
+        );
+        assert_eq!(
+            layout(OrderBy::Custom(vec![cover])),
+            ["images/b.png", "docs/z.txt", "images/a.png"]
+        );
+    }
+
+    #[test]
+    fn duplicate_archive_entries_are_rejected() {
+        let doc = sample_doc();
+        let mut writer =
+            zip::ZipWriter::new_append(Cursor::new(doc.to_bytes(Format::Tmdz).unwrap())).unwrap();
+        writer
+            .start_file("index.md", zip::write::FileOptions::default())
+            .unwrap();
+        std::io::Write::write_all(&mut writer, b"# Impostor").unwrap();
+        let bytes = writer.finish().unwrap().into_inner();
+
+        match read_tmdz(&mut Cursor::new(bytes), ReadMode::default()) {
+            Err(TmdError::InvalidFormat(msg)) => assert_eq!(msg, "duplicate entry: index.md"),
+            other => panic!(
+                "expected duplicate entry error, got {:?}",
+                other.map(|_| ())
+            ),
+        }
+    }
+
+    #[test]
+    fn content_checksum_detects_removed_attachments() {
+        let mut doc = build_doc_with_attachment();
+        doc.add_attachment("docs/extra.txt", TEXT_PLAIN, b"extra".to_vec())
+            .unwrap();
+        let write = WriteMode {
+            compute_checksum: true,
+            ..WriteMode::default()
+        };
+        let verify = ReadMode {
+            verify_checksum: true,
+            ..ReadMode::default()
+        };
+        let mut buffer = Cursor::new(Vec::new());
+        write_tmdz(&mut buffer, &doc, write).unwrap();
+        let bytes = buffer.into_inner();
+        let reread = read_tmdz(&mut Cursor::new(bytes.clone()), verify).unwrap();
+        assert_eq!(
+            reread.manifest.content_checksum,
+            Some(doc.content_checksum())
+        );
+        assert_eq!(reread.verify_content_checksum(), Some(true));
+
+        // Drop one attachment from both the index and the archive.
+        let tampered = rewrite_archive(bytes, |name, data| match name {
+            "docs/extra.txt" => None,
+            "attachments.json" => {
+                let mut index: serde_json::Value = serde_json::from_slice(&data).unwrap();
+                index["attachments"]
+                    .as_array_mut()
+                    .unwrap()
+                    .retain(|meta| meta["logical_path"] != "docs/extra.txt");
+                Some(serde_json::to_vec(&index).unwrap())
+            }
+            _ => Some(data),
+        });
+        let lenient = read_tmdz(&mut Cursor::new(tampered.clone()), ReadMode::default()).unwrap();
+        assert_eq!(lenient.verify_content_checksum(), Some(false));
+        assert!(matches!(
+            read_tmdz(&mut Cursor::new(tampered), verify),
+            Err(TmdError::InvalidFormat(_))
+        ));
+
+        // Saving without recomputing drops the now-stale checksum.
+        let resaved = TmdDoc::try_from(lenient.to_bytes(Format::Tmd).unwrap().as_slice()).unwrap();
+        assert_eq!(resaved.manifest.content_checksum, None);
+    }
+
+    #[test]
+    fn infer_attachments_without_attachments_json() {
+        let mut doc = build_doc_with_attachment();
+        doc.add_attachment("docs/readme.txt", TEXT_PLAIN, b"hello".to_vec())
+            .unwrap();
+        let mut buffer = Cursor::new(Vec::new());
+        write_tmdz(&mut buffer, &doc, WriteMode::default()).expect("write");
+
+        // Drop attachments.json, as a hand-built archive would.
+        let bytes = rewrite_archive(buffer.into_inner(), |name, data| {
+            (name != "attachments.json").then_some(data)
+        });
+
+        assert!(read_tmdz(&mut Cursor::new(bytes.clone()), ReadMode::default()).is_err());
+        let mode = ReadMode {
+            infer_attachments: true,
+            ..ReadMode::default()
+        };
+        let inferred = read_tmdz(&mut Cursor::new(bytes), mode).expect("infer");
+        assert_eq!(inferred.markdown, doc.markdown);
+        let mut paths: Vec<_> = inferred
+            .list_attachments()
+            .map(|meta| {
+                (
+                    meta.logical_path.clone(),
+                    meta.mime.to_string(),
+                    meta.length,
+                )
+            })
+            .collect();
+        paths.sort();
+        assert_eq!(
+            paths,
+            [
+                ("docs/readme.txt".to_string(), "text/plain".to_string(), 5),
+                ("images/pixel.png".to_string(), "image/png".to_string(), 4),
+            ]
+        );
+        let readme = inferred.attachment_meta_by_path("docs/readme.txt").unwrap();
+        assert_eq!(
+            readme.sha256,
+            doc.attachment_meta_by_path("docs/readme.txt")
+                .unwrap()
+                .sha256
+        );
+    }
+
+    #[test]
+    fn duplicate_path_policy_salvages_entries() {
+        let mut doc = TmdDoc::new("# Dupes".into()).unwrap();
+        doc.add_attachment("images/a.png", mime::IMAGE_PNG, vec![1, 2, 3])
+            .unwrap();
+        doc.add_attachment("images/b.png", mime::IMAGE_PNG, vec![1, 2, 3])
+            .unwrap();
+        let mut buffer = Cursor::new(Vec::new());
+        write_tmdz(&mut buffer, &doc, WriteMode::default()).expect("write");
+
+        // Rewrite attachments.json so both entries claim the same logical path.
+        let bytes = rewrite_archive(buffer.into_inner(), |name, data| {
+            if name != "attachments.json" {
+                return Some(data);
+            }
+            let index = String::from_utf8(data).unwrap();
+            Some(index.replace("images/b.png", "images/a.png").into_bytes())
+        });
+
+        let read = |policy| {
+            let mode = ReadMode {
+                on_duplicate_path: policy,
+                ..ReadMode::default()
+            };
+            read_tmdz(&mut Cursor::new(bytes.clone()), mode)
+        };
+        assert!(matches!(
+            read(DuplicatePolicy::Error),
+            Err(TmdError::Attachment(_))
+        ));
+
+        let skipped = read(DuplicatePolicy::Skip).expect("skip");
+        assert_eq!(skipped.list_attachments().count(), 1);
+
+        let renamed = read(DuplicatePolicy::Rename).expect("rename");
+        let mut paths: Vec<_> = renamed
+            .list_attachments()
+            .map(|meta| meta.logical_path.clone())
+            .collect();
+        paths.sort();
+        assert_eq!(paths, vec!["images/a-1.png", "images/a.png"]);
+    }
+
+    #[test]
+    fn duplicate_archive_entries_keep_their_own_content() {
+        let mut doc = TmdDoc::new("# Dupes".into()).unwrap();
+        doc.add_attachment("images/a.png", mime::IMAGE_PNG, vec![1, 2, 3])
+            .unwrap();
+        doc.add_attachment("images/b.png", mime::IMAGE_PNG, vec![4, 5, 6, 7])
+            .unwrap();
+
+        // List both under `images/a.png` and store the second one's bytes under that
+        // name too, as a second entry after the first.
+        let bytes = rewrite_archive(
+            doc.to_bytes(Format::Tmdz).unwrap(),
+            |name, data| match name {
+                "images/b.png" => None,
+                "attachments.json" => {
+                    let index = String::from_utf8(data).unwrap();
+                    Some(index.replace("images/b.png", "images/a.png").into_bytes())
+                }
+                _ => Some(data),
+            },
+        );
+        let mut writer = zip::ZipWriter::new_append(Cursor::new(bytes)).unwrap();
+        writer
+            .start_file("images/a.png", zip::write::FileOptions::default())
+            .unwrap();
+        std::io::Write::write_all(&mut writer, &[4, 5, 6, 7]).unwrap();
+        let bytes = writer.finish().unwrap().into_inner();
+        let bytes_of = |doc: &TmdDoc, path: &str| {
+            let id = doc.attachment_meta_by_path(path).unwrap().id;
+            doc.attachment_bytes(id).unwrap().into_owned()
+        };
+
+        let read = |policy| {
+            let mode = ReadMode {
+                on_duplicate_path: policy,
+                ..ReadMode::default()
+            };
+            let mut events = Vec::new();
+            let doc = Reader::new(Cursor::new(bytes.clone()), Some(Format::Tmdz), mode)
+                .unwrap()
+                .on_progress(|event| events.push(event))
+                .read_doc();
+            (doc, events)
+        };
+        let (doc, _) = read(DuplicatePolicy::Error);
+        assert!(matches!(
+            doc,
+            Err(TmdError::InvalidFormat(msg)) if msg == "duplicate entry: images/a.png"
+        ));
+
+        let (skipped, events) = read(DuplicatePolicy::Skip);
+        let skipped = skipped.unwrap();
+        assert_eq!(bytes_of(&skipped, "images/a.png"), [1, 2, 3]);
+        assert_eq!(skipped.list_attachments().count(), 1);
+        assert_eq!(
+            events,
+            [ReadProgress::DuplicateSkipped {
+                path: "images/a.png".into()
+            }]
+        );
+
+        let (renamed, events) = read(DuplicatePolicy::Rename);
+        let renamed = renamed.unwrap();
+        assert_eq!(bytes_of(&renamed, "images/a.png"), [1, 2, 3]);
+        assert_eq!(bytes_of(&renamed, "images/a-1.png"), [4, 5, 6, 7]);
+        assert_eq!(
+            events,
+            [ReadProgress::DuplicateRenamed {
+                path: "images/a.png".into(),
+                renamed: "images/a-1.png".into()
+            }]
+        );
+
+        // Lazy reads fetch the renamed attachment from its own entry as well.
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("dupes.tmdz");
+        std::fs::write(&path, &bytes).unwrap();
+        let mode = ReadMode {
+            lazy_attachments: true,
+            on_duplicate_path: DuplicatePolicy::Rename,
+            ..ReadMode::default()
+        };
+        let lazy = read_from_path_with_mode(&path, None, mode).unwrap();
+        assert_eq!(bytes_of(&lazy, "images/a-1.png"), [4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn repair_metadata_fixes_wrong_length() {
+        let mut doc = TmdDoc::new("# Repair".into()).unwrap();
+        let id = doc
+            .add_attachment("images/a.png", mime::IMAGE_PNG, vec![1, 2, 3])
+            .unwrap();
+        let mut buffer = Cursor::new(Vec::new());
+        write_tmdz(&mut buffer, &doc, WriteMode::default()).expect("write");
+
+        // Rewrite attachments.json with a length that disagrees with the stored bytes.
+        let bytes = rewrite_archive(buffer.into_inner(), |name, data| {
+            if name != "attachments.json" {
+                return Some(data);
+            }
+            let mut json: serde_json::Value = serde_json::from_slice(&data).unwrap();
+            json["attachments"][0]["length"] = 99.into();
+            Some(serde_json::to_vec(&json).unwrap())
+        });
+
+        assert!(matches!(
+            read_tmdz(&mut Cursor::new(bytes.clone()), ReadMode::default()),
+            Err(TmdError::Attachment(msg)) if msg.contains("length mismatch")
+        ));
+        let mode = ReadMode {
+            repair_metadata: true,
+            ..ReadMode::default()
+        };
+        let repaired = read_tmdz(&mut Cursor::new(bytes), mode).expect("repaired read");
+        assert_eq!(repaired.attachment_meta(id).unwrap().length, 3);
+        assert!(repaired.content_eq(&doc));
+    }
+
+    #[test]
+    fn read_attachment_from_path_reads_single_entry() {
+        let mut doc = build_doc_with_attachment();
+        let id = doc
+            .add_attachment("notes/b.txt", TEXT_PLAIN, b"second".to_vec())
+            .unwrap();
+        doc.add_attachment_alias(id, "notes/alias.txt").unwrap();
+        let dir = tempdir().unwrap();
+        for format in [Format::Tmd, Format::Tmdz] {
+            let path = dir.path().join("doc.out");
+            write_to_path(&path, &doc, format).unwrap();
+            let (meta, data) = read_attachment_from_path(&path, "notes/b.txt").unwrap();
+            assert_eq!(meta.id, id);
+            assert_eq!(data, b"second");
+            let (meta, _) = read_attachment_from_path(&path, "notes/alias.txt").unwrap();
+            assert_eq!(meta.id, id);
+            assert!(matches!(
+                read_attachment_from_path(&path, "notes/missing.txt"),
+                Err(TmdError::NotFound(msg)) if msg.contains("notes/missing.txt")
+            ));
+        }
+    }
+
+    #[test]
+    fn lazy_attachments_are_read_on_demand() {
+        let mut doc = build_doc_with_attachment();
+        doc.add_attachment("notes/b.txt", TEXT_PLAIN, b"second".to_vec())
+            .unwrap();
+        let dir = tempdir().unwrap();
+        let mode = ReadMode {
+            lazy_attachments: true,
+            ..ReadMode::default()
+        };
+        for format in [Format::Tmd, Format::Tmdz] {
+            let path = dir.path().join("lazy.doc");
+            write_to_path(&path, &doc, format).unwrap();
+            let mut lazy = read_from_path_with_mode(&path, None, mode).unwrap();
+            // The open handle keeps serving reads once the path is gone.
+            std::fs::remove_file(&path).unwrap();
+
+            assert_eq!(lazy.markdown, doc.markdown);
+            let id = lazy.attachments.meta_by_path("notes/b.txt").unwrap().id;
+            assert!(!lazy.attachments.is_resident(id));
+            assert!(lazy.attachments.data(id).is_none());
+            assert_eq!(lazy.attachment_bytes(id).unwrap().as_ref(), b"second");
+
+            let copy = TmdDoc::try_from(lazy.to_bytes(Format::Tmdz).unwrap().as_slice()).unwrap();
+            assert!(copy.content_eq(&doc));
+
+            assert_eq!(lazy.load_attachments().unwrap(), 2);
+            assert!(lazy.lazy.is_none());
+            assert!(lazy.attachments.is_resident(id));
+            assert!(lazy.content_eq(&doc));
+        }
+    }
+
+    #[test]
+    fn verify_attachment_streams_lazy_entries() {
+        let doc = build_doc_with_attachment();
+        let id = doc.attachments.meta_by_path("images/pixel.png").unwrap().id;
+        assert!(doc.verify_attachment(id).unwrap());
+
+        // Store a digest that does not match the entry's bytes.
+        let tampered = rewrite_archive(doc.to_bytes(Format::Tmdz).unwrap(), |name, data| {
+            if name != "attachments.json" {
+                return Some(data);
+            }
+            let mut json: serde_json::Value = serde_json::from_slice(&data).unwrap();
+            json["attachments"][0]["sha256"] = "00".repeat(32).into();
+            Some(serde_json::to_vec(&json).unwrap())
+        });
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("tampered.tmdz");
+        std::fs::write(&path, tampered).unwrap();
+
+        let mode = ReadMode {
+            lazy_attachments: true,
+            verify_hashes: false,
+            ..ReadMode::default()
+        };
+        let lazy = read_from_path_with_mode(&path, None, mode).unwrap();
+        assert!(!lazy.attachments.is_resident(id));
+        assert!(!lazy.verify_attachment(id).unwrap());
+        assert!(!lazy.attachments.is_resident(id));
+        assert!(lazy.verify_attachment(Uuid::new_v4()).is_err());
+    }
+
+    #[test]
+    fn merge_appends_markdown_attachments_and_tables() {
+        let mut base = TmdDoc::new("# Report\n\n![a](attach:img/a.png)\n".into()).unwrap();
+        base.add_attachment("img/a.png", mime::IMAGE_PNG, vec![1])
+            .unwrap();
+        base.add_tag("report");
+
+        let mut part =
+            TmdDoc::new("## Part\n\n![a](attach:img/a.png) [b](img/b.txt)\n".into()).unwrap();
+        part.manifest.title = Some("Part".into());
+        part.add_attachment("img/a.png", mime::IMAGE_PNG, vec![2])
+            .unwrap();
+        part.add_attachment("img/b.txt", TEXT_PLAIN, b"b".to_vec())
+            .unwrap();
+        part.add_tag("appendix");
+        part.db_with_conn_mut(|conn| {
+            conn.execute_batch("CREATE TABLE rows(v INTEGER); INSERT INTO rows VALUES (7);")
+                .unwrap();
+        })
+        .unwrap();
+
+        let options = MergeOptions {
+            db: DbMerge::Prefix("part_".into()),
+            ..MergeOptions::default()
+        };
+        let report = base.merge(part, options).unwrap();
+        assert_eq!(
+            report.renamed,
+            [("img/a.png".to_string(), "img/a-1.png".to_string())]
+        );
+        assert_eq!(report.tables, ["part_rows"]);
+        assert!(report.dropped.iter().any(|item| item.contains("Part")));
+        assert_eq!(
+            base.markdown,
+            "# Report\n\n![a](attach:img/a.png)\n\n\n## Part\n\n![a](attach:img/a-1.png) [b](img/b.txt)\n"
+        );
+        assert_eq!(
+            base.attachments.data_by_path("img/a-1.png"),
+            Some(&[2u8][..])
+        );
+        assert_eq!(base.attachments.data_by_path("img/b.txt"), Some(&b"b"[..]));
+        assert_eq!(base.manifest.tags, ["report", "appendix"]);
+        let copied = base
+            .db_with_conn(|conn| {
+                conn.query_row("SELECT v FROM part_rows", [], |row| row.get::<_, i64>(0))
+            })
+            .unwrap()
+            .unwrap();
+        assert_eq!(copied, 7);
+
+        let mut other = TmdDoc::new(String::new()).unwrap();
+        other
+            .add_attachment("img/b.txt", TEXT_PLAIN, b"x".to_vec())
+            .unwrap();
+        let strict = MergeOptions {
+            on_collision: DuplicatePolicy::Error,
+            ..MergeOptions::default()
+        };
+        let before = base.markdown.clone();
+        assert!(base.merge(other, strict).is_err());
+        assert_eq!(base.markdown, before);
+    }
+
+    #[test]
+    fn merge_relinks_renamed_reference_definitions() {
+        let mut base = TmdDoc::new("# Base\n".into()).unwrap();
+        base.add_attachment("img/a.png", mime::IMAGE_PNG, vec![1])
+            .unwrap();
+        let mut part =
+            TmdDoc::new("See [the figure][fig].\n\n[fig]: img/a.png \"img/a.png\"\n".into())
+                .unwrap();
+        part.add_attachment("img/a.png", mime::IMAGE_PNG, vec![2])
+            .unwrap();
+
+        let options = MergeOptions {
+            separator: "\n".into(),
+            ..MergeOptions::default()
+        };
+        base.merge(part, options).unwrap();
+        assert_eq!(
+            base.markdown,
+            "# Base\n\nSee [the figure][fig].\n\n[fig]: img/a-1.png \"img/a.png\"\n"
+        );
+    }
+
+    #[test]
+    fn merge_table_copy_rolls_back_on_failure() {
+        let mut base = TmdDoc::new("# Base\n".into()).unwrap();
+        base.db_with_conn_mut(|conn| conn.execute_batch("CREATE TABLE part_b(v INTEGER);"))
+            .unwrap()
+            .unwrap();
+        let mut part = TmdDoc::new("## Part\n".into()).unwrap();
+        part.add_attachment("img/a.png", mime::IMAGE_PNG, vec![1])
+            .unwrap();
+        part.db_with_conn_mut(|conn| {
+            conn.execute_batch("CREATE TABLE a(v INTEGER); CREATE TABLE b(v INTEGER);")
+        })
+        .unwrap()
+        .unwrap();
+
+        let options = MergeOptions {
+            db: DbMerge::Prefix("part_".into()),
+            ..MergeOptions::default()
+        };
+        assert!(base.merge(part, options).is_err());
+        assert_eq!(base.markdown, "# Base\n");
+        assert!(base.attachments.is_empty());
+        let tables = base
+            .db_with_conn(|conn| {
+                conn.query_row(
+                    "SELECT group_concat(name) FROM sqlite_master WHERE type = 'table'",
+                    [],
+                    |row| row.get::<_, String>(0),
+                )
+            })
+            .unwrap()
+            .unwrap();
+        assert_eq!(tables, "part_b");
+    }
+
+    #[test]
+    fn durable_write_to_path_roundtrips() {
+        let doc = build_doc_with_attachment();
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("durable.tmdz");
+        let mode = WriteMode {
+            durable: true,
+            ..WriteMode::default()
+        };
+        write_to_path_with_mode(&path, &doc, Format::Tmdz, mode).expect("write");
+        let rebuilt = read_from_path(&path, None).expect("read");
+        assert!(rebuilt.content_eq(&doc));
+    }
+
+    #[test]
+    fn attachment_aliases_resolve_and_roundtrip() {
+        let mut doc = TmdDoc::new("# Aliases".into()).unwrap();
+        let logo = doc
+            .add_attachment("logo.png", mime::IMAGE_PNG, vec![9, 9, 9])
+            .unwrap();
+        let other = doc
+            .add_attachment("other.png", mime::IMAGE_PNG, vec![1])
+            .unwrap();
+        doc.add_attachment_alias(logo, "brand/primary.png").unwrap();
+        assert_eq!(
+            doc.attachment_meta_by_path("brand/primary.png").unwrap().id,
+            logo
+        );
+        assert_eq!(
+            doc.attachments.data_by_path("brand/primary.png"),
+            Some(&[9u8, 9, 9][..])
+        );
+        assert!(doc.add_attachment_alias(other, "logo.png").is_err());
+        assert!(doc
+            .add_attachment_alias(other, "brand/primary.png")
+            .is_err());
+        assert!(doc
+            .add_attachment("brand/primary.png", mime::IMAGE_PNG, vec![2])
+            .is_err());
+
+        let mut buffer = Cursor::new(Vec::new());
+        write_tmdz(&mut buffer, &doc, WriteMode::default()).expect("write");
+        let zip = zip::ZipArchive::new(Cursor::new(buffer.get_ref().clone())).unwrap();
+        assert!(zip.file_names().all(|name| name != "brand/primary.png"));
+        let reread = read_tmdz(&mut buffer, ReadMode::default()).expect("read");
+        assert_eq!(
+            reread
+                .attachment_meta_by_path("brand/primary.png")
+                .unwrap()
+                .id,
+            logo
+        );
+
+        doc.remove_attachment(logo).unwrap();
+        assert!(doc.attachment_meta_by_path("brand/primary.png").is_none());
+    }
+
+    #[test]
+    fn validate_full_collects_every_issue() {
+        let mut doc = TmdDoc::new("# Report\n![x](attach:missing.png)".into()).unwrap();
+        doc.add_attachment("images/a.png", mime::IMAGE_PNG, vec![1, 2, 3])
+            .unwrap();
+        doc.manifest.cover_image = Some(AttachmentRef { id: Uuid::new_v4() });
+        doc.manifest.db_schema_version = Some(42);
+
+        let report = doc.validate_full();
+        assert!(report.has_errors());
+        let categories: Vec<_> = report.errors().map(|issue| issue.category).collect();
+        assert!(categories.contains(&IssueCategory::Reference));
+        assert!(categories.contains(&IssueCategory::Database));
+        assert!(report
+            .errors()
+            .any(|issue| issue.message.contains("missing.png")));
+        assert!(report
+            .warnings()
+            .any(|issue| issue.message.contains("images/a.png")));
+
+        let clean = TmdDoc::new("# Clean".into()).unwrap();
+        assert!(!clean.validate_full().has_errors());
+    }
+
+    #[test]
+    fn check_path_portability_flags_cross_platform_hazards() {
+        let mut doc = sample_doc();
+        for path in [
+            "docs/Readme.md",
+            "docs/readme.md",
+            "dev/nul.txt",
+            "notes/draft./a.txt",
+            "images/ok.png",
+        ] {
+            doc.add_attachment(path, TEXT_PLAIN, b"x".to_vec()).unwrap();
+        }
+        let long = format!("data/{}.bin", "x".repeat(300));
+        doc.add_attachment(&long, TEXT_PLAIN, b"x".to_vec())
+            .unwrap();
+
+        let warnings = doc.check_path_portability();
+        assert_eq!(
+            warnings,
+            [
+                PortabilityWarning::CaseCollision {
+                    paths: vec!["docs/Readme.md".into(), "docs/readme.md".into()],
+                },
+                PortabilityWarning::TooLong {
+                    path: long,
+                    limit: 260,
+                },
+                PortabilityWarning::ReservedName {
+                    path: "dev/nul.txt".into(),
+                    component: "nul.txt".into(),
+                },
+                PortabilityWarning::TrailingDotOrSpace {
+                    path: "notes/draft./a.txt".into(),
+                },
+            ]
+        );
+        assert!(doc
+            .validate_full()
+            .warnings()
+            .any(|issue| issue.message.contains("differ only by case")));
+        assert!(sample_doc().check_path_portability().is_empty());
+    }
+
+    #[test]
+    fn empty_markdown_tmd_is_sniffed_as_tmd() {
+        let mut doc = TmdDoc::new(String::new()).unwrap();
+        doc.add_attachment("notes/a.txt", TEXT_PLAIN, b"a".to_vec())
+            .unwrap();
+        let mut buffer = Cursor::new(Vec::new());
+        write_tmd(&mut buffer, &doc, WriteMode::default()).expect("write");
+        assert!(buffer.get_ref().starts_with(b"PK\x03\x04"));
+
+        buffer.seek(SeekFrom::Start(0)).unwrap();
+        let mut reader = Reader::new(buffer, None, ReadMode::default()).expect("reader");
+        assert_eq!(reader.format(), Format::Tmd);
+        let rebuilt = reader.read_doc().expect("read");
+        assert!(rebuilt.markdown.is_empty());
+        assert!(rebuilt.content_eq(&doc));
+
+        let mut tmdz = Cursor::new(Vec::new());
+        write_tmdz(&mut tmdz, &doc, WriteMode::default()).expect("write");
+        tmdz.seek(SeekFrom::Start(0)).unwrap();
+        let reader = Reader::new(tmdz, None, ReadMode::default()).expect("reader");
+        assert_eq!(reader.format(), Format::Tmdz);
+    }
+
+    #[test]
+    fn tmd_roundtrip_preserves_content() {
+        let doc = build_doc_with_attachment();
+        let mut buffer = Cursor::new(Vec::new());
+        write_tmd(&mut buffer, &doc, WriteMode::default()).expect("write");
+        buffer.seek(SeekFrom::Start(0)).unwrap();
+        let mut reader =
+            Reader::new(buffer, Some(Format::Tmd), ReadMode::default()).expect("reader");
+        let rebuilt = reader.read_doc().expect("read");
+
+        assert!(rebuilt.content_eq(&doc));
+        assert!(rebuilt.content_eq_with_db(&doc).expect("compare db"));
+        assert_eq!(rebuilt.attachments, doc.attachments);
+        assert_eq!(rebuilt.markdown, doc.markdown);
+        assert_eq!(rebuilt.manifest.title, doc.manifest.title);
+        assert_eq!(
+            rebuilt.manifest.db_schema_version,
+            doc.manifest.db_schema_version
+        );
+
+        let original_meta = doc
+            .list_attachments()
+            .next()
+            .expect("original attachment meta");
+        let rebuilt_meta = rebuilt
+            .list_attachments()
+            .next()
+            .expect("rebuilt attachment meta");
+        assert_eq!(original_meta.logical_path, rebuilt_meta.logical_path);
+        assert_eq!(original_meta.length, rebuilt_meta.length);
+        assert_eq!(
+            rebuilt.attachments.data(rebuilt_meta.id).unwrap(),
+            &[0, 1, 2, 3]
+        );
+
+        let user_version: u32 = rebuilt
+            .db_with_conn(|conn| {
+                conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+                    .unwrap()
+            })
+            .expect("user version");
+        assert_eq!(user_version, 2);
+    }
+
+    #[test]
+    fn byte_conversions_roundtrip() {
+        let doc = build_doc_with_attachment();
+        let bytes = Vec::<u8>::try_from(&doc).expect("to bytes");
+        assert_eq!(sniff_format(&bytes), Some(Format::Tmd));
+        let rebuilt = TmdDoc::try_from(bytes.as_slice()).expect("from bytes");
+        assert!(rebuilt.content_eq(&doc));
+
+        let tmdz = doc.to_bytes(Format::Tmdz).expect("tmdz bytes");
+        assert!(TmdDoc::try_from(tmdz.as_slice()).unwrap().content_eq(&doc));
+        assert!(matches!(
+            TmdDoc::try_from(&b"not a document"[..]),
+            Err(TmdError::InvalidFormat(_))
+        ));
+    }
+
+    #[test]
+    fn tmdz_roundtrip_preserves_content() {
+        let doc = build_doc_with_attachment();
+        let mut buffer = Cursor::new(Vec::new());
+        write_tmdz(&mut buffer, &doc, WriteMode::default()).expect("write");
+        buffer.seek(SeekFrom::Start(0)).unwrap();
+        let mut reader =
+            Reader::new(buffer, Some(Format::Tmdz), ReadMode::default()).expect("reader");
+        let rebuilt = reader.read_doc().expect("read");
+        assert_eq!(rebuilt.markdown, doc.markdown);
+        assert_eq!(rebuilt.manifest.title, doc.manifest.title);
+    }
+
+    #[test]
+    fn existing_zip_comment_survives_tmd_conversion() {
+        let mut doc = build_doc_with_attachment();
+        doc.zip_comment = b"built by site-generator 2.1".to_vec();
+        let tmdz = doc.to_bytes(Format::Tmdz).unwrap();
+        let from_tmdz = TmdDoc::try_from(tmdz.as_slice()).unwrap();
+        assert_eq!(from_tmdz.zip_comment, doc.zip_comment);
+
+        let tmd = from_tmdz.to_bytes(Format::Tmd).unwrap();
+        assert!(tmd.ends_with(&(doc.markdown.len() as u64).to_le_bytes()));
+        let zip = zip::ZipArchive::new(Cursor::new(tmd[doc.markdown.len()..].to_vec())).unwrap();
+        assert!(zip
+            .comment()
+            .starts_with(b"built by site-generator 2.1TMD1\0"));
+
+        let rebuilt = TmdDoc::try_from(tmd.as_slice()).unwrap();
+        assert_eq!(rebuilt.zip_comment, doc.zip_comment);
+        assert_eq!(rebuilt.markdown, doc.markdown);
+    }
+
+    #[test]
+    fn read_tmd_uses_zip_markdown_for_zero_length_prefix() {
+        let doc = build_doc_with_attachment();
+        let mut zip = Cursor::new(Vec::new());
+        write_tmdz(&mut zip, &doc, WriteMode::default()).expect("write");
+        let mut bytes = zip.into_inner();
+
+        // Attach a TMD comment declaring an empty markdown prefix.
+        let eocd = bytes.len() - 22;
+        let mut comment = b"TMD1\0".to_vec();
+        comment.extend_from_slice(&0u64.to_le_bytes());
+        bytes[eocd + 20..eocd + 22].copy_from_slice(&(comment.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(&comment);
+
+        let rebuilt = read_tmd(&mut Cursor::new(bytes), ReadMode::default()).expect("read");
+        assert_eq!(rebuilt.markdown, doc.markdown);
+        assert_eq!(rebuilt.list_attachments().count(), 1);
+    }
+
+    #[test]
+    fn preserve_timestamps_reapplies_archive_entry_times() {
+        let doc = build_doc_with_attachment();
+        let mut buffer = Cursor::new(Vec::new());
+        write_tmdz(&mut buffer, &doc, WriteMode::default()).expect("write");
+        let mut rebuilt = read_tmdz(&mut buffer, ReadMode::default()).expect("read");
+        let id = rebuilt.list_attachments().next().unwrap().id;
+        assert!(rebuilt.attachment_archive_modified(id).is_some());
+
+        let fixed = NaiveDate::from_ymd_opt(2001, 2, 3)
+            .unwrap()
+            .and_hms_opt(4, 5, 6)
+            .unwrap();
+        rebuilt
+            .attachments
+            .set_archive_modified(id, Some(fixed))
+            .unwrap();
+
+        let entry_year = |mode: WriteMode| {
+            let mut out = Cursor::new(Vec::new());
+            write_tmdz(&mut out, &rebuilt, mode).expect("rewrite");
+            let mut zip = zip::ZipArchive::new(out).expect("zip");
+            let file = zip.by_name("images/pixel.png").expect("entry");
+            file.last_modified().year()
+        };
+        let preserving = WriteMode {
+            preserve_timestamps: true,
+            ..WriteMode::default()
+        };
+        assert_eq!(entry_year(preserving), 2001);
+        assert_ne!(entry_year(WriteMode::default()), 2001);
+    }
+
+    #[test]
+    fn entry_times_follow_the_installed_clock() {
+        let doc = build_doc_with_attachment();
+        let fixed = chrono::DateTime::parse_from_rfc3339("2003-04-05T06:07:08Z")
+            .unwrap()
+            .with_timezone(&chrono::Utc);
+        let write = || {
+            with_clock(FixedClock(fixed), || {
+                let mut out = Cursor::new(Vec::new());
+                write_tmdz(&mut out, &doc, WriteMode::default()).expect("write");
+                out.into_inner()
+            })
+        };
+        let bytes = write();
+        assert_eq!(bytes, write());
+
+        let mut zip = zip::ZipArchive::new(Cursor::new(bytes)).expect("zip");
+        for index in 0..zip.len() {
+            let file = zip.by_index(index).expect("entry");
+            let time = file.last_modified();
+            assert_eq!(
+                (time.year(), time.month(), time.day(), time.hour()),
+                (2003, 4, 5, 6),
+                "{}",
+                file.name()
+            );
+        }
+    }
+
+    #[test]
+    fn explode_and_reassemble_directory() {
+        let doc = build_doc_with_attachment();
+        let dir = tempdir().unwrap();
+        doc.explode_to_dir(dir.path()).expect("explode");
+
+        for part in [
+            "index.md",
+            "manifest.json",
+            "attachments.json",
+            "db/main.sqlite3",
+            "images/pixel.png",
+        ] {
+            assert!(dir.path().join(part).is_file(), "missing {}", part);
+        }
+
+        let rebuilt = TmdDoc::from_exploded_dir(dir.path()).expect("reassemble");
+        assert!(rebuilt.content_eq_with_db(&doc).expect("compare"));
+
+        std::fs::write(dir.path().join("images/pixel.png"), b"tampered").unwrap();
+        assert!(TmdDoc::from_exploded_dir(dir.path()).is_err());
+    }
+
+    #[test]
+    fn json_roundtrip_is_lossless() {
+        let doc = build_doc_with_attachment();
+        let json = doc.to_json().unwrap();
+        assert_eq!(json["markdown"], doc.markdown.as_str());
+        assert_eq!(json["attachments"][0]["data_base64"], "AAECAw==");
+
+        let rebuilt = TmdDoc::from_json(json.clone()).unwrap();
+        assert!(rebuilt.content_eq_with_db(&doc).unwrap());
+
+        let mut tampered = json;
+        tampered["attachments"][0]["data_base64"] = "AAECBA==".into();
+        assert!(matches!(
+            TmdDoc::from_json(tampered),
+            Err(TmdError::Attachment(_))
+        ));
+    }
+
+    #[test]
+    fn write_to_path_best_picks_the_smaller_container() {
+        let dir = tempdir().unwrap();
+        let mut doc = sample_doc();
+        doc.markdown = "# Repetitive\n\n".to_string() + &"all work and no play\n".repeat(2000);
+
+        let (path, format) =
+            write_to_path_best(dir.path().join("notes"), &doc, WriteMode::default()).unwrap();
+        assert_eq!(format, Format::Tmdz);
+        assert_eq!(path, dir.path().join("notes.tmdz"));
+        let written = std::fs::read(&path).unwrap();
+        assert_eq!(written.len(), doc.to_bytes(Format::Tmdz).unwrap().len());
+        assert!(written.len() < doc.to_bytes(Format::Tmd).unwrap().len());
+        assert_eq!(read_from_path(&path, None).unwrap().markdown, doc.markdown);
+
+        // Without text compression the archives match, so the readable container wins.
+        let stored = WriteMode {
+            deflate_text: false,
+            ..WriteMode::default()
+        };
+        let (path, format) = write_to_path_best(dir.path().join("plain"), &doc, stored).unwrap();
+        assert_eq!(format, Format::Tmd);
+        assert_eq!(path, dir.path().join("plain.tmd"));
+        assert!(std::fs::read(&path)
+            .unwrap()
+            .starts_with(doc.markdown.as_bytes()));
+        assert_eq!(read_from_path(&path, None).unwrap().markdown, doc.markdown);
+    }
+
+    #[test]
+    fn attachment_compression_overrides_hints_without_recording_them() {
+        let mut doc = build_doc_with_attachment();
+        let id = doc.attachment_meta_by_path("images/pixel.png").unwrap().id;
+        doc.set_attachment_compression(id, Some(CompressionHint::Store))
+            .unwrap();
+        let mode = WriteMode {
+            attachment_compression: Some(CompressionHint::Deflate),
+            ..WriteMode::default()
+        };
+        let mut out = Cursor::new(Vec::new());
+        write_tmdz(&mut out, &doc, mode).unwrap();
+
+        let bytes = out.into_inner();
+        let mut zip = zip::ZipArchive::new(Cursor::new(bytes.clone())).unwrap();
+        assert_eq!(
+            zip.by_name("images/pixel.png").unwrap().compression(),
+            zip::CompressionMethod::Deflated
+        );
+        let reread = read_tmdz(&mut Cursor::new(bytes), ReadMode::default()).unwrap();
+        assert_eq!(
+            reread.attachment_meta(id).unwrap().compression_hint,
+            Some(CompressionHint::Store)
+        );
+    }
+
+    #[test]
+    fn markdown_path_renames_the_markdown_entry() {
+        let mut doc = build_doc_with_attachment();
+        assert_eq!(doc.markdown_path(), "index.md");
+        assert!(doc.set_markdown_path(Some("manifest.json")).is_err());
+        assert!(doc.set_markdown_path(Some("images/pixel.png")).is_err());
+        assert!(doc.set_markdown_path(Some("../README.md")).is_err());
+        doc.set_markdown_path(Some("README.md")).unwrap();
+
+        for format in [Format::Tmd, Format::Tmdz] {
+            let bytes = doc.to_bytes(format).unwrap();
+            let start = if format == Format::Tmd {
+                doc.markdown.len()
+            } else {
+                0
+            };
+            let zip = zip::ZipArchive::new(Cursor::new(bytes[start..].to_vec())).unwrap();
+            let names: Vec<_> = zip.file_names().collect();
+            assert!(names.contains(&"README.md"));
+            assert!(!names.contains(&"index.md"));
+
+            let rebuilt = TmdDoc::try_from(bytes.as_slice()).unwrap();
+            assert!(rebuilt.content_eq(&doc));
+        }
+
+        let dir = tempdir().unwrap();
+        doc.explode_to_dir(dir.path()).unwrap();
+        assert!(dir.path().join("README.md").is_file());
+        let rebuilt = TmdDoc::from_exploded_dir(dir.path()).unwrap();
+        assert_eq!(rebuilt.markdown, doc.markdown);
+    }
+
+    #[test]
+    fn tmdz_deflates_text_entries_by_default() {
+        let mut doc = sample_doc();
+        doc.set_markdown("All work and no play makes Jack a dull boy.\n".repeat(2000));
+        doc.add_attachment("images/pixel.png", mime::IMAGE_PNG, vec![7u8; 64])
+            .unwrap();
+
+        let mut deflated = Cursor::new(Vec::new());
+        write_tmdz(&mut deflated, &doc, WriteMode::default()).unwrap();
+        let mut stored = Cursor::new(Vec::new());
+        let mode = WriteMode {
+            deflate_text: false,
+            ..WriteMode::default()
         };
+        write_tmdz(&mut stored, &doc, mode).unwrap();
+        assert!(deflated.get_ref().len() * 4 < stored.get_ref().len());
+
+        let mut zip = zip::ZipArchive::new(deflated).unwrap();
+        for (name, method) in [
+            ("index.md", zip::CompressionMethod::Deflated),
+            ("manifest.json", zip::CompressionMethod::Deflated),
+            ("attachments.json", zip::CompressionMethod::Deflated),
+            ("db/main.sqlite3", zip::CompressionMethod::Stored),
+            ("images/pixel.png", zip::CompressionMethod::Stored),
+        ] {
+            assert_eq!(zip.by_name(name).unwrap().compression(), method, "{}", name);
+        }
+        let rebuilt = read_tmdz(&mut zip.into_inner(), ReadMode::default()).unwrap();
+        assert!(rebuilt.content_eq(&doc));
+    }
+
+    #[test]
+    fn compression_hint_deflates_selected_attachments() {
+        let mut doc = sample_doc();
+        let csv = doc
+            .add_attachment("data/table.csv", TEXT_PLAIN, b"a,b\n".repeat(500))
+            .expect("add csv");
+        doc.add_attachment("data/raw.bin", TEXT_PLAIN, b"a,b\n".repeat(500))
+            .expect("add raw");
+        doc.set_attachment_compression(csv, Some(CompressionHint::Deflate))
+            .expect("set hint");
+
+        let mut buffer = Cursor::new(Vec::new());
+        write_tmd(&mut buffer, &doc, WriteMode::default()).expect("write");
+        let rebuilt = read_tmd(&mut buffer, ReadMode::default()).expect("read");
+        assert!(rebuilt.content_eq(&doc));
+        assert_eq!(
+            rebuilt.attachment_meta(csv).unwrap().compression_hint,
+            Some(CompressionHint::Deflate)
+        );
+
+        let mut zip_buffer = Cursor::new(Vec::new());
+        write_tmdz(&mut zip_buffer, &doc, WriteMode::default()).expect("write tmdz");
+        let mut zip = zip::ZipArchive::new(zip_buffer).expect("zip");
+        assert_eq!(
+            zip.by_name("data/table.csv").unwrap().compression(),
+            zip::CompressionMethod::Deflated
+        );
+        assert_eq!(
+            zip.by_name("data/raw.bin").unwrap().compression(),
+            zip::CompressionMethod::Stored
+        );
+    }
 
-        let path_buf = match path_from_ptr(path) {
-            Ok(path) => path,
-            Err(message) => {
-                set_last_error_message(message);
-                return -1;
-            }
-        };
+    #[test]
+    fn read_raw_manifest_returns_unparsed_json() {
+        let dir = tempdir().unwrap();
+        let doc = sample_doc();
+        let path = dir.path().join("doc.tmd");
+        write_to_path(&path, &doc, Format::Tmd).unwrap();
+        let raw = read_raw_manifest(&path).unwrap();
+        let parsed: Manifest = serde_json::from_str(&raw).unwrap();
+        assert_eq!(parsed.doc_id, doc.manifest.doc_id);
+        assert!(raw.contains('\n'), "stored pretty-printed: {}", raw);
+    }
 
-        let doc_ref = unsafe { &*doc };
-        match write_to_path(&path_buf, doc_ref, format) {
-            Ok(()) => {
-                clear_last_error();
-                0
-            }
-            Err(err) => {
-                set_last_error(err);
-                -1
-            }
+    #[test]
+    fn read_markdown_from_path_skips_the_rest() {
+        let dir = tempdir().unwrap();
+        let mut doc = build_doc_with_attachment();
+        for format in [Format::Tmd, Format::Tmdz] {
+            let path = dir.path().join(format!("doc.{}", format.extension()));
+            write_to_path(&path, &doc, format).unwrap();
+            assert_eq!(read_markdown_from_path(&path).unwrap(), doc.markdown);
         }
+
+        doc.set_markdown_path(Some("README.md")).unwrap();
+        let path = dir.path().join("renamed.tmdz");
+        write_to_path(&path, &doc, Format::Tmdz).unwrap();
+        assert_eq!(read_markdown_from_path(&path).unwrap(), doc.markdown);
     }
 
-    /// Retrieve the Markdown content of the document.
-    ///
-    /// The returned pointer must be released with [`tmd_string_free`].
-    ///
-    /// # Safety
-    ///
-    /// `doc` must either be null or point to a [`TmdDoc`] allocated by this
-    /// library.
-    #[no_mangle]
-    pub unsafe extern "C" fn tmd_doc_get_markdown(doc: *const TmdDoc) -> *mut c_char {
-        if doc.is_null() {
-            set_last_error_message(NULL_PTR_MESSAGE);
-            return ptr::null_mut();
-        }
+    #[test]
+    fn reader_with_len_fetches_only_what_it_needs() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use std::sync::Arc;
 
-        let doc_ref = unsafe { &*doc };
-        match c_string_from_str(&doc_ref.markdown) {
-            Ok(markdown) => {
-                clear_last_error();
-                markdown.into_raw()
-            }
-            Err(()) => {
-                set_last_error_message(INTERIOR_NUL_MESSAGE);
-                ptr::null_mut()
+        /// Serves absolute seeks only, like a store read through HTTP range requests.
+        struct RangeSource {
+            data: Vec<u8>,
+            pos: usize,
+            served: Arc<AtomicU64>,
+        }
+        impl Read for RangeSource {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let n = (&self.data[self.pos.min(self.data.len())..]).read(buf)?;
+                self.pos += n;
+                self.served.fetch_add(n as u64, Ordering::SeqCst);
+                Ok(n)
             }
         }
-    }
-
-    /// Replace the Markdown content of the document.
-    ///
-    /// # Safety
-    ///
-    /// `doc` must either be null or point to a [`TmdDoc`] allocated by this
-    /// library. `markdown` must either be null or point to a valid,
-    /// NUL-terminated UTF-8 string.
-    #[no_mangle]
-    pub unsafe extern "C" fn tmd_doc_set_markdown(
-        doc: *mut TmdDoc,
-        markdown: *const c_char,
-    ) -> i32 {
-        if doc.is_null() {
-            set_last_error_message(NULL_PTR_MESSAGE);
-            return -1;
+        impl Seek for RangeSource {
+            fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+                match pos {
+                    SeekFrom::Start(offset) => {
+                        self.pos = offset as usize;
+                        Ok(offset)
+                    }
+                    _ => Err(std::io::Error::other("only absolute seeks")),
+                }
+            }
         }
 
-        let markdown = match string_from_ptr(markdown) {
-            Ok(value) => value,
-            Err(message) => {
-                set_last_error_message(message);
-                return -1;
-            }
+        let mut doc = build_doc_with_attachment();
+        let big: Vec<u8> = (0..2_000_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let id = doc
+            .add_attachment("data/big.bin", mime::APPLICATION_OCTET_STREAM, big.clone())
+            .unwrap();
+        let bytes = doc.to_bytes(Format::Tmd).unwrap();
+        let len = bytes.len() as u64;
+        let served = Arc::new(AtomicU64::new(0));
+        let source = RangeSource {
+            data: bytes,
+            pos: 0,
+            served: served.clone(),
+        };
+        let mode = ReadMode {
+            lazy_attachments: true,
+            ..ReadMode::default()
         };
 
-        let doc_ref = unsafe { &mut *doc };
-        doc_ref.markdown = markdown;
-        doc_ref.touch();
-        clear_last_error();
-        0
+        let reader = Reader::with_len(source, len, None, mode).unwrap();
+        assert_eq!(reader.format(), Format::Tmd);
+        let lazy = reader.into_doc().unwrap();
+        assert_eq!(lazy.markdown, doc.markdown);
+        let before = served.load(Ordering::SeqCst);
+        assert!(
+            before < len / 4,
+            "read {} of {} bytes up front",
+            before,
+            len
+        );
+
+        assert_eq!(lazy.attachment_bytes(id).unwrap().as_ref(), big.as_slice());
+        assert!(served.load(Ordering::SeqCst) >= before + big.len() as u64);
     }
 
-    /// Release a document created by the FFI helpers.
-    ///
-    /// # Safety
-    ///
-    /// `doc` must be a pointer previously returned by this library or null.
-    /// Each document must be freed at most once.
-    #[no_mangle]
-    pub unsafe extern "C" fn tmd_doc_free(doc: *mut TmdDoc) {
-        if doc.is_null() {
-            return;
-        }
-        unsafe {
-            drop(Box::from_raw(doc));
+    #[test]
+    fn checkpoint_sync_renames_the_part_file() {
+        let dir = tempdir().unwrap();
+        let mut doc = build_doc_with_attachment();
+        doc.zip_comment = b"archived".to_vec();
+        let mode = WriteMode {
+            checkpoint_sync: true,
+            ..WriteMode::default()
+        };
+        for format in [Format::Tmd, Format::Tmdz] {
+            let path = dir.path().join(format!("doc.{}", format.extension()));
+            write_to_path_with_mode(&path, &doc, format, mode.clone()).unwrap();
+            assert!(!dir
+                .path()
+                .join(format!("doc.{}.part", format.extension()))
+                .exists());
+            assert_eq!(std::fs::read(&path).unwrap(), doc.to_bytes(format).unwrap());
         }
-        clear_last_error();
     }
 
-    /// Release a string allocated by the FFI helpers.
-    ///
-    /// # Safety
-    ///
-    /// `ptr` must be a pointer previously returned by this library or null.
-    /// Each string must be freed at most once.
-    #[no_mangle]
-    pub unsafe extern "C" fn tmd_string_free(ptr: *mut c_char) {
-        if ptr.is_null() {
-            return;
+    #[test]
+    fn serialized_size_matches_written_bytes() {
+        let doc = build_doc_with_attachment();
+        for format in [Format::Tmd, Format::Tmdz] {
+            let size = doc.serialized_size(format, WriteMode::default()).unwrap();
+            assert_eq!(size, doc.to_bytes(format).unwrap().len() as u64);
         }
-        unsafe {
-            drop(CString::from_raw(ptr));
+        let compact = WriteMode {
+            pretty_json: false,
+            ..WriteMode::default()
+        };
+        assert!(
+            doc.serialized_size(Format::Tmd, compact).unwrap()
+                < doc
+                    .serialized_size(Format::Tmd, WriteMode::default())
+                    .unwrap()
+        );
+    }
+
+    #[test]
+    fn compact_json_shrinks_large_documents() {
+        let mut doc = sample_doc();
+        for i in 0..1000 {
+            doc.add_attachment(&format!("data/{:04}.txt", i), TEXT_PLAIN, vec![i as u8])
+                .unwrap();
         }
+        let write = |pretty_json| {
+            let mut out = std::io::Cursor::new(Vec::new());
+            let mode = WriteMode {
+                pretty_json,
+                ..WriteMode::default()
+            };
+            write_tmd(&mut out, &doc, mode).unwrap();
+            out.into_inner()
+        };
+        let (pretty, compact) = (write(true), write(false));
+        // Roughly 18% of the file is indentation with one-byte attachments.
+        assert!(
+            compact.len() * 10 < pretty.len() * 9,
+            "compact {} vs pretty {}",
+            compact.len(),
+            pretty.len()
+        );
+        let reread = TmdDoc::try_from(compact.as_slice()).unwrap();
+        assert_eq!(reread.attachments.iter().count(), 1000);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use mime::TEXT_PLAIN;
-    use sha2::{Digest, Sha256};
-    use std::io::{Cursor, Seek, SeekFrom};
-    use tempfile::tempdir;
+    #[test]
+    fn compress_db_shrinks_sparse_databases() {
+        let mut doc = sample_doc();
+        doc.db_with_conn_mut(|conn| {
+            conn.execute_batch(
+                "CREATE TABLE blobs (data BLOB); INSERT INTO blobs VALUES (zeroblob(262144));",
+            )
+        })
+        .unwrap()
+        .unwrap();
+        let write = |compress_db| {
+            let mut out = Cursor::new(Vec::new());
+            let mode = WriteMode {
+                compress_db,
+                ..WriteMode::default()
+            };
+            write_tmdz(&mut out, &doc, mode).unwrap();
+            out.into_inner()
+        };
+        let (stored, deflated) = (write(false), write(true));
+        assert!(
+            deflated.len() * 10 < stored.len(),
+            "deflated {} vs stored {}",
+            deflated.len(),
+            stored.len()
+        );
 
-    fn sample_doc() -> TmdDoc {
-        TmdDoc::new("# Sample\n".to_string()).expect("doc creation")
+        let reread = read_tmdz(&mut Cursor::new(deflated), ReadMode::default()).unwrap();
+        let count = reread
+            .db_with_conn(|conn| {
+                conn.query_row("SELECT length(data) FROM blobs", [], |row| {
+                    row.get::<_, i64>(0)
+                })
+            })
+            .unwrap()
+            .unwrap();
+        assert_eq!(count, 262144);
     }
 
     #[test]
-    fn normalize_logical_path_rejects_invalid_segments() {
-        assert!(normalize_logical_path("foo/../bar").is_err());
-        assert!(normalize_logical_path("/absolute").is_err());
+    fn record_mode_round_trips_through_extras() {
+        let doc = build_doc_with_attachment();
+        let mode = WriteMode {
+            record_mode: true,
+            pretty_json: false,
+            order_by: OrderBy::MimeThenPath,
+            ..WriteMode::default()
+        };
         assert_eq!(
-            normalize_logical_path("images/figure.png").unwrap(),
-            "images/figure.png"
+            mode.summary(),
+            "order_by=\"mime_then_path\" pretty_json=false record_mode=true"
         );
+        assert_eq!(WriteMode::default().summary(), "default");
+        assert_eq!(ReadMode::default().summary(), "default");
+
+        let mut out = Cursor::new(Vec::new());
+        write_tmd(&mut out, &doc, mode).unwrap();
+        let reread = TmdDoc::try_from(out.into_inner().as_slice()).unwrap();
+        let recorded = reread.last_write_mode().expect("recorded mode");
+        assert!(recorded.record_mode);
+        assert!(!recorded.pretty_json);
+        assert_eq!(recorded.order_by, OrderBy::MimeThenPath);
+
+        assert!(doc.last_write_mode().is_none());
+        let plain = TmdDoc::try_from(doc.to_bytes(Format::Tmd).unwrap().as_slice()).unwrap();
+        assert!(plain.last_write_mode().is_none());
+
+        // Re-saving without `record_mode` drops the stale record.
+        let resaved = TmdDoc::try_from(reread.to_bytes(Format::Tmd).unwrap().as_slice()).unwrap();
+        assert!(resaved.last_write_mode().is_none());
     }
 
     #[test]
-    fn new_doc_initializes_database() {
-        let doc = sample_doc();
-        let result = doc
-            .db_with_conn(|conn| {
-                conn.query_row("SELECT 1", [], |row| row.get::<_, i32>(0))
-                    .unwrap()
-            })
-            .expect("db query");
-        assert_eq!(result, 1);
+    fn tmdz_trailing_bytes_warn_or_fail() {
+        let doc = build_doc_with_attachment();
+        let mut bytes = doc.to_bytes(Format::Tmdz).unwrap();
+        bytes.extend_from_slice(b"garbage");
+
+        let lenient = read_tmd_buffered(bytes.as_slice(), ReadMode::default()).unwrap();
+        assert_eq!(lenient.markdown, doc.markdown);
+
+        let strict = ReadMode {
+            strict_trailing: true,
+            ..ReadMode::default()
+        };
+        assert!(matches!(
+            read_tmd_buffered(bytes.as_slice(), strict),
+            Err(TmdError::InvalidFormat(msg)) if msg.starts_with("7 trailing bytes")
+        ));
+        assert!(probe(&bytes)
+            .problems
+            .contains(&"7 trailing bytes after the ZIP archive".to_string()));
     }
 
     #[test]
-    fn attachment_lifecycle() {
-        let mut doc = sample_doc();
-        let attachment_id = doc
-            .add_attachment("attachments/data.bin", TEXT_PLAIN, vec![1, 2, 3])
-            .expect("add attachment");
-        let meta = doc.attachment_meta(attachment_id).expect("meta exists");
-        assert_eq!(meta.logical_path, "attachments/data.bin");
-        assert_eq!(meta.length, 3);
+    fn probe_reports_container_structure() {
+        let doc = build_doc_with_attachment();
+        let tmd = doc.to_bytes(Format::Tmd).unwrap();
+        let report = probe(&tmd);
+        assert_eq!(report.format, Some(Format::Tmd));
+        assert!(report.has_marker && report.markdown_len_valid);
+        assert_eq!(report.markdown_len, Some(doc.markdown.len() as u64));
+        assert_eq!(report.entry_count, Some(5));
+        assert_eq!(report.reserved_present.len(), 4);
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+
+        let tmdz = probe(&doc.to_bytes(Format::Tmdz).unwrap());
+        assert_eq!(tmdz.format, Some(Format::Tmdz));
+        assert!(!tmdz.has_marker && tmdz.problems.is_empty());
+
+        // Declare one byte too many of markdown.
+        let mut shifted = tmd.clone();
+        let at = shifted.len() - 8;
+        let declared = doc.markdown.len() as u64 + 1;
+        shifted[at..].copy_from_slice(&declared.to_le_bytes());
+        let report = probe(&shifted);
+        assert!(!report.markdown_len_valid);
+        assert!(report.problems[0].contains("does not point at the ZIP payload"));
+
+        let garbage = probe(b"not a document");
+        assert_eq!(garbage.eocd_offset, None);
+        assert_eq!(garbage.problems.len(), 1);
+    }
 
-        doc.rename_attachment(attachment_id, "data/renamed.bin")
-            .expect("rename");
-        assert!(doc
-            .attachment_meta_by_path("attachments/data.bin")
-            .is_none());
-        assert!(doc.attachment_meta_by_path("data/renamed.bin").is_some());
+    fn crc32(data: &[u8]) -> u32 {
+        let mut crc = !0u32;
+        for &byte in data {
+            crc ^= byte as u32;
+            for _ in 0..8 {
+                crc = if crc & 1 != 0 {
+                    (crc >> 1) ^ 0xEDB8_8320
+                } else {
+                    crc >> 1
+                };
+            }
+        }
+        !crc
+    }
 
-        doc.remove_attachment(attachment_id).expect("remove");
-        assert!(doc.attachment_meta(attachment_id).is_none());
+    /// Build a stored ZIP the way a streaming writer does: local headers carry flag
+    /// bit 3 and zero sizes, and a data descriptor follows each entry's data. Every
+    /// other descriptor omits the optional signature, giving 12- and 16-byte forms.
+    fn streamed_zip(entries: &[(String, Vec<u8>)]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut central = Vec::new();
+        for (index, (name, data)) in entries.iter().enumerate() {
+            let offset = out.len() as u32;
+            let crc = crc32(data);
+            let size = data.len() as u32;
+            out.extend_from_slice(&[0x50, 0x4b, 0x03, 0x04, 20, 0, 0x08, 0, 0, 0, 0, 0, 0, 0]);
+            out.extend_from_slice(&[0; 12]);
+            out.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            out.extend_from_slice(&0u16.to_le_bytes());
+            out.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(data);
+            if index % 2 == 0 {
+                out.extend_from_slice(&[0x50, 0x4b, 0x07, 0x08]);
+            }
+            for value in [crc, size, size] {
+                out.extend_from_slice(&value.to_le_bytes());
+            }
+
+            central.extend_from_slice(&[0x50, 0x4b, 0x01, 0x02, 20, 0, 20, 0, 0x08, 0]);
+            central.extend_from_slice(&[0; 6]);
+            for value in [crc, size, size] {
+                central.extend_from_slice(&value.to_le_bytes());
+            }
+            central.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            central.extend_from_slice(&[0; 12]);
+            central.extend_from_slice(&offset.to_le_bytes());
+            central.extend_from_slice(name.as_bytes());
+        }
+        let central_offset = out.len() as u32;
+        out.extend_from_slice(&central);
+        out.extend_from_slice(&[0x50, 0x4b, 0x05, 0x06, 0, 0, 0, 0]);
+        out.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(central.len() as u32).to_le_bytes());
+        out.extend_from_slice(&central_offset.to_le_bytes());
+        out.extend_from_slice(&0u16.to_le_bytes());
+        out
     }
 
     #[test]
-    fn attachment_data_mut_refreshes_metadata() {
-        let mut doc = sample_doc();
-        let attachment_id = doc
-            .add_attachment("attachments/blob.bin", TEXT_PLAIN, vec![0, 1, 2, 3])
-            .expect("add attachment");
+    fn reads_archives_with_data_descriptors() {
+        let doc = build_doc_with_attachment();
+        let mut source =
+            zip::ZipArchive::new(Cursor::new(doc.to_bytes(Format::Tmdz).unwrap())).unwrap();
+        let entries: Vec<(String, Vec<u8>)> = (0..source.len())
+            .map(|index| {
+                let mut file = source.by_index(index).unwrap();
+                let mut data = Vec::new();
+                file.read_to_end(&mut data).unwrap();
+                (file.name().to_string(), data)
+            })
+            .collect();
+        let streamed = streamed_zip(&entries);
 
-        {
-            let mut data = doc
-                .attachments
-                .data_mut(attachment_id)
-                .expect("mutable handle");
-            data.extend_from_slice(&[4, 5, 6]);
-        }
+        let report = probe(&streamed);
+        assert_eq!(report.streamed_entries, entries.len());
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+        assert_eq!(
+            probe(&doc.to_bytes(Format::Tmdz).unwrap()).streamed_entries,
+            0
+        );
 
-        let meta = doc
-            .attachment_meta(attachment_id)
-            .expect("updated metadata");
-        assert_eq!(meta.length, 7);
+        let eager = read_tmdz(&mut Cursor::new(streamed.clone()), ReadMode::default()).unwrap();
+        assert!(eager.content_eq(&doc));
 
-        let expected = {
-            let digest = Sha256::digest([0, 1, 2, 3, 4, 5, 6]);
-            let mut arr = [0u8; 32];
-            arr.copy_from_slice(&digest);
-            arr
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("streamed.tmdz");
+        std::fs::write(&path, &streamed).unwrap();
+        let mode = ReadMode {
+            lazy_attachments: true,
+            ..ReadMode::default()
         };
-        assert_eq!(meta.sha256, Some(expected));
+        let lazy = read_from_path_with_mode(&path, None, mode).unwrap();
+        let id = lazy.attachment_meta_by_path("images/pixel.png").unwrap().id;
+        assert_eq!(lazy.attachment_bytes(id).unwrap().as_ref(), &[0, 1, 2, 3]);
     }
 
     #[test]
-    fn writing_after_mutation_keeps_manifest_consistent() {
-        let mut doc = sample_doc();
-        let attachment_id = doc
-            .add_attachment("attachments/data.bin", TEXT_PLAIN, vec![1, 2, 3, 4])
-            .expect("add attachment");
+    fn read_spooled_parses_streamed_input() {
+        let doc = build_doc_with_attachment();
+        for format in [Format::Tmd, Format::Tmdz] {
+            let bytes = doc.to_bytes(format).unwrap();
+            // `&[u8]` implements `Read` but not `Seek`.
+            let rebuilt = read_spooled(bytes.as_slice(), ReadMode::default()).unwrap();
+            assert!(rebuilt.content_eq(&doc));
+        }
+        assert!(read_spooled(&b"garbage"[..], ReadMode::default()).is_err());
+    }
 
-        {
-            let mut data = doc
-                .attachments
-                .data_mut(attachment_id)
-                .expect("mutable handle");
-            data.extend_from_slice(&[5, 6]);
+    #[test]
+    fn read_tmd_buffered_accepts_plain_readers() {
+        let doc = build_doc_with_attachment();
+        for format in [Format::Tmd, Format::Tmdz] {
+            let mut buffer = Cursor::new(Vec::new());
+            Writer::new(&mut buffer, format, WriteMode::default())
+                .unwrap()
+                .write_doc(&doc)
+                .expect("write");
+            let bytes = buffer.into_inner();
+            // `&[u8]` implements `Read` but not `Seek`.
+            let rebuilt = read_tmd_buffered(bytes.as_slice(), ReadMode::default()).expect("read");
+            assert!(rebuilt.content_eq(&doc));
+        }
+        assert!(read_tmd_buffered(std::io::empty(), ReadMode::default()).is_err());
+    }
+
+    #[test]
+    fn validate_manifest_rejects_dangling_references() {
+        let strict = ReadMode {
+            validate_manifest: true,
+            ..ReadMode::default()
+        };
+        let write_and_read = |doc: &TmdDoc, mode: ReadMode| {
+            let mut buffer = Cursor::new(Vec::new());
+            write_tmdz(&mut buffer, doc, WriteMode::default()).expect("write");
+            read_tmdz(&mut buffer, mode)
+        };
+
+        let mut doc = build_doc_with_attachment();
+        let id = doc.list_attachments().next().unwrap().id;
+        doc.manifest.cover_image = Some(AttachmentRef { id });
+        doc.manifest.links = vec![
+            LinkRef {
+                rel: "figure".into(),
+                href: "attach:images/pixel.png".into(),
+            },
+            LinkRef {
+                rel: "home".into(),
+                href: "https://example.com/".into(),
+            },
+        ];
+        write_and_read(&doc, strict).expect("valid references");
+
+        let missing_id = Uuid::new_v4();
+        doc.manifest.cover_image = Some(AttachmentRef { id: missing_id });
+        match write_and_read(&doc, strict) {
+            Err(TmdError::InvalidFormat(message)) => {
+                assert!(message.contains(&missing_id.to_string()))
+            }
+            other => panic!("expected dangling cover error, got {:?}", other.err()),
+        }
+        write_and_read(&doc, ReadMode::default()).expect("lenient read");
+
+        doc.manifest.cover_image = Some(AttachmentRef { id });
+        doc.manifest.links.push(LinkRef {
+            rel: "data".into(),
+            href: "data/missing.csv".into(),
+        });
+        match write_and_read(&doc, strict) {
+            Err(TmdError::InvalidFormat(message)) => {
+                assert!(message.contains("data/missing.csv"))
+            }
+            other => panic!("expected dangling link error, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn sniff_format_detects_variants() {
+        assert_eq!(sniff_format(b"PK\x03\x04"), Some(Format::Tmdz));
+        assert_eq!(sniff_format(b"#"), Some(Format::Tmd));
+        assert_eq!(sniff_format(b""), None);
+    }
+
+    #[test]
+    fn manifest_timestamps_use_millisecond_resolution() {
+        let mut doc = sample_doc();
+        doc.touch();
+        for ts in [doc.manifest.created_utc, doc.manifest.modified_utc] {
+            assert_eq!(ts.timestamp_subsec_nanos() % 1_000_000, 0);
         }
+        let json = serde_json::to_string(&doc.manifest).unwrap();
+        let parsed: Manifest = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, doc.manifest);
+    }
+
+    #[test]
+    fn set_markdown_derives_missing_title() {
+        let mut doc = TmdDoc::new(String::new()).expect("doc");
+        let before = doc.manifest.modified_utc;
+        doc.set_markdown("```\n# not a heading\n```\n\n## Sub\n\n# Real `Title`\n");
+        assert_eq!(doc.manifest.title.as_deref(), Some("Real Title"));
+        assert!(doc.manifest.modified_utc >= before);
+
+        doc.set_markdown("# Other\n");
+        assert_eq!(doc.markdown(), "# Other\n");
+        assert_eq!(doc.manifest.title.as_deref(), Some("Real Title"));
+    }
+
+    #[test]
+    fn language_is_validated_and_round_trips() {
+        let mut doc = build_doc_with_attachment();
+        assert!(doc.set_language(Some("")).is_err());
+        assert!(doc.set_language(Some("en US")).is_err());
+        assert!(doc.set_language(Some("en--US")).is_err());
+        doc.set_language(Some("ja-JP")).expect("valid tag");
 
         let mut buffer = Cursor::new(Vec::new());
-        write_tmd(&mut buffer, &doc, WriteMode::default()).expect("write");
-        buffer.seek(SeekFrom::Start(0)).unwrap();
-        let mut reader =
-            Reader::new(buffer, Some(Format::Tmd), ReadMode::default()).expect("reader");
-        let rebuilt = reader.read_doc().expect("read");
+        write_tmdz(&mut buffer, &doc, WriteMode::default()).expect("write");
+        let rebuilt = read_tmdz(&mut buffer, ReadMode::default()).expect("read");
+        assert_eq!(rebuilt.language(), Some("ja-JP"));
+
+        let legacy: Manifest = serde_json::from_value(serde_json::json!({
+            "tmd_version": {"major": 1, "minor": 0, "patch": 0},
+            "doc_id": Uuid::nil(),
+            "title": null,
+            "authors": [],
+            "created_utc": "2024-01-01T00:00:00Z",
+            "modified_utc": "2024-01-01T00:00:00Z",
+            "tags": [],
+            "cover_image": null,
+            "links": [],
+            "db_schema_version": null
+        }))
+        .expect("manifest without language");
+        assert_eq!(legacy.language, None);
+    }
 
-        let rebuilt_meta = rebuilt
-            .attachment_meta(attachment_id)
-            .expect("attachment meta");
-        assert_eq!(rebuilt_meta.length, 6);
+    #[test]
+    fn data_engine_round_trips_and_is_optional() {
+        let mut doc = sample_doc();
+        assert!(doc.data_engine().is_none());
+        let json = serde_json::to_value(&doc.manifest).unwrap();
+        assert!(json.get("data").is_none());
+
+        doc.set_data_engine(Some(DataSection {
+            engine: "sheet".into(),
+            entry: "main".into(),
+        }));
+        let json = serde_json::to_string(&doc.manifest).unwrap();
+        let parsed: Manifest = serde_json::from_str(&json).unwrap();
         assert_eq!(
-            rebuilt.attachments.data(attachment_id).unwrap(),
-            &[1, 2, 3, 4, 5, 6]
+            parsed.data.as_ref().map(|d| d.engine.as_str()),
+            Some("sheet")
         );
+        assert_eq!(parsed, doc.manifest);
     }
 
-    fn build_doc_with_attachment() -> TmdDoc {
+    #[test]
+    fn tag_and_author_helpers_report_changes() {
         let mut doc = sample_doc();
-        doc.markdown.push_str("Body text\n");
-        doc.manifest.title = Some("Roundtrip".into());
-        doc.manifest.tags = vec!["report".into()];
-        doc.add_attachment(
-            "images/pixel.png",
-            "image/png".parse().unwrap(),
-            vec![0, 1, 2, 3],
-        )
-        .expect("add attachment");
-        doc.db_with_conn_mut(|conn| {
-            conn.execute("CREATE TABLE items(id INTEGER PRIMARY KEY, name TEXT)", [])
-                .unwrap();
-            conn.execute("INSERT INTO items(name) VALUES ('apricot')", [])
-                .unwrap();
-            conn.pragma_update(None, "user_version", 2).unwrap();
-        })
-        .expect("populate db");
-        doc.manifest.db_schema_version = Some(2);
-        doc
+        assert!(doc.add_tag("draft"));
+        assert!(!doc.add_tag("draft"));
+        assert!(doc.add_author("Ada"));
+        assert!(!doc.add_author("Ada"));
+        assert!(doc.set_authors(["Grace"]));
+        assert!(!doc.set_authors(vec!["Grace".to_string()]));
+        assert!(doc.remove_tag("draft"));
+        assert!(!doc.remove_tag("draft"));
+        assert!(doc.manifest.tags.is_empty());
+        assert_eq!(doc.manifest.authors, vec!["Grace"]);
     }
 
     #[test]
-    fn tmd_roundtrip_preserves_content() {
-        let doc = build_doc_with_attachment();
-        let mut buffer = Cursor::new(Vec::new());
-        write_tmd(&mut buffer, &doc, WriteMode::default()).expect("write");
-        buffer.seek(SeekFrom::Start(0)).unwrap();
-        let mut reader =
-            Reader::new(buffer, Some(Format::Tmd), ReadMode::default()).expect("reader");
-        let rebuilt = reader.read_doc().expect("read");
-
-        assert_eq!(rebuilt.markdown, doc.markdown);
-        assert_eq!(rebuilt.manifest.title, doc.manifest.title);
-        assert_eq!(
-            rebuilt.manifest.db_schema_version,
-            doc.manifest.db_schema_version
-        );
+    fn authors_accept_strings_and_objects() {
+        let mut doc = sample_doc();
+        assert!(doc.add_author("Ada"));
+        let grace = Author {
+            name: "Grace".into(),
+            email: Some("grace@example.org".into()),
+            affiliation: None,
+        };
+        assert!(doc.add_author_full(grace.clone()));
+        assert!(!doc.add_author_full(grace.clone()));
+        assert!(!doc.add_author("Grace"));
 
-        let original_meta = doc
-            .list_attachments()
-            .next()
-            .expect("original attachment meta");
-        let rebuilt_meta = rebuilt
-            .list_attachments()
-            .next()
-            .expect("rebuilt attachment meta");
-        assert_eq!(original_meta.logical_path, rebuilt_meta.logical_path);
-        assert_eq!(original_meta.length, rebuilt_meta.length);
+        let json = serde_json::to_value(&doc.manifest).unwrap();
         assert_eq!(
-            rebuilt.attachments.data(rebuilt_meta.id).unwrap(),
-            &[0, 1, 2, 3]
+            json["authors"],
+            serde_json::json!(["Ada", {"name": "Grace", "email": "grace@example.org"}])
         );
+        let parsed: Manifest = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed.authors, [Author::new("Ada"), grace]);
 
-        let user_version: u32 = rebuilt
-            .db_with_conn(|conn| {
-                conn.query_row("PRAGMA user_version", [], |row| row.get(0))
-                    .unwrap()
-            })
-            .expect("user version");
-        assert_eq!(user_version, 2);
+        let mut full = Author::new("Ada");
+        full.affiliation = Some("Analytical Engines".into());
+        assert!(doc.add_author_full(full.clone()));
+        assert_eq!(doc.manifest.authors[0], full);
     }
 
     #[test]
-    fn tmdz_roundtrip_preserves_content() {
+    fn write_to_path_atomic_replaces_existing_file() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("atomic.tmd");
+        std::fs::write(&path, b"stale").unwrap();
+
         let doc = build_doc_with_attachment();
-        let mut buffer = Cursor::new(Vec::new());
-        write_tmdz(&mut buffer, &doc, WriteMode::default()).expect("write");
-        buffer.seek(SeekFrom::Start(0)).unwrap();
-        let mut reader =
-            Reader::new(buffer, Some(Format::Tmdz), ReadMode::default()).expect("reader");
-        let rebuilt = reader.read_doc().expect("read");
-        assert_eq!(rebuilt.markdown, doc.markdown);
-        assert_eq!(rebuilt.manifest.title, doc.manifest.title);
+        write_to_path_atomic(&path, &doc, Format::Tmd).expect("atomic write");
+        let loaded = read_from_path(&path, None).expect("read back");
+        assert!(loaded.content_eq(&doc));
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
     }
 
     #[test]
-    fn sniff_format_detects_variants() {
-        assert_eq!(sniff_format(b"PK\x03\x04"), Some(Format::Tmdz));
-        assert_eq!(sniff_format(b"#"), Some(Format::Tmd));
-        assert_eq!(sniff_format(b""), None);
+    fn write_to_path_auto_infers_format() {
+        assert_eq!(Format::from_extension("TMDZ"), Some(Format::Tmdz));
+        assert_eq!(Format::from_extension("md"), None);
+
+        let doc = build_doc_with_attachment();
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("auto.tmdz");
+        write_to_path_auto(&path, &doc).expect("write auto");
+        let header = std::fs::read(&path).unwrap();
+        assert_eq!(sniff_format(&header), Some(Format::Tmdz));
+
+        let err = write_to_path_auto(dir.path().join("auto.txt"), &doc);
+        assert!(matches!(err, Err(TmdError::InvalidFormat(_))));
     }
 
     #[test]
@@ -1707,6 +9312,309 @@ mod tests {
         assert_eq!(version, 1);
     }
 
+    #[test]
+    fn db_query_returns_typed_cells() {
+        let mut doc = sample_doc();
+        doc.db_with_conn_mut(|conn| {
+            conn.execute_batch(
+                "CREATE TABLE cells(i INTEGER, t TEXT, b BLOB, n TEXT);
+                 INSERT INTO cells VALUES (7, 'line1\nline2', x'00ff', NULL);",
+            )
+            .unwrap();
+        })
+        .unwrap();
+
+        let result = doc.db_query("SELECT i, t, b, n FROM cells").expect("query");
+        assert_eq!(result.columns, vec!["i", "t", "b", "n"]);
+        assert_eq!(
+            result.rows,
+            vec![vec![
+                SqlCell::Integer(7),
+                SqlCell::Text("line1\nline2".into()),
+                SqlCell::Blob(vec![0x00, 0xff]),
+                SqlCell::Null,
+            ]]
+        );
+
+        let json = serde_json::to_value(&result).expect("json");
+        assert_eq!(json["rows"][0][2], "AP8=");
+        assert!(json["rows"][0][3].is_null());
+    }
+
+    #[test]
+    fn db_schema_sql_lists_user_objects() {
+        let mut doc = sample_doc();
+        doc.db_with_conn_mut(|conn| {
+            conn.execute_batch(
+                "CREATE TABLE notes(id INTEGER PRIMARY KEY, body TEXT UNIQUE);
+                 CREATE INDEX notes_body ON notes(body);
+                 CREATE VIEW note_bodies AS SELECT body FROM notes;",
+            )
+            .unwrap();
+        })
+        .unwrap();
+
+        let schema = doc.db_schema_sql().unwrap();
+        assert!(schema.contains("CREATE TABLE notes(id INTEGER PRIMARY KEY, body TEXT UNIQUE);\n"));
+        assert!(schema.contains("CREATE INDEX notes_body ON notes(body);\n"));
+        assert!(schema.contains("CREATE VIEW note_bodies"));
+        assert!(!schema.contains("sqlite_"));
+        assert!(schema.find("CREATE TABLE notes").unwrap() < schema.find("CREATE VIEW").unwrap());
+    }
+
+    #[test]
+    fn db_transaction_commits_or_rolls_back() {
+        let mut doc = sample_doc();
+        doc.db_transaction(|tx| {
+            tx.execute_batch("CREATE TABLE notes(body TEXT NOT NULL);")?;
+            tx.execute("INSERT INTO notes VALUES ('kept')", [])
+        })
+        .unwrap();
+
+        let err = doc.db_transaction(|tx| {
+            tx.execute("INSERT INTO notes VALUES ('discarded')", [])?;
+            tx.execute("INSERT INTO notes VALUES (NULL)", [])
+        });
+        assert!(matches!(err, Err(TmdError::Db(_))));
+
+        let count = doc
+            .db_with_conn(|conn| {
+                conn.query_row("SELECT COUNT(*) FROM notes", [], |row| row.get::<_, i64>(0))
+            })
+            .unwrap()
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn with_conn_retry_retries_busy_errors() {
+        let mut doc = sample_doc();
+        doc.db
+            .ensure_initialized(Some(DbOptions {
+                busy_timeout_ms: Some(50),
+                ..DbOptions::default()
+            }))
+            .unwrap();
+        let busy = || {
+            rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_BUSY),
+                None,
+            )
+        };
+
+        let mut attempts = 0;
+        let value = doc
+            .db
+            .with_conn_retry(3, |conn| {
+                attempts += 1;
+                if attempts < 3 {
+                    return Err(busy());
+                }
+                conn.query_row("SELECT 7", [], |row| row.get::<_, i64>(0))
+            })
+            .unwrap();
+        assert_eq!((value, attempts), (7, 3));
+
+        attempts = 0;
+        let err = doc.db.with_conn_retry(1, |_| -> rusqlite::Result<()> {
+            attempts += 1;
+            Err(busy())
+        });
+        assert!(matches!(err, Err(TmdError::Db(_))));
+        assert_eq!(attempts, 2);
+    }
+
+    #[test]
+    fn from_parts_assembles_a_document() {
+        let source = sample_doc();
+        let mut manifest = source.manifest_snapshot();
+        manifest.title = Some("Assembled".into());
+        let mut store = AttachmentStore::new();
+        let id = store
+            .insert(
+                Uuid::new_v4(),
+                "notes/a.txt".into(),
+                TEXT_PLAIN,
+                b"hi".to_vec(),
+            )
+            .unwrap();
+
+        let doc = TmdDoc::from_parts_with_empty_db("# Parts".into(), manifest, store).unwrap();
+        assert_eq!(doc.manifest.doc_id, source.manifest.doc_id);
+        let reread = TmdDoc::try_from(doc.to_bytes(Format::Tmdz).unwrap().as_slice()).unwrap();
+        assert_eq!(reread.manifest.title.as_deref(), Some("Assembled"));
+        assert_eq!(reread.attachment_bytes(id).unwrap().as_ref(), b"hi");
+
+        let db = source.db;
+        let doc = TmdDoc::from_parts("# Parts".into(), doc.manifest, AttachmentStore::new(), db);
+        assert_eq!(doc.db_user_version().unwrap(), 0);
+    }
+
+    #[test]
+    fn replace_attachment_if_checks_the_current_digest() {
+        let mut doc = build_doc_with_attachment();
+        let id = doc.attachment_meta_by_path("images/pixel.png").unwrap().id;
+        let seen: [u8; 32] = Sha256::digest([0u8, 1, 2, 3]).into();
+
+        assert!(doc.replace_attachment_if(id, seen, vec![9; 8]).unwrap());
+        let meta = doc.attachment_meta(id).unwrap();
+        assert_eq!(meta.length, 8);
+        assert_eq!(meta.sha256, Some(Sha256::digest([9u8; 8]).into()));
+
+        // The editor's view is now stale.
+        assert!(!doc.replace_attachment_if(id, seen, vec![7]).unwrap());
+        assert_eq!(doc.attachment_bytes(id).unwrap().as_ref(), &[9; 8]);
+
+        assert!(matches!(
+            doc.replace_attachment_if(Uuid::new_v4(), seen, vec![]),
+            Err(TmdError::NotFound(_))
+        ));
+
+        // Replacing with the same bytes succeeds without touching anything.
+        let current: [u8; 32] = Sha256::digest([9u8; 8]).into();
+        let before = (doc.manifest.modified_utc, doc.attachment_meta(id).cloned());
+        let later = doc.manifest.modified_utc + chrono::Duration::hours(1);
+        assert!(with_clock(FixedClock(later), || {
+            doc.replace_attachment_if(id, current, vec![9; 8])
+        })
+        .unwrap());
+        assert_eq!(
+            (doc.manifest.modified_utc, doc.attachment_meta(id).cloned()),
+            before
+        );
+    }
+
+    #[test]
+    fn snapshots_outlive_the_document() {
+        let doc = build_doc_with_attachment();
+        let expected_id = doc.manifest.doc_id;
+        let (metas, manifest) = (doc.attachment_snapshot(), doc.manifest_snapshot());
+        drop(doc);
+        assert_eq!(manifest.doc_id, expected_id);
+        assert_eq!(metas.len(), 1);
+        assert_eq!(metas[0].logical_path, "images/pixel.png");
+        assert_eq!(metas[0].length, 4);
+    }
+
+    #[test]
+    fn strip_db_leaves_an_empty_database() {
+        let mut doc = sample_doc();
+        doc.db_with_conn_mut(|conn| {
+            conn.execute_batch("CREATE TABLE secrets(v); PRAGMA user_version = 3;")
+                .unwrap();
+        })
+        .unwrap();
+        doc.sync_schema_version().unwrap();
+
+        doc.strip_db().unwrap();
+        assert_eq!(doc.manifest.db_schema_version, None);
+        let reread = TmdDoc::try_from(doc.to_bytes(Format::Tmd).unwrap().as_slice()).unwrap();
+        assert_eq!(reread.db_schema_sql().unwrap(), "");
+        assert_eq!(reread.db_user_version().unwrap(), 0);
+    }
+
+    #[test]
+    fn db_row_count_quotes_table_names() {
+        let mut doc = sample_doc();
+        doc.db_with_conn_mut(|conn| {
+            conn.execute_batch(
+                "CREATE TABLE \"select\"(v); INSERT INTO \"select\" VALUES (1), (2), (3);
+                 CREATE TABLE \"we\"\"ird\"(v); INSERT INTO \"we\"\"ird\" VALUES (1);",
+            )
+            .unwrap();
+        })
+        .unwrap();
+        assert_eq!(doc.db_row_count("select").unwrap(), 3);
+        assert_eq!(doc.db_row_count("we\"ird").unwrap(), 1);
+        assert!(doc.db_row_count("missing").is_err());
+    }
+
+    #[test]
+    fn db_integrity_check_reports_ok_database() {
+        let mut doc = sample_doc();
+        doc.db_with_conn_mut(|conn| {
+            conn.execute_batch("CREATE TABLE notes(body TEXT); INSERT INTO notes VALUES ('a');")
+                .unwrap();
+        })
+        .unwrap();
+        assert!(doc.db_integrity_check().unwrap().is_empty());
+    }
+
+    #[test]
+    fn db_integrity_check_reports_corrupted_pages() {
+        let mut doc = sample_doc();
+        doc.db_with_conn_mut(|conn| {
+            conn.execute_batch(
+                "PRAGMA page_size = 4096; VACUUM;
+                 CREATE TABLE notes(body TEXT);
+                 WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 500)
+                 INSERT INTO notes SELECT printf('note %04d with some padding text', i) FROM n;",
+            )
+            .unwrap();
+        })
+        .unwrap();
+        let mut bytes = std::fs::read(doc.db.as_path()).unwrap();
+        assert!(bytes.len() > 4 * 4096);
+        // Scribble over the cell area of a leaf page in the middle of the table.
+        let page = 3 * 4096;
+        bytes[page + 100..page + 4096].fill(0xA5);
+        doc.db = DbHandle::from_bytes(&bytes).unwrap();
+
+        let problems = doc.db_integrity_check().unwrap();
+        assert!(!problems.is_empty());
+    }
+
+    #[test]
+    fn db_query_each_streams_rows() {
+        let mut doc = sample_doc();
+        doc.db_with_conn_mut(|conn| {
+            conn.execute_batch(
+                "CREATE TABLE nums(n INTEGER);
+                 INSERT INTO nums VALUES (1), (2), (3);",
+            )
+            .unwrap();
+        })
+        .unwrap();
+
+        let mut seen = Vec::new();
+        doc.db_query_each("SELECT n FROM nums ORDER BY n", |row| {
+            seen.push(row.to_vec());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            seen,
+            vec![
+                vec![SqlCell::Integer(1)],
+                vec![SqlCell::Integer(2)],
+                vec![SqlCell::Integer(3)]
+            ]
+        );
+
+        let mut calls = 0;
+        let err = doc
+            .db_query_each("SELECT n FROM nums", |_| {
+                calls += 1;
+                Err(TmdError::Db("stop".into()))
+            })
+            .unwrap_err();
+        assert!(matches!(err, TmdError::Db(msg) if msg == "stop"));
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn sync_schema_version_repairs_manifest_drift() {
+        let mut doc = sample_doc();
+        doc.manifest.db_schema_version = Some(1);
+        doc.db_with_conn_mut(|conn| conn.pragma_update(None, "user_version", 5))
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(doc.sync_schema_version().expect("sync"), 5);
+        assert_eq!(doc.manifest.db_schema_version, Some(5));
+        assert_eq!(doc.db_user_version().unwrap(), 5);
+    }
+
     #[test]
     fn module_with_conn_helpers_work() {
         let mut doc = sample_doc();
@@ -1736,6 +9644,156 @@ mod tests {
         assert_eq!(loaded.list_attachments().count(), 1);
     }
 
+    #[cfg(feature = "image")]
+    #[test]
+    fn generate_thumbnail_scales_images_to_fit() {
+        let source = image::RgbImage::from_pixel(40, 20, image::Rgb([200, 10, 10]));
+        let mut png = Cursor::new(Vec::new());
+        source
+            .write_to(&mut png, image::ImageFormat::Png)
+            .expect("encode source");
+
+        let mut doc = sample_doc();
+        let id = doc
+            .add_attachment("images/wide.png", mime::IMAGE_PNG, png.into_inner())
+            .expect("add image");
+        let text = doc
+            .add_attachment("notes.txt", TEXT_PLAIN, b"hi".to_vec())
+            .expect("add text");
+
+        let thumb = doc.generate_thumbnail(id, 10).expect("thumbnail");
+        let decoded = image::load_from_memory(&thumb).expect("decode thumbnail");
+        assert_eq!((decoded.width(), decoded.height()), (10, 5));
+        assert!(matches!(
+            doc.generate_thumbnail(text, 10),
+            Err(TmdError::Attachment(_))
+        ));
+
+        let cached = doc.cache_thumbnail(id, 10).expect("cache");
+        assert_eq!(
+            doc.attachment_meta(cached).unwrap().logical_path,
+            "thumbnails/images/wide.png.png"
+        );
+        doc.cache_thumbnail(id, 8)
+            .expect("replace cached thumbnail");
+        assert_eq!(doc.list_attachments().count(), 3);
+    }
+
+    #[cfg(feature = "crypto")]
+    #[test]
+    fn encrypted_roundtrip_requires_passphrase() {
+        let mut doc = TmdDoc::new("# Secret".into()).unwrap();
+        doc.add_attachment("notes/key.txt", mime::TEXT_PLAIN, b"hunter2".to_vec())
+            .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secret.tmd");
+        write_to_path_encrypted(&path, &doc, Format::Tmdz, "correct horse").unwrap();
+
+        let raw = std::fs::read(&path).unwrap();
+        assert!(!raw.windows(7).any(|w| w == b"hunter2"));
+        let lazy = ReadMode {
+            lazy_attachments: true,
+            ..ReadMode::default()
+        };
+        for result in [
+            read_from_path(&path, None),
+            read_from_path(&path, Some(Format::Tmd)),
+            read_from_path(&path, Some(Format::Tmdz)),
+            read_from_path_with_mode(&path, Some(Format::Tmdz), lazy),
+            read_tmdz(&mut Cursor::new(raw.clone()), ReadMode::default()),
+        ] {
+            assert!(matches!(
+                result,
+                Err(TmdError::InvalidFormat(msg)) if msg.contains("encrypted")
+            ));
+        }
+        assert!(matches!(
+            read_from_path_encrypted(&path, "wrong"),
+            Err(TmdError::InvalidFormat(_))
+        ));
+
+        let loaded = read_from_path_encrypted(&path, "correct horse").unwrap();
+        assert!(loaded.content_eq(&doc));
+    }
+
+    #[test]
+    fn sha256_remains_the_default_digest() {
+        let mut doc = sample_doc();
+        let id = doc
+            .add_attachment("notes/a.txt", TEXT_PLAIN, b"abc".to_vec())
+            .unwrap();
+        let meta = doc.attachments.meta(id).unwrap();
+        assert_eq!(meta.hash_algorithm(), HashAlgorithm::Sha256);
+        assert!(meta.digest.is_none());
+        assert_eq!(
+            meta.sha256.as_ref().map(|sha| sha.to_vec()),
+            Some(Sha256::digest(b"abc").to_vec())
+        );
+        assert_eq!(
+            doc.set_hash_algorithm(HashAlgorithm::Blake3).is_ok(),
+            cfg!(feature = "blake3")
+        );
+    }
+
+    #[cfg(feature = "blake3")]
+    #[test]
+    fn blake3_digests_roundtrip_and_verify() {
+        let mut doc = sample_doc();
+        doc.set_hash_algorithm(HashAlgorithm::Blake3).unwrap();
+        let id = doc
+            .add_attachment("notes/a.txt", TEXT_PLAIN, b"abc".to_vec())
+            .unwrap();
+        let meta = doc.attachments.meta(id).unwrap();
+        assert!(meta.sha256.is_none());
+        assert_eq!(
+            meta.content_digest(),
+            Some((HashAlgorithm::Blake3, &blake3::hash(b"abc").as_bytes()[..]))
+        );
+
+        let mut buffer = Cursor::new(Vec::new());
+        write_tmdz(&mut buffer, &doc, WriteMode::default()).unwrap();
+        buffer.seek(SeekFrom::Start(0)).unwrap();
+        let mut reader = Reader::new(buffer, Some(Format::Tmdz), ReadMode::default()).unwrap();
+        let mut rebuilt = reader.read_doc().unwrap();
+        assert!(rebuilt.content_eq(&doc));
+        assert!(!rebuilt.validate_full().has_errors());
+
+        rebuilt.attachments.data_mut(id).unwrap().push(b'!');
+        let meta = rebuilt.attachments.meta(id).unwrap();
+        assert_eq!(meta.hash_algorithm(), HashAlgorithm::Blake3);
+        assert_eq!(
+            meta.content_digest().unwrap().1,
+            &blake3::hash(b"abc!").as_bytes()[..]
+        );
+    }
+
+    #[test]
+    fn unknown_manifest_fields_round_trip() {
+        let mut doc = sample_doc();
+        let mut value = serde_json::to_value(&doc.manifest).unwrap();
+        value["license"] = serde_json::json!("CC-BY-4.0");
+        doc.manifest = serde_json::from_value(value).unwrap();
+        assert_eq!(doc.manifest.unknown["license"], "CC-BY-4.0");
+
+        let reread = TmdDoc::try_from(doc.to_bytes(Format::Tmdz).unwrap().as_slice()).unwrap();
+        assert_eq!(reread.manifest.unknown["license"], "CC-BY-4.0");
+        let written = serde_json::to_value(&reread.manifest).unwrap();
+        assert_eq!(written["license"], "CC-BY-4.0");
+        assert!(written.get("unknown").is_none());
+    }
+
+    #[cfg(feature = "schemars")]
+    #[test]
+    fn manifest_json_schema_describes_fields() {
+        let schema = manifest_json_schema();
+        let manifest_props = &schema["manifest"]["properties"];
+        assert!(manifest_props["doc_id"].is_object());
+        assert!(manifest_props["language"].is_object());
+        let meta_props = &schema["attachment_meta"]["properties"];
+        assert!(meta_props["mime"]["type"] == "string");
+        assert!(meta_props["sha256"].is_object());
+    }
+
     #[cfg(feature = "ffi")]
     #[test]
     fn ffi_allows_null_markdown_pointers() {
@@ -1770,4 +9828,25 @@ mod tests {
             tmd_doc_free(doc);
         }
     }
+
+    #[cfg(feature = "ffi")]
+    #[test]
+    fn ffi_markdown_ptr_borrows_document_bytes() {
+        use crate::ffi::{tmd_doc_free, tmd_doc_markdown_ptr, tmd_doc_new};
+        use std::ffi::CString;
+        use std::ptr;
+
+        let source = CString::new("# Borrowed\nBody").unwrap();
+        unsafe {
+            let doc = tmd_doc_new(source.as_ptr());
+            let mut len = 0usize;
+            let data = tmd_doc_markdown_ptr(doc, &mut len);
+            assert!(!data.is_null());
+            assert_eq!(std::slice::from_raw_parts(data, len), b"# Borrowed\nBody");
+            assert_eq!(len, (*doc).markdown_len());
+
+            assert!(tmd_doc_markdown_ptr(doc, ptr::null_mut()).is_null());
+            tmd_doc_free(doc);
+        }
+    }
 }